@@ -0,0 +1,152 @@
+use meshtastic::protobufs::routing::{self, Variant};
+use meshtastic::protobufs::{FromRadio, PortNum, Routing, from_radio, mesh_packet};
+use prost::Message;
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{Instant, timeout_at};
+
+/// How many acknowledged packet IDs are remembered for a sender that hasn't started waiting yet
+const RECENT_ACKS: usize = 32;
+
+/// Acknowledgements for packets sent with `want_ack`, shared between the router that sees each
+/// packet go out, the task reading packets off the radio, and the sender waiting on a chunk
+pub static ACKS: LazyLock<AckTracker> = LazyLock::new(AckTracker::default);
+
+pub fn acks() -> &'static AckTracker {
+  &ACKS
+}
+
+#[derive(Debug, Default)]
+pub struct AckTracker {
+  state: Mutex<AckState>,
+  acked: Notify,
+}
+
+#[derive(Debug, Default)]
+struct AckState {
+  last_sent: Option<u32>,
+  recent: VecDeque<u32>,
+}
+
+impl AckTracker {
+  /// Records the ID of a packet that asked for an acknowledgement as it goes out
+  pub fn sent(&self, packet_id: u32) {
+    self.state().last_sent = Some(packet_id);
+  }
+
+  /// The ID of the last packet sent with `want_ack`
+  pub fn last_sent(&self) -> Option<u32> {
+    self.state().last_sent
+  }
+
+  pub fn acknowledge(&self, packet_id: u32) {
+    let mut state = self.state();
+    if state.recent.len() == RECENT_ACKS {
+      state.recent.pop_front();
+    }
+    state.recent.push_back(packet_id);
+    drop(state);
+    self.acked.notify_waiters();
+  }
+
+  /// Waits up to `timeout` for the packet to be acknowledged. Returns whether it was.
+  pub async fn wait_for(&self, packet_id: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+      let acked = self.acked.notified();
+      if self.state().recent.contains(&packet_id) {
+        return true;
+      }
+      if timeout_at(deadline, acked).await.is_err() {
+        return false;
+      }
+    }
+  }
+
+  fn state(&self) -> MutexGuard<'_, AckState> {
+    self
+      .state
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+/// The ID of the packet a radio message acknowledges, if it's a successful routing response.
+/// Routing responses with an error (e.g. `MAX_RETRANSMIT`) mean the packet didn't get through.
+pub fn acknowledged_packet(message: &FromRadio) -> Option<u32> {
+  let Some(from_radio::PayloadVariant::Packet(packet)) = &message.payload_variant else {
+    return None;
+  };
+  let Some(mesh_packet::PayloadVariant::Decoded(data)) = &packet.payload_variant else {
+    return None;
+  };
+  if data.portnum != PortNum::RoutingApp as i32 || data.request_id == 0 {
+    return None;
+  }
+
+  let routing = Routing::decode(data.payload.as_slice()).ok()?;
+  matches!(
+    routing.variant,
+    Some(Variant::ErrorReason(reason)) if reason == routing::Error::None as i32
+  )
+  .then_some(data.request_id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use meshtastic::protobufs::{Data, MeshPacket};
+
+  fn routing_response(request_id: u32, reason: routing::Error) -> FromRadio {
+    let routing = Routing {
+      variant: Some(Variant::ErrorReason(reason as i32)),
+    };
+    FromRadio {
+      payload_variant: Some(from_radio::PayloadVariant::Packet(MeshPacket {
+        payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+          portnum: PortNum::RoutingApp as i32,
+          payload: routing.encode_to_vec(),
+          request_id,
+          ..Default::default()
+        })),
+        ..Default::default()
+      })),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn successful_routing_response_acknowledges_the_request() {
+    let message = routing_response(7, routing::Error::None);
+    assert_eq!(acknowledged_packet(&message), Some(7));
+  }
+
+  #[test]
+  fn routing_error_is_not_an_ack() {
+    let message = routing_response(7, routing::Error::MaxRetransmit);
+    assert_eq!(acknowledged_packet(&message), None);
+  }
+
+  #[tokio::test]
+  async fn waiting_returns_once_the_packet_is_acknowledged() {
+    let tracker = AckTracker::default();
+    tracker.sent(7);
+    assert_eq!(tracker.last_sent(), Some(7));
+
+    let (acked, ()) = tokio::join!(tracker.wait_for(7, Duration::from_secs(5)), async {
+      tracker.acknowledge(3);
+      tokio::task::yield_now().await;
+      tracker.acknowledge(7);
+    });
+    assert!(acked);
+  }
+
+  #[tokio::test]
+  async fn waiting_gives_up_after_the_timeout() {
+    let tracker = AckTracker::default();
+    tracker.acknowledge(3);
+    assert!(!tracker.wait_for(7, Duration::from_millis(10)).await);
+  }
+}
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const AIRTIME_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Rolling one-hour accumulator of the bytes transmitted, used as an estimate of airtime so the
+/// bot can stay within a regional duty-cycle limit. A budget of `0` disables the limit.
+#[derive(Debug)]
+pub struct AirtimeBudget {
+  budget_bytes: usize,
+  sent: VecDeque<(Instant, usize)>,
+  notified: bool,
+}
+
+impl AirtimeBudget {
+  pub fn new(budget_bytes: usize) -> Self {
+    Self {
+      budget_bytes,
+      sent: VecDeque::new(),
+      notified: false,
+    }
+  }
+
+  /// Records `bytes` as sent and returns `true` if they fit in the remaining budget; otherwise
+  /// records nothing and returns `false`
+  pub fn try_consume(&mut self, bytes: usize) -> bool {
+    self.try_consume_at(bytes, Instant::now())
+  }
+
+  /// Records bytes that are sent regardless of the budget (e.g. the exhaustion notice itself)
+  pub fn record(&mut self, bytes: usize) {
+    self.record_at(bytes, Instant::now());
+  }
+
+  /// Returns `true` only for the first rejected reply after the budget is exhausted, so the
+  /// notice isn't repeated for every dropped reply
+  pub fn should_notify(&mut self) -> bool {
+    !std::mem::replace(&mut self.notified, true)
+  }
+
+  /// Time until the oldest transmission leaves the window and frees up budget
+  pub fn time_until_reset(&mut self) -> Duration {
+    self.time_until_reset_at(Instant::now())
+  }
+
+  /// The transmissions still counted against the budget, oldest first
+  pub fn sent(&self) -> impl Iterator<Item = (Instant, usize)> + '_ {
+    self.sent.iter().copied()
+  }
+
+  /// Counts previously recorded transmissions (e.g. from before a restart) against the budget
+  pub fn restore(&mut self, sent: impl IntoIterator<Item = (Instant, usize)>) {
+    for (sent_at, bytes) in sent {
+      self.record_at(bytes, sent_at);
+    }
+    self
+      .sent
+      .make_contiguous()
+      .sort_by_key(|(sent_at, _)| *sent_at);
+  }
+
+  fn try_consume_at(&mut self, bytes: usize, now: Instant) -> bool {
+    if self.budget_bytes == 0 {
+      return true;
+    }
+
+    if self.used_at(now) + bytes > self.budget_bytes {
+      return false;
+    }
+
+    self.record_at(bytes, now);
+    self.notified = false;
+    true
+  }
+
+  fn record_at(&mut self, bytes: usize, now: Instant) {
+    if self.budget_bytes > 0 {
+      self.sent.push_back((now, bytes));
+    }
+  }
+
+  fn used_at(&mut self, now: Instant) -> usize {
+    while self
+      .sent
+      .front()
+      .is_some_and(|(sent_at, _)| now.duration_since(*sent_at) >= AIRTIME_WINDOW)
+    {
+      self.sent.pop_front();
+    }
+
+    self.sent.iter().map(|(_, bytes)| bytes).sum()
+  }
+
+  fn time_until_reset_at(&mut self, now: Instant) -> Duration {
+    self.used_at(now);
+    self.sent.front().map_or(Duration::ZERO, |(sent_at, _)| {
+      AIRTIME_WINDOW.saturating_sub(now.duration_since(*sent_at))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn zero_budget_is_unlimited() {
+    let mut budget = AirtimeBudget::new(0);
+    assert!(budget.try_consume(usize::MAX));
+    assert!(budget.try_consume(usize::MAX));
+  }
+
+  #[test]
+  fn accumulates_sent_bytes() {
+    let mut budget = AirtimeBudget::new(500);
+    let now = Instant::now();
+    assert!(budget.try_consume_at(200, now));
+    assert!(budget.try_consume_at(200, now));
+    assert_eq!(budget.used_at(now), 400);
+  }
+
+  #[test]
+  fn rejects_sends_once_exhausted() {
+    let mut budget = AirtimeBudget::new(500);
+    let now = Instant::now();
+    assert!(budget.try_consume_at(400, now));
+    assert!(!budget.try_consume_at(200, now));
+    assert_eq!(budget.used_at(now), 400);
+    assert!(budget.try_consume_at(100, now));
+    assert!(!budget.try_consume_at(1, now));
+  }
+
+  #[test]
+  fn budget_frees_up_as_window_rolls_over() {
+    let mut budget = AirtimeBudget::new(500);
+    let start = Instant::now();
+    assert!(budget.try_consume_at(300, start));
+    assert!(budget.try_consume_at(200, start + Duration::from_secs(600)));
+    assert!(!budget.try_consume_at(100, start + Duration::from_secs(1200)));
+
+    assert!(budget.try_consume_at(300, start + AIRTIME_WINDOW));
+    assert_eq!(budget.used_at(start + AIRTIME_WINDOW), 500);
+  }
+
+  #[test]
+  fn time_until_reset_tracks_oldest_send() {
+    let mut budget = AirtimeBudget::new(500);
+    let start = Instant::now();
+    assert!(budget.try_consume_at(500, start));
+    assert_eq!(
+      budget.time_until_reset_at(start + Duration::from_secs(600)),
+      Duration::from_secs(3000)
+    );
+    assert_eq!(
+      budget.time_until_reset_at(start + AIRTIME_WINDOW),
+      Duration::ZERO
+    );
+  }
+
+  #[test]
+  fn notifies_once_per_exhaustion() {
+    let mut budget = AirtimeBudget::new(100);
+    let start = Instant::now();
+    assert!(budget.try_consume_at(100, start));
+    assert!(!budget.try_consume_at(10, start));
+    assert!(budget.should_notify());
+    assert!(!budget.should_notify());
+
+    assert!(budget.try_consume_at(10, start + AIRTIME_WINDOW));
+    assert!(!budget.try_consume_at(100, start + AIRTIME_WINDOW));
+    assert!(budget.should_notify());
+  }
+
+  #[test]
+  fn recorded_bytes_count_against_budget() {
+    let mut budget = AirtimeBudget::new(100);
+    let now = Instant::now();
+    budget.record_at(90, now);
+    assert!(!budget.try_consume_at(20, now));
+  }
+}
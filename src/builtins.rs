@@ -0,0 +1,689 @@
+use crate::command::{AliasResult, ResolveOptions, resolve_alias_with};
+use crate::config::{Command, Config, ConfigLoader, Validate};
+use crate::executor::retain_available_commands;
+use anyhow::Result;
+use std::fmt::Write;
+use std::mem;
+use std::path::Path;
+use tokio::task;
+
+pub const DEFAULT_MAINTENANCE_MESSAGE: &str =
+  "Under maintenance. Commands are temporarily disabled, please try again later.";
+
+/// Reserved commands handled by the server itself rather than the configured commands. All of
+/// them are admin-only except `!who`, which anyone can send once it's enabled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Builtin<'a> {
+  Maintenance(&'a str),
+  Errors(&'a str),
+  Config,
+  Who,
+  Explain(&'a str),
+  Log(&'a str),
+  Reload(&'a str),
+  Cancel,
+}
+
+impl<'a> Builtin<'a> {
+  /// Parses a built-in from a message whose command prefix has already been stripped
+  pub fn parse(input: &'a str) -> Option<Self> {
+    let (name, rest) = match input.split_once(char::is_whitespace) {
+      Some((name, rest)) => (name, rest.trim()),
+      None => (input, ""),
+    };
+
+    match name {
+      "maintenance" => Some(Builtin::Maintenance(rest)),
+      "errors" => Some(Builtin::Errors(rest)),
+      "config" => Some(Builtin::Config),
+      "who" => Some(Builtin::Who),
+      "explain" => Some(Builtin::Explain(rest)),
+      "log" => Some(Builtin::Log(rest)),
+      "reload" => Some(Builtin::Reload(rest)),
+      "cancel" => Some(Builtin::Cancel),
+      _ => None,
+    }
+  }
+
+  /// Whether the sender may run this built-in; otherwise the message is resolved as usual
+  pub fn is_available(&self, config: &Config, is_admin: bool) -> bool {
+    match self {
+      Builtin::Who => config.enable_who,
+      _ => is_admin,
+    }
+  }
+}
+
+/// This node's identity, used to answer `!who` and to tag replies. The names are filled in once
+/// the radio reports this node's info, until then only the node ID is known.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeIdentity {
+  num: u32,
+  short_name: String,
+  long_name: String,
+}
+
+impl NodeIdentity {
+  pub fn new(num: u32) -> Self {
+    Self {
+      num,
+      ..Self::default()
+    }
+  }
+
+  pub fn set_names(&mut self, short_name: &str, long_name: &str) {
+    self.short_name = short_name.trim().to_owned();
+    self.long_name = long_name.trim().to_owned();
+  }
+
+  /// The node ID in Meshtastic's `!xxxxxxxx` notation
+  pub fn id(&self) -> String {
+    format!("!{:08x}", self.num)
+  }
+
+  /// Short name if known, else the node ID
+  pub fn label(&self) -> String {
+    if self.short_name.is_empty() {
+      self.id()
+    } else {
+      self.short_name.clone()
+    }
+  }
+
+  /// Reply to `!who`, e.g. `BOT1 (Garage Pi) !a1b2c3d4`
+  pub fn describe(&self) -> String {
+    match (self.short_name.is_empty(), self.long_name.is_empty()) {
+      (true, true) => self.id(),
+      (false, true) => format!("{} {}", self.short_name, self.id()),
+      (true, false) => format!("{} {}", self.long_name, self.id()),
+      (false, false) => format!("{} ({}) {}", self.short_name, self.long_name, self.id()),
+    }
+  }
+}
+
+/// Summarizes the effective config for `!config`. Only operational settings are included; secrets,
+/// file paths and command definitions are never part of the reply.
+pub fn format_config_summary(config: &Config) -> String {
+  let help_chunk_delay = config.help_chunk_delay.unwrap_or(config.chunk_delay);
+
+  [
+    format!("Device: {}", config.node_address()),
+    format!("Channels: {}", config.channel_list()),
+    format!("Commands: {}", config.commands.len()),
+    format!("max_text_bytes: {}", config.max_text_bytes),
+    format!("chunk_delay: {}ms", config.chunk_delay),
+    format!("help_chunk_delay: {help_chunk_delay}ms"),
+    format!("max_command_bytes: {}", config.max_command_bytes),
+    format!("Admins: {}", config.admin_nodes.len()),
+  ]
+  .join("\n")
+}
+
+/// Reply to `!explain <alias...>`: what the server would do with the given message, without running
+/// it. Values of secrets are redacted from the command and its variables.
+pub fn format_explanation(config: &Config, alias: &str) -> String {
+  let options = ResolveOptions::from(config);
+  if alias.is_empty() {
+    return format!("Usage: {}explain <command...>", options.prefix);
+  }
+
+  let message = if alias.starts_with(options.prefix.as_str()) {
+    alias.to_owned()
+  } else {
+    format!("{}{alias}", options.prefix)
+  };
+
+  let explanation = match resolve_alias_with(&message, &config.commands, &options) {
+    Ok(AliasResult::Command { command, env, .. }) => {
+      let mut explanation = format!("Would run: {command}");
+      let mut vars: Vec<_> = env.iter().collect();
+      vars.sort();
+      for (name, value) in vars {
+        let _ = write!(explanation, "\n{name}={value}");
+      }
+      explanation
+    }
+    Ok(AliasResult::HelpText(text) | AliasResult::Reply(text)) => format!("Would reply:\n{text}"),
+    Err(e) => format!("Error: {e}"),
+  };
+
+  config.secrets.redact(&explanation)
+}
+
+/// Handles `!reload <file>`: re-reads one imported command file and splices its commands into
+/// `loaded_commands`, the full command tree as loaded from the config. Returns the config to run
+/// with from now on, whose commands are that tree after dropping the ones unavailable on this
+/// host, and how many commands the file defines. Nothing changes if the file or the resulting
+/// config is invalid. The `when` predicates run on a blocking thread, so a slow one doesn't stall
+/// the runtime; one that times out leaves its command unavailable.
+pub async fn reload_commands(
+  config: &Config,
+  config_path: &Path,
+  loaded_commands: &mut Vec<Command>,
+  file: &str,
+) -> Result<(Config, usize)> {
+  let mut commands = loaded_commands.clone();
+  let count =
+    ConfigLoader::for_config(config_path, config)?.reload_command_file(&mut commands, file)?;
+
+  let mut reloaded = Config {
+    commands,
+    ..config.clone()
+  };
+  reloaded.validate()?;
+
+  let commands = mem::take(&mut reloaded.commands);
+  let available = {
+    let (config, commands) = (reloaded.clone(), commands.clone());
+    task::spawn_blocking(move || retain_available_commands(&config, commands)).await?
+  };
+  *loaded_commands = commands;
+  reloaded.commands = available;
+  Ok((reloaded, count))
+}
+
+#[derive(Debug)]
+pub struct MaintenanceMode {
+  active: bool,
+  message: String,
+  allow_help: bool,
+}
+
+impl MaintenanceMode {
+  pub fn new(config: &Config) -> Self {
+    Self {
+      active: false,
+      message: config
+        .maintenance_message
+        .clone()
+        .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_owned()),
+      allow_help: !config.maintenance_block_help,
+    }
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.active
+  }
+
+  /// Applies a `!maintenance [on|off|status]` request and returns the reply for the admin. The
+  /// usage is shown with the configured command `prefix`.
+  pub fn toggle(&mut self, arg: &str, prefix: &str) -> String {
+    match arg {
+      "on" if self.active => "Maintenance mode is already on".to_owned(),
+      "on" => {
+        self.active = true;
+        "Maintenance mode enabled".to_owned()
+      }
+      "off" if !self.active => "Maintenance mode is already off".to_owned(),
+      "off" => {
+        self.active = false;
+        "Maintenance mode disabled".to_owned()
+      }
+      "" | "status" => format!(
+        "Maintenance mode is {}",
+        if self.active { "on" } else { "off" }
+      ),
+      _ => format!("Usage: {prefix}maintenance [on|off|status]"),
+    }
+  }
+
+  /// Returns the maintenance notice to reply with when the resolved alias must not be acted upon
+  pub fn gate(&self, resolution: &Result<AliasResult>) -> Option<&str> {
+    if !self.active {
+      return None;
+    }
+
+    match resolution {
+      Ok(AliasResult::HelpText(_)) if self.allow_help => None,
+      _ => Some(&self.message),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::load_config;
+  use anyhow::anyhow;
+  use std::collections::HashMap;
+
+  fn maintenance(allow_help: bool) -> MaintenanceMode {
+    MaintenanceMode {
+      active: false,
+      message: "down for upgrades".to_owned(),
+      allow_help,
+    }
+  }
+
+  fn command_result() -> Result<AliasResult> {
+    Ok(AliasResult::Command {
+      command: "echo hi".to_owned(),
+      env: HashMap::new(),
+      definition: Box::default(),
+    })
+  }
+
+  fn help_result() -> Result<AliasResult> {
+    Ok(AliasResult::HelpText("Commands:".to_owned()))
+  }
+
+  #[test]
+  fn parse_maintenance_with_argument() {
+    assert_eq!(
+      Builtin::parse("maintenance on"),
+      Some(Builtin::Maintenance("on"))
+    );
+  }
+
+  #[test]
+  fn parse_maintenance_without_argument() {
+    assert_eq!(
+      Builtin::parse("maintenance"),
+      Some(Builtin::Maintenance(""))
+    );
+  }
+
+  #[test]
+  fn parse_errors_with_and_without_count() {
+    assert_eq!(Builtin::parse("errors"), Some(Builtin::Errors("")));
+    assert_eq!(Builtin::parse("errors 3"), Some(Builtin::Errors("3")));
+  }
+
+  #[test]
+  fn parse_log_is_admin_only() {
+    assert_eq!(Builtin::parse("log"), Some(Builtin::Log("")));
+    assert_eq!(Builtin::parse("log 20"), Some(Builtin::Log("20")));
+    assert!(!Builtin::Log("").is_available(&Config::default(), false));
+    assert!(Builtin::Log("").is_available(&Config::default(), true));
+  }
+
+  #[test]
+  fn parse_config() {
+    assert_eq!(Builtin::parse("config"), Some(Builtin::Config));
+  }
+
+  #[test]
+  fn parse_cancel() {
+    assert_eq!(Builtin::parse("cancel"), Some(Builtin::Cancel));
+    assert!(!Builtin::Cancel.is_available(&Config::default(), false));
+    assert!(Builtin::Cancel.is_available(&Config::default(), true));
+  }
+
+  #[test]
+  fn config_summary_lists_key_settings() {
+    let config = Config {
+      device: "/dev/ttyUSB0".to_owned(),
+      baud: Some(115200),
+      channels: vec![2, 5],
+      max_text_bytes: 200,
+      chunk_delay: 1500,
+      max_command_bytes: 1024,
+      admin_nodes: vec![1, 2],
+      commands: vec![Command::default(), Command::default()],
+      ..Config::default()
+    };
+
+    assert_eq!(
+      format_config_summary(&config),
+      indoc::indoc! {"
+        Device: /dev/ttyUSB0 @ 115200 baud
+        Channels: 2, 5
+        Commands: 2
+        max_text_bytes: 200
+        chunk_delay: 1500ms
+        help_chunk_delay: 1500ms
+        max_command_bytes: 1024
+        Admins: 2"}
+    );
+  }
+
+  #[test]
+  fn config_summary_shows_tcp_connection() {
+    let config = Config {
+      connection: crate::config::Connection::Tcp {
+        host: "meshpi.local".to_owned(),
+        port: 4403,
+      },
+      ..Config::default()
+    };
+    assert!(format_config_summary(&config).starts_with("Device: tcp://meshpi.local:4403\n"));
+  }
+
+  #[test]
+  fn config_summary_excludes_secrets() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let secrets_path = dir.path().join("secrets.env");
+    std::fs::write(&secrets_path, "API_TOKEN=hunter2\n").unwrap();
+    let config = Config {
+      secrets_file: Some(secrets_path.clone()),
+      secrets: crate::secrets::Secrets::load(&secrets_path).unwrap(),
+      ..Config::default()
+    };
+
+    let summary = format_config_summary(&config);
+
+    assert!(!summary.contains("hunter2"));
+    assert!(!summary.contains("API_TOKEN"));
+    assert!(!summary.contains("secrets.env"));
+  }
+
+  #[test]
+  fn parse_who() {
+    assert_eq!(Builtin::parse("who"), Some(Builtin::Who));
+  }
+
+  #[test]
+  fn who_is_available_to_everyone_only_when_enabled() {
+    let disabled = Config::default();
+    let enabled = Config {
+      enable_who: true,
+      ..Config::default()
+    };
+
+    assert!(!Builtin::Who.is_available(&disabled, true));
+    assert!(Builtin::Who.is_available(&enabled, false));
+    assert!(!Builtin::Config.is_available(&enabled, false));
+    assert!(Builtin::Config.is_available(&disabled, true));
+  }
+
+  #[test]
+  fn identity_without_names_uses_node_id() {
+    let identity = NodeIdentity::new(0xa1b2c3d4);
+    assert_eq!(identity.describe(), "!a1b2c3d4");
+    assert_eq!(identity.label(), "!a1b2c3d4");
+  }
+
+  #[test]
+  fn identity_with_names() {
+    let mut identity = NodeIdentity::new(0x2a);
+    identity.set_names("BOT1", " Garage Pi ");
+    assert_eq!(identity.describe(), "BOT1 (Garage Pi) !0000002a");
+    assert_eq!(identity.label(), "BOT1");
+
+    identity.set_names("BOT1", "");
+    assert_eq!(identity.describe(), "BOT1 !0000002a");
+  }
+
+  #[test]
+  fn parse_reload() {
+    assert_eq!(
+      Builtin::parse("reload commands/net.yaml"),
+      Some(Builtin::Reload("commands/net.yaml"))
+    );
+    assert_eq!(Builtin::parse("reload"), Some(Builtin::Reload("")));
+  }
+
+  /// A config with an inline `ping` command that imports `net.yaml`, which defines `myip`
+  fn reload_tree() -> tempfile::TempDir {
+    let dir = tempfile::TempDir::new().unwrap();
+    let config = indoc::indoc! {"
+      device: /dev/ttyUSB0
+      channel: 1
+      shell: bash
+      max_text_bytes: 200
+      chunk_delay: 0
+      max_content_bytes: 180
+      commands:
+        - name: ping
+          command: echo pong
+        - import: net.yaml
+    "};
+    std::fs::write(dir.path().join("config.yaml"), config).unwrap();
+    std::fs::write(
+      dir.path().join("net.yaml"),
+      "name: myip\ncommand: curl ifconfig.me\n",
+    )
+    .unwrap();
+    dir
+  }
+
+  #[tokio::test]
+  async fn reload_splices_the_file_into_the_running_config() {
+    let dir = reload_tree();
+    let config_path = dir.path().join("config.yaml");
+    let config = load_config(&config_path).unwrap();
+    let mut loaded_commands = config.commands.clone();
+    let net = indoc::indoc! {"
+      - name: myip
+        command: curl icanhazip.com
+      - name: myip6
+        command: curl -6 icanhazip.com
+    "};
+    std::fs::write(dir.path().join("net.yaml"), net).unwrap();
+
+    let (reloaded, count) =
+      reload_commands(&config, &config_path, &mut loaded_commands, "net.yaml")
+        .await
+        .unwrap();
+
+    assert_eq!(count, 2);
+    let names: Vec<_> = reloaded.commands.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["ping", "myip", "myip6"]);
+    assert_eq!(reloaded.commands[1].command, "curl icanhazip.com");
+    assert_eq!(loaded_commands.len(), 3);
+  }
+
+  #[tokio::test]
+  async fn invalid_reload_changes_nothing() {
+    let dir = reload_tree();
+    let config_path = dir.path().join("config.yaml");
+    let config = load_config(&config_path).unwrap();
+    let mut loaded_commands = config.commands.clone();
+    std::fs::write(dir.path().join("net.yaml"), "name: ping\ncommand: true\n").unwrap();
+
+    let err = reload_commands(&config, &config_path, &mut loaded_commands, "net.yaml")
+      .await
+      .unwrap_err()
+      .to_string();
+
+    assert!(
+      err.contains("Duplicate command name"),
+      "unexpected error: {err}"
+    );
+    assert_eq!(loaded_commands[1].name, "myip");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn reload_leaves_out_commands_whose_when_predicate_fails() {
+    let dir = reload_tree();
+    let config_path = dir.path().join("config.yaml");
+    let config = load_config(&config_path).unwrap();
+    let mut loaded_commands = config.commands.clone();
+    let net = indoc::indoc! {"
+      - name: myip
+        command: curl icanhazip.com
+        when: \"false\"
+    "};
+    std::fs::write(dir.path().join("net.yaml"), net).unwrap();
+
+    let (reloaded, _) = reload_commands(&config, &config_path, &mut loaded_commands, "net.yaml")
+      .await
+      .unwrap();
+
+    let names: Vec<_> = reloaded.commands.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["ping"]);
+    assert_eq!(loaded_commands.len(), 2);
+  }
+
+  fn explain_config() -> Config {
+    let dir = tempfile::TempDir::new().unwrap();
+    let secrets_path = dir.path().join("secrets.env");
+    std::fs::write(&secrets_path, "API_TOKEN=hunter2\n").unwrap();
+    Config {
+      commands: vec![Command {
+        name: "deploy".to_owned(),
+        command: "deploy.sh \"$target\"".to_owned(),
+        args: vec![crate::config::Arg {
+          name: "target".to_owned(),
+          help: String::new(),
+          default: None,
+          greedy: false,
+          kind: crate::config::ArgType::String,
+          choices: Vec::new(),
+        }],
+        ..Command::default()
+      }],
+      secrets: crate::secrets::Secrets::load(&secrets_path).unwrap(),
+      verbose_errors: true,
+      prefix: "!".to_owned(),
+      ..Config::default()
+    }
+  }
+
+  #[test]
+  fn parse_explain() {
+    assert_eq!(
+      Builtin::parse("explain deploy prod"),
+      Some(Builtin::Explain("deploy prod"))
+    );
+    assert!(!Builtin::Explain("").is_available(&Config::default(), false));
+  }
+
+  #[test]
+  fn explain_valid_alias_shows_command_and_redacted_env() {
+    let config = explain_config();
+    assert_eq!(
+      format_explanation(&config, "deploy hunter2"),
+      "Would run: deploy.sh \"$target\"\ntarget=<redacted>"
+    );
+    assert_eq!(
+      format_explanation(&config, "!deploy prod"),
+      "Would run: deploy.sh \"$target\"\ntarget=prod"
+    );
+  }
+
+  #[test]
+  fn explain_invalid_alias_shows_error() {
+    let config = explain_config();
+    assert_eq!(
+      format_explanation(&config, "deploy"),
+      "Error: Missing required argument: target"
+    );
+    assert_eq!(
+      format_explanation(&config, "nope"),
+      "Error: Unknown command: !nope"
+    );
+  }
+
+  #[test]
+  fn explain_without_alias_replies_with_usage() {
+    assert_eq!(
+      format_explanation(&explain_config(), ""),
+      "Usage: !explain <command...>"
+    );
+  }
+
+  #[test]
+  fn parse_non_builtin_returns_none() {
+    assert_eq!(Builtin::parse("ping"), None);
+    assert_eq!(Builtin::parse("maintenances on"), None);
+  }
+
+  #[test]
+  fn new_uses_default_message_when_unset() {
+    let mode = MaintenanceMode::new(&Config::default());
+    assert!(!mode.is_active());
+    assert_eq!(mode.message, DEFAULT_MAINTENANCE_MESSAGE);
+    assert!(mode.allow_help);
+  }
+
+  #[test]
+  fn new_uses_configured_message_and_help_policy() {
+    let config = Config {
+      maintenance_message: Some("back soon".to_owned()),
+      maintenance_block_help: true,
+      ..Config::default()
+    };
+    let mode = MaintenanceMode::new(&config);
+    assert_eq!(mode.message, "back soon");
+    assert!(!mode.allow_help);
+  }
+
+  #[test]
+  fn toggle_on_then_off() {
+    let mut mode = maintenance(true);
+    assert_eq!(mode.toggle("on", "!"), "Maintenance mode enabled");
+    assert!(mode.is_active());
+    assert_eq!(mode.toggle("off", "!"), "Maintenance mode disabled");
+    assert!(!mode.is_active());
+  }
+
+  #[test]
+  fn toggle_on_when_already_on_is_noop() {
+    let mut mode = maintenance(true);
+    mode.toggle("on", "!");
+    assert_eq!(mode.toggle("on", "!"), "Maintenance mode is already on");
+    assert!(mode.is_active());
+  }
+
+  #[test]
+  fn toggle_off_when_already_off_is_noop() {
+    let mut mode = maintenance(true);
+    assert_eq!(mode.toggle("off", "!"), "Maintenance mode is already off");
+    assert!(!mode.is_active());
+  }
+
+  #[test]
+  fn toggle_status_reports_without_changing_state() {
+    let mut mode = maintenance(true);
+    assert_eq!(mode.toggle("", "!"), "Maintenance mode is off");
+    mode.toggle("on", "!");
+    assert_eq!(mode.toggle("status", "!"), "Maintenance mode is on");
+    assert!(mode.is_active());
+  }
+
+  #[test]
+  fn toggle_unknown_argument_returns_usage() {
+    let mut mode = maintenance(true);
+    assert!(mode.toggle("maybe", "!").starts_with("Usage:"));
+    assert!(!mode.is_active());
+  }
+
+  #[test]
+  fn toggle_usage_uses_the_configured_prefix() {
+    let mut mode = maintenance(true);
+    assert_eq!(
+      mode.toggle("maybe", "/"),
+      "Usage: /maintenance [on|off|status]"
+    );
+  }
+
+  #[test]
+  fn gate_inactive_allows_everything() {
+    let mode = maintenance(false);
+    assert_eq!(mode.gate(&command_result()), None);
+    assert_eq!(mode.gate(&help_result()), None);
+  }
+
+  #[test]
+  fn gate_active_blocks_commands() {
+    let mut mode = maintenance(true);
+    mode.toggle("on", "!");
+    assert_eq!(mode.gate(&command_result()), Some("down for upgrades"));
+  }
+
+  #[test]
+  fn gate_active_blocks_resolution_errors() {
+    let mut mode = maintenance(true);
+    mode.toggle("on", "!");
+    assert_eq!(
+      mode.gate(&Err(anyhow!("Unknown command: !nope"))),
+      Some("down for upgrades")
+    );
+  }
+
+  #[test]
+  fn gate_active_allows_help_when_configured() {
+    let mut mode = maintenance(true);
+    mode.toggle("on", "!");
+    assert_eq!(mode.gate(&help_result()), None);
+  }
+
+  #[test]
+  fn gate_active_blocks_help_when_configured() {
+    let mut mode = maintenance(false);
+    mode.toggle("on", "!");
+    assert_eq!(mode.gate(&help_result()), Some("down for upgrades"));
+  }
+}
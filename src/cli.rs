@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
-use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum, command};
+use clap::{ArgAction, Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use log::LevelFilter;
 
 #[derive(Parser, Debug)]
@@ -24,6 +25,15 @@ pub struct GlobalOpts {
   /// Specify the logging level
   #[arg(long, short, value_enum, default_value_t = LogLevel::Info, env = "MESHEXEC_LOG_LEVEL")]
   pub log_level: LogLevel,
+  /// Write logs to the log file in addition to the console (disable on read-only filesystems)
+  #[arg(long, action = ArgAction::Set, default_value_t = true, env = "MESHEXEC_LOG_TO_FILE")]
+  pub log_to_file: bool,
+  /// Label added to every log line to tell runners apart when their logs are aggregated
+  #[arg(long, env = "MESHEXEC_INSTANCE_NAME")]
+  pub instance_name: Option<String>,
+  /// Format of log lines: human-readable text, or one JSON object per line for log shippers
+  #[arg(long, value_enum, default_value_t = LogFormat::Text, env = "MESHEXEC_LOG_FORMAT")]
+  pub log_format: LogFormat,
 }
 
 #[derive(Subcommand, Debug)]
@@ -38,6 +48,22 @@ pub enum Commands {
   },
   /// Print the default config file path for this system
   ConfigPath,
+  /// Check that the config and its imports load and are valid, without connecting to a device
+  Validate,
+  /// Print a shell completion script to stdout
+  Completions {
+    /// The shell to generate completions for
+    #[arg(value_enum)]
+    shell: Shell,
+  },
+  /// Dry-run recorded messages against the config and print what would be run or replied
+  Replay {
+    /// File of recorded incoming messages, one per line
+    file: PathBuf,
+    /// Print resolved commands as shell-sourceable `export` lines followed by the command
+    #[arg(long)]
+    export: bool,
+  },
 }
 
 #[derive(ValueEnum, Debug, Clone, Copy)]
@@ -50,6 +76,13 @@ pub enum LogLevel {
   Trace,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+  #[default]
+  Text,
+  Json,
+}
+
 impl From<LogLevel> for LevelFilter {
   fn from(level: LogLevel) -> Self {
     match level {
@@ -2,16 +2,30 @@ use anyhow::{Result, anyhow};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::CharIndices;
 
-use crate::config::{Command, Flag};
+use crate::config::{
+  Arg, ArgType, Command, CommandSizePolicy, Config, DEFAULT_PREFIX, Flag, HelpSort,
+  SUBCOMMAND_PLACEHOLDER, parse_bool,
+};
+
+/// Ends a greedy arg or flag's value so that flags can still follow it
+const GREEDY_TERMINATOR: &str = "--";
+
+/// Variable that unknown flags are collected into for commands with `allow_unknown_flags`
+pub const EXTRA_FLAGS_VAR: &str = "extra_flags";
 
 #[derive(Debug)]
 pub enum AliasResult {
   Command {
     command: String,
     env: HashMap<String, String>,
+    definition: Box<Command>,
   },
   HelpText(String),
+  /// Sent back as-is without running anything, e.g. the `!echo` built-in
+  Reply(String),
 }
 
 #[derive(Debug)]
@@ -20,8 +34,32 @@ pub enum AliasError {
   MissingRequiredArg(String),
   MissingRequiredFlag(String),
   MissingFlagValue(String),
+  UnexpectedFlagValue(String),
   UnknownFlag(String),
-  TooManyArgs { expected: usize },
+  TooManyArgs {
+    expected: usize,
+  },
+  ConfirmationRequired {
+    command: String,
+    phrase: String,
+  },
+  CommandTooLong {
+    max_bytes: usize,
+  },
+  ValueTooLong {
+    name: String,
+    max_bytes: usize,
+  },
+  UnbalancedQuote(char),
+  InvalidBool {
+    name: String,
+    value: String,
+  },
+  InvalidChoice {
+    name: String,
+    value: String,
+    allowed: Vec<String>,
+  },
 }
 
 impl Display for AliasError {
@@ -31,135 +69,479 @@ impl Display for AliasError {
       AliasError::MissingRequiredArg(name) => write!(f, "Missing required argument: {name}"),
       AliasError::MissingRequiredFlag(name) => write!(f, "Missing required flag: {name}"),
       AliasError::MissingFlagValue(name) => write!(f, "Flag {name} requires a value"),
+      AliasError::UnexpectedFlagValue(name) => write!(f, "Flag {name} doesn't take a value"),
       AliasError::UnknownFlag(name) => write!(f, "Unknown flag: {name}"),
       AliasError::TooManyArgs { expected } => {
         write!(f, "Too many arguments (expected {expected})")
       }
+      AliasError::ConfirmationRequired { command, phrase } => write!(
+        f,
+        "{command} requires confirmation. Resend it ending with: {phrase}"
+      ),
+      AliasError::CommandTooLong { max_bytes } => {
+        write!(f, "Command too long (max {max_bytes} bytes)")
+      }
+      AliasError::ValueTooLong { name, max_bytes } => {
+        write!(f, "Value for {name} too long (max {max_bytes} bytes)")
+      }
+      AliasError::UnbalancedQuote(quote) => write!(f, "Missing closing {quote} quote"),
+      AliasError::InvalidBool { name, value } => write!(
+        f,
+        "Invalid value for {name}: {value} (expected true/false, yes/no or 1/0)"
+      ),
+      AliasError::InvalidChoice {
+        name,
+        value,
+        allowed,
+      } => write!(
+        f,
+        "Invalid value for {name}: {value} (expected one of: {})",
+        allowed.join(", ")
+      ),
+    }
+  }
+}
+
+/// Server-wide settings that change how incoming messages are matched against the commands
+#[derive(Debug, Clone)]
+pub struct ResolveOptions {
+  pub flag_case_insensitive: bool,
+  pub strip_embedded_prefix: bool,
+  pub bool_true: String,
+  pub bool_false: String,
+  pub help_sort: HelpSort,
+  pub enable_echo: bool,
+  pub verbose_errors: bool,
+  pub prefix: String,
+}
+
+impl Default for ResolveOptions {
+  fn default() -> Self {
+    Self {
+      flag_case_insensitive: false,
+      strip_embedded_prefix: false,
+      bool_true: "true".to_owned(),
+      bool_false: "false".to_owned(),
+      help_sort: HelpSort::default(),
+      enable_echo: false,
+      verbose_errors: true,
+      prefix: DEFAULT_PREFIX.to_owned(),
+    }
+  }
+}
+
+impl From<&Config> for ResolveOptions {
+  fn from(config: &Config) -> Self {
+    Self {
+      flag_case_insensitive: config.flag_case_insensitive,
+      strip_embedded_prefix: config.strip_embedded_prefix,
+      bool_true: config.bool_true.clone(),
+      bool_false: config.bool_false.clone(),
+      help_sort: config.help_sort,
+      enable_echo: config.enable_echo,
+      verbose_errors: config.verbose_errors,
+      prefix: config.prefix.clone(),
+    }
+  }
+}
+
+/// Applies the `max_command_bytes` limit to an incoming message, either rejecting it or cutting it
+/// down to the longest prefix that fits on a character boundary
+pub fn enforce_command_size(
+  message: &str,
+  max_bytes: usize,
+  policy: CommandSizePolicy,
+) -> Result<&str> {
+  if message.len() <= max_bytes {
+    return Ok(message);
+  }
+
+  match policy {
+    CommandSizePolicy::Reject => Err(anyhow!(AliasError::CommandTooLong { max_bytes })),
+    CommandSizePolicy::Truncate => {
+      let mut end = max_bytes;
+      while !message.is_char_boundary(end) {
+        end -= 1;
+      }
+      Ok(message[..end].trim_end())
     }
   }
 }
 
 pub fn resolve_alias(message: &str, commands: &[Command]) -> Result<AliasResult> {
-  let rest = &message[1..];
+  resolve_alias_with(message, commands, &ResolveOptions::default())
+}
+
+pub fn resolve_alias_with(
+  message: &str,
+  commands: &[Command],
+  options: &ResolveOptions,
+) -> Result<AliasResult> {
+  let prefix = options.prefix.as_str();
+  let rest = message.strip_prefix(prefix).unwrap_or(message);
 
   if rest == "help" {
-    return Ok(AliasResult::HelpText(format_help_listing(commands, "!")));
+    return Ok(AliasResult::HelpText(format_help_listing(
+      commands,
+      prefix,
+      options.help_sort,
+    )));
+  }
+
+  if options.enable_echo
+    && let Some(text) = echo_text(rest)
+    && !commands.iter().any(|c| match_command(rest, c).is_some())
+  {
+    let reply = if text.is_empty() {
+      format!("Usage: {prefix}echo <text>")
+    } else {
+      text.to_owned()
+    };
+    return Ok(AliasResult::Reply(reply));
   }
 
-  resolve_from(rest, commands, "!")
+  resolve_from(rest, commands, prefix, options)
 }
 
-fn resolve_from(input: &str, commands: &[Command], prefix: &str) -> Result<AliasResult> {
-  let mut sorted: Vec<&Command> = commands.iter().collect();
-  sorted.sort_by(|a, b| b.name.len().cmp(&a.name.len()));
+/// Text to send back for the `!echo` built-in, which a configured `echo` command overrides
+fn echo_text(input: &str) -> Option<&str> {
+  match input.strip_prefix("echo")? {
+    "" => Some(""),
+    text if text.starts_with(char::is_whitespace) => Some(text.trim()),
+    _ => None,
+  }
+}
 
-  let (cmd, args_str) = sorted
+fn resolve_from(
+  input: &str,
+  commands: &[Command],
+  prefix: &str,
+  options: &ResolveOptions,
+) -> Result<AliasResult> {
+  let (cmd, args_str) = commands
     .iter()
-    .find_map(|c| match_command(input, c))
+    .filter_map(|c| match_command(input, c))
+    .max_by_key(|(_, _, name)| name.len())
+    .map(|(cmd, args_str, _)| (cmd, args_str))
     .ok_or_else(|| {
       let first_word = input.split_whitespace().next().unwrap_or(input);
-      anyhow!(AliasError::UnknownAlias(format!("{prefix}{first_word}")))
+      unknown_alias(prefix, first_word, options)
     })?;
 
-  let is_group = !cmd.commands.is_empty();
+  let is_group = cmd.is_group();
   let new_prefix = format!("{prefix}{} ", cmd.name);
 
   if is_group {
     if args_str.is_empty() {
-      return Ok(AliasResult::HelpText(format_group_help(cmd, prefix)));
+      return Ok(AliasResult::HelpText(format_group_help(
+        cmd,
+        prefix,
+        options.help_sort,
+      )));
     }
 
     let trimmed = args_str.trim();
     if trimmed == "--help" || trimmed == "-h" {
-      return Ok(AliasResult::HelpText(format_group_help(cmd, prefix)));
+      return Ok(AliasResult::HelpText(format_group_help(
+        cmd,
+        prefix,
+        options.help_sort,
+      )));
     }
 
-    return resolve_from(args_str, &cmd.commands, &new_prefix);
+    let is_explicit = cmd
+      .commands
+      .iter()
+      .any(|subcommand| match_command(args_str, subcommand).is_some());
+    if !is_explicit && let Some(template) = cmd.command_template.as_deref() {
+      let templated = templated_subcommand(args_str, template, &new_prefix, options)?;
+      return resolve_from(args_str, &[templated], &new_prefix, options);
+    }
+
+    return resolve_from(args_str, &cmd.commands, &new_prefix, options);
   }
 
-  let tokens: Vec<&str> = if args_str.is_empty() {
-    Vec::new()
-  } else {
-    args_str.split_whitespace().collect()
-  };
+  let tokens = tokenize(args_str);
 
-  if tokens.iter().any(|t| *t == "-h" || *t == "--help") {
+  if tokens
+    .iter()
+    .any(|t| t.value == "-h" || t.value == "--help")
+  {
     return Ok(AliasResult::HelpText(format_command_help(cmd, prefix)));
   }
 
-  let env = parse_tokens(&tokens, cmd)?;
+  let tokens = match cmd.confirm_phrase.as_deref() {
+    Some(phrase) => strip_confirm_phrase(&tokens, phrase).ok_or_else(|| {
+      anyhow!(AliasError::ConfirmationRequired {
+        command: format!("{prefix}{}", cmd.name),
+        phrase: phrase.trim().to_owned(),
+      })
+    })?,
+    None => &tokens,
+  };
+
+  let env = parse_tokens(args_str, tokens, cmd, options)?;
+
+  let command = if cmd.steps.is_empty() {
+    cmd.command.clone()
+  } else if cmd.pipe_steps {
+    cmd.steps.join(" | ")
+  } else {
+    cmd.steps.join(" && ")
+  };
 
   Ok(AliasResult::Command {
-    command: cmd.command.clone(),
+    command,
     env,
+    definition: Box::new((*cmd).clone()),
   })
 }
 
-fn match_command<'a>(input: &'a str, cmd: &'a Command) -> Option<(&'a Command, &'a str)> {
-  if input == cmd.name {
-    Some((cmd, ""))
-  } else if input.starts_with(&cmd.name) && input.as_bytes().get(cmd.name.len()) == Some(&b' ') {
-    Some((cmd, input[cmd.name.len()..].trim()))
+/// Matches the start of `input` against the command's name and aliases, returning the command, the
+/// rest of the input, and the (longest) name that matched
+fn match_command<'a>(input: &'a str, cmd: &'a Command) -> Option<(&'a Command, &'a str, &'a str)> {
+  cmd
+    .names()
+    .filter_map(|name| {
+      if input == name {
+        Some((cmd, "", name))
+      } else if input.starts_with(name) && input.as_bytes().get(name.len()) == Some(&b' ') {
+        Some((cmd, input[name.len()..].trim(), name))
+      } else {
+        None
+      }
+    })
+    .max_by_key(|(_, _, name)| name.len())
+}
+
+/// The command's name as shown in help listings, with any aliases after it: `status (stat, st)`
+fn help_name(cmd: &Command) -> String {
+  if cmd.aliases.is_empty() {
+    cmd.name.clone()
+  } else {
+    format!("{} ({})", cmd.name, cmd.aliases.join(", "))
+  }
+}
+
+/// The error for an unknown command: its full path (`!a b zzz`) with `verbose_errors`, else just
+/// the unknown token (`zzz`)
+fn unknown_alias(prefix: &str, name: &str, options: &ResolveOptions) -> anyhow::Error {
+  if options.verbose_errors {
+    anyhow!(AliasError::UnknownAlias(format!("{prefix}{name}")))
   } else {
-    None
+    anyhow!(AliasError::UnknownAlias(name.to_owned()))
+  }
+}
+
+/// Builds the leaf for a subcommand that isn't explicitly defined in a templated group. The name is
+/// substituted straight into the shell command, so only plain names are accepted, and they must
+/// start with a letter or digit so one can't pass as an option (`--force`) or a path (`..`).
+fn templated_subcommand(
+  input: &str,
+  template: &str,
+  prefix: &str,
+  options: &ResolveOptions,
+) -> Result<Command> {
+  let name = input.split_whitespace().next().unwrap_or(input);
+  let is_plain = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+  if !is_plain {
+    return Err(unknown_alias(prefix, name, options));
+  }
+
+  Ok(Command {
+    name: name.to_owned(),
+    command: template.replace(SUBCOMMAND_PLACEHOLDER, name),
+    ..Command::default()
+  })
+}
+
+/// Returns the tokens preceding the confirmation phrase, or `None` if the message doesn't end with
+/// the exact phrase
+fn strip_confirm_phrase<'a>(tokens: &'a [Token], phrase: &str) -> Option<&'a [Token]> {
+  let phrase_tokens: Vec<&str> = phrase.split_whitespace().collect();
+  let start = tokens.len().checked_sub(phrase_tokens.len())?;
+  tokens[start..]
+    .iter()
+    .map(|token| token.value.as_str())
+    .eq(phrase_tokens)
+    .then(|| &tokens[..start])
+}
+
+/// A word of a command's arguments with its quotes and escapes removed, and where in the input it
+/// was typed, so a greedy value can be taken as typed
+struct Token {
+  value: String,
+  span: Range<usize>,
+  /// A quote that's never closed, kept in `value` as a literal character
+  unclosed_quote: Option<char>,
+}
+
+impl Token {
+  /// The token's value, which can't be used if it has an unclosed quote
+  fn value(&self) -> Result<&str> {
+    match self.unclosed_quote {
+      Some(quote) => Err(anyhow!(AliasError::UnbalancedQuote(quote))),
+      None => Ok(&self.value),
+    }
+  }
+}
+
+/// Splits the arguments into tokens the way a shell would: whitespace separates tokens except
+/// within quotes, single quotes keep everything literal, and a backslash escapes the next
+/// character (within double quotes, only `"` and `\`). A quote that's never closed is kept as
+/// is, and only an error if its token is used as a value (see [`Token::value`]).
+fn tokenize(input: &str) -> Vec<Token> {
+  let mut tokens = Vec::new();
+  let mut current: Option<Token> = None;
+  let mut chars = input.char_indices();
+
+  while let Some((idx, c)) = chars.next() {
+    if c.is_whitespace() {
+      if let Some(mut token) = current.take() {
+        token.span.end = idx;
+        tokens.push(token);
+      }
+      continue;
+    }
+
+    let token = current.get_or_insert_with(|| Token {
+      value: String::new(),
+      span: idx..input.len(),
+      unclosed_quote: None,
+    });
+    match c {
+      '\'' | '"' => {
+        let after_quote = chars.clone();
+        let value_len = token.value.len();
+        if !read_quoted(&mut chars, c, &mut token.value) {
+          token.value.truncate(value_len);
+          token.value.push(c);
+          token.unclosed_quote.get_or_insert(c);
+          chars = after_quote;
+        }
+      }
+      '\\' => token.value.push(chars.next().map_or('\\', |(_, c)| c)),
+      c => token.value.push(c),
+    }
   }
+
+  tokens.extend(current);
+  tokens
+}
+
+/// Reads up to the closing `quote`, adding what's quoted to `value`. Returns whether the quote was
+/// closed.
+fn read_quoted(chars: &mut CharIndices, quote: char, value: &mut String) -> bool {
+  while let Some((_, c)) = chars.next() {
+    match c {
+      c if c == quote => return true,
+      '\\' if quote == '"' => match chars.next() {
+        Some((_, c @ ('"' | '\\'))) => value.push(c),
+        Some((_, c)) => {
+          value.push('\\');
+          value.push(c);
+        }
+        None => return false,
+      },
+      c => value.push(c),
+    }
+  }
+
+  false
 }
 
-fn parse_tokens(tokens: &[&str], cmd: &Command) -> Result<HashMap<String, String>> {
+fn parse_tokens(
+  input: &str,
+  tokens: &[Token],
+  cmd: &Command,
+  options: &ResolveOptions,
+) -> Result<HashMap<String, String>> {
   let mut vars = HashMap::new();
+  let mut extra_flags = Vec::new();
   let mut positional_idx = 0;
   let mut i = 0;
 
   while i < tokens.len() {
-    let token = tokens[i];
+    let token = tokens[i].value.as_str();
 
     if token.starts_with('-') {
-      let flag = find_flag(token, &cmd.flags)
-        .ok_or_else(|| anyhow!(AliasError::UnknownFlag(token.to_string())))?;
-
-      if let Some(arg_name) = flag.arg.as_ref() {
-        i += 1;
-        if flag.greedy {
-          if i >= tokens.len() {
-            return Err(anyhow!(AliasError::MissingFlagValue(flag.long.clone())));
+      let (name, inline_value) = match token.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (token, None),
+      };
+      i = match find_flag(name, &cmd.flags, options.flag_case_insensitive) {
+        Some(flag) => apply_flag(flag, inline_value, input, tokens, i, &mut vars, options)?,
+        None
+          if is_short_bundle(token)
+            && !(cmd.allow_unknown_flags && has_unknown_short(token, cmd, options)) =>
+        {
+          apply_short_bundle(token, cmd, input, tokens, i, &mut vars, options)?
+        }
+        None if cmd.allow_unknown_flags => {
+          extra_flags.push(tokens[i].value()?);
+          // Without `=`, the next word is taken as the flag's value unless it could be an arg
+          match tokens.get(i + 1) {
+            Some(value)
+              if inline_value.is_none()
+                && !value.value.starts_with('-')
+                && positional_idx >= cmd.args.len() =>
+            {
+              extra_flags.push(value.value()?);
+              i + 2
+            }
+            _ => i + 1,
           }
-          let value = tokens[i..].join(" ");
-          vars.insert(arg_name.clone(), value);
-          break;
         }
-        let value = tokens
-          .get(i)
-          .ok_or_else(|| anyhow!(AliasError::MissingFlagValue(flag.long.clone())))?;
-        vars.insert(arg_name.clone(), value.to_string());
-      } else {
-        let var_name = flag.long.trim_start_matches('-').replace('-', "_");
-        vars.insert(var_name, "true".to_string());
-      }
-    } else {
-      if positional_idx >= cmd.args.len() {
-        return Err(anyhow!(AliasError::TooManyArgs {
-          expected: cmd.args.len(),
-        }));
-      }
-      let arg = &cmd.args[positional_idx];
-      let var_name = arg.name.replace('-', "_");
-      if arg.greedy {
-        let value = tokens[i..].join(" ");
-        vars.insert(var_name, value);
-        positional_idx = cmd.args.len();
-        break;
-      }
-      vars.insert(var_name, token.to_string());
-      positional_idx += 1;
+        None => return Err(anyhow!(AliasError::UnknownFlag(name.to_string()))),
+      };
+      continue;
     }
 
+    if positional_idx >= cmd.args.len() {
+      return Err(anyhow!(AliasError::TooManyArgs {
+        expected: cmd.args.len(),
+      }));
+    }
+    let arg = &cmd.args[positional_idx];
+    let var_name = arg.name.replace('-', "_");
+    if arg.greedy {
+      let end = greedy_end(tokens, i);
+      let value = greedy_value(typed_text(input, &tokens[i..end]), options);
+      check_choice(&arg.name, &arg.choices, &value)?;
+      vars.insert(var_name, value);
+      positional_idx = cmd.args.len();
+      i = end + 1;
+      continue;
+    }
+    vars.insert(var_name, arg_value(arg, tokens[i].value()?, options)?);
+    positional_idx += 1;
     i += 1;
   }
 
+  if cmd.allow_unknown_flags {
+    vars.insert(EXTRA_FLAGS_VAR.to_owned(), extra_flags.join(" "));
+  }
+
+  if let Some(max_bytes) = cmd.max_value_bytes
+    && let Some(name) = vars
+      .iter()
+      .filter(|(_, value)| value.len() > max_bytes)
+      .map(|(name, _)| name)
+      .min()
+  {
+    return Err(anyhow!(AliasError::ValueTooLong {
+      name: name.clone(),
+      max_bytes,
+    }));
+  }
+
   for arg in cmd.args.iter().skip(positional_idx) {
     let var_name = arg.name.replace('-', "_");
     if let Some(default) = arg.default.as_ref() {
-      vars.insert(var_name, default.clone());
+      vars.insert(var_name, arg_value(arg, default, options)?);
     } else {
       return Err(anyhow!(AliasError::MissingRequiredArg(arg.name.clone())));
     }
@@ -174,7 +556,12 @@ fn parse_tokens(tokens: &[&str], cmd: &Command) -> Result<HashMap<String, String
 
     if let Entry::Vacant(e) = vars.entry(var_name) {
       if let Some(default) = flag.default.as_ref() {
-        e.insert(default.clone());
+        let value = match default.as_str() {
+          "true" if flag.arg.is_none() => options.bool_true.clone(),
+          "false" if flag.arg.is_none() => options.bool_false.clone(),
+          _ => default.clone(),
+        };
+        e.insert(value);
       } else if flag.required {
         return Err(anyhow!(AliasError::MissingRequiredFlag(flag.long.clone())));
       }
@@ -184,16 +571,231 @@ fn parse_tokens(tokens: &[&str], cmd: &Command) -> Result<HashMap<String, String
   Ok(vars)
 }
 
-fn find_flag<'a>(token: &str, flags: &'a [Flag]) -> Option<&'a Flag> {
-  flags
+/// The value an arg's variable is set to: as given, or for a `bool` arg, `bool_true`/`bool_false`
+fn arg_value(arg: &Arg, value: &str, options: &ResolveOptions) -> Result<String> {
+  match (arg.kind, parse_bool(value)) {
+    (ArgType::String, _) => {
+      check_choice(&arg.name, &arg.choices, value)?;
+      Ok(value.to_owned())
+    }
+    (ArgType::Bool, Some(true)) => Ok(options.bool_true.clone()),
+    (ArgType::Bool, Some(false)) => Ok(options.bool_false.clone()),
+    (ArgType::Bool, None) => Err(anyhow!(AliasError::InvalidBool {
+      name: arg.name.clone(),
+      value: value.to_owned(),
+    })),
+  }
+}
+
+/// Sets the variable for the flag at `tokens[i]`, taking its value from `inline_value` (`--flag=value`)
+/// or the following token(s). Returns the index of the next token to parse.
+fn apply_flag(
+  flag: &Flag,
+  inline_value: Option<&str>,
+  input: &str,
+  tokens: &[Token],
+  i: usize,
+  vars: &mut HashMap<String, String>,
+  options: &ResolveOptions,
+) -> Result<usize> {
+  let Some(arg_name) = flag.arg.as_ref() else {
+    if inline_value.is_some() {
+      return Err(anyhow!(AliasError::UnexpectedFlagValue(flag.long.clone())));
+    }
+    let var_name = flag.long.trim_start_matches('-').replace('-', "_");
+    vars.insert(var_name, options.bool_true.clone());
+    return Ok(i + 1);
+  };
+
+  let start = i + 1;
+  if flag.greedy {
+    let end = greedy_end(tokens, start);
+    let value = match inline_value {
+      Some(value) => {
+        let rest = typed_text(input, &tokens[i..end]);
+        let rest = &rest[tokens[i].span.len()..];
+        greedy_value(&format!("{value}{rest}"), options)
+      }
+      None if end == start => {
+        return Err(anyhow!(AliasError::MissingFlagValue(flag.long.clone())));
+      }
+      None => greedy_value(typed_text(input, &tokens[start..end]), options),
+    };
+    set_flag_value(flag, arg_name, value, vars)?;
+    return Ok(end + 1);
+  }
+
+  match inline_value {
+    Some(value) => {
+      tokens[i].value()?;
+      set_flag_value(flag, arg_name, value.to_string(), vars)?;
+      Ok(start)
+    }
+    None => {
+      let value = tokens
+        .get(start)
+        .ok_or_else(|| anyhow!(AliasError::MissingFlagValue(flag.long.clone())))?
+        .value()?;
+      set_flag_value(flag, arg_name, value.to_string(), vars)?;
+      Ok(start + 1)
+    }
+  }
+}
+
+/// Sets a flag's variable, once the value is checked against its `choices`. Each repeat of a
+/// `multiple` flag adds its value to the ones given so far, joined by its separator; for other
+/// flags the last value wins.
+fn set_flag_value(
+  flag: &Flag,
+  var_name: &str,
+  value: String,
+  vars: &mut HashMap<String, String>,
+) -> Result<()> {
+  check_choice(&flag.long, &flag.choices, &value)?;
+  match vars.get_mut(var_name) {
+    Some(values) if flag.multiple => {
+      values.push_str(flag.separator());
+      values.push_str(&value);
+    }
+    _ => {
+      vars.insert(var_name.to_owned(), value);
+    }
+  }
+
+  Ok(())
+}
+
+/// Without `choices` any value is accepted
+fn check_choice(name: &str, choices: &[String], value: &str) -> Result<()> {
+  if choices.is_empty() || choices.iter().any(|choice| choice == value) {
+    return Ok(());
+  }
+
+  Err(anyhow!(AliasError::InvalidChoice {
+    name: name.to_owned(),
+    value: value.to_owned(),
+    allowed: choices.to_vec(),
+  }))
+}
+
+/// Whether a token that isn't a known flag looks like several short flags bundled together
+/// (`-vfx`, or `-vo/tmp` where `-o` takes a value)
+fn is_short_bundle(token: &str) -> bool {
+  token.len() > 2
+    && !token.starts_with("--")
+    && token[1..].starts_with(|c: char| c.is_ascii_alphabetic())
+}
+
+/// Whether a short flag bundle contains a letter that isn't one of the command's flags, before any
+/// flag that takes the rest of the bundle as its value
+fn has_unknown_short(token: &str, cmd: &Command, options: &ResolveOptions) -> bool {
+  for c in token[1..].chars() {
+    match find_flag(&format!("-{c}"), &cmd.flags, options.flag_case_insensitive) {
+      None => return true,
+      Some(flag) if flag.arg.is_some() => return false,
+      Some(_) => {}
+    }
+  }
+
+  false
+}
+
+/// Applies each short flag in a bundle like `-vfx` in turn. The first flag that takes a value ends
+/// the bundle: the rest of the token (after an optional `=`) is its value, or the next token if
+/// it's the last letter.
+fn apply_short_bundle(
+  token: &str,
+  cmd: &Command,
+  input: &str,
+  tokens: &[Token],
+  i: usize,
+  vars: &mut HashMap<String, String>,
+  options: &ResolveOptions,
+) -> Result<usize> {
+  let shorts = &token[1..];
+  for (offset, c) in shorts.char_indices() {
+    let short = format!("-{c}");
+    let flag = find_flag(&short, &cmd.flags, options.flag_case_insensitive)
+      .ok_or_else(|| anyhow!(AliasError::UnknownFlag(short.clone())))?;
+    let rest = &shorts[offset + c.len_utf8()..];
+
+    if flag.arg.is_some() {
+      let value = (!rest.is_empty()).then(|| rest.strip_prefix('=').unwrap_or(rest));
+      return apply_flag(flag, value, input, tokens, i, vars, options);
+    }
+    if let Some(value) = rest.strip_prefix('=') {
+      return apply_flag(flag, Some(value), input, tokens, i, vars, options);
+    }
+    apply_flag(flag, None, input, tokens, i, vars, options)?;
+  }
+
+  Ok(i + 1)
+}
+
+/// Index where a greedy value starting at `start` ends: the first `--` separator, or the end of
+/// the message. Tokens after the separator are parsed as usual.
+fn greedy_end(tokens: &[Token], start: usize) -> usize {
+  tokens[start..]
+    .iter()
+    .position(|token| token.value == GREEDY_TERMINATOR)
+    .map_or(tokens.len(), |offset| start + offset)
+}
+
+/// The input the tokens were read from, quotes and all
+fn typed_text<'a>(input: &'a str, tokens: &[Token]) -> &'a str {
+  match (tokens.first(), tokens.last()) {
+    (Some(first), Some(last)) => &input[first.span.start..last.span.end],
+    _ => "",
+  }
+}
+
+/// The value of a greedy arg or flag, as typed. Words starting with the command prefix (`!` by
+/// default) are kept as-is unless `strip_embedded_prefix` is set.
+fn greedy_value(text: &str, options: &ResolveOptions) -> String {
+  if !options.strip_embedded_prefix {
+    return text.to_owned();
+  }
+
+  text
+    .split_inclusive(char::is_whitespace)
+    .map(|word| word.strip_prefix(options.prefix.as_str()).unwrap_or(word))
+    .collect()
+}
+
+/// An exact match always wins, so `-v` and `-V` can still be distinct flags when matching
+/// case-insensitively
+fn find_flag<'a>(token: &str, flags: &'a [Flag], case_insensitive: bool) -> Option<&'a Flag> {
+  let exact = flags
     .iter()
-    .find(|f| f.long == token || f.short.as_deref() == Some(token))
+    .find(|f| f.long == token || f.short.as_deref() == Some(token));
+
+  if exact.is_some() || !case_insensitive {
+    return exact;
+  }
+
+  flags.iter().find(|f| {
+    f.long.eq_ignore_ascii_case(token)
+      || f
+        .short
+        .as_deref()
+        .is_some_and(|short| short.eq_ignore_ascii_case(token))
+  })
+}
+
+/// The commands a help listing shows, leaving out hidden ones, ordered for display only;
+/// resolution always uses the declared order
+fn sorted_for_help(commands: &[Command], sort: HelpSort) -> Vec<&Command> {
+  let mut sorted: Vec<&Command> = commands.iter().filter(|c| !c.hidden).collect();
+  if sort == HelpSort::Alpha {
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+  }
+  sorted
 }
 
-fn format_help_listing(commands: &[Command], prefix: &str) -> String {
+fn format_help_listing(commands: &[Command], prefix: &str, sort: HelpSort) -> String {
   let mut output = String::from("Commands:\n");
-  for cmd in commands {
-    output.push_str(&format!("  {prefix}{}", cmd.name));
+  for cmd in sorted_for_help(commands, sort) {
+    output.push_str(&format!("  {prefix}{}", help_name(cmd)));
     if !cmd.help.is_empty() {
       output.push_str(&format!(" - {}", cmd.help));
     }
@@ -203,7 +805,7 @@ fn format_help_listing(commands: &[Command], prefix: &str) -> String {
   output
 }
 
-fn format_group_help(cmd: &Command, prefix: &str) -> String {
+fn format_group_help(cmd: &Command, prefix: &str, sort: HelpSort) -> String {
   let mut output = format!("{prefix}{}", cmd.name);
   if !cmd.help.is_empty() {
     output.push_str(&format!(" - {}", cmd.help));
@@ -212,8 +814,8 @@ fn format_group_help(cmd: &Command, prefix: &str) -> String {
 
   let sub_prefix = format!("{prefix}{} ", cmd.name);
   output.push_str("\nSubcommands:\n");
-  for subcommand in &cmd.commands {
-    output.push_str(&format!("  {sub_prefix}{}", subcommand.name));
+  for subcommand in sorted_for_help(&cmd.commands, sort) {
+    output.push_str(&format!("  {sub_prefix}{}", help_name(subcommand)));
     if !subcommand.help.is_empty() {
       output.push_str(&format!(" - {}", subcommand.help));
     }
@@ -241,6 +843,9 @@ fn format_command_help(cmd: &Command, prefix: &str) -> String {
       if !arg.help.is_empty() {
         output.push_str(&format!(" - {}", arg.help));
       }
+      if !arg.choices.is_empty() {
+        output.push_str(&format!(" (one of: {})", arg.choices.join(", ")));
+      }
       if let Some(ref default) = arg.default {
         output.push_str(&format!(" (default: {default})"));
       }
@@ -269,6 +874,12 @@ fn format_command_help(cmd: &Command, prefix: &str) -> String {
       if flag.required {
         output.push_str(" (required)");
       }
+      if flag.multiple {
+        output.push_str(" (repeatable)");
+      }
+      if !flag.choices.is_empty() {
+        output.push_str(&format!(" (one of: {})", flag.choices.join(", ")));
+      }
       if let Some(ref default) = flag.default {
         output.push_str(&format!(" (default: {default})"));
       }
@@ -276,13 +887,20 @@ fn format_command_help(cmd: &Command, prefix: &str) -> String {
     }
   }
 
+  if let Some(ref phrase) = cmd.confirm_phrase {
+    output.push_str(&format!(
+      "\nRequires confirmation: end with {}\n",
+      phrase.trim()
+    ));
+  }
+
   output
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::config::{Arg, Command, Flag};
+  use crate::config::{Command, Flag, OutputStream};
 
   fn leaf(name: &str, command: &str) -> Command {
     Command {
@@ -292,6 +910,7 @@ mod tests {
       flags: vec![],
       command: command.to_string(),
       commands: vec![],
+      ..Default::default()
     }
   }
 
@@ -303,6 +922,7 @@ mod tests {
       flags: vec![],
       command: command.to_string(),
       commands: vec![],
+      ..Default::default()
     }
   }
 
@@ -312,6 +932,8 @@ mod tests {
       help: String::new(),
       default: None,
       greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
     }
   }
 
@@ -321,6 +943,8 @@ mod tests {
       help: String::new(),
       default: Some(default.to_string()),
       greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
     }
   }
 
@@ -330,6 +954,8 @@ mod tests {
       help: String::new(),
       default: None,
       greedy: true,
+      kind: ArgType::String,
+      choices: Vec::new(),
     }
   }
 
@@ -342,6 +968,9 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     }
   }
 
@@ -354,13 +983,17 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     }
   }
 
   fn unwrap_command(result: AliasResult) -> (String, HashMap<String, String>) {
     match result {
-      AliasResult::Command { command, env } => (command, env),
+      AliasResult::Command { command, env, .. } => (command, env),
       AliasResult::HelpText(t) => panic!("expected Command, got HelpText: {t}"),
+      AliasResult::Reply(t) => panic!("expected Command, got Reply: {t}"),
     }
   }
 
@@ -370,6 +1003,7 @@ mod tests {
       AliasResult::Command { command, .. } => {
         panic!("expected HelpText, got Command: {command}")
       }
+      AliasResult::Reply(t) => panic!("expected HelpText, got Reply: {t}"),
     }
   }
 
@@ -395,6 +1029,49 @@ mod tests {
     assert!(env.is_empty());
   }
 
+  fn echo_options() -> ResolveOptions {
+    ResolveOptions {
+      enable_echo: true,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn echo_builtin_replies_with_text() {
+    let cmds = vec![leaf("ping", "do-ping")];
+    let result = resolve_alias_with("!echo hello  mesh", &cmds, &echo_options()).unwrap();
+    assert!(matches!(result, AliasResult::Reply(text) if text == "hello  mesh"));
+  }
+
+  #[test]
+  fn echo_builtin_without_text_replies_with_usage() {
+    let result = resolve_alias_with("!echo", &[], &echo_options()).unwrap();
+    assert!(matches!(result, AliasResult::Reply(text) if text.starts_with("Usage:")));
+  }
+
+  #[test]
+  fn echo_builtin_is_disabled_by_default() {
+    let err = resolve_alias("!echo hi", &[leaf("ping", "do-ping")]).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: !echo");
+  }
+
+  #[test]
+  fn configured_echo_command_overrides_builtin() {
+    let mut c = leaf("echo", "run-echo");
+    c.args.push(greedy_arg("message"));
+    let cmds = vec![c];
+    let result = resolve_alias_with("!echo hi there", &cmds, &echo_options()).unwrap();
+    let (cmd, env) = unwrap_command(result);
+    assert_eq!(cmd, "run-echo");
+    assert_eq!(env.get("message").unwrap(), "hi there");
+  }
+
+  #[test]
+  fn echo_builtin_requires_exact_name() {
+    let err = resolve_alias_with("!echoes hi", &[], &echo_options()).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: !echoes");
+  }
+
   #[test]
   fn leaf_with_one_positional_arg() {
     let mut c = leaf("greet", "say-hello");
@@ -437,6 +1114,7 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![leaf("prod", "deploy-prod")],
+      ..Default::default()
     };
     let cmds = vec![group];
     let text = unwrap_help(resolve_alias("!deploy", &cmds).unwrap());
@@ -452,6 +1130,7 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![leaf("prod", "deploy-prod")],
+      ..Default::default()
     };
     let cmds = vec![group];
     let text = unwrap_help(resolve_alias("!deploy --help", &cmds).unwrap());
@@ -467,6 +1146,7 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![leaf("prod", "deploy-prod")],
+      ..Default::default()
     };
     let cmds = vec![group];
     let (cmd, _) = unwrap_command(resolve_alias("!deploy prod", &cmds).unwrap());
@@ -482,12 +1162,63 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![leaf("prod", "deploy-prod")],
+      ..Default::default()
     };
     let cmds = vec![group];
     let err = resolve_alias("!deploy staging", &cmds).unwrap_err();
     assert!(err.to_string().contains("Unknown command"));
   }
 
+  fn templated_group() -> Command {
+    Command {
+      name: "svc".to_string(),
+      commands: vec![leaf("status", "systemctl status foo --no-pager")],
+      command_template: Some("systemctl {subcommand} foo".to_string()),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn templated_group_dispatches_undefined_subcommand() {
+    let cmds = vec![templated_group()];
+    let (cmd, env) = unwrap_command(resolve_alias("!svc restart", &cmds).unwrap());
+    assert_eq!(cmd, "systemctl restart foo");
+    assert!(env.is_empty());
+  }
+
+  #[test]
+  fn templated_group_prefers_explicit_subcommand() {
+    let cmds = vec![templated_group()];
+    let (cmd, _) = unwrap_command(resolve_alias("!svc status", &cmds).unwrap());
+    assert_eq!(cmd, "systemctl status foo --no-pager");
+  }
+
+  #[test]
+  fn templated_group_rejects_unsafe_subcommand_name() {
+    let cmds = vec![templated_group()];
+    let err = resolve_alias("!svc start;reboot", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: !svc start;reboot");
+  }
+
+  #[test]
+  fn templated_group_rejects_option_like_subcommand_name() {
+    let cmds = vec![templated_group()];
+    let err = resolve_alias("!svc --now", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: !svc --now");
+    let err = resolve_alias("!svc ..", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: !svc ..");
+  }
+
+  #[test]
+  fn templated_group_rejects_extra_args() {
+    let cmds = vec![templated_group()];
+    let err = resolve_alias("!svc start now", &cmds).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<AliasError>(),
+      Some(AliasError::TooManyArgs { expected: 0 })
+    ));
+  }
+
   #[test]
   fn nested_group_resolution() {
     let inner = Command {
@@ -497,6 +1228,7 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![leaf("c", "run-c")],
+      ..Default::default()
     };
     let outer = Command {
       name: "a".to_string(),
@@ -505,6 +1237,7 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![inner],
+      ..Default::default()
     };
     let cmds = vec![outer];
     let (cmd, _) = unwrap_command(resolve_alias("!a b c", &cmds).unwrap());
@@ -601,75 +1334,830 @@ mod tests {
   }
 
   #[test]
-  fn required_flag_not_provided() {
+  fn flag_with_equals_value() {
     let mut c = leaf("cmd", "run-cmd");
-    c.flags.push(Flag {
-      long: "--env".to_string(),
-      short: None,
-      help: None,
-      arg: Some("env_name".to_string()),
-      required: true,
-      default: None,
-      greedy: false,
-    });
+    c.flags.push(value_flag("--output", Some("-o"), "path"));
     let cmds = vec![c];
-    let err = resolve_alias("!cmd", &cmds).unwrap_err();
-    assert!(err.to_string().contains("Missing required flag"));
+    let (_, env) = unwrap_command(resolve_alias("!cmd --output=/tmp/a=b", &cmds).unwrap());
+    assert_eq!(env.get("path").unwrap(), "/tmp/a=b");
+    let (_, env) = unwrap_command(resolve_alias("!cmd -o=/var", &cmds).unwrap());
+    assert_eq!(env.get("path").unwrap(), "/var");
   }
 
   #[test]
-  fn flag_default_used_when_not_provided() {
+  fn flag_with_equals_and_no_value_is_empty() {
     let mut c = leaf("cmd", "run-cmd");
-    c.flags.push(Flag {
-      long: "--env".to_string(),
-      short: None,
-      help: None,
-      arg: Some("env_name".to_string()),
-      required: false,
-      default: Some("production".to_string()),
-      greedy: false,
-    });
+    c.flags.push(value_flag("--output", None, "path"));
+    c.args.push(arg("target"));
     let cmds = vec![c];
-    let (_, env) = unwrap_command(resolve_alias("!cmd", &cmds).unwrap());
-    assert_eq!(env.get("env_name").unwrap(), "production");
+    let (_, env) = unwrap_command(resolve_alias("!cmd --output= prod", &cmds).unwrap());
+    assert_eq!(env.get("path").unwrap(), "");
+    assert_eq!(env.get("target").unwrap(), "prod");
   }
 
   #[test]
-  fn greedy_flag_consumes_remaining() {
+  fn flag_with_equals_quoted_value() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(value_flag("--msg", None, "msg"));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!cmd --msg=\"a b\"", &cmds).unwrap());
+    assert_eq!(env.get("msg").unwrap(), "a b");
+  }
+
+  #[test]
+  fn greedy_flag_with_equals_value_keeps_consuming() {
     let mut c = leaf("cmd", "run-cmd");
     c.flags.push(Flag {
-      long: "--message".to_string(),
-      short: None,
-      help: None,
-      arg: Some("msg".to_string()),
-      required: false,
-      default: None,
       greedy: true,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+      ..value_flag("--msg", None, "msg")
     });
     let cmds = vec![c];
-    let (_, env) = unwrap_command(resolve_alias("!cmd --message hello world foo", &cmds).unwrap());
-    assert_eq!(env.get("msg").unwrap(), "hello world foo");
+    let (_, env) = unwrap_command(resolve_alias("!cmd --msg=fix the bug", &cmds).unwrap());
+    assert_eq!(env.get("msg").unwrap(), "fix the bug");
   }
 
   #[test]
-  fn flag_long_hyphens_become_underscores() {
+  fn boolean_flag_with_equals_value_errors() {
     let mut c = leaf("cmd", "run-cmd");
-    c.flags.push(bool_flag("--dry-run", None));
+    c.flags.push(bool_flag("--verbose", Some("-v")));
     let cmds = vec![c];
-    let (_, env) = unwrap_command(resolve_alias("!cmd --dry-run", &cmds).unwrap());
-    assert_eq!(env.get("dry_run").unwrap(), "true");
+    let err = resolve_alias("!cmd --verbose=yes", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Flag --verbose doesn't take a value");
+    assert!(resolve_alias("!cmd -v=", &cmds).is_err());
   }
 
   #[test]
-  fn help_listing_includes_names_and_help() {
-    let cmds = vec![leaf_with_help("ping", "do-ping", "Check connectivity")];
-    let text = unwrap_help(resolve_alias("!help", &cmds).unwrap());
-    assert!(text.contains("ping"));
-    assert!(text.contains("Check connectivity"));
+  fn bundled_boolean_shorts_expand() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    c.flags.push(bool_flag("--force", Some("-f")));
+    c.flags.push(bool_flag("--extra", Some("-x")));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!cmd -vfx", &cmds).unwrap());
+    assert_eq!(env.get("verbose").unwrap(), "true");
+    assert_eq!(env.get("force").unwrap(), "true");
+    assert_eq!(env.get("extra").unwrap(), "true");
   }
 
   #[test]
-  fn help_listing_includes_footer() {
+  fn bundled_shorts_with_unknown_letter_errors() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    let cmds = vec![c];
+    let err = resolve_alias("!cmd -vq", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown flag: -q");
+  }
+
+  #[test]
+  fn bundled_value_short_takes_remainder_or_next_token() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    c.flags.push(value_flag("--output", Some("-o"), "path"));
+    let cmds = vec![c];
+
+    let (_, env) = unwrap_command(resolve_alias("!cmd -vo/tmp", &cmds).unwrap());
+    assert_eq!(env.get("verbose").unwrap(), "true");
+    assert_eq!(env.get("path").unwrap(), "/tmp");
+
+    let (_, env) = unwrap_command(resolve_alias("!cmd -o/var", &cmds).unwrap());
+    assert_eq!(env.get("path").unwrap(), "/var");
+
+    let (_, env) = unwrap_command(resolve_alias("!cmd -vo=/etc", &cmds).unwrap());
+    assert_eq!(env.get("path").unwrap(), "/etc");
+
+    let (_, env) = unwrap_command(resolve_alias("!cmd -vo /srv", &cmds).unwrap());
+    assert_eq!(env.get("path").unwrap(), "/srv");
+
+    let err = resolve_alias("!cmd -vo", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Flag --output requires a value");
+  }
+
+  fn limited_cmd() -> Command {
+    let mut c = leaf("cmd", "run-cmd");
+    c.args.push(arg("target"));
+    c.flags.push(value_flag("--msg", None, "msg"));
+    c.max_value_bytes = Some(5);
+    c
+  }
+
+  #[test]
+  fn values_within_max_value_bytes_are_accepted() {
+    let cmds = vec![limited_cmd()];
+    let (_, env) = unwrap_command(resolve_alias("!cmd abcd --msg abcde", &cmds).unwrap());
+    assert_eq!(env.get("target").unwrap(), "abcd");
+    assert_eq!(env.get("msg").unwrap(), "abcde");
+  }
+
+  #[test]
+  fn value_over_max_value_bytes_errors() {
+    let cmds = vec![limited_cmd()];
+    let err = resolve_alias("!cmd abcdef", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Value for target too long (max 5 bytes)");
+
+    let err = resolve_alias("!cmd ok --msg \"a b c d\"", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Value for msg too long (max 5 bytes)");
+  }
+
+  #[test]
+  fn max_value_bytes_counts_bytes_not_chars() {
+    let cmds = vec![limited_cmd()];
+    assert!(resolve_alias("!cmd \u{e9}\u{e9}", &cmds).is_ok());
+    assert!(resolve_alias("!cmd \u{e9}\u{e9}\u{e9}", &cmds).is_err());
+  }
+
+  fn passthrough_cmd() -> Command {
+    let mut c = leaf("lint", "tool ${extra_flags} ${path}");
+    c.args.push(arg("path"));
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    c.allow_unknown_flags = true;
+    c
+  }
+
+  #[test]
+  fn unknown_flags_are_collected_when_allowed() {
+    let cmds = vec![passthrough_cmd()];
+    let (_, env) = unwrap_command(
+      resolve_alias(
+        "!lint --fix -q src --max-warnings 0 -v --format=json",
+        &cmds,
+      )
+      .unwrap(),
+    );
+    assert_eq!(
+      env.get(EXTRA_FLAGS_VAR).unwrap(),
+      "--fix -q --max-warnings 0 --format=json"
+    );
+    assert_eq!(env.get("path").unwrap(), "src");
+    assert_eq!(env.get("verbose").unwrap(), "true");
+  }
+
+  #[test]
+  fn unknown_short_bundle_is_collected_when_allowed() {
+    let cmds = vec![passthrough_cmd()];
+    let (_, env) = unwrap_command(resolve_alias("!lint -vx src", &cmds).unwrap());
+    assert_eq!(env.get(EXTRA_FLAGS_VAR).unwrap(), "-vx");
+    assert_eq!(env.get("path").unwrap(), "src");
+  }
+
+  #[test]
+  fn extra_flags_is_empty_without_unknown_flags() {
+    let cmds = vec![passthrough_cmd()];
+    let (_, env) = unwrap_command(resolve_alias("!lint src -v", &cmds).unwrap());
+    assert_eq!(env.get(EXTRA_FLAGS_VAR).unwrap(), "");
+  }
+
+  #[test]
+  fn unknown_flags_are_rejected_by_default() {
+    let mut c = passthrough_cmd();
+    c.allow_unknown_flags = false;
+    let cmds = vec![c];
+    let err = resolve_alias("!lint --fix src", &cmds).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown flag: --fix");
+    let (_, env) = unwrap_command(resolve_alias("!lint src", &cmds).unwrap());
+    assert!(!env.contains_key(EXTRA_FLAGS_VAR));
+  }
+
+  #[test]
+  fn required_flag_not_provided() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(Flag {
+      long: "--env".to_string(),
+      short: None,
+      help: None,
+      arg: Some("env_name".to_string()),
+      required: true,
+      default: None,
+      greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+    });
+    let cmds = vec![c];
+    let err = resolve_alias("!cmd", &cmds).unwrap_err();
+    assert!(err.to_string().contains("Missing required flag"));
+  }
+
+  #[test]
+  fn flag_default_used_when_not_provided() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(Flag {
+      long: "--env".to_string(),
+      short: None,
+      help: None,
+      arg: Some("env_name".to_string()),
+      required: false,
+      default: Some("production".to_string()),
+      greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+    });
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!cmd", &cmds).unwrap());
+    assert_eq!(env.get("env_name").unwrap(), "production");
+  }
+
+  #[test]
+  fn greedy_flag_consumes_remaining() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(Flag {
+      long: "--message".to_string(),
+      short: None,
+      help: None,
+      arg: Some("msg".to_string()),
+      required: false,
+      default: None,
+      greedy: true,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+    });
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!cmd --message hello world foo", &cmds).unwrap());
+    assert_eq!(env.get("msg").unwrap(), "hello world foo");
+  }
+
+  fn greedy_message_command() -> Command {
+    let mut c = leaf("notify", "run-notify");
+    c.flags.push(bool_flag("--urgent", Some("-u")));
+    c.flags.push(Flag {
+      long: "--msg".to_string(),
+      short: None,
+      help: None,
+      arg: Some("msg".to_string()),
+      required: false,
+      default: None,
+      greedy: true,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+    });
+    c
+  }
+
+  #[test]
+  fn greedy_flag_stops_at_separator() {
+    let cmds = vec![greedy_message_command()];
+    let (_, env) =
+      unwrap_command(resolve_alias("!notify --msg hello world -- --urgent", &cmds).unwrap());
+    assert_eq!(env.get("msg").unwrap(), "hello world");
+    assert_eq!(env.get("urgent").unwrap(), "true");
+  }
+
+  #[test]
+  fn greedy_flag_without_separator_consumes_flags() {
+    let cmds = vec![greedy_message_command()];
+    let (_, env) = unwrap_command(resolve_alias("!notify --msg hello --urgent", &cmds).unwrap());
+    assert_eq!(env.get("msg").unwrap(), "hello --urgent");
+    assert!(!env.contains_key("urgent"));
+  }
+
+  #[test]
+  fn greedy_flag_with_empty_value_before_separator_errors() {
+    let cmds = vec![greedy_message_command()];
+    let err = resolve_alias("!notify --msg -- --urgent", &cmds).unwrap_err();
+    assert!(err.to_string().contains("requires a value"));
+  }
+
+  #[test]
+  fn greedy_arg_stops_at_separator() {
+    let mut c = leaf("echo", "run-echo");
+    c.args.push(greedy_arg("message"));
+    c.flags.push(bool_flag("--loud", None));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!echo hello world -- --loud", &cmds).unwrap());
+    assert_eq!(env.get("message").unwrap(), "hello world");
+    assert_eq!(env.get("loud").unwrap(), "true");
+  }
+
+  #[test]
+  fn positional_after_greedy_separator_is_too_many_args() {
+    let mut c = leaf("echo", "run-echo");
+    c.args.push(greedy_arg("message"));
+    let cmds = vec![c];
+    let err = resolve_alias("!echo hello -- extra", &cmds).unwrap_err();
+    assert!(err.to_string().contains("Too many arguments"));
+  }
+
+  #[test]
+  fn boolean_flag_uses_configured_true_value() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      bool_true: "1".into(),
+      bool_false: "0".into(),
+      ..Default::default()
+    };
+
+    let (_, env) = unwrap_command(resolve_alias_with("!cmd -v", &cmds, &options).unwrap());
+    assert_eq!(env.get("verbose").unwrap(), "1");
+
+    let (_, env) = unwrap_command(resolve_alias_with("!cmd", &cmds, &options).unwrap());
+    assert!(!env.contains_key("verbose"));
+  }
+
+  #[test]
+  fn boolean_flag_defaults_use_configured_values() {
+    let mut c = leaf("cmd", "run-cmd");
+    let mut color = bool_flag("--color", None);
+    color.default = Some("true".into());
+    let mut dry_run = bool_flag("--dry-run", None);
+    dry_run.default = Some("false".into());
+    let mut mode = value_flag("--mode", None, "mode");
+    mode.default = Some("true".into());
+    c.flags.extend([color, dry_run, mode]);
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      bool_true: "yes".into(),
+      bool_false: "no".into(),
+      ..Default::default()
+    };
+
+    let (_, env) = unwrap_command(resolve_alias_with("!cmd", &cmds, &options).unwrap());
+    assert_eq!(env.get("color").unwrap(), "yes");
+    assert_eq!(env.get("dry_run").unwrap(), "no");
+    assert_eq!(env.get("mode").unwrap(), "true");
+  }
+
+  fn multiple_flag(long: &str, short: Option<&str>, arg_name: &str) -> Flag {
+    Flag {
+      multiple: true,
+      ..value_flag(long, short, arg_name)
+    }
+  }
+
+  #[test]
+  fn multiple_flag_collects_two_values() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.flags.push(multiple_flag("--host", Some("-H"), "hosts"));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!deploy --host a -H b", &cmds).unwrap());
+    assert_eq!(env.get("hosts").unwrap(), "a b");
+  }
+
+  #[test]
+  fn multiple_flag_collects_three_values_with_separator() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.flags.push(Flag {
+      separator: Some("\n".into()),
+      ..multiple_flag("--host", None, "hosts")
+    });
+    let cmds = vec![c];
+    let (_, env) =
+      unwrap_command(resolve_alias("!deploy --host a --host=b --host c", &cmds).unwrap());
+    assert_eq!(env.get("hosts").unwrap(), "a\nb\nc");
+  }
+
+  #[test]
+  fn repeated_flag_without_multiple_keeps_last_value() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.flags.push(value_flag("--host", None, "host"));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!deploy --host a --host b", &cmds).unwrap());
+    assert_eq!(env.get("host").unwrap(), "b");
+  }
+
+  #[test]
+  fn help_marks_multiple_flags_repeatable() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.flags.push(multiple_flag("--host", None, "hosts"));
+    let cmds = vec![c];
+    let help = unwrap_help(resolve_alias("!deploy --help", &cmds).unwrap());
+    assert!(help.contains("--host <hosts> (repeatable)"), "{help}");
+  }
+
+  fn choices(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+  }
+
+  #[test]
+  fn arg_choices_accept_listed_values_only() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.args.push(Arg {
+      choices: choices(&["prod", "staging", "dev"]),
+      ..arg("env")
+    });
+    let cmds = vec![c];
+
+    let (_, env) = unwrap_command(resolve_alias("!deploy staging", &cmds).unwrap());
+    assert_eq!(env.get("env").unwrap(), "staging");
+
+    let err = resolve_alias("!deploy qa", &cmds).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Invalid value for env: qa (expected one of: prod, staging, dev)"
+    );
+  }
+
+  #[test]
+  fn flag_choices_apply_to_every_value() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.flags.push(Flag {
+      choices: choices(&["a", "b"]),
+      ..multiple_flag("--host", None, "hosts")
+    });
+    let cmds = vec![c];
+
+    let (_, env) = unwrap_command(resolve_alias("!deploy --host a --host=b", &cmds).unwrap());
+    assert_eq!(env.get("hosts").unwrap(), "a b");
+
+    let err = resolve_alias("!deploy --host a --host c", &cmds).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Invalid value for --host: c (expected one of: a, b)"
+    );
+  }
+
+  #[test]
+  fn help_lists_choices() {
+    let mut c = leaf("deploy", "run-deploy");
+    c.args.push(Arg {
+      choices: choices(&["prod", "dev"]),
+      ..arg("env")
+    });
+    c.flags.push(Flag {
+      choices: choices(&["fast", "safe"]),
+      ..value_flag("--mode", None, "mode")
+    });
+    let cmds = vec![c];
+    let help = unwrap_help(resolve_alias("!deploy --help", &cmds).unwrap());
+    assert!(help.contains("<env> (one of: prod, dev)"), "{help}");
+    assert!(
+      help.contains("--mode <mode> (one of: fast, safe)"),
+      "{help}"
+    );
+  }
+
+  fn bool_arg(name: &str) -> Arg {
+    Arg {
+      kind: ArgType::Bool,
+      choices: Vec::new(),
+      ..arg(name)
+    }
+  }
+
+  #[test]
+  fn bool_arg_accepts_each_spelling_and_normalizes_it() {
+    let mut c = leaf("lights", "set-lights");
+    c.args.push(bool_arg("on"));
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      bool_true: "1".into(),
+      bool_false: "0".into(),
+      ..Default::default()
+    };
+
+    for (value, expected) in [
+      ("true", "1"),
+      ("YES", "1"),
+      ("1", "1"),
+      ("False", "0"),
+      ("no", "0"),
+      ("0", "0"),
+    ] {
+      let message = format!("!lights {value}");
+      let (_, env) = unwrap_command(resolve_alias_with(&message, &cmds, &options).unwrap());
+      assert_eq!(env.get("on").unwrap(), expected, "for {value}");
+    }
+  }
+
+  #[test]
+  fn bool_arg_default_is_normalized() {
+    let mut c = leaf("lights", "set-lights");
+    c.args.push(Arg {
+      default: Some("no".into()),
+      ..bool_arg("on")
+    });
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!lights", &cmds).unwrap());
+    assert_eq!(env.get("on").unwrap(), "false");
+  }
+
+  #[test]
+  fn bool_arg_rejects_other_values() {
+    let mut c = leaf("lights", "set-lights");
+    c.args.push(bool_arg("on"));
+    let cmds = vec![c];
+    let err = resolve_alias("!lights maybe", &cmds).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Invalid value for on: maybe (expected true/false, yes/no or 1/0)"
+    );
+  }
+
+  #[test]
+  fn greedy_arg_preserves_embedded_prefix_by_default() {
+    let mut c = leaf("note", "save-note");
+    c.args.push(greedy_arg("text"));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!note !urgent fix", &cmds).unwrap());
+    assert_eq!(env.get("text").unwrap(), "!urgent fix");
+  }
+
+  #[test]
+  fn greedy_arg_strips_embedded_prefix_when_enabled() {
+    let mut c = leaf("note", "save-note");
+    c.args.push(greedy_arg("text"));
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      strip_embedded_prefix: true,
+      ..Default::default()
+    };
+    let (_, env) =
+      unwrap_command(resolve_alias_with("!note !urgent fix !!now a!b", &cmds, &options).unwrap());
+    assert_eq!(env.get("text").unwrap(), "urgent fix !now a!b");
+  }
+
+  #[test]
+  fn greedy_flag_strips_embedded_prefix_when_enabled() {
+    let mut c = leaf("note", "save-note");
+    let mut flag = value_flag("--message", Some("-m"), "message");
+    flag.greedy = true;
+    c.flags.push(flag);
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      strip_embedded_prefix: true,
+      ..Default::default()
+    };
+
+    let (_, env) = unwrap_command(resolve_alias("!note -m !ping me", &cmds).unwrap());
+    assert_eq!(env.get("message").unwrap(), "!ping me");
+
+    let (_, env) =
+      unwrap_command(resolve_alias_with("!note -m !ping me", &cmds, &options).unwrap());
+    assert_eq!(env.get("message").unwrap(), "ping me");
+  }
+
+  #[test]
+  fn flag_long_hyphens_become_underscores() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--dry-run", None));
+    let cmds = vec![c];
+    let (_, env) = unwrap_command(resolve_alias("!cmd --dry-run", &cmds).unwrap());
+    assert_eq!(env.get("dry_run").unwrap(), "true");
+  }
+
+  fn confirmed_leaf(name: &str, command: &str, phrase: &str) -> Command {
+    Command {
+      confirm_phrase: Some(phrase.to_string()),
+      ..leaf(name, command)
+    }
+  }
+
+  #[test]
+  fn confirm_phrase_present_runs_command() {
+    let cmds = vec![confirmed_leaf("wipe", "do-wipe", "CONFIRM-WIPE")];
+    let (cmd, env) = unwrap_command(resolve_alias("!wipe CONFIRM-WIPE", &cmds).unwrap());
+    assert_eq!(cmd, "do-wipe");
+    assert!(env.is_empty());
+  }
+
+  #[test]
+  fn confirm_phrase_wrong_is_rejected() {
+    let cmds = vec![confirmed_leaf("wipe", "do-wipe", "CONFIRM-WIPE")];
+    let err = resolve_alias("!wipe confirm-wipe", &cmds).unwrap_err();
+    assert!(
+      err
+        .to_string()
+        .contains("!wipe requires confirmation. Resend it ending with: CONFIRM-WIPE")
+    );
+  }
+
+  #[test]
+  fn confirm_phrase_missing_is_rejected() {
+    let cmds = vec![confirmed_leaf("wipe", "do-wipe", "CONFIRM-WIPE")];
+    let err = resolve_alias("!wipe", &cmds).unwrap_err();
+    assert!(err.to_string().contains("requires confirmation"));
+  }
+
+  #[test]
+  fn confirm_phrase_must_be_trailing() {
+    let mut c = confirmed_leaf("wipe", "do-wipe", "CONFIRM-WIPE");
+    c.args.push(arg("disk"));
+    let cmds = vec![c];
+    let err = resolve_alias("!wipe CONFIRM-WIPE sda", &cmds).unwrap_err();
+    assert!(err.to_string().contains("requires confirmation"));
+  }
+
+  #[test]
+  fn confirm_phrase_is_not_passed_to_greedy_arg() {
+    let mut c = confirmed_leaf("wipe", "do-wipe", "YES I AM SURE");
+    c.args.push(greedy_arg("paths"));
+    let cmds = vec![c];
+    let (_, env) =
+      unwrap_command(resolve_alias("!wipe /tmp/a /tmp/b YES I AM SURE", &cmds).unwrap());
+    assert_eq!(env.get("paths").unwrap(), "/tmp/a /tmp/b");
+  }
+
+  #[test]
+  fn confirm_phrase_not_required_for_help() {
+    let cmds = vec![confirmed_leaf("wipe", "do-wipe", "CONFIRM-WIPE")];
+    let text = unwrap_help(resolve_alias("!wipe --help", &cmds).unwrap());
+    assert!(text.contains("Requires confirmation: end with CONFIRM-WIPE"));
+  }
+
+  #[test]
+  fn mixed_case_flag_rejected_by_default() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    let cmds = vec![c];
+    let err = resolve_alias("!cmd --Verbose", &cmds).unwrap_err();
+    assert!(err.to_string().contains("Unknown flag: --Verbose"));
+  }
+
+  #[test]
+  fn mixed_case_flags_match_when_case_insensitive() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    c.flags.push(value_flag("--output", None, "path"));
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      flag_case_insensitive: true,
+      ..Default::default()
+    };
+
+    let (_, env) =
+      unwrap_command(resolve_alias_with("!cmd --Verbose --OUTPUT /tmp", &cmds, &options).unwrap());
+    assert_eq!(env.get("verbose").unwrap(), "true");
+    assert_eq!(env.get("path").unwrap(), "/tmp");
+
+    let (_, env) = unwrap_command(resolve_alias_with("!cmd -V", &cmds, &options).unwrap());
+    assert_eq!(env.get("verbose").unwrap(), "true");
+  }
+
+  #[test]
+  fn exact_short_flag_wins_when_case_insensitive() {
+    let mut c = leaf("cmd", "run-cmd");
+    c.flags.push(bool_flag("--verbose", Some("-v")));
+    c.flags.push(bool_flag("--version", Some("-V")));
+    let cmds = vec![c];
+    let options = ResolveOptions {
+      flag_case_insensitive: true,
+      ..Default::default()
+    };
+
+    let (_, env) = unwrap_command(resolve_alias_with("!cmd -V", &cmds, &options).unwrap());
+    assert_eq!(env.get("version").unwrap(), "true");
+    assert!(!env.contains_key("verbose"));
+  }
+
+  #[test]
+  fn command_names_stay_case_sensitive_when_flags_are_not() {
+    let cmds = vec![leaf("ping", "do-ping")];
+    let options = ResolveOptions {
+      flag_case_insensitive: true,
+      ..Default::default()
+    };
+    let err = resolve_alias_with("!Ping", &cmds, &options).unwrap_err();
+    assert!(err.to_string().contains("Unknown command: !Ping"));
+  }
+
+  #[test]
+  fn help_listing_includes_names_and_help() {
+    let cmds = vec![leaf_with_help("ping", "do-ping", "Check connectivity")];
+    let text = unwrap_help(resolve_alias("!help", &cmds).unwrap());
+    assert!(text.contains("ping"));
+    assert!(text.contains("Check connectivity"));
+  }
+
+  fn aliased(name: &str, aliases: &[&str], command: &str) -> Command {
+    Command {
+      aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+      ..leaf(name, command)
+    }
+  }
+
+  #[test]
+  fn command_resolves_by_name_and_each_alias() {
+    let mut status = aliased("status", &["stat", "st"], "show-status");
+    status.args.push(arg_with_default("service", "all"));
+    let cmds = vec![status, leaf("stop", "do-stop")];
+
+    for message in ["!status", "!stat", "!st"] {
+      let (command, env) = unwrap_command(resolve_alias(message, &cmds).unwrap());
+      assert_eq!(command, "show-status", "for {message}");
+      assert_eq!(env.get("service").unwrap(), "all");
+    }
+
+    let (command, env) = unwrap_command(resolve_alias("!st nginx", &cmds).unwrap());
+    assert_eq!(command, "show-status");
+    assert_eq!(env.get("service").unwrap(), "nginx");
+
+    let (command, _) = unwrap_command(resolve_alias("!stop", &cmds).unwrap());
+    assert_eq!(command, "do-stop");
+    assert!(resolve_alias("!sta", &cmds).is_err());
+  }
+
+  #[test]
+  fn subcommand_resolves_by_alias() {
+    let group = Command {
+      aliases: vec!["svc".into()],
+      commands: vec![aliased("restart", &["rs"], "do-restart")],
+      ..leaf("service", "")
+    };
+    let cmds = vec![group];
+
+    let (command, _) = unwrap_command(resolve_alias("!svc rs", &cmds).unwrap());
+    assert_eq!(command, "do-restart");
+    let (command, _) = unwrap_command(resolve_alias("!service restart", &cmds).unwrap());
+    assert_eq!(command, "do-restart");
+  }
+
+  #[test]
+  fn help_notes_aliases_after_the_name() {
+    let group = Command {
+      commands: vec![aliased("restart", &["rs"], "do-restart")],
+      ..leaf("service", "")
+    };
+    let cmds = vec![aliased("status", &["stat", "st"], "show-status"), group];
+
+    let text = unwrap_help(resolve_alias("!help", &cmds).unwrap());
+    assert!(text.contains("!status (stat, st)\n"), "{text}");
+    let text = unwrap_help(resolve_alias("!service", &cmds).unwrap());
+    assert!(text.contains("!service restart (rs)\n"), "{text}");
+  }
+
+  #[test]
+  fn hidden_commands_are_left_out_of_help_but_still_run() {
+    let hidden = |name: &str, command: &str| Command {
+      hidden: true,
+      ..leaf_with_help(name, command, "Maintenance")
+    };
+    let group = Command {
+      commands: vec![leaf("status", "show-status"), hidden("flush", "do-flush")],
+      ..leaf("cache", "")
+    };
+    let cmds = vec![
+      leaf("myip", "curl ifconfig.me"),
+      hidden("reboot", "do-reboot"),
+      group,
+    ];
+
+    let text = unwrap_help(resolve_alias("!help", &cmds).unwrap());
+    assert!(text.contains("!myip"), "{text}");
+    assert!(!text.contains("reboot"), "{text}");
+    let text = unwrap_help(resolve_alias("!cache", &cmds).unwrap());
+    assert!(text.contains("!cache status"), "{text}");
+    assert!(!text.contains("flush"), "{text}");
+
+    let (command, _) = unwrap_command(resolve_alias("!reboot", &cmds).unwrap());
+    assert_eq!(command, "do-reboot");
+    let (command, _) = unwrap_command(resolve_alias("!cache flush", &cmds).unwrap());
+    assert_eq!(command, "do-flush");
+    let text = unwrap_help(resolve_alias("!reboot --help", &cmds).unwrap());
+    assert!(text.starts_with("!reboot - Maintenance"), "{text}");
+  }
+
+  #[test]
+  fn help_listing_uses_declared_order_by_default() {
+    let cmds = vec![leaf("zap", "z"), leaf("alpha", "a"), leaf("mid", "m")];
+    let text = unwrap_help(resolve_alias("!help", &cmds).unwrap());
+    let zap = text.find("!zap").unwrap();
+    let alpha = text.find("!alpha").unwrap();
+    let mid = text.find("!mid").unwrap();
+    assert!(zap < alpha && alpha < mid);
+  }
+
+  #[test]
+  fn help_listing_sorted_alphabetically_when_requested() {
+    let cmds = vec![leaf("zap", "z"), leaf("alpha", "a"), leaf("mid", "m")];
+    let options = ResolveOptions {
+      help_sort: HelpSort::Alpha,
+      ..Default::default()
+    };
+    let text = unwrap_help(resolve_alias_with("!help", &cmds, &options).unwrap());
+    let zap = text.find("!zap").unwrap();
+    let alpha = text.find("!alpha").unwrap();
+    let mid = text.find("!mid").unwrap();
+    assert!(alpha < mid && mid < zap);
+  }
+
+  #[test]
+  fn group_help_follows_help_sort() {
+    let group = Command {
+      name: "svc".to_string(),
+      commands: vec![leaf("stop", "s"), leaf("restart", "r")],
+      ..Default::default()
+    };
+    let cmds = vec![group];
+
+    let text = unwrap_help(resolve_alias("!svc", &cmds).unwrap());
+    assert!(text.find("!svc stop").unwrap() < text.find("!svc restart").unwrap());
+
+    let options = ResolveOptions {
+      help_sort: HelpSort::Alpha,
+      ..Default::default()
+    };
+    let text = unwrap_help(resolve_alias_with("!svc", &cmds, &options).unwrap());
+    assert!(text.find("!svc restart").unwrap() < text.find("!svc stop").unwrap());
+  }
+
+  #[test]
+  fn help_listing_includes_footer() {
     let cmds = vec![leaf("ping", "do-ping")];
     let text = unwrap_help(resolve_alias("!help", &cmds).unwrap());
     assert!(text.contains("Send !<command> --help for details."));
@@ -687,6 +2175,7 @@ mod tests {
         leaf_with_help("prod", "deploy-prod", "Production deploy"),
         leaf("staging", "deploy-staging"),
       ],
+      ..Default::default()
     };
     let cmds = vec![group];
     let text = unwrap_help(resolve_alias("!deploy", &cmds).unwrap());
@@ -703,6 +2192,8 @@ mod tests {
       help: "Who to greet".to_string(),
       default: None,
       greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
     });
     c.flags.push(Flag {
       long: "--loud".to_string(),
@@ -712,6 +2203,9 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     });
     let cmds = vec![c];
     let text = unwrap_help(resolve_alias("!greet --help", &cmds).unwrap());
@@ -742,6 +2236,9 @@ mod tests {
       required: true,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     });
     let cmds = vec![c];
     let text = unwrap_help(resolve_alias("!cmd --help", &cmds).unwrap());
@@ -760,6 +2257,9 @@ mod tests {
       required: false,
       default: Some("dev".to_string()),
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     });
     let cmds = vec![c];
     let text = unwrap_help(resolve_alias("!cmd --help", &cmds).unwrap());
@@ -767,6 +2267,20 @@ mod tests {
     assert!(text.contains("(default: dev)"));
   }
 
+  #[test]
+  fn resolved_command_carries_its_definition() {
+    let mut c = leaf("errs", "run-errs");
+    c.output_stream = OutputStream::Stderr;
+    let cmds = vec![c];
+    match resolve_alias("!errs", &cmds).unwrap() {
+      AliasResult::Command { definition, .. } => {
+        assert_eq!(definition.name, "errs");
+        assert_eq!(definition.output_stream, OutputStream::Stderr);
+      }
+      other => panic!("expected Command, got {other:?}"),
+    }
+  }
+
   #[test]
   fn exact_match_resolves() {
     let cmds = vec![leaf("foo", "run-foo")];
@@ -797,6 +2311,164 @@ mod tests {
     assert!(err.to_string().contains("Unknown command: !zzz"));
   }
 
+  fn nested_groups() -> Vec<Command> {
+    vec![Command {
+      name: "a".to_string(),
+      commands: vec![Command {
+        name: "b".to_string(),
+        commands: vec![leaf("c", "run-c")],
+        ..Default::default()
+      }],
+      ..Default::default()
+    }]
+  }
+
+  #[test]
+  fn verbose_error_shows_full_path_of_unknown_subcommand() {
+    let err = resolve_alias("!a b zzz", &nested_groups()).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: !a b zzz");
+  }
+
+  #[test]
+  fn concise_error_shows_only_unknown_subcommand() {
+    let options = ResolveOptions {
+      verbose_errors: false,
+      ..Default::default()
+    };
+    let err = resolve_alias_with("!a b zzz now", &nested_groups(), &options).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: zzz");
+  }
+
+  fn prefixed(prefix: &str) -> ResolveOptions {
+    ResolveOptions {
+      prefix: prefix.to_owned(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn custom_prefix_resolves_commands() {
+    let cmds = vec![leaf("ping", "do-ping")];
+    let (cmd, _) = unwrap_command(resolve_alias_with("#ping", &cmds, &prefixed("#")).unwrap());
+    assert_eq!(cmd, "do-ping");
+
+    let (cmd, _) =
+      unwrap_command(resolve_alias_with("bot ping", &cmds, &prefixed("bot ")).unwrap());
+    assert_eq!(cmd, "do-ping");
+  }
+
+  #[test]
+  fn custom_prefix_is_used_in_help_and_errors() {
+    let cmds = vec![leaf_with_help("ping", "do-ping", "Ping it")];
+    let text = unwrap_help(resolve_alias_with("bot help", &cmds, &prefixed("bot ")).unwrap());
+    assert!(text.contains("  bot ping - Ping it"), "{text}");
+    assert!(text.contains("Send bot <command> --help"), "{text}");
+
+    let err = resolve_alias_with("#zzz", &cmds, &prefixed("#")).unwrap_err();
+    assert_eq!(err.to_string(), "Unknown command: #zzz");
+  }
+
+  fn note_cmd() -> Command {
+    Command {
+      name: "deploy".to_string(),
+      command: "deploy.sh".to_string(),
+      args: vec![Arg {
+        name: "target".to_string(),
+        help: String::new(),
+        default: Some("prod".to_string()),
+        greedy: false,
+        kind: ArgType::String,
+        choices: Vec::new(),
+      }],
+      flags: vec![Flag {
+        long: "--msg".to_string(),
+        short: Some("-m".to_string()),
+        help: None,
+        arg: Some("msg".to_string()),
+        required: false,
+        default: None,
+        greedy: false,
+        multiple: false,
+        separator: None,
+        choices: Vec::new(),
+      }],
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn double_quoted_flag_value_is_one_token() {
+    let (_, env) = unwrap_command(
+      resolve_alias("!deploy --msg \"fix the bug\" staging", &[note_cmd()]).unwrap(),
+    );
+    assert_eq!(env.get("msg").unwrap(), "fix the bug");
+    assert_eq!(env.get("target").unwrap(), "staging");
+  }
+
+  #[test]
+  fn single_quoted_arg_is_literal() {
+    let (_, env) =
+      unwrap_command(resolve_alias("!deploy 'my \\ \"env\"' -m x", &[note_cmd()]).unwrap());
+    assert_eq!(env.get("target").unwrap(), "my \\ \"env\"");
+  }
+
+  #[test]
+  fn backslash_escapes_quotes_and_spaces() {
+    let (_, env) = unwrap_command(
+      resolve_alias("!deploy don\\'t -m \"say \\\"hi\\\" C:\\x\"", &[note_cmd()]).unwrap(),
+    );
+    assert_eq!(env.get("target").unwrap(), "don't");
+    assert_eq!(env.get("msg").unwrap(), "say \"hi\" C:\\x");
+  }
+
+  #[test]
+  fn empty_quotes_are_an_empty_value() {
+    let (_, env) = unwrap_command(resolve_alias("!deploy '' -m \"\"", &[note_cmd()]).unwrap());
+    assert_eq!(env.get("target").unwrap(), "");
+    assert_eq!(env.get("msg").unwrap(), "");
+  }
+
+  #[test]
+  fn unbalanced_quotes_are_an_error() {
+    let err = resolve_alias("!deploy --msg \"fix the bug", &[note_cmd()]).unwrap_err();
+    assert_eq!(err.to_string(), "Missing closing \" quote");
+
+    let err = resolve_alias("!deploy don't", &[note_cmd()]).unwrap_err();
+    assert_eq!(err.to_string(), "Missing closing ' quote");
+  }
+
+  #[test]
+  fn greedy_arg_is_taken_as_typed() {
+    let cmd = Command {
+      name: "say".to_string(),
+      command: "echo".to_string(),
+      args: vec![Arg {
+        name: "text".to_string(),
+        help: String::new(),
+        default: None,
+        greedy: true,
+        kind: ArgType::String,
+        choices: Vec::new(),
+      }],
+      ..Default::default()
+    };
+    let (_, env) = unwrap_command(resolve_alias("!say \"a  b\" c\\d", &[cmd]).unwrap());
+    assert_eq!(env.get("text").unwrap(), "\"a  b\" c\\d");
+  }
+
+  #[test]
+  fn greedy_message_can_contain_an_apostrophe() {
+    let mut c = leaf("note", "save-note");
+    c.args.push(greedy_arg("text"));
+    let (_, env) = unwrap_command(resolve_alias("!note don't forget", &[c]).unwrap());
+    assert_eq!(env.get("text").unwrap(), "don't forget");
+
+    let cmds = vec![greedy_message_command()];
+    let (_, env) = unwrap_command(resolve_alias("!notify --msg=it's done -- -u", &cmds).unwrap());
+    assert_eq!(env.get("msg").unwrap(), "it's done");
+    assert_eq!(env.get("urgent").unwrap(), "true");
+  }
+
   #[test]
   fn alias_error_unknown_alias_display() {
     let e = AliasError::UnknownAlias("!bad".to_string());
@@ -827,6 +2499,48 @@ mod tests {
     assert_eq!(e.to_string(), "Unknown flag: --nope");
   }
 
+  #[test]
+  fn command_under_size_limit_is_unchanged() {
+    let message = "!ping host";
+    for policy in [CommandSizePolicy::Reject, CommandSizePolicy::Truncate] {
+      assert_eq!(
+        enforce_command_size(message, message.len(), policy).unwrap(),
+        message
+      );
+    }
+  }
+
+  #[test]
+  fn command_over_size_limit_rejected() {
+    let err = enforce_command_size("!echo hello world", 10, CommandSizePolicy::Reject).unwrap_err();
+    assert_eq!(err.to_string(), "Command too long (max 10 bytes)");
+  }
+
+  #[test]
+  fn command_over_size_limit_truncated() {
+    let truncated =
+      enforce_command_size("!echo hello world", 12, CommandSizePolicy::Truncate).unwrap();
+    assert_eq!(truncated, "!echo hello");
+  }
+
+  #[test]
+  fn command_truncated_on_char_boundary() {
+    let truncated = enforce_command_size("!echo héllo", 8, CommandSizePolicy::Truncate).unwrap();
+    assert_eq!(truncated, "!echo h");
+  }
+
+  #[test]
+  fn alias_error_confirmation_required_display() {
+    let e = AliasError::ConfirmationRequired {
+      command: "!wipe".to_string(),
+      phrase: "CONFIRM-WIPE".to_string(),
+    };
+    assert_eq!(
+      e.to_string(),
+      "!wipe requires confirmation. Resend it ending with: CONFIRM-WIPE"
+    );
+  }
+
   #[test]
   fn alias_error_too_many_args_display() {
     let e = AliasError::TooManyArgs { expected: 2 };
@@ -0,0 +1,178 @@
+use crate::config::Config;
+use miniz_oxide::deflate::compress_to_vec;
+use std::borrow::Cow;
+
+/// First line of a compressed reply, telling a decoder what follows
+pub const COMPRESSED_HEADER: &str = "GZ64:";
+
+/// Room left in each chunk for the header line and the `[n/total]` footer
+const CHUNK_OVERHEAD_BYTES: usize = 24;
+
+const BASE64_ALPHABET: &[u8; 64] =
+  b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A reply over `compress_threshold` bytes, gzipped and base64-encoded after a `GZ64:` header
+/// line. The base64 is broken into lines that each fit in a chunk, so chunking never cuts one
+/// off. Smaller replies, and ones that wouldn't get any shorter, are sent unchanged.
+pub fn compress_reply<'a>(reply: &'a str, config: &Config) -> Cow<'a, str> {
+  let Some(threshold) = config.compress_threshold else {
+    return Cow::Borrowed(reply);
+  };
+  if reply.len() <= threshold {
+    return Cow::Borrowed(reply);
+  }
+
+  let encoded = base64(&gzip(reply.as_bytes()));
+  // Whole base64 groups per line, so each line can be decoded on its own
+  let usable_bytes = config
+    .max_content_bytes
+    .saturating_sub(CHUNK_OVERHEAD_BYTES);
+  let line_width = (usable_bytes / 4 * 4).max(4);
+  let lines: Vec<&str> = encoded
+    .as_bytes()
+    .chunks(line_width)
+    .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+    .collect();
+  let compressed = format!("{COMPRESSED_HEADER}\n{}", lines.join("\n"));
+
+  if compressed.len() < reply.len() {
+    Cow::Owned(compressed)
+  } else {
+    Cow::Borrowed(reply)
+  }
+}
+
+/// Wraps raw deflate data in a minimal gzip member (RFC 1952)
+fn gzip(data: &[u8]) -> Vec<u8> {
+  // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+  let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+  gzip.extend(compress_to_vec(data, 9));
+  gzip.extend(crc32(data).to_le_bytes());
+  gzip.extend((data.len() as u32).to_le_bytes());
+  gzip
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in data {
+    crc ^= u32::from(byte);
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+fn base64(data: &[u8]) -> String {
+  let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+  for group in data.chunks(3) {
+    let bytes = [
+      group[0],
+      *group.get(1).unwrap_or(&0),
+      *group.get(2).unwrap_or(&0),
+    ];
+    let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    for i in 0..4 {
+      if i <= group.len() {
+        let index = (bits >> (18 - 6 * i)) & 0x3f;
+        encoded.push(char::from(BASE64_ALPHABET[index as usize]));
+      } else {
+        encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use miniz_oxide::inflate::decompress_to_vec;
+
+  fn config(compress_threshold: Option<usize>) -> Config {
+    Config {
+      compress_threshold,
+      max_content_bytes: 100,
+      ..Config::default()
+    }
+  }
+
+  fn decode_base64(encoded: &str) -> Vec<u8> {
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut decoded = Vec::new();
+    for c in encoded.bytes().filter(|&c| c != b'=') {
+      let value = BASE64_ALPHABET.iter().position(|&a| a == c).unwrap() as u32;
+      bits = (bits << 6) | value;
+      bit_count += 6;
+      if bit_count >= 8 {
+        bit_count -= 8;
+        decoded.push((bits >> bit_count) as u8);
+      }
+    }
+    decoded
+  }
+
+  fn decompress(reply: &str) -> String {
+    let encoded: String = reply.strip_prefix("GZ64:\n").unwrap().split('\n').collect();
+    let gzip = decode_base64(&encoded);
+    assert_eq!(gzip[..3], [0x1f, 0x8b, 8]);
+
+    let (deflated, trailer) = gzip[10..].split_at(gzip.len() - 18);
+    let data = decompress_to_vec(deflated).unwrap();
+    assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+    assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+    String::from_utf8(data).unwrap()
+  }
+
+  #[test]
+  fn base64_pads_partial_groups() {
+    assert_eq!(base64(b"Man"), "TWFu");
+    assert_eq!(base64(b"Ma"), "TWE=");
+    assert_eq!(base64(b"M"), "TQ==");
+    assert_eq!(base64(b""), "");
+  }
+
+  #[test]
+  fn crc32_matches_the_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+  }
+
+  #[test]
+  fn large_reply_is_compressed_and_round_trips() {
+    let reply = "Oct 16 12:00:01 pi systemd[1]: Started Session 42 of user pi.\n".repeat(50);
+    let compressed = compress_reply(&reply, &config(Some(500)));
+
+    assert!(compressed.starts_with("GZ64:\n"), "{compressed}");
+    assert!(compressed.len() < reply.len());
+    assert!(compressed.lines().all(|line| line.len() <= 76));
+    assert_eq!(decompress(&compressed), reply);
+  }
+
+  #[test]
+  fn small_reply_is_unchanged() {
+    let reply = "up 3 days, load average: 0.08";
+    assert!(matches!(
+      compress_reply(reply, &config(Some(500))),
+      Cow::Borrowed(r) if r == reply
+    ));
+  }
+
+  #[test]
+  fn nothing_is_compressed_without_a_threshold() {
+    let reply = "a".repeat(10_000);
+    assert_eq!(compress_reply(&reply, &config(None)), reply);
+  }
+
+  #[test]
+  fn reply_that_would_not_shrink_is_unchanged() {
+    let reply: String = (0..200u32)
+      .map(|i| char::from(b'!' + (i.wrapping_mul(2_654_435_761) >> 26) as u8))
+      .collect();
+    assert_eq!(compress_reply(&reply, &config(Some(10))), reply);
+  }
+}
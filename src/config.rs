@@ -1,12 +1,21 @@
+use crate::executor::DEFAULT_MAX_CONCURRENT_COMMANDS;
+use crate::recent_errors::DEFAULT_ERROR_BUFFER_SIZE;
+use crate::secrets::Secrets;
 use anyhow::{Result, anyhow};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// Messages starting with the `prefix` are commands
+pub const DEFAULT_PREFIX: &str = "!";
+
+/// Replaced with the subcommand name when a group's `command_template` handles it
+pub const SUBCOMMAND_PLACEHOLDER: &str = "{subcommand}";
+
 pub trait Validate {
   fn validate(&self) -> Result<()>;
 }
@@ -18,10 +27,17 @@ pub struct Arg {
   pub default: Option<String>,
   #[serde(default)]
   pub greedy: bool,
+  #[serde(default, rename = "type")]
+  pub kind: ArgType,
+  /// The only values accepted, if any are listed
+  #[serde(default)]
+  pub choices: Vec<String>,
 }
 
 impl Validate for Arg {
   fn validate(&self) -> Result<()> {
+    validate_var_name(&self.name)?;
+
     if let Some(default_value) = self.default.as_deref()
       && default_value.is_empty()
     {
@@ -30,10 +46,97 @@ impl Validate for Arg {
       ))));
     }
 
+    validate_choices(&self.name, &self.choices, self.default.as_deref())?;
+
+    if self.kind == ArgType::Bool {
+      if !self.choices.is_empty() {
+        return Err(anyhow!(ConfigError::ValidationError(format!(
+          "Boolean argument {} cannot have choices",
+          self.name
+        ))));
+      }
+
+      if self.greedy {
+        return Err(anyhow!(ConfigError::ValidationError(format!(
+          "Boolean argument {} cannot be greedy",
+          self.name
+        ))));
+      }
+
+      if let Some(default_value) = self.default.as_deref()
+        && parse_bool(default_value).is_none()
+      {
+        return Err(anyhow!(ConfigError::ValidationError(format!(
+          "Default value of boolean argument {} must be true/false, yes/no or 1/0: {default_value}",
+          self.name
+        ))));
+      }
+    }
+
     Ok(())
   }
 }
 
+/// A `bool` arg accepts [`parse_bool`]'s spellings and is passed to the command as `bool_true` or
+/// `bool_false`, like a boolean flag
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgType {
+  #[default]
+  String,
+  Bool,
+}
+
+/// `true`/`false`, `yes`/`no` or `1`/`0`, in any case
+pub fn parse_bool(value: &str) -> Option<bool> {
+  match value.to_ascii_lowercase().as_str() {
+    "true" | "yes" | "1" => Some(true),
+    "false" | "no" | "0" => Some(false),
+    _ => None,
+  }
+}
+
+/// Choices can't be blank or repeated, and a default must be one of them
+fn validate_choices(name: &str, choices: &[String], default: Option<&str>) -> Result<()> {
+  if choices.iter().any(|choice| choice.trim().is_empty()) {
+    return Err(anyhow!(ConfigError::ValidationError(format!(
+      "Choices for {name} cannot be blank"
+    ))));
+  }
+
+  if let Some(duplicate) = choices
+    .iter()
+    .enumerate()
+    .find_map(|(i, choice)| choices[..i].contains(choice).then_some(choice))
+  {
+    return Err(anyhow!(ConfigError::ValidationError(format!(
+      "Duplicate choice for {name}: {duplicate}"
+    ))));
+  }
+
+  if let Some(default) = default
+    && !choices.is_empty()
+    && !choices.iter().any(|choice| choice == default)
+  {
+    return Err(anyhow!(ConfigError::ValidationError(format!(
+      "Default value of {name} must be one of its choices: {default}"
+    ))));
+  }
+
+  Ok(())
+}
+
+/// Names starting with `__` are reserved for the built-in `${__channel}`-style placeholders
+fn validate_var_name(name: &str) -> Result<()> {
+  if name.starts_with("__") {
+    return Err(anyhow!(ConfigError::ValidationError(format!(
+      "Variable names starting with '__' are reserved: {name}"
+    ))));
+  }
+
+  Ok(())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Flag {
   pub long: String,
@@ -45,6 +148,20 @@ pub struct Flag {
   pub default: Option<String>,
   #[serde(default)]
   pub greedy: bool,
+  /// Whether the flag can be given more than once, collecting every value into its variable
+  #[serde(default)]
+  pub multiple: bool,
+  /// What a `multiple` flag's values are joined with. Default: a space
+  pub separator: Option<String>,
+  /// The only values accepted, if any are listed
+  #[serde(default)]
+  pub choices: Vec<String>,
+}
+
+impl Flag {
+  pub fn separator(&self) -> &str {
+    self.separator.as_deref().unwrap_or(" ")
+  }
 }
 
 impl Validate for Flag {
@@ -64,6 +181,10 @@ impl Validate for Flag {
       ))));
     }
 
+    if let Some(arg_name) = self.arg.as_deref() {
+      validate_var_name(arg_name)?;
+    }
+
     if self.greedy && self.arg.is_none() {
       return Err(anyhow!(ConfigError::ValidationError(format!(
         "Greedy flag {} must have an 'arg' field",
@@ -71,23 +192,203 @@ impl Validate for Flag {
       ))));
     }
 
+    if self.multiple && self.arg.is_none() {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Multiple flag {} must have an 'arg' field",
+        self.long
+      ))));
+    }
+
+    if !self.choices.is_empty() && self.arg.is_none() {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Flag {} with choices must have an 'arg' field",
+        self.long
+      ))));
+    }
+    validate_choices(&self.long, &self.choices, self.default.as_deref())?;
+
+    if self.separator.is_some() && !self.multiple {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Flag {} has a 'separator' but isn't 'multiple'",
+        self.long
+      ))));
+    }
+
     Ok(())
   }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+  #[default]
+  Stdout,
+  Stderr,
+  Both,
+}
+
+/// Removed from the start of each output line: either a literal string, or `{ regex: ... }` for a
+/// pattern matched at the start of the line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LinePrefix {
+  Literal(String),
+  Regex { regex: String },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HelpSort {
+  #[default]
+  Declared,
+  Alpha,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandSizePolicy {
+  #[default]
+  Reject,
+  Truncate,
+}
+
+/// What to do at startup about commands whose program isn't an executable on `PATH`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandPathCheck {
+  #[default]
+  Off,
+  Warn,
+  Strict,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizeChunkPolicy {
+  Drop,
+  #[default]
+  Truncate,
+  Split,
+}
+
+/// `channel` accepts a single channel index or a list of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ChannelList {
+  One(u32),
+  Many(Vec<u32>),
+}
+
+impl From<ChannelList> for Vec<u32> {
+  fn from(channels: ChannelList) -> Self {
+    match channels {
+      ChannelList::One(channel) => vec![channel],
+      ChannelList::Many(channels) => channels,
+    }
+  }
+}
+
+/// Whether replies are broadcast on the channel or sent as a direct message to the requester
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyMode {
+  #[default]
+  Broadcast,
+  Direct,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Command {
   pub name: String,
+  /// Other names the command can be run by, e.g. `st` for `status`
+  #[serde(default)]
+  pub aliases: Vec<String>,
   #[serde(default)]
   pub help: String,
+  /// Left out of `!help` listings, but still runs and answers `--help`
+  #[serde(default)]
+  pub hidden: bool,
   #[serde(default)]
   pub args: Vec<Arg>,
   #[serde(default)]
   pub flags: Vec<Flag>,
   #[serde(default)]
   pub command: String,
+  /// Run in order instead of `command`, stopping at the first one that fails
+  #[serde(default)]
+  pub steps: Vec<String>,
+  /// Run the `steps` as a pipeline, each one's stdout piped into the next one's stdin
+  #[serde(default)]
+  pub pipe_steps: bool,
+  /// Fixed environment variables for the command, overridden by its arg and flag values
+  #[serde(default)]
+  pub env: HashMap<String, String>,
   #[serde(default)]
   pub commands: Vec<Command>,
+  #[serde(default)]
+  pub output_stream: OutputStream,
+  /// With `output_stream: both`, mark stderr lines so they can be told apart from stdout
+  #[serde(default)]
+  pub label_streams: bool,
+  pub confirm_phrase: Option<String>,
+  pub on_success: Option<String>,
+  pub max_output_lines: Option<usize>,
+  pub max_output_bytes: Option<usize>,
+  /// Longest value, in bytes, accepted for any of the command's args or flags
+  pub max_value_bytes: Option<usize>,
+  pub command_template: Option<String>,
+  pub precondition: Option<String>,
+  pub precondition_message: Option<String>,
+  #[serde(default)]
+  pub requires_os: Vec<String>,
+  pub when: Option<String>,
+  pub output_filter: Option<String>,
+  pub strip_line_prefix: Option<LinePrefix>,
+  pub timeout: Option<u64>,
+  pub progress_interval_secs: Option<u64>,
+  /// Collect unknown flags into `extra_flags` instead of rejecting them
+  #[serde(default)]
+  pub allow_unknown_flags: bool,
+  /// Run the command without replying, e.g. to trigger a background job
+  #[serde(default)]
+  pub no_reply: bool,
+  /// With `no_reply`, still reply when the command fails
+  #[serde(default)]
+  pub reply_on_error: bool,
+  /// Emoji tapback on the command's message when it exits successfully
+  pub success_reaction: Option<String>,
+  /// Emoji tapback on the command's message when it fails, times out or can't be run
+  pub failure_reaction: Option<String>,
+  /// Files the command was imported through, outermost first. Empty for commands defined in the
+  /// root config.
+  #[serde(skip)]
+  pub sources: Vec<PathBuf>,
+}
+
+impl Command {
+  /// Groups have explicit subcommands, a `command_template` for any other subcommand name, or both
+  pub fn is_group(&self) -> bool {
+    !self.commands.is_empty() || self.command_template.is_some()
+  }
+
+  /// The command's name followed by its aliases
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(String::as_str))
+  }
+
+  /// Whether the outcome of running the command is sent back, given whether it failed
+  pub fn replies(&self, failed: bool) -> bool {
+    !self.no_reply || (failed && self.reply_on_error)
+  }
+
+  /// The tapback to react to the command's message with, given whether it succeeded
+  pub fn reaction(&self, succeeded: bool) -> Option<&str> {
+    if succeeded {
+      self.success_reaction.as_deref()
+    } else {
+      self.failure_reaction.as_deref()
+    }
+  }
 }
 
 impl Validate for Command {
@@ -98,12 +399,27 @@ impl Validate for Command {
       ))));
     }
 
-    let is_group = !self.commands.is_empty();
-    let is_leaf = !self.command.is_empty();
+    if self.aliases.iter().any(|alias| alias.trim().is_empty()) {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': aliases cannot be empty",
+        self.name
+      ))));
+    }
+
+    let reactions = [&self.success_reaction, &self.failure_reaction];
+    if reactions.into_iter().flatten().any(|r| r.trim().is_empty()) {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': reactions cannot be empty",
+        self.name
+      ))));
+    }
+
+    let is_group = self.is_group();
+    let is_leaf = !self.command.is_empty() || !self.steps.is_empty();
 
     if is_group && is_leaf {
       return Err(anyhow!(ConfigError::ValidationError(format!(
-        "Command '{}': cannot have both 'command' and 'commands'",
+        "Command '{}': cannot have both 'command' or 'steps' and 'commands' or 'command_template'",
         self.name
       ))));
     }
@@ -115,6 +431,27 @@ impl Validate for Command {
       ))));
     }
 
+    if !self.command.is_empty() && !self.steps.is_empty() {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': cannot have both 'command' and 'steps'",
+        self.name
+      ))));
+    }
+
+    if self.steps.iter().any(|step| step.trim().is_empty()) {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': steps cannot be empty",
+        self.name
+      ))));
+    }
+
+    if self.pipe_steps && self.steps.len() < 2 {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': pipe_steps needs at least two steps",
+        self.name
+      ))));
+    }
+
     if is_group {
       if !self.args.is_empty() || !self.flags.is_empty() {
         return Err(anyhow!(ConfigError::ValidationError(format!(
@@ -122,18 +459,75 @@ impl Validate for Command {
           self.name
         ))));
       }
+      if self
+        .command_template
+        .as_deref()
+        .is_some_and(|template| !template.contains(SUBCOMMAND_PLACEHOLDER))
+      {
+        return Err(anyhow!(ConfigError::ValidationError(format!(
+          "Command '{}': command_template must contain {SUBCOMMAND_PLACEHOLDER}",
+          self.name
+        ))));
+      }
+      validate_unique_names(&self.commands)?;
       for subcommand in &self.commands {
         subcommand.validate()?;
       }
       return Ok(());
     }
 
+    if self
+      .confirm_phrase
+      .as_deref()
+      .is_some_and(|phrase| phrase.trim().is_empty())
+    {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': confirm_phrase cannot be empty",
+        self.name
+      ))));
+    }
+
+    match &self.strip_line_prefix {
+      Some(LinePrefix::Literal(prefix)) if prefix.is_empty() => {
+        return Err(anyhow!(ConfigError::ValidationError(format!(
+          "Command '{}': strip_line_prefix cannot be empty",
+          self.name
+        ))));
+      }
+      Some(LinePrefix::Regex { regex }) => {
+        if let Err(e) = Regex::new(regex) {
+          return Err(anyhow!(ConfigError::ValidationError(format!(
+            "Command '{}': invalid strip_line_prefix regex: {e}",
+            self.name
+          ))));
+        }
+      }
+      _ => {}
+    }
+
+    if self.env.keys().any(|key| key.trim().is_empty()) {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Command '{}': env variable names cannot be empty",
+        self.name
+      ))));
+    }
+
     for arg in &self.args {
       arg.validate()?;
     }
 
+    let mut seen_flags = HashSet::new();
     for flag in &self.flags {
       flag.validate()?;
+
+      for name in std::iter::once(&flag.long).chain(flag.short.as_ref()) {
+        if !seen_flags.insert(name.trim()) {
+          return Err(anyhow!(ConfigError::ValidationError(format!(
+            "Command '{}': duplicate flag '{}'",
+            self.name, name
+          ))));
+        }
+      }
     }
 
     let greedy_arg_count = self.args.iter().filter(|a| a.greedy).count();
@@ -165,113 +559,640 @@ impl Validate for Command {
   }
 }
 
+/// Sibling commands' names and aliases must all be distinct, so each resolves to one command
+fn validate_unique_names(commands: &[Command]) -> Result<()> {
+  let mut seen = HashSet::new();
+  for name in commands.iter().flat_map(Command::names) {
+    if !seen.insert(name) {
+      return Err(anyhow!(ConfigError::ValidationError(format!(
+        "Duplicate command name: '{name}'"
+      ))));
+    }
+  }
+
+  Ok(())
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 enum CommandEntry {
   Import { import: String },
-  Command(RawCommand),
+  Command(Box<RawCommand>),
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct RawCommand {
   name: String,
   #[serde(default)]
+  aliases: Vec<String>,
+  #[serde(default)]
   help: String,
   #[serde(default)]
+  hidden: bool,
+  #[serde(default)]
   args: Vec<Arg>,
   #[serde(default)]
   flags: Vec<Flag>,
   #[serde(default)]
   command: String,
   #[serde(default)]
+  steps: Vec<String>,
+  #[serde(default)]
+  pipe_steps: bool,
+  #[serde(default)]
+  env: HashMap<String, String>,
+  #[serde(default)]
   commands: Vec<CommandEntry>,
+  #[serde(default)]
+  output_stream: OutputStream,
+  #[serde(default)]
+  label_streams: bool,
+  confirm_phrase: Option<String>,
+  on_success: Option<String>,
+  max_output_lines: Option<usize>,
+  max_output_bytes: Option<usize>,
+  max_value_bytes: Option<usize>,
+  command_template: Option<String>,
+  precondition: Option<String>,
+  precondition_message: Option<String>,
+  #[serde(default)]
+  requires_os: Vec<String>,
+  when: Option<String>,
+  output_filter: Option<String>,
+  strip_line_prefix: Option<LinePrefix>,
+  timeout: Option<u64>,
+  progress_interval_secs: Option<u64>,
+  #[serde(default)]
+  allow_unknown_flags: bool,
+  #[serde(default)]
+  no_reply: bool,
+  #[serde(default)]
+  reply_on_error: bool,
+  success_reaction: Option<String>,
+  failure_reaction: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct RawConfig {
-  device: String,
-  channel: u32,
-  baud: Option<u32>,
-  shell: String,
-  #[serde(default)]
-  shell_args: Vec<String>,
-  max_text_bytes: usize,
-  chunk_delay: u64,
-  max_content_bytes: usize,
-  commands: Vec<CommandEntry>,
+/// How to reach the Meshtastic node: the serial `device`, or the node's TCP API over the network
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Connection {
+  #[default]
+  Serial,
+  Tcp {
+    host: String,
+    #[serde(default = "default_tcp_port")]
+    port: u16,
+  },
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Config {
-  pub device: String,
-  pub channel: u32,
-  pub baud: Option<u32>,
-  pub shell: String,
-  pub shell_args: Vec<String>,
-  pub max_text_bytes: usize,
-  pub chunk_delay: u64,
-  pub max_content_bytes: usize,
-  pub commands: Vec<Command>,
+fn default_tcp_port() -> u16 {
+  4403
 }
 
-impl Validate for Config {
+impl Display for Connection {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Connection::Serial => write!(f, "serial"),
+      Connection::Tcp { host, port } => write!(f, "tcp://{host}:{port}"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectRetry {
+  pub attempts: u32,
+  pub backoff_ms: u64,
+  pub max_backoff_ms: u64,
+}
+
+impl Default for ConnectRetry {
+  fn default() -> Self {
+    Self {
+      attempts: 1,
+      backoff_ms: 1000,
+      max_backoff_ms: 30000,
+    }
+  }
+}
+
+impl Validate for ConnectRetry {
   fn validate(&self) -> Result<()> {
-    if self.commands.is_empty() {
+    if self.attempts == 0 {
       return Err(anyhow!(ConfigError::ValidationError(
-        "At least one command is required to be defined".to_owned()
+        "connect_retry.attempts must be at least 1".to_owned()
       )));
     }
 
-    for command in &self.commands {
-      command.validate()?
+    if self.max_backoff_ms < self.backoff_ms {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "connect_retry.max_backoff_ms cannot be less than connect_retry.backoff_ms".to_owned()
+      )));
     }
 
     Ok(())
   }
 }
 
-pub struct ConfigLoader {
-  base_path: PathBuf,
-  loaded_files: HashSet<PathBuf>,
+/// At most `max_commands` commands per node every `per_secs` seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+  pub max_commands: u32,
+  pub per_secs: u64,
 }
 
-impl ConfigLoader {
-  pub fn new(base_path: impl AsRef<Path>) -> Self {
-    Self {
-      base_path: base_path.as_ref().to_path_buf(),
-      loaded_files: HashSet::new(),
+impl Validate for RateLimit {
+  fn validate(&self) -> Result<()> {
+    if self.max_commands == 0 || self.per_secs == 0 {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "rate_limit.max_commands and rate_limit.per_secs must be at least 1".to_owned()
+      )));
     }
+
+    Ok(())
   }
+}
 
-  pub fn load(&mut self, config_path: impl AsRef<Path>) -> Result<Config> {
-    let config_path = self.base_path.join(config_path.as_ref());
-    let canonical_path = config_path
-      .canonicalize()
-      .map_err(|e| ConfigError::FileNotFound(config_path.clone(), e))?;
+fn default_max_command_bytes() -> usize {
+  1024
+}
 
-    if !self.loaded_files.insert(canonical_path.clone()) {
-      return Err(anyhow!(ConfigError::CircularImport(canonical_path)));
-    }
+fn default_error_buffer_size() -> usize {
+  DEFAULT_ERROR_BUFFER_SIZE
+}
 
-    let content = fs::read_to_string(&config_path)
-      .map_err(|e| ConfigError::FileNotFound(config_path.clone(), e))?;
+fn default_max_concurrent_commands() -> usize {
+  DEFAULT_MAX_CONCURRENT_COMMANDS
+}
 
-    let raw: RawConfig = serde_yaml::from_str(&content)
-      .map_err(|e| ConfigError::ParseError(config_path.clone(), e))?;
+fn default_packet_dedup_window_secs() -> u64 {
+  60
+}
 
-    let commands = self.resolve_commands(&raw.commands, &config_path)?;
+fn default_pending_input_ttl_secs() -> u64 {
+  600
+}
 
-    Ok(Config {
-      device: raw.device,
-      channel: raw.channel,
-      baud: raw.baud,
-      shell: raw.shell,
-      shell_args: raw.shell_args,
-      max_text_bytes: raw.max_text_bytes,
-      chunk_delay: raw.chunk_delay,
-      max_content_bytes: raw.max_content_bytes,
-      commands,
-    })
+fn default_send_retries() -> u32 {
+  1
+}
+
+fn default_ack_timeout_secs() -> u64 {
+  30
+}
+
+fn default_ack_retries() -> u32 {
+  2
+}
+
+fn default_prefix() -> String {
+  DEFAULT_PREFIX.to_owned()
+}
+
+fn default_verbose_errors() -> bool {
+  true
+}
+
+fn default_bool_true() -> String {
+  "true".to_owned()
+}
+
+fn default_bool_false() -> String {
+  "false".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+struct RawConfig {
+  #[serde(default)]
+  device: String,
+  #[serde(default)]
+  connection: Connection,
+  channel: ChannelList,
+  baud: Option<u32>,
+  shell: String,
+  #[serde(default)]
+  shell_args: Vec<String>,
+  max_text_bytes: usize,
+  chunk_delay: u64,
+  help_chunk_delay: Option<u64>,
+  help_entries_per_chunk: Option<usize>,
+  max_content_bytes: usize,
+  max_output_lines: Option<usize>,
+  max_output_bytes: Option<usize>,
+  max_reply_bytes: Option<usize>,
+  #[serde(default)]
+  connect_retry: ConnectRetry,
+  #[serde(default)]
+  admin_nodes: Vec<u32>,
+  #[serde(default)]
+  allowed_nodes: Vec<u32>,
+  #[serde(default)]
+  reply_unauthorized: bool,
+  rate_limit: Option<RateLimit>,
+  maintenance_message: Option<String>,
+  #[serde(default)]
+  maintenance_block_help: bool,
+  #[serde(default)]
+  dedup_window_secs: u64,
+  #[serde(default)]
+  global_flags: Vec<Flag>,
+  #[serde(default)]
+  airtime_budget_bytes: usize,
+  #[serde(default)]
+  flag_case_insensitive: bool,
+  #[serde(default)]
+  strip_embedded_prefix: bool,
+  #[serde(default = "default_max_command_bytes")]
+  max_command_bytes: usize,
+  #[serde(default)]
+  command_size_policy: CommandSizePolicy,
+  #[serde(default)]
+  oversize_chunk_policy: OversizeChunkPolicy,
+  #[serde(default)]
+  reply_mode: ReplyMode,
+  #[serde(default = "default_bool_true")]
+  bool_true: String,
+  #[serde(default = "default_bool_false")]
+  bool_false: String,
+  #[serde(default = "default_error_buffer_size")]
+  error_buffer_size: usize,
+  #[serde(default = "default_packet_dedup_window_secs")]
+  packet_dedup_window_secs: u64,
+  #[serde(default = "default_pending_input_ttl_secs")]
+  pending_input_ttl_secs: u64,
+  #[serde(default = "default_max_concurrent_commands")]
+  max_concurrent_commands: usize,
+  #[serde(default)]
+  help_sort: HelpSort,
+  #[serde(default)]
+  require_direct: bool,
+  min_snr: Option<f32>,
+  min_rssi: Option<i32>,
+  #[serde(default)]
+  reply_weak_signal: bool,
+  #[serde(default)]
+  ack_last_chunk: bool,
+  #[serde(default = "default_send_retries")]
+  send_retries: u32,
+  retry_backoff_ms: Option<u64>,
+  #[serde(default)]
+  reliable_send: bool,
+  #[serde(default = "default_ack_timeout_secs")]
+  ack_timeout_secs: u64,
+  #[serde(default = "default_ack_retries")]
+  ack_retries: u32,
+  #[serde(default)]
+  collapse_blank_lines: bool,
+  compress_threshold: Option<usize>,
+  #[serde(default)]
+  wrap_words: bool,
+  #[serde(default)]
+  enable_who: bool,
+  #[serde(default)]
+  enable_echo: bool,
+  #[serde(default = "default_verbose_errors")]
+  verbose_errors: bool,
+  #[serde(default = "default_prefix")]
+  prefix: String,
+  #[serde(default)]
+  contain_imports: bool,
+  #[serde(default)]
+  require_commands: bool,
+  #[serde(default)]
+  check_command_paths: CommandPathCheck,
+  #[serde(default)]
+  error_reply_window_secs: u64,
+  silent_failure_message: Option<String>,
+  command_timeout: Option<u64>,
+  on_connect: Option<String>,
+  #[serde(default)]
+  tag_replies: bool,
+  state_file: Option<PathBuf>,
+  secrets_file: Option<PathBuf>,
+  commands_dir: Option<PathBuf>,
+  #[serde(default)]
+  commands: Vec<CommandEntry>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Config {
+  pub device: String,
+  pub connection: Connection,
+  /// The channels commands are served on, from `channel`
+  pub channels: Vec<u32>,
+  pub baud: Option<u32>,
+  pub shell: String,
+  pub shell_args: Vec<String>,
+  pub max_text_bytes: usize,
+  pub chunk_delay: u64,
+  pub help_chunk_delay: Option<u64>,
+  /// Most help entries (indented lines such as commands, args and flags) sent in one chunk
+  pub help_entries_per_chunk: Option<usize>,
+  pub max_content_bytes: usize,
+  /// Default output limits for commands that don't set their own
+  pub max_output_lines: Option<usize>,
+  pub max_output_bytes: Option<usize>,
+  /// Most bytes sent for any one reply, counting every chunk and footer
+  pub max_reply_bytes: Option<usize>,
+  pub connect_retry: ConnectRetry,
+  pub admin_nodes: Vec<u32>,
+  /// Nodes allowed to run commands; empty allows every node
+  pub allowed_nodes: Vec<u32>,
+  pub reply_unauthorized: bool,
+  pub rate_limit: Option<RateLimit>,
+  pub maintenance_message: Option<String>,
+  pub maintenance_block_help: bool,
+  pub dedup_window_secs: u64,
+  pub global_flags: Vec<Flag>,
+  pub airtime_budget_bytes: usize,
+  pub flag_case_insensitive: bool,
+  pub strip_embedded_prefix: bool,
+  pub max_command_bytes: usize,
+  pub command_size_policy: CommandSizePolicy,
+  pub oversize_chunk_policy: OversizeChunkPolicy,
+  pub reply_mode: ReplyMode,
+  pub bool_true: String,
+  pub bool_false: String,
+  pub error_buffer_size: usize,
+  pub packet_dedup_window_secs: u64,
+  /// How long a command waiting for the sender's input is kept. `0` keeps it until answered.
+  pub pending_input_ttl_secs: u64,
+  pub max_concurrent_commands: usize,
+  pub help_sort: HelpSort,
+  pub require_direct: bool,
+  pub min_snr: Option<f32>,
+  pub min_rssi: Option<i32>,
+  pub reply_weak_signal: bool,
+  pub ack_last_chunk: bool,
+  /// Times a chunk whose send fails is retried before it's skipped
+  pub send_retries: u32,
+  /// Wait before the first retry, doubling with each one after. Defaults to the chunk delay.
+  pub retry_backoff_ms: Option<u64>,
+  /// Send every chunk with `want_ack` and wait for its acknowledgement before the next one
+  pub reliable_send: bool,
+  pub ack_timeout_secs: u64,
+  /// Times a chunk is resent after going unacknowledged for `ack_timeout_secs`
+  pub ack_retries: u32,
+  pub collapse_blank_lines: bool,
+  /// Replies longer than this many bytes are sent gzipped and base64-encoded
+  pub compress_threshold: Option<usize>,
+  /// Split lines too long for one chunk across chunks at whitespace, instead of cutting them off
+  pub wrap_words: bool,
+  pub enable_who: bool,
+  pub enable_echo: bool,
+  pub verbose_errors: bool,
+  pub prefix: String,
+  pub error_reply_window_secs: u64,
+  pub silent_failure_message: Option<String>,
+  pub command_timeout: Option<u64>,
+  /// Local shell command run each time the node connection is established
+  pub on_connect: Option<String>,
+  pub tag_replies: bool,
+  /// Refuse to start, instead of only warning, when no commands are available on this host
+  pub require_commands: bool,
+  pub check_command_paths: CommandPathCheck,
+  pub contain_imports: bool,
+  /// Resolved against the config file's directory
+  pub state_file: Option<PathBuf>,
+  pub secrets_file: Option<PathBuf>,
+  #[serde(skip)]
+  pub secrets: Secrets,
+  pub commands_dir: Option<PathBuf>,
+  pub commands: Vec<Command>,
+}
+
+impl Config {
+  /// Where the node is reached, for logs and status replies
+  pub fn node_address(&self) -> String {
+    match (&self.connection, self.baud) {
+      (Connection::Serial, Some(baud)) => format!("{} @ {baud} baud", self.device),
+      (Connection::Serial, None) => self.device.clone(),
+      (connection, _) => connection.to_string(),
+    }
+  }
+
+  /// Whether the node may send commands: every node when `allowed_nodes` is empty, else only the
+  /// listed nodes and the admins
+  pub fn is_allowed(&self, node: u32) -> bool {
+    self.allowed_nodes.is_empty()
+      || self.allowed_nodes.contains(&node)
+      || self.admin_nodes.contains(&node)
+  }
+
+  /// The served channels for logs and status replies, e.g. `1, 3`
+  pub fn channel_list(&self) -> String {
+    self
+      .channels
+      .iter()
+      .map(u32::to_string)
+      .collect::<Vec<_>>()
+      .join(", ")
+  }
+}
+
+impl Validate for Config {
+  fn validate(&self) -> Result<()> {
+    if self.commands.is_empty() {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "At least one command is required to be defined".to_owned()
+      )));
+    }
+
+    validate_unique_names(&self.commands)?;
+
+    for command in &self.commands {
+      command.validate()?
+    }
+
+    match &self.connection {
+      Connection::Tcp { host, .. } if host.trim().is_empty() => {
+        return Err(anyhow!(ConfigError::ValidationError(
+          "'connection.host' cannot be empty".to_owned()
+        )));
+      }
+      _ => {}
+    }
+
+    if self.channels.is_empty() {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "At least one channel is required".to_owned()
+      )));
+    }
+
+    self.connect_retry.validate()?;
+    if let Some(rate_limit) = &self.rate_limit {
+      rate_limit.validate()?;
+    }
+
+    if self.prefix.trim().is_empty() {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "'prefix' cannot be empty or whitespace".to_owned()
+      )));
+    }
+
+    if self.help_entries_per_chunk == Some(0) {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "'help_entries_per_chunk' must be at least 1".to_owned()
+      )));
+    }
+
+    if self.reliable_send && self.ack_timeout_secs == 0 {
+      return Err(anyhow!(ConfigError::ValidationError(
+        "'ack_timeout_secs' must be at least 1 with 'reliable_send'".to_owned()
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+pub struct ConfigLoader {
+  base_path: PathBuf,
+  loaded_files: HashSet<PathBuf>,
+  global_flags: Vec<Flag>,
+  contain_imports: bool,
+  root_dir: Option<PathBuf>,
+}
+
+impl ConfigLoader {
+  pub fn new(base_path: impl AsRef<Path>) -> Self {
+    Self {
+      base_path: base_path.as_ref().to_path_buf(),
+      loaded_files: HashSet::new(),
+      global_flags: Vec::new(),
+      contain_imports: false,
+      root_dir: None,
+    }
+  }
+
+  /// A loader for re-reading the files imported by an already loaded config, with the same global
+  /// flags and import restrictions it was loaded with
+  pub fn for_config(config_path: impl AsRef<Path>, config: &Config) -> Result<Self> {
+    let config_dir = config_path
+      .as_ref()
+      .parent()
+      .filter(|dir| !dir.as_os_str().is_empty())
+      .unwrap_or(Path::new("."));
+    let root_dir = config_dir
+      .canonicalize()
+      .map_err(|e| ConfigError::FileNotFound(config_dir.to_path_buf(), e))?;
+
+    Ok(Self {
+      base_path: root_dir.clone(),
+      loaded_files: HashSet::new(),
+      global_flags: config.global_flags.clone(),
+      contain_imports: config.contain_imports,
+      root_dir: Some(root_dir),
+    })
+  }
+
+  /// Rejects any import (or `commands_dir` file) that resolves outside the root config's
+  /// directory tree, e.g. via `../` or a symlink. The root config can also opt in with
+  /// `contain_imports: true`.
+  pub fn with_contained_imports(mut self) -> Self {
+    self.contain_imports = true;
+    self
+  }
+
+  pub fn load(&mut self, config_path: impl AsRef<Path>) -> Result<Config> {
+    let config_path = self.base_path.join(config_path.as_ref());
+    let canonical_path = config_path
+      .canonicalize()
+      .map_err(|e| ConfigError::FileNotFound(config_path.clone(), e))?;
+
+    if !self.loaded_files.insert(canonical_path.clone()) {
+      return Err(anyhow!(ConfigError::CircularImport(canonical_path)));
+    }
+    self.root_dir = canonical_path.parent().map(Path::to_path_buf);
+
+    let content = fs::read_to_string(&config_path)
+      .map_err(|e| ConfigError::FileNotFound(config_path.clone(), e))?;
+
+    let raw: RawConfig = serde_yaml::from_str(&content)
+      .map_err(|e| ConfigError::ParseError(config_path.clone(), e))?;
+
+    self.global_flags = raw.global_flags.clone();
+    self.contain_imports |= raw.contain_imports;
+    let mut commands = self.resolve_commands(&raw.commands, &config_path)?;
+    let parent_dir = config_path.parent().unwrap_or(Path::new("."));
+    if let Some(commands_dir) = raw.commands_dir.as_ref() {
+      commands.extend(self.load_commands_dir(&parent_dir.join(commands_dir))?);
+    }
+
+    let secrets = match raw.secrets_file.as_ref() {
+      Some(secrets_file) => Secrets::load(&parent_dir.join(secrets_file))?,
+      None => Secrets::default(),
+    };
+
+    Ok(Config {
+      device: raw.device,
+      connection: raw.connection,
+      channels: raw.channel.into(),
+      baud: raw.baud,
+      shell: raw.shell,
+      shell_args: raw.shell_args,
+      max_text_bytes: raw.max_text_bytes,
+      chunk_delay: raw.chunk_delay,
+      help_chunk_delay: raw.help_chunk_delay,
+      help_entries_per_chunk: raw.help_entries_per_chunk,
+      max_content_bytes: raw.max_content_bytes,
+      max_output_lines: raw.max_output_lines,
+      max_output_bytes: raw.max_output_bytes,
+      max_reply_bytes: raw.max_reply_bytes,
+      connect_retry: raw.connect_retry,
+      admin_nodes: raw.admin_nodes,
+      allowed_nodes: raw.allowed_nodes,
+      reply_unauthorized: raw.reply_unauthorized,
+      rate_limit: raw.rate_limit,
+      maintenance_message: raw.maintenance_message,
+      maintenance_block_help: raw.maintenance_block_help,
+      dedup_window_secs: raw.dedup_window_secs,
+      global_flags: raw.global_flags,
+      airtime_budget_bytes: raw.airtime_budget_bytes,
+      flag_case_insensitive: raw.flag_case_insensitive,
+      strip_embedded_prefix: raw.strip_embedded_prefix,
+      max_command_bytes: raw.max_command_bytes,
+      command_size_policy: raw.command_size_policy,
+      oversize_chunk_policy: raw.oversize_chunk_policy,
+      reply_mode: raw.reply_mode,
+      bool_true: raw.bool_true,
+      bool_false: raw.bool_false,
+      error_buffer_size: raw.error_buffer_size,
+      packet_dedup_window_secs: raw.packet_dedup_window_secs,
+      pending_input_ttl_secs: raw.pending_input_ttl_secs,
+      max_concurrent_commands: raw.max_concurrent_commands,
+      help_sort: raw.help_sort,
+      require_direct: raw.require_direct,
+      min_snr: raw.min_snr,
+      min_rssi: raw.min_rssi,
+      reply_weak_signal: raw.reply_weak_signal,
+      ack_last_chunk: raw.ack_last_chunk,
+      send_retries: raw.send_retries,
+      retry_backoff_ms: raw.retry_backoff_ms,
+      reliable_send: raw.reliable_send,
+      ack_timeout_secs: raw.ack_timeout_secs,
+      ack_retries: raw.ack_retries,
+      collapse_blank_lines: raw.collapse_blank_lines,
+      compress_threshold: raw.compress_threshold,
+      wrap_words: raw.wrap_words,
+      enable_who: raw.enable_who,
+      enable_echo: raw.enable_echo,
+      verbose_errors: raw.verbose_errors,
+      prefix: raw.prefix,
+      error_reply_window_secs: raw.error_reply_window_secs,
+      silent_failure_message: raw.silent_failure_message,
+      command_timeout: raw.command_timeout,
+      on_connect: raw.on_connect,
+      tag_replies: raw.tag_replies,
+      require_commands: raw.require_commands,
+      check_command_paths: raw.check_command_paths,
+      contain_imports: self.contain_imports,
+      state_file: raw.state_file.map(|state_file| parent_dir.join(state_file)),
+      secrets_file: raw.secrets_file,
+      secrets,
+      commands_dir: raw.commands_dir,
+      commands,
+    })
   }
 
   fn resolve_commands(
@@ -290,7 +1211,7 @@ impl ConfigLoader {
           resolved.extend(imported_commands);
         }
         CommandEntry::Command(raw_cmd) => {
-          let cmd = self.resolve_command(raw_cmd.clone(), current_file)?;
+          let cmd = self.resolve_command((**raw_cmd).clone(), current_file)?;
           resolved.push(cmd);
         }
       }
@@ -299,11 +1220,53 @@ impl ConfigLoader {
     Ok(resolved)
   }
 
+  /// Loads every `.yaml`/`.yml` file in the directory in file name order. Files that were
+  /// already loaded (e.g. the main config or an explicit import) are skipped.
+  fn load_commands_dir(&mut self, dir: &Path) -> Result<Vec<Command>> {
+    let entries = fs::read_dir(dir).map_err(|e| ConfigError::FileNotFound(dir.to_path_buf(), e))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+      let path = entry
+        .map_err(|e| ConfigError::FileNotFound(dir.to_path_buf(), e))?
+        .path();
+      let is_yaml = path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml");
+
+      if is_yaml && path.is_file() {
+        paths.push(path);
+      }
+    }
+    paths.sort();
+
+    let mut commands = Vec::new();
+    for path in paths {
+      if path
+        .canonicalize()
+        .is_ok_and(|canonical| self.loaded_files.contains(&canonical))
+      {
+        continue;
+      }
+
+      commands.extend(self.load_command_file(&path)?);
+    }
+
+    Ok(commands)
+  }
+
   fn load_command_file(&mut self, path: &Path) -> Result<Vec<Command>> {
     let canonical_path = path
       .canonicalize()
       .map_err(|e| ConfigError::FileNotFound(path.to_path_buf(), e))?;
 
+    if self.contain_imports
+      && let Some(root_dir) = self.root_dir.as_deref()
+      && !canonical_path.starts_with(root_dir)
+    {
+      return Err(anyhow!(ConfigError::ImportOutsideConfigDir(canonical_path)));
+    }
+
     if !self.loaded_files.insert(canonical_path.clone()) {
       return Err(anyhow!(ConfigError::CircularImport(canonical_path)));
     }
@@ -311,15 +1274,18 @@ impl ConfigLoader {
     let content =
       fs::read_to_string(path).map_err(|e| ConfigError::FileNotFound(path.to_path_buf(), e))?;
 
-    if let Ok(raw_cmd) = serde_yaml::from_str::<RawCommand>(&content) {
-      let cmd = self.resolve_command(raw_cmd, path)?;
-      return Ok(vec![cmd]);
-    }
-
-    let entries: Vec<CommandEntry> =
-      serde_yaml::from_str(&content).map_err(|e| ConfigError::ParseError(path.to_path_buf(), e))?;
+    let mut commands = if let Ok(raw_cmd) = serde_yaml::from_str::<RawCommand>(&content) {
+      vec![self.resolve_command(raw_cmd, path)?]
+    } else {
+      let entries: Vec<CommandEntry> = serde_yaml::from_str(&content)
+        .map_err(|e| ConfigError::ParseError(path.to_path_buf(), e))?;
+      self.resolve_commands(&entries, path)?
+    };
 
-    self.resolve_commands(&entries, path)
+    for command in &mut commands {
+      command.sources.insert(0, canonical_path.clone());
+    }
+    Ok(commands)
   }
 
   fn resolve_command(&mut self, raw: RawCommand, current_file: &Path) -> Result<Command> {
@@ -334,21 +1300,130 @@ impl ConfigLoader {
           resolved_subcommands.extend(imported);
         }
         CommandEntry::Command(sub_raw) => {
-          let sub_cmd = self.resolve_command(sub_raw, current_file)?;
+          let sub_cmd = self.resolve_command(*sub_raw, current_file)?;
           resolved_subcommands.push(sub_cmd);
         }
       }
     }
 
+    let flags = if raw.command.is_empty() && raw.steps.is_empty() {
+      raw.flags
+    } else {
+      self.merge_global_flags(raw.flags)
+    };
+
     Ok(Command {
       name: raw.name,
+      aliases: raw.aliases,
       help: raw.help,
+      hidden: raw.hidden,
       args: raw.args,
-      flags: raw.flags,
+      flags,
       command: raw.command,
+      steps: raw.steps,
+      pipe_steps: raw.pipe_steps,
+      env: raw.env,
       commands: resolved_subcommands,
+      output_stream: raw.output_stream,
+      label_streams: raw.label_streams,
+      confirm_phrase: raw.confirm_phrase,
+      on_success: raw.on_success,
+      max_output_lines: raw.max_output_lines,
+      max_output_bytes: raw.max_output_bytes,
+      max_value_bytes: raw.max_value_bytes,
+      command_template: raw.command_template,
+      precondition: raw.precondition,
+      precondition_message: raw.precondition_message,
+      requires_os: raw.requires_os,
+      when: raw.when,
+      output_filter: raw.output_filter,
+      strip_line_prefix: raw.strip_line_prefix,
+      timeout: raw.timeout,
+      progress_interval_secs: raw.progress_interval_secs,
+      allow_unknown_flags: raw.allow_unknown_flags,
+      no_reply: raw.no_reply,
+      reply_on_error: raw.reply_on_error,
+      success_reaction: raw.success_reaction,
+      failure_reaction: raw.failure_reaction,
+      sources: Vec::new(),
     })
   }
+
+  /// Re-reads one imported command file (relative to the config's directory) and splices its
+  /// commands into `commands` in place of the ones previously loaded from it, leaving every other
+  /// command as it was. Returns how many commands the file now defines.
+  pub fn reload_command_file(
+    &mut self,
+    commands: &mut Vec<Command>,
+    path: impl AsRef<Path>,
+  ) -> Result<usize> {
+    let path = self.base_path.join(path.as_ref());
+    let canonical_path = path
+      .canonicalize()
+      .map_err(|e| ConfigError::FileNotFound(path.clone(), e))?;
+
+    let Some((list, range, outer_sources)) = find_imported_commands(commands, &canonical_path)
+    else {
+      return Err(anyhow!(ConfigError::NotImported(path)));
+    };
+
+    let mut reloaded = self.load_command_file(&path)?;
+    for command in &mut reloaded {
+      command.sources.splice(0..0, outer_sources.iter().cloned());
+    }
+    let count = reloaded.len();
+    list.splice(range, reloaded);
+
+    Ok(count)
+  }
+
+  /// Global flags go first so a greedy command-local flag stays last. A command-local flag
+  /// overrides any global flag sharing its long or short name.
+  fn merge_global_flags(&self, local_flags: Vec<Flag>) -> Vec<Flag> {
+    let collides = |global: &Flag| {
+      local_flags.iter().any(|local| {
+        local.long == global.long || (local.short.is_some() && local.short == global.short)
+      })
+    };
+
+    self
+      .global_flags
+      .iter()
+      .filter(|global| !collides(global))
+      .cloned()
+      .chain(local_flags.iter().cloned())
+      .collect()
+  }
+}
+
+type ImportedCommands<'a> = (
+  &'a mut Vec<Command>,
+  std::ops::RangeInclusive<usize>,
+  Vec<PathBuf>,
+);
+
+/// Finds the list holding the commands imported from `file`, the range they occupy in it, and
+/// the files `file` itself was imported through. A file can only be imported once, so its
+/// commands are always adjacent in a single list.
+fn find_imported_commands<'a>(
+  commands: &'a mut Vec<Command>,
+  file: &Path,
+) -> Option<ImportedCommands<'a>> {
+  let from_file = |command: &Command| command.sources.iter().any(|source| source == file);
+  if let Some(first) = commands.iter().position(from_file) {
+    let last = commands.iter().rposition(from_file).unwrap_or(first);
+    let outer_sources = commands[first]
+      .sources
+      .iter()
+      .take_while(|source| *source != file)
+      .cloned()
+      .collect();
+    return Some((commands, first..=last, outer_sources));
+  }
+
+  commands
+    .iter_mut()
+    .find_map(|command| find_imported_commands(&mut command.commands, file))
 }
 
 #[derive(Debug)]
@@ -356,6 +1431,8 @@ pub enum ConfigError {
   FileNotFound(PathBuf, std::io::Error),
   ParseError(PathBuf, serde_yaml::Error),
   CircularImport(PathBuf),
+  ImportOutsideConfigDir(PathBuf),
+  NotImported(PathBuf),
   ValidationError(String),
   ConfigNotFound(Vec<PathBuf>),
 }
@@ -372,6 +1449,14 @@ impl Display for ConfigError {
       ConfigError::CircularImport(path) => {
         write!(f, "Circular import detected: '{}'", path.display())
       }
+      ConfigError::ImportOutsideConfigDir(path) => write!(
+        f,
+        "Import '{}' is outside the config directory",
+        path.display()
+      ),
+      ConfigError::NotImported(path) => {
+        write!(f, "No commands were imported from '{}'", path.display())
+      }
       ConfigError::ValidationError(message) => {
         write!(f, "Validation failed: '{message}'")
       }
@@ -400,6 +1485,8 @@ pub fn load_config(path: impl AsRef<Path>) -> Result<Config> {
   let mut loader = ConfigLoader::new(base_yaml_path);
   let config = match loader.load(yaml_file_name) {
     Ok(config) => Ok(config),
+    // Only fall back to `.yml` if there's no `.yaml`, so errors in the `.yaml` aren't masked
+    Err(e) if yaml_path.exists() => Err(e),
     Err(_) => {
       let yml_path = path.as_ref().with_extension("yml");
       let base_yml_path = yml_path.parent().unwrap_or(Path::new("."));
@@ -463,20 +1550,23 @@ mod tests {
       flags: vec![],
       command: command.to_string(),
       commands: vec![],
+      ..Default::default()
     }
   }
 
   fn valid_config() -> Config {
     Config {
       device: "/dev/ttyUSB0".into(),
-      channel: 1,
+      channels: vec![1],
       baud: None,
       shell: "bash".into(),
       shell_args: vec!["-lc".into()],
       max_text_bytes: 200,
       chunk_delay: 10000,
       max_content_bytes: 180,
+      prefix: DEFAULT_PREFIX.into(),
       commands: vec![leaf_cmd("test", "echo hello")],
+      ..Config::default()
     }
   }
 
@@ -498,72 +1588,189 @@ mod tests {
   }
 
   #[test]
-  fn arg_valid_no_default() {
+  fn arg_with_reserved_name_fails() {
     let arg = Arg {
-      name: "file".into(),
-      help: "path to file".into(),
+      name: "__sender".into(),
+      help: String::new(),
       default: None,
       greedy: false,
-    };
-    assert!(arg.validate().is_ok());
-  }
-
-  #[test]
-  fn arg_valid_non_empty_default() {
-    let arg = Arg {
-      name: "file".into(),
-      help: "path to file".into(),
-      default: Some("default.txt".into()),
-      greedy: false,
-    };
-    assert!(arg.validate().is_ok());
-  }
-
-  #[test]
-  fn arg_empty_default_fails() {
-    let arg = Arg {
-      name: "file".into(),
-      help: "path to file".into(),
-      default: Some(String::new()),
-      greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
     };
     let err = arg.validate().unwrap_err().to_string();
     assert!(
-      err.contains("Default values in arguments cannot be empty"),
+      err.contains("reserved: __sender"),
       "unexpected error: {err}"
     );
   }
 
   #[test]
-  fn flag_valid_long_only() {
+  fn multiple_flag_requires_arg() {
     let flag = Flag {
-      long: "--foo".into(),
+      long: "--host".into(),
       short: None,
       help: None,
       arg: None,
       required: false,
       default: None,
       greedy: false,
+      multiple: true,
+      separator: None,
+      choices: Vec::new(),
     };
-    assert!(flag.validate().is_ok());
-  }
+    let err = flag.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("must have an 'arg' field"),
+      "unexpected error: {err}"
+    );
 
-  #[test]
-  fn flag_valid_long_and_short() {
     let flag = Flag {
-      long: "--foo".into(),
-      short: Some("-f".into()),
-      help: None,
-      arg: None,
-      required: false,
-      default: None,
-      greedy: false,
+      arg: Some("hosts".into()),
+      ..flag
     };
     assert!(flag.validate().is_ok());
+
+    let flag = Flag {
+      multiple: false,
+      separator: Some(",".into()),
+      ..flag
+    };
+    let err = flag.validate().unwrap_err().to_string();
+    assert!(err.contains("isn't 'multiple'"), "unexpected error: {err}");
   }
 
   #[test]
-  fn flag_invalid_long_no_dashes() {
+  fn choices_must_be_distinct_and_include_the_default() {
+    let arg = |choices: &[&str], default: Option<&str>| Arg {
+      name: "env".into(),
+      help: String::new(),
+      default: default.map(Into::into),
+      greedy: false,
+      kind: ArgType::String,
+      choices: choices.iter().map(|c| c.to_string()).collect(),
+    };
+    assert!(arg(&["prod", "dev"], Some("dev")).validate().is_ok());
+
+    let err = arg(&["prod", "dev"], Some("qa")).validate().unwrap_err();
+    assert!(err.to_string().contains("must be one of its choices: qa"));
+    let err = arg(&["prod", "prod"], None).validate().unwrap_err();
+    assert!(err.to_string().contains("Duplicate choice for env: prod"));
+    let err = arg(&["prod", " "], None).validate().unwrap_err();
+    assert!(err.to_string().contains("cannot be blank"));
+  }
+
+  #[test]
+  fn bool_arg_default_must_be_a_boolean() {
+    let arg = |default: &str| Arg {
+      name: "on".into(),
+      help: String::new(),
+      default: Some(default.into()),
+      greedy: false,
+      kind: ArgType::Bool,
+      choices: Vec::new(),
+    };
+    assert!(arg("Yes").validate().is_ok());
+    assert!(arg("0").validate().is_ok());
+    let err = arg("maybe").validate().unwrap_err().to_string();
+    assert!(
+      err.contains("must be true/false"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn bool_arg_cannot_be_greedy() {
+    let arg = Arg {
+      name: "on".into(),
+      help: String::new(),
+      default: None,
+      greedy: true,
+      kind: ArgType::Bool,
+      choices: Vec::new(),
+    };
+    let err = arg.validate().unwrap_err().to_string();
+    assert!(err.contains("cannot be greedy"), "unexpected error: {err}");
+  }
+
+  #[test]
+  fn arg_valid_no_default() {
+    let arg = Arg {
+      name: "file".into(),
+      help: "path to file".into(),
+      default: None,
+      greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
+    };
+    assert!(arg.validate().is_ok());
+  }
+
+  #[test]
+  fn arg_valid_non_empty_default() {
+    let arg = Arg {
+      name: "file".into(),
+      help: "path to file".into(),
+      default: Some("default.txt".into()),
+      greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
+    };
+    assert!(arg.validate().is_ok());
+  }
+
+  #[test]
+  fn arg_empty_default_fails() {
+    let arg = Arg {
+      name: "file".into(),
+      help: "path to file".into(),
+      default: Some(String::new()),
+      greedy: false,
+      kind: ArgType::String,
+      choices: Vec::new(),
+    };
+    let err = arg.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("Default values in arguments cannot be empty"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn flag_valid_long_only() {
+    let flag = Flag {
+      long: "--foo".into(),
+      short: None,
+      help: None,
+      arg: None,
+      required: false,
+      default: None,
+      greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+    };
+    assert!(flag.validate().is_ok());
+  }
+
+  #[test]
+  fn flag_valid_long_and_short() {
+    let flag = Flag {
+      long: "--foo".into(),
+      short: Some("-f".into()),
+      help: None,
+      arg: None,
+      required: false,
+      default: None,
+      greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
+    };
+    assert!(flag.validate().is_ok());
+  }
+
+  #[test]
+  fn flag_invalid_long_no_dashes() {
     let flag = Flag {
       long: "foo".into(),
       short: None,
@@ -572,6 +1779,9 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     };
     let err = flag.validate().unwrap_err().to_string();
     assert!(err.contains("Invalid long flag"), "unexpected error: {err}");
@@ -587,6 +1797,9 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     };
     let err = flag.validate().unwrap_err().to_string();
     assert!(err.contains("Invalid long flag"), "unexpected error: {err}");
@@ -602,6 +1815,9 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     };
     let err = flag.validate().unwrap_err().to_string();
     assert!(
@@ -620,6 +1836,9 @@ mod tests {
       required: false,
       default: None,
       greedy: false,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     };
     let err = flag.validate().unwrap_err().to_string();
     assert!(
@@ -638,6 +1857,9 @@ mod tests {
       required: false,
       default: None,
       greedy: true,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     };
     let err = flag.validate().unwrap_err().to_string();
     assert!(
@@ -656,6 +1878,9 @@ mod tests {
       required: false,
       default: None,
       greedy: true,
+      multiple: false,
+      separator: None,
+      choices: Vec::new(),
     };
     assert!(flag.validate().is_ok());
   }
@@ -669,6 +1894,7 @@ mod tests {
       flags: vec![],
       command: "echo hi".into(),
       commands: vec![],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(err.contains("cannot be empty"), "unexpected error: {err}");
@@ -683,6 +1909,7 @@ mod tests {
       flags: vec![],
       command: "echo hi".into(),
       commands: vec![leaf_cmd("sub", "echo sub")],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(err.contains("cannot have both"), "unexpected error: {err}");
@@ -697,11 +1924,202 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(err.contains("must have either"), "unexpected error: {err}");
   }
 
+  #[test]
+  fn command_with_steps_is_a_valid_leaf() {
+    let cmd = Command {
+      name: "update".into(),
+      steps: vec!["apt-get update".into(), "apt-get upgrade -y".into()],
+      ..Default::default()
+    };
+    assert!(cmd.validate().is_ok());
+  }
+
+  #[test]
+  fn command_both_command_and_steps_fails() {
+    let cmd = Command {
+      steps: vec!["echo two".into()],
+      ..leaf_cmd("mixed", "echo one")
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("cannot have both 'command' and 'steps'"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn pipe_steps_with_a_single_step_fails() {
+    let cmd = Command {
+      name: "logs".into(),
+      steps: vec!["journalctl -n 50".into()],
+      pipe_steps: true,
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("pipe_steps needs at least two steps"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn reaction_follows_the_outcome() {
+    let cmd = Command {
+      success_reaction: Some("👍".into()),
+      failure_reaction: Some("❌".into()),
+      ..leaf_cmd("deploy", "deploy.sh")
+    };
+    assert_eq!(cmd.reaction(true), Some("👍"));
+    assert_eq!(cmd.reaction(false), Some("❌"));
+
+    let cmd = Command {
+      failure_reaction: Some("❌".into()),
+      ..leaf_cmd("deploy", "deploy.sh")
+    };
+    assert_eq!(cmd.reaction(true), None);
+    assert_eq!(cmd.reaction(false), Some("❌"));
+  }
+
+  #[test]
+  fn blank_reaction_fails() {
+    let cmd = Command {
+      success_reaction: Some(" ".into()),
+      ..leaf_cmd("deploy", "deploy.sh")
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("reactions cannot be empty"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn command_with_blank_step_fails() {
+    let cmd = Command {
+      name: "update".into(),
+      steps: vec!["echo one".into(), "  ".into()],
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("steps cannot be empty"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn command_with_blank_env_name_fails() {
+    let cmd = Command {
+      env: HashMap::from([(" ".into(), "default".into())]),
+      ..leaf_cmd("deploy", "kubectl apply -f deploy.yaml")
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("env variable names cannot be empty"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn strip_line_prefix_accepts_literal_or_regex() {
+    let dir = TempDir::new().unwrap();
+    let yaml = format!(
+      "{}{}",
+      valid_config_yaml(),
+      concat!(
+        "  - name: literal\n",
+        "    command: journalctl\n",
+        "    strip_line_prefix: \"[app] \"\n",
+        "  - name: pattern\n",
+        "    command: journalctl\n",
+        "    strip_line_prefix:\n",
+        "      regex: '\\d{2}:\\d{2} '\n",
+      )
+    );
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(
+      config.commands[1].strip_line_prefix,
+      Some(LinePrefix::Literal("[app] ".into()))
+    );
+    assert_eq!(
+      config.commands[2].strip_line_prefix,
+      Some(LinePrefix::Regex {
+        regex: r"\d{2}:\d{2} ".into()
+      })
+    );
+  }
+
+  #[test]
+  fn invalid_strip_line_prefix_regex_fails() {
+    let cmd = Command {
+      strip_line_prefix: Some(LinePrefix::Regex {
+        regex: "([a-z]".into(),
+      }),
+      ..leaf_cmd("logs", "journalctl")
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("invalid strip_line_prefix regex"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn command_template_coexists_with_explicit_subcommands() {
+    let cmd = Command {
+      name: "svc".into(),
+      commands: vec![leaf_cmd("status", "systemctl status foo")],
+      command_template: Some("systemctl {subcommand} foo".into()),
+      ..Default::default()
+    };
+    assert!(cmd.validate().is_ok());
+  }
+
+  #[test]
+  fn command_template_without_subcommands_is_a_group() {
+    let cmd = Command {
+      name: "svc".into(),
+      command_template: Some("systemctl {subcommand} foo".into()),
+      ..Default::default()
+    };
+    assert!(cmd.is_group());
+    assert!(cmd.validate().is_ok());
+  }
+
+  #[test]
+  fn command_template_without_placeholder_fails() {
+    let cmd = Command {
+      name: "svc".into(),
+      command_template: Some("systemctl restart foo".into()),
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("must contain {subcommand}"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn command_template_with_command_fails() {
+    let cmd = Command {
+      name: "svc".into(),
+      command: "echo hi".into(),
+      command_template: Some("systemctl {subcommand} foo".into()),
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(err.contains("cannot have both"), "unexpected error: {err}");
+  }
+
   #[test]
   fn group_command_with_args_fails() {
     let cmd = Command {
@@ -712,10 +2130,13 @@ mod tests {
         help: String::new(),
         default: None,
         greedy: false,
+        kind: ArgType::String,
+        choices: Vec::new(),
       }],
       flags: vec![],
       command: String::new(),
       commands: vec![leaf_cmd("sub", "echo sub")],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(
@@ -738,9 +2159,13 @@ mod tests {
         required: false,
         default: None,
         greedy: false,
+        multiple: false,
+        separator: None,
+        choices: Vec::new(),
       }],
       command: String::new(),
       commands: vec![leaf_cmd("sub", "echo sub")],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(
@@ -764,6 +2189,7 @@ mod tests {
       flags: vec![],
       command: String::new(),
       commands: vec![leaf_cmd("sub", "echo sub")],
+      ..Default::default()
     };
     assert!(cmd.validate().is_ok());
   }
@@ -779,17 +2205,22 @@ mod tests {
           help: String::new(),
           default: None,
           greedy: true,
+          kind: ArgType::String,
+          choices: Vec::new(),
         },
         Arg {
           name: "b".into(),
           help: String::new(),
           default: None,
           greedy: true,
+          kind: ArgType::String,
+          choices: Vec::new(),
         },
       ],
       flags: vec![],
       command: "echo hi".into(),
       commands: vec![],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(
@@ -809,17 +2240,22 @@ mod tests {
           help: String::new(),
           default: None,
           greedy: true,
+          kind: ArgType::String,
+          choices: Vec::new(),
         },
         Arg {
           name: "second".into(),
           help: String::new(),
           default: None,
           greedy: false,
+          kind: ArgType::String,
+          choices: Vec::new(),
         },
       ],
       flags: vec![],
       command: "echo hi".into(),
       commands: vec![],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(
@@ -843,6 +2279,9 @@ mod tests {
           required: false,
           default: None,
           greedy: true,
+          multiple: false,
+          separator: None,
+          choices: Vec::new(),
         },
         Flag {
           long: "--second".into(),
@@ -852,10 +2291,14 @@ mod tests {
           required: false,
           default: None,
           greedy: false,
+          multiple: false,
+          separator: None,
+          choices: Vec::new(),
         },
       ],
       command: "echo hi".into(),
       commands: vec![],
+      ..Default::default()
     };
     let err = cmd.validate().unwrap_err().to_string();
     assert!(
@@ -875,17 +2318,22 @@ mod tests {
           help: String::new(),
           default: None,
           greedy: false,
+          kind: ArgType::String,
+          choices: Vec::new(),
         },
         Arg {
           name: "rest".into(),
           help: String::new(),
           default: None,
           greedy: true,
+          kind: ArgType::String,
+          choices: Vec::new(),
         },
       ],
       flags: vec![],
       command: "echo hi".into(),
       commands: vec![],
+      ..Default::default()
     };
     assert!(cmd.validate().is_ok());
   }
@@ -905,6 +2353,9 @@ mod tests {
           required: false,
           default: None,
           greedy: false,
+          multiple: false,
+          separator: None,
+          choices: Vec::new(),
         },
         Flag {
           long: "--rest".into(),
@@ -914,63 +2365,545 @@ mod tests {
           required: false,
           default: None,
           greedy: true,
+          multiple: false,
+          separator: None,
+          choices: Vec::new(),
         },
       ],
       command: "echo hi".into(),
       commands: vec![],
+      ..Default::default()
     };
     assert!(cmd.validate().is_ok());
   }
 
   #[test]
-  fn recursive_group_validates_subcommands() {
-    let cmd = Command {
-      name: "parent".into(),
-      help: String::new(),
-      args: vec![],
-      flags: vec![],
-      command: String::new(),
-      commands: vec![Command {
-        name: String::new(),
-        help: String::new(),
-        args: vec![],
-        flags: vec![],
-        command: "echo x".into(),
-        commands: vec![],
-      }],
-    };
-    let err = cmd.validate().unwrap_err().to_string();
-    assert!(err.contains("cannot be empty"), "unexpected error: {err}");
+  fn recursive_group_validates_subcommands() {
+    let cmd = Command {
+      name: "parent".into(),
+      help: String::new(),
+      args: vec![],
+      flags: vec![],
+      command: String::new(),
+      commands: vec![Command {
+        name: String::new(),
+        help: String::new(),
+        args: vec![],
+        flags: vec![],
+        command: "echo x".into(),
+        commands: vec![],
+        ..Default::default()
+      }],
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(err.contains("cannot be empty"), "unexpected error: {err}");
+  }
+
+  #[test]
+  fn config_empty_commands_fails() {
+    let mut cfg = valid_config();
+    cfg.commands.clear();
+    let err = cfg.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("At least one command"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn config_valid_commands_ok() {
+    assert!(valid_config().validate().is_ok());
+  }
+
+  #[test]
+  fn config_validates_nested_command() {
+    let mut cfg = valid_config();
+    cfg.commands.push(Command {
+      name: String::new(),
+      help: String::new(),
+      args: vec![],
+      flags: vec![],
+      command: "echo x".into(),
+      commands: vec![],
+      ..Default::default()
+    });
+    assert!(cfg.validate().is_err());
+  }
+
+  #[test]
+  fn connect_retry_zero_attempts_fails() {
+    let mut cfg = valid_config();
+    cfg.connect_retry.attempts = 0;
+    let err = cfg.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("attempts must be at least 1"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn connect_retry_max_backoff_below_base_fails() {
+    let mut cfg = valid_config();
+    cfg.connect_retry.backoff_ms = 5000;
+    cfg.connect_retry.max_backoff_ms = 1000;
+    let err = cfg.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("max_backoff_ms cannot be less than"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn load_config_connect_retry_defaults_when_omitted() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.connect_retry.attempts, 1);
+    assert_eq!(config.connect_retry.backoff_ms, 1000);
+    assert_eq!(config.connect_retry.max_backoff_ms, 30000);
+  }
+
+  #[test]
+  fn load_config_with_partial_connect_retry() {
+    let dir = TempDir::new().unwrap();
+    let yaml = format!("{}connect_retry:\n  attempts: 5\n", valid_config_yaml());
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.connect_retry.attempts, 5);
+    assert_eq!(config.connect_retry.backoff_ms, 1000);
+  }
+
+  #[test]
+  fn load_config_with_admin_nodes_and_maintenance_options() {
+    let dir = TempDir::new().unwrap();
+    let yaml = format!(
+      "{}admin_nodes: [1234, 5678]\nmaintenance_message: Upgrading\nmaintenance_block_help: true\n",
+      valid_config_yaml()
+    );
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.admin_nodes, vec![1234, 5678]);
+    assert_eq!(config.maintenance_message.as_deref(), Some("Upgrading"));
+    assert!(config.maintenance_block_help);
+  }
+
+  #[test]
+  fn empty_allowed_nodes_allows_everyone() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert!(config.allowed_nodes.is_empty());
+    assert!(!config.reply_unauthorized);
+    assert!(config.is_allowed(1234));
+  }
+
+  #[test]
+  fn allowed_nodes_restricts_to_listed_nodes_and_admins() {
+    let dir = TempDir::new().unwrap();
+    let yaml = format!(
+      "{}allowed_nodes: [1234]
+admin_nodes: [42]
+reply_unauthorized: true
+",
+      valid_config_yaml()
+    );
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert!(config.reply_unauthorized);
+    assert!(config.is_allowed(1234));
+    assert!(config.is_allowed(42));
+    assert!(!config.is_allowed(5678));
+  }
+
+  #[test]
+  fn load_config_with_rate_limit() {
+    let dir = TempDir::new().unwrap();
+    let yaml = format!(
+      "{}rate_limit:\n  max_commands: 5\n  per_secs: 60\n",
+      valid_config_yaml()
+    );
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(
+      config.rate_limit,
+      Some(RateLimit {
+        max_commands: 5,
+        per_secs: 60
+      })
+    );
+  }
+
+  #[test]
+  fn zero_rate_limit_fails() {
+    let config = Config {
+      rate_limit: Some(RateLimit {
+        max_commands: 0,
+        per_secs: 60,
+      }),
+      ..valid_config()
+    };
+    let err = config.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("rate_limit.max_commands"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn empty_confirm_phrase_fails() {
+    let cmd = Command {
+      name: "wipe".into(),
+      command: "do-wipe".into(),
+      confirm_phrase: Some("  ".into()),
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("confirm_phrase cannot be empty"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn duplicate_flag_fails() {
+    let cmd = Command {
+      name: "test".into(),
+      command: "echo".into(),
+      flags: vec![
+        Flag {
+          long: "--verbose".into(),
+          short: Some("-v".into()),
+          help: None,
+          arg: None,
+          required: false,
+          default: None,
+          greedy: false,
+          multiple: false,
+          separator: None,
+          choices: Vec::new(),
+        },
+        Flag {
+          long: "--version".into(),
+          short: Some("-v".into()),
+          help: None,
+          arg: None,
+          required: false,
+          default: None,
+          greedy: false,
+          multiple: false,
+          separator: None,
+          choices: Vec::new(),
+        },
+      ],
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("duplicate flag '-v'"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn load_config_merges_global_flags_into_leaf_commands() {
+    let dir = TempDir::new().unwrap();
+    let yaml = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            global_flags:
+              - long: --verbose
+                short: -v
+                help: Verbose output
+            commands:
+              - name: plain
+                command: echo plain
+              - name: quiet
+                command: echo quiet
+                flags:
+                  - long: --verbose
+                    help: Local verbose
+              - name: search
+                command: grep
+                flags:
+                  - long: --pattern
+                    arg: pattern
+                    greedy: true
+              - name: group
+                commands:
+                  - name: sub
+                    command: echo sub
+        "};
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.global_flags.len(), 1);
+
+    let plain = &config.commands[0];
+    assert_eq!(plain.flags.len(), 1);
+    assert_eq!(plain.flags[0].long, "--verbose");
+    assert_eq!(plain.flags[0].short.as_deref(), Some("-v"));
+
+    let quiet = &config.commands[1];
+    assert_eq!(quiet.flags.len(), 1);
+    assert_eq!(quiet.flags[0].help.as_deref(), Some("Local verbose"));
+    assert!(quiet.flags[0].short.is_none());
+
+    let search = &config.commands[2];
+    let longs: Vec<_> = search.flags.iter().map(|f| f.long.as_str()).collect();
+    assert_eq!(longs, vec!["--verbose", "--pattern"]);
+
+    let group = &config.commands[3];
+    assert!(group.flags.is_empty());
+    assert_eq!(group.commands[0].flags[0].long, "--verbose");
+  }
+
+  #[test]
+  fn load_config_greedy_global_flag_fails_validation() {
+    let dir = TempDir::new().unwrap();
+    let yaml = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            global_flags:
+              - long: --message
+                arg: text
+                greedy: true
+            commands:
+              - name: test
+                command: echo
+                flags:
+                  - long: --loud
+        "};
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let err = load_config(dir.path().join("config"))
+      .unwrap_err()
+      .to_string();
+    assert!(
+      err.contains("greedy flag must be the last flag"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn load_config_defaults_to_serial_connection() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.connection, Connection::Serial);
+  }
+
+  #[test]
+  fn load_config_tcp_connection_without_device() {
+    let dir = TempDir::new().unwrap();
+    let yaml = valid_config_yaml().replace(
+      "device: /dev/ttyUSB0\n",
+      "connection:\n  type: tcp\n  host: meshpi.local\n",
+    );
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(
+      config.connection,
+      Connection::Tcp {
+        host: "meshpi.local".into(),
+        port: 4403
+      }
+    );
+    assert_eq!(config.connection.to_string(), "tcp://meshpi.local:4403");
+  }
+
+  #[test]
+  fn serial_connection_without_device_is_auto_detected() {
+    let dir = TempDir::new().unwrap();
+    let yaml = valid_config_yaml().replace("device: /dev/ttyUSB0\n", "");
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.connection, Connection::Serial);
+    assert!(config.device.is_empty());
+  }
+
+  #[test]
+  fn tcp_connection_requires_host() {
+    let config = Config {
+      connection: Connection::Tcp {
+        host: " ".into(),
+        port: 4403,
+      },
+      commands: vec![leaf_cmd("ping", "echo pong")],
+      ..Config::default()
+    };
+    let err = config.validate().unwrap_err().to_string();
+    assert!(err.contains("connection.host"), "unexpected error: {err}");
+  }
+
+  #[test]
+  fn load_config_command_size_defaults() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.max_command_bytes, 1024);
+    assert_eq!(config.command_size_policy, CommandSizePolicy::Reject);
+    assert_eq!(config.oversize_chunk_policy, OversizeChunkPolicy::Truncate);
+  }
+
+  #[test]
+  fn load_config_bool_values_default_to_true_and_false() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.bool_true, "true");
+    assert_eq!(config.bool_false, "false");
+  }
+
+  #[test]
+  fn load_config_with_secrets_file() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("secrets.yaml"), "API_TOKEN: s3cr3t-value\n").unwrap();
+    let yaml = format!("secrets_file: secrets.yaml\n{}", valid_config_yaml());
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    let secrets: Vec<_> = config.secrets.iter().collect();
+    assert_eq!(
+      secrets,
+      vec![(&"API_TOKEN".to_owned(), &"s3cr3t-value".to_owned())]
+    );
+
+    let dump = serde_yaml::to_string(&config).unwrap();
+    assert!(dump.contains("secrets_file: secrets.yaml"));
+    assert!(!dump.contains("s3cr3t-value"));
+    assert!(!format!("{config:?}").contains("s3cr3t-value"));
+  }
+
+  #[test]
+  fn load_config_without_secrets_file_has_no_secrets() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert!(config.secrets.is_empty());
+  }
+
+  #[test]
+  fn load_config_dedup_window_defaults_to_disabled() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.dedup_window_secs, 0);
+    assert_eq!(config.packet_dedup_window_secs, 60);
+    assert_eq!(config.pending_input_ttl_secs, 600);
+  }
+
+  #[test]
+  fn reliable_send_defaults() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert!(!config.reliable_send);
+    assert_eq!(config.send_retries, 1);
+    assert_eq!(config.retry_backoff_ms, None);
+    assert_eq!(config.ack_timeout_secs, 30);
+    assert_eq!(config.ack_retries, 2);
+  }
+
+  #[test]
+  fn reliable_send_requires_an_ack_timeout() {
+    let dir = TempDir::new().unwrap();
+    let yaml = format!(
+      "{}reliable_send: true\nack_timeout_secs: 0\n",
+      valid_config_yaml()
+    );
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let err = load_config(dir.path().join("config")).unwrap_err();
+    assert!(
+      format!("{err:#}").contains("'ack_timeout_secs' must be at least 1"),
+      "unexpected error: {err:#}"
+    );
+  }
+
+  #[test]
+  fn load_config_with_channel_list() {
+    let dir = TempDir::new().unwrap();
+    let yaml = valid_config_yaml().replace("channel: 1", "channel: [1, 3]");
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.channels, vec![1, 3]);
   }
 
   #[test]
-  fn config_empty_commands_fails() {
-    let mut cfg = valid_config();
-    cfg.commands.clear();
-    let err = cfg.validate().unwrap_err().to_string();
-    assert!(
-      err.contains("At least one command"),
-      "unexpected error: {err}"
-    );
+  fn load_config_with_empty_channel_list_errors() {
+    let dir = TempDir::new().unwrap();
+    let yaml = valid_config_yaml().replace("channel: 1", "channel: []");
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let err = load_config(dir.path().join("config")).unwrap_err();
+    assert!(err.to_string().contains("At least one channel is required"));
   }
 
   #[test]
-  fn config_valid_commands_ok() {
-    assert!(valid_config().validate().is_ok());
+  fn load_config_prefix_defaults_to_bang() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.prefix, "!");
   }
 
   #[test]
-  fn config_validates_nested_command() {
-    let mut cfg = valid_config();
-    cfg.commands.push(Command {
-      name: String::new(),
-      help: String::new(),
-      args: vec![],
-      flags: vec![],
-      command: "echo x".into(),
-      commands: vec![],
-    });
-    assert!(cfg.validate().is_err());
+  fn whitespace_prefix_is_rejected() {
+    for prefix in ["", "  "] {
+      let config = Config {
+        prefix: prefix.into(),
+        ..valid_config()
+      };
+      let err = config.validate().unwrap_err().to_string();
+      assert!(err.contains("'prefix' cannot be empty"), "{err}");
+    }
+  }
+
+  #[test]
+  fn zero_help_entries_per_chunk_is_rejected() {
+    let config = Config {
+      help_entries_per_chunk: Some(0),
+      ..valid_config()
+    };
+    let err = config.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("'help_entries_per_chunk' must be at least 1"),
+      "{err}"
+    );
+  }
+
+  #[test]
+  fn load_config_errors_are_verbose_by_default() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.yaml"), valid_config_yaml()).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert!(config.verbose_errors);
   }
 
   #[test]
@@ -980,7 +2913,7 @@ mod tests {
 
     let config = load_config(dir.path().join("config")).unwrap();
     assert_eq!(config.device, "/dev/ttyUSB0");
-    assert_eq!(config.channel, 1);
+    assert_eq!(config.channels, vec![1]);
     assert!(config.baud.is_none());
     assert_eq!(config.shell, "bash");
     assert_eq!(config.shell_args, vec!["-lc"]);
@@ -1049,6 +2982,188 @@ mod tests {
     assert_eq!(config.commands[1].name, "inline");
   }
 
+  #[test]
+  fn load_config_with_commands_dir() {
+    let dir = TempDir::new().unwrap();
+    let commands_dir = dir.path().join("commands.d");
+    fs::create_dir(&commands_dir).unwrap();
+
+    fs::write(
+      commands_dir.join("b.yml"),
+      "- name: b_cmd\n  command: echo b\n",
+    )
+    .unwrap();
+    fs::write(
+      commands_dir.join("a.yaml"),
+      "name: a_cmd\ncommand: echo a\n",
+    )
+    .unwrap();
+    fs::write(commands_dir.join("notes.txt"), "not a command").unwrap();
+
+    let main = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            commands_dir: commands.d
+            commands:
+              - name: inline
+                command: echo inline
+        "};
+    fs::write(dir.path().join("config.yaml"), main).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    let names: Vec<_> = config.commands.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["inline", "a_cmd", "b_cmd"]);
+  }
+
+  #[test]
+  fn load_config_with_only_commands_dir() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("commands.d")).unwrap();
+    fs::write(
+      dir.path().join("commands.d").join("ping.yaml"),
+      "name: ping\ncommand: echo pong\n",
+    )
+    .unwrap();
+
+    let main = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            commands_dir: commands.d
+        "};
+    fs::write(dir.path().join("config.yaml"), main).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.commands.len(), 1);
+    assert_eq!(config.commands[0].name, "ping");
+  }
+
+  #[test]
+  fn commands_dir_skips_explicitly_imported_files() {
+    let dir = TempDir::new().unwrap();
+    fs::write(
+      dir.path().join("extra.yaml"),
+      "name: extra\ncommand: echo extra\n",
+    )
+    .unwrap();
+
+    let main = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            commands_dir: .
+            commands:
+              - import: extra.yaml
+        "};
+    fs::write(dir.path().join("config.yaml"), main).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.commands.len(), 1);
+    assert_eq!(config.commands[0].name, "extra");
+  }
+
+  #[test]
+  fn commands_dir_duplicate_name_fails() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("commands.d")).unwrap();
+    fs::write(
+      dir.path().join("commands.d").join("ping.yaml"),
+      "name: ping\ncommand: echo other\n",
+    )
+    .unwrap();
+
+    let main = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            commands_dir: commands.d
+            commands:
+              - name: ping
+                command: echo pong
+        "};
+    fs::write(dir.path().join("config.yaml"), main).unwrap();
+
+    let err = load_config(dir.path().join("config"))
+      .unwrap_err()
+      .to_string();
+    assert!(
+      err.contains("Duplicate command name: 'ping'"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn duplicate_subcommand_name_fails() {
+    let cmd = Command {
+      name: "group".into(),
+      commands: vec![
+        Command {
+          name: "sub".into(),
+          command: "echo 1".into(),
+          ..Default::default()
+        },
+        Command {
+          name: "sub".into(),
+          command: "echo 2".into(),
+          ..Default::default()
+        },
+      ],
+      ..Default::default()
+    };
+    let err = cmd.validate().unwrap_err().to_string();
+    assert!(
+      err.contains("Duplicate command name: 'sub'"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn alias_colliding_with_a_sibling_fails() {
+    let command = |name: &str, aliases: &[&str]| Command {
+      name: name.into(),
+      aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+      command: "true".into(),
+      ..Default::default()
+    };
+    let group = |commands| Command {
+      name: "group".into(),
+      commands,
+      ..Default::default()
+    };
+
+    assert!(
+      group(vec![command("status", &["st"]), command("stop", &["sp"])])
+        .validate()
+        .is_ok()
+    );
+
+    let err = group(vec![command("status", &["st"]), command("start", &["st"])])
+      .validate()
+      .unwrap_err();
+    assert!(err.to_string().contains("Duplicate command name: 'st'"));
+
+    let err = group(vec![command("status", &["stop"]), command("stop", &[])])
+      .validate()
+      .unwrap_err();
+    assert!(err.to_string().contains("Duplicate command name: 'stop'"));
+
+    let err = command("status", &[" "]).validate().unwrap_err();
+    assert!(err.to_string().contains("aliases cannot be empty"));
+  }
+
   #[test]
   fn circular_import_detected() {
     let dir = TempDir::new().unwrap();
@@ -1108,6 +3223,237 @@ mod tests {
     );
   }
 
+  /// `config.yaml` with an inline command, then `net.yaml` (which imports `dns.yaml`), then
+  /// `disk.yaml` holding a group whose subcommands are imported from `disk/usage.yaml`
+  fn reload_tree() -> TempDir {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("disk")).unwrap();
+    let main = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            shell: bash
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            global_flags:
+              - long: --verbose
+            commands:
+              - name: ping
+                command: echo pong
+              - import: net.yaml
+              - import: disk.yaml
+        "};
+    fs::write(dir.path().join("config.yaml"), main).unwrap();
+    let net = indoc! {"
+            - name: myip
+              command: curl ifconfig.me
+            - import: dns.yaml
+        "};
+    fs::write(dir.path().join("net.yaml"), net).unwrap();
+    fs::write(
+      dir.path().join("dns.yaml"),
+      "name: dig\ncommand: dig example.com\n",
+    )
+    .unwrap();
+    let disk = indoc! {"
+            name: disk
+            commands:
+              - import: disk/usage.yaml
+        "};
+    fs::write(dir.path().join("disk.yaml"), disk).unwrap();
+    fs::write(
+      dir.path().join("disk/usage.yaml"),
+      "name: usage\ncommand: df -h\n",
+    )
+    .unwrap();
+    dir
+  }
+
+  fn command_names(commands: &[Command]) -> Vec<&str> {
+    commands.iter().map(|c| c.name.as_str()).collect()
+  }
+
+  #[test]
+  fn imported_commands_record_their_source_files() {
+    let dir = reload_tree();
+    let config = load_config(dir.path().join("config")).unwrap();
+    let root = dir.path().canonicalize().unwrap();
+
+    assert!(config.commands[0].sources.is_empty());
+    assert_eq!(config.commands[1].sources, vec![root.join("net.yaml")]);
+    assert_eq!(
+      config.commands[2].sources,
+      vec![root.join("net.yaml"), root.join("dns.yaml")]
+    );
+    assert_eq!(
+      config.commands[3].commands[0].sources,
+      vec![root.join("disk/usage.yaml")]
+    );
+  }
+
+  #[test]
+  fn reloading_a_file_only_replaces_its_commands() {
+    let dir = reload_tree();
+    let config_path = dir.path().join("config");
+    let mut config = load_config(&config_path).unwrap();
+    let net = indoc! {"
+            - name: myip
+              command: curl icanhazip.com
+            - name: myip6
+              command: curl -6 icanhazip.com
+            - import: dns.yaml
+        "};
+    fs::write(dir.path().join("net.yaml"), net).unwrap();
+    fs::write(
+      dir.path().join("disk/usage.yaml"),
+      "name: usage\ncommand: du\n",
+    )
+    .unwrap();
+
+    let mut loader = ConfigLoader::for_config(&config_path, &config).unwrap();
+    let count = loader
+      .reload_command_file(&mut config.commands, "net.yaml")
+      .unwrap();
+
+    assert_eq!(count, 3);
+    assert_eq!(
+      command_names(&config.commands),
+      vec!["ping", "myip", "myip6", "dig", "disk"]
+    );
+    assert_eq!(config.commands[1].command, "curl icanhazip.com");
+    assert_eq!(config.commands[1].flags[0].long, "--verbose");
+    assert_eq!(config.commands[4].commands[0].command, "df -h");
+  }
+
+  #[test]
+  fn reloading_a_nested_import_keeps_its_import_chain() {
+    let dir = reload_tree();
+    let config_path = dir.path().join("config");
+    let mut config = load_config(&config_path).unwrap();
+    fs::write(
+      dir.path().join("disk/usage.yaml"),
+      "name: usage\ncommand: du\n",
+    )
+    .unwrap();
+    fs::write(dir.path().join("dns.yaml"), "name: host\ncommand: host x\n").unwrap();
+
+    let mut loader = ConfigLoader::for_config(&config_path, &config).unwrap();
+    loader
+      .reload_command_file(&mut config.commands, "disk/usage.yaml")
+      .unwrap();
+    loader
+      .reload_command_file(&mut config.commands, "dns.yaml")
+      .unwrap();
+
+    let root = dir.path().canonicalize().unwrap();
+    assert_eq!(config.commands[3].commands[0].command, "du");
+    assert_eq!(config.commands[2].name, "host");
+    assert_eq!(
+      config.commands[2].sources,
+      vec![root.join("net.yaml"), root.join("dns.yaml")]
+    );
+  }
+
+  #[test]
+  fn reloading_a_file_that_was_not_imported_fails() {
+    let dir = reload_tree();
+    let config_path = dir.path().join("config");
+    let mut config = load_config(&config_path).unwrap();
+    fs::write(
+      dir.path().join("other.yaml"),
+      "name: other\ncommand: true\n",
+    )
+    .unwrap();
+
+    let mut loader = ConfigLoader::for_config(&config_path, &config).unwrap();
+    for file in ["other.yaml", "config.yaml"] {
+      let err = loader
+        .reload_command_file(&mut config.commands, file)
+        .unwrap_err()
+        .to_string();
+      assert!(
+        err.contains("No commands were imported"),
+        "unexpected error: {err}"
+      );
+    }
+    assert_eq!(config.commands.len(), 4);
+  }
+
+  fn config_importing(import: &str, contain_imports: bool) -> String {
+    format!(
+      "{}\ncontain_imports: {contain_imports}\ncommands:\n  - import: {import}\n",
+      indoc! {"
+        device: /dev/ttyUSB0
+        channel: 1
+        shell: bash
+        max_text_bytes: 200
+        chunk_delay: 10000
+        max_content_bytes: 180"}
+    )
+  }
+
+  /// Lays out `root/config.yaml` importing `import`, with `root/commands/ping.yaml` and
+  /// `outside.yaml` (next to `root`) both defining a command
+  fn import_tree(import: &str, contain_imports: bool) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("commands")).unwrap();
+    let command = "name: ping\nhelp: Ping\ncommand: echo pong\n";
+    fs::write(root.join("commands").join("ping.yaml"), command).unwrap();
+    fs::write(dir.path().join("outside.yaml"), command).unwrap();
+    fs::write(
+      root.join("config.yaml"),
+      config_importing(import, contain_imports),
+    )
+    .unwrap();
+    dir
+  }
+
+  #[test]
+  fn contained_imports_allow_in_tree_import() {
+    let dir = import_tree("commands/ping.yaml", false);
+    let mut loader = ConfigLoader::new(dir.path().join("root")).with_contained_imports();
+    let config = loader.load("config.yaml").unwrap();
+    assert_eq!(config.commands[0].name, "ping");
+  }
+
+  #[test]
+  fn contained_imports_reject_escaping_import() {
+    let dir = import_tree("../outside.yaml", false);
+    let mut loader = ConfigLoader::new(dir.path().join("root")).with_contained_imports();
+    let err = loader.load("config.yaml").unwrap_err().to_string();
+    assert!(
+      err.contains("is outside the config directory"),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[test]
+  fn contained_imports_reject_escape_through_subdirectory() {
+    let dir = import_tree("commands/../../outside.yaml", false);
+    let mut loader = ConfigLoader::new(dir.path().join("root")).with_contained_imports();
+    assert!(loader.load("config.yaml").is_err());
+  }
+
+  #[test]
+  fn escaping_import_is_allowed_by_default() {
+    let dir = import_tree("../outside.yaml", false);
+    let config = load_config(dir.path().join("root").join("config")).unwrap();
+    assert_eq!(config.commands[0].name, "ping");
+  }
+
+  #[test]
+  fn config_can_opt_in_to_contained_imports() {
+    let dir = import_tree("../outside.yaml", true);
+    let err = load_config(dir.path().join("root").join("config"))
+      .unwrap_err()
+      .to_string();
+    assert!(
+      err.contains("is outside the config directory"),
+      "unexpected error: {err}"
+    );
+  }
+
   #[test]
   fn invalid_yaml_fails() {
     let dir = TempDir::new().unwrap();
@@ -1203,6 +3549,36 @@ mod tests {
     assert_eq!(config.commands[0].commands[1].name, "direct");
   }
 
+  #[test]
+  fn load_config_output_stream_defaults_and_overrides() {
+    let dir = TempDir::new().unwrap();
+    let yaml = indoc! {"
+            device: /dev/ttyUSB0
+            channel: 1
+            baud: null
+            shell: bash
+            shell_args: [\"-lc\"]
+            max_text_bytes: 200
+            chunk_delay: 10000
+            max_content_bytes: 180
+            commands:
+              - name: default
+                command: echo default
+              - name: errors
+                command: echo errors >&2
+                output_stream: stderr
+              - name: everything
+                command: echo everything
+                output_stream: both
+        "};
+    fs::write(dir.path().join("config.yaml"), yaml).unwrap();
+
+    let config = load_config(dir.path().join("config")).unwrap();
+    assert_eq!(config.commands[0].output_stream, OutputStream::Stdout);
+    assert_eq!(config.commands[1].output_stream, OutputStream::Stderr);
+    assert_eq!(config.commands[2].output_stream, OutputStream::Both);
+  }
+
   #[test]
   fn deeply_nested_imports() {
     let dir = TempDir::new().unwrap();
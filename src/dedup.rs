@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Tracks the last reply sent to each node so an identical reply within the window (e.g. when a
+/// user double-taps a command) isn't broadcast again
+#[derive(Debug)]
+pub struct ReplyDeduplicator {
+  window: Duration,
+  last_replies: HashMap<u32, (u64, Instant)>,
+}
+
+impl ReplyDeduplicator {
+  pub fn new(window: Duration) -> Self {
+    Self {
+      window,
+      last_replies: HashMap::new(),
+    }
+  }
+
+  /// Records the reply for the sender and returns whether it duplicates their previous reply
+  pub fn is_duplicate(&mut self, sender: u32, reply: &str) -> bool {
+    self.is_duplicate_at(sender, reply, Instant::now())
+  }
+
+  fn is_duplicate_at(&mut self, sender: u32, reply: &str, now: Instant) -> bool {
+    if self.window.is_zero() {
+      return false;
+    }
+
+    let hash = hash_reply(reply);
+    let duplicate = self
+      .last_replies
+      .get(&sender)
+      .is_some_and(|(last_hash, sent_at)| {
+        *last_hash == hash && now.duration_since(*sent_at) < self.window
+      });
+
+    if !duplicate {
+      self.last_replies.insert(sender, (hash, now));
+    }
+
+    duplicate
+  }
+}
+
+/// Remembers recently seen packet IDs so a packet the mesh delivers more than once (e.g. a
+/// retransmission) doesn't run its command twice
+#[derive(Debug)]
+pub struct PacketDeduplicator {
+  window: Duration,
+  seen: HashMap<(u32, u32), Instant>,
+}
+
+impl PacketDeduplicator {
+  pub fn new(window: Duration) -> Self {
+    Self {
+      window,
+      seen: HashMap::new(),
+    }
+  }
+
+  /// Records the packet and returns whether the same packet was already seen within the window.
+  /// Packets without an ID (`0`) are never considered duplicates.
+  pub fn is_duplicate(&mut self, sender: u32, packet_id: u32) -> bool {
+    self.is_duplicate_at(sender, packet_id, Instant::now())
+  }
+
+  fn is_duplicate_at(&mut self, sender: u32, packet_id: u32, now: Instant) -> bool {
+    if self.window.is_zero() || packet_id == 0 {
+      return false;
+    }
+
+    self
+      .seen
+      .retain(|_, seen_at| now.duration_since(*seen_at) < self.window);
+
+    match self.seen.entry((sender, packet_id)) {
+      Entry::Occupied(_) => true,
+      Entry::Vacant(entry) => {
+        entry.insert(now);
+        false
+      }
+    }
+  }
+}
+
+/// Sends at most one error reply (unknown command, parse error) per sender per window, so a node
+/// spamming bad commands doesn't cost airtime for every one of them
+#[derive(Debug)]
+pub struct ErrorReplyThrottle {
+  window: Duration,
+  last_replies: HashMap<u32, (Instant, usize)>,
+}
+
+impl ErrorReplyThrottle {
+  pub fn new(window: Duration) -> Self {
+    Self {
+      window,
+      last_replies: HashMap::new(),
+    }
+  }
+
+  /// Records an error reply for the sender. Returns `None` if it should be sent, or the number of
+  /// error replies suppressed for the sender so far in the current window if it should not.
+  pub fn throttle(&mut self, sender: u32) -> Option<usize> {
+    self.throttle_at(sender, Instant::now())
+  }
+
+  /// Each sender's last error reply and how many have been suppressed since
+  pub fn replies(&self) -> impl Iterator<Item = (u32, Instant, usize)> + '_ {
+    self
+      .last_replies
+      .iter()
+      .map(|(sender, (replied_at, suppressed))| (*sender, *replied_at, *suppressed))
+  }
+
+  /// Restores previously recorded error replies (e.g. from before a restart)
+  pub fn restore(&mut self, replies: impl IntoIterator<Item = (u32, Instant, usize)>) {
+    self.last_replies.extend(
+      replies
+        .into_iter()
+        .map(|(sender, replied_at, suppressed)| (sender, (replied_at, suppressed))),
+    );
+  }
+
+  fn throttle_at(&mut self, sender: u32, now: Instant) -> Option<usize> {
+    if self.window.is_zero() {
+      return None;
+    }
+
+    match self.last_replies.entry(sender) {
+      Entry::Occupied(mut entry) if now.duration_since(entry.get().0) < self.window => {
+        let suppressed = &mut entry.get_mut().1;
+        *suppressed += 1;
+        Some(*suppressed)
+      }
+      Entry::Occupied(mut entry) => {
+        entry.insert((now, 0));
+        None
+      }
+      Entry::Vacant(entry) => {
+        entry.insert((now, 0));
+        None
+      }
+    }
+  }
+}
+
+fn hash_reply(reply: &str) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  reply.hash(&mut hasher);
+  hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn first_reply_is_not_duplicate() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    assert!(!dedup.is_duplicate(1, "uptime 3 days"));
+  }
+
+  #[test]
+  fn identical_reply_within_window_is_duplicate() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "uptime 3 days", now));
+    assert!(dedup.is_duplicate_at(1, "uptime 3 days", now + Duration::from_secs(10)));
+  }
+
+  #[test]
+  fn identical_reply_after_window_is_not_duplicate() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "uptime 3 days", now));
+    assert!(!dedup.is_duplicate_at(1, "uptime 3 days", now + Duration::from_secs(30)));
+  }
+
+  #[test]
+  fn distinct_reply_is_not_duplicate() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "uptime 3 days", now));
+    assert!(!dedup.is_duplicate_at(1, "uptime 4 days", now + Duration::from_secs(1)));
+  }
+
+  #[test]
+  fn only_the_immediately_previous_reply_is_compared() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "a", now));
+    assert!(!dedup.is_duplicate_at(1, "b", now));
+    assert!(!dedup.is_duplicate_at(1, "a", now));
+  }
+
+  #[test]
+  fn replies_are_tracked_per_sender() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "same", now));
+    assert!(!dedup.is_duplicate_at(2, "same", now));
+  }
+
+  #[test]
+  fn duplicate_does_not_extend_the_window() {
+    let mut dedup = ReplyDeduplicator::new(Duration::from_secs(30));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "same", now));
+    assert!(dedup.is_duplicate_at(1, "same", now + Duration::from_secs(20)));
+    assert!(!dedup.is_duplicate_at(1, "same", now + Duration::from_secs(35)));
+  }
+
+  #[test]
+  fn duplicate_packet_id_within_window_is_duplicate() {
+    let mut dedup = PacketDeduplicator::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, 42, now));
+    assert!(dedup.is_duplicate_at(1, 42, now + Duration::from_secs(5)));
+  }
+
+  #[test]
+  fn distinct_packet_id_is_not_duplicate() {
+    let mut dedup = PacketDeduplicator::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, 42, now));
+    assert!(!dedup.is_duplicate_at(1, 43, now));
+    assert!(!dedup.is_duplicate_at(2, 42, now));
+  }
+
+  #[test]
+  fn packet_id_seen_before_window_is_not_duplicate() {
+    let mut dedup = PacketDeduplicator::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, 42, now));
+    assert!(!dedup.is_duplicate_at(1, 42, now + Duration::from_secs(60)));
+    assert_eq!(dedup.seen.len(), 1);
+  }
+
+  #[test]
+  fn packets_without_id_are_never_duplicates() {
+    let mut dedup = PacketDeduplicator::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, 0, now));
+    assert!(!dedup.is_duplicate_at(1, 0, now));
+  }
+
+  #[test]
+  fn zero_window_disables_deduplication() {
+    let mut dedup = ReplyDeduplicator::new(Duration::ZERO);
+    let now = Instant::now();
+    assert!(!dedup.is_duplicate_at(1, "same", now));
+    assert!(!dedup.is_duplicate_at(1, "same", now));
+  }
+
+  #[test]
+  fn repeated_errors_within_window_are_throttled() {
+    let mut throttle = ErrorReplyThrottle::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert_eq!(throttle.throttle_at(1, now), None);
+    assert_eq!(
+      throttle.throttle_at(1, now + Duration::from_secs(5)),
+      Some(1)
+    );
+    assert_eq!(
+      throttle.throttle_at(1, now + Duration::from_secs(59)),
+      Some(2)
+    );
+  }
+
+  #[test]
+  fn error_after_window_is_sent_again() {
+    let mut throttle = ErrorReplyThrottle::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert_eq!(throttle.throttle_at(1, now), None);
+    assert_eq!(
+      throttle.throttle_at(1, now + Duration::from_secs(30)),
+      Some(1)
+    );
+    assert_eq!(throttle.throttle_at(1, now + Duration::from_secs(60)), None);
+    assert_eq!(
+      throttle.throttle_at(1, now + Duration::from_secs(61)),
+      Some(1)
+    );
+  }
+
+  #[test]
+  fn errors_are_throttled_per_sender() {
+    let mut throttle = ErrorReplyThrottle::new(Duration::from_secs(60));
+    let now = Instant::now();
+    assert_eq!(throttle.throttle_at(1, now), None);
+    assert_eq!(throttle.throttle_at(2, now), None);
+  }
+
+  #[test]
+  fn zero_window_disables_error_throttling() {
+    let mut throttle = ErrorReplyThrottle::new(Duration::ZERO);
+    let now = Instant::now();
+    assert_eq!(throttle.throttle_at(1, now), None);
+    assert_eq!(throttle.throttle_at(1, now), None);
+  }
+}
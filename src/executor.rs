@@ -0,0 +1,2133 @@
+use crate::config::{Command as CommandDefinition, CommandPathCheck, Config};
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::mem;
+use std::path::Path;
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// Exit code a command uses to signal that its stdout is a prompt and it's waiting for the
+/// sender's next message, which is piped to the command's stdin when it's re-run
+pub const AWAITING_INPUT_EXIT_CODE: i32 = 42;
+
+/// Environment variables identifying who sent a command, see [`add_sender_env`]
+pub const FROM_NODE_ENV: &str = "MESHEXEC_FROM_NODE";
+pub const FROM_NAME_ENV: &str = "MESHEXEC_FROM_NAME";
+pub const CHANNEL_ENV: &str = "MESHEXEC_CHANNEL";
+
+/// Longest a command's `when` predicate may run before the command is treated as unavailable
+const WHEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shell builtins and keywords a command can start with that needn't be programs on `PATH`
+const SHELL_BUILTINS: &[&str] = &[
+  ".", "[", "alias", "case", "cd", "command", "echo", "eval", "exec", "exit", "export", "false",
+  "for", "if", "local", "printf", "pwd", "read", "set", "shift", "source", "test", "time", "trap",
+  "true", "type", "ulimit", "umask", "unset", "until", "wait", "while",
+];
+
+#[derive(Debug)]
+pub enum ExecutionError {
+  EmptyCommand,
+  OutputFilterFailed(String),
+  TimedOut(Duration),
+  ServerBusy(usize),
+  RateLimited(Duration),
+  NoCommandsAvailable,
+  UnresolvedPrograms(Vec<String>),
+  Cancelled,
+}
+
+impl Display for ExecutionError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ExecutionError::EmptyCommand => write!(f, "Command resolved to an empty command string"),
+      ExecutionError::OutputFilterFailed(reason) => write!(f, "Output filter failed: {reason}"),
+      ExecutionError::TimedOut(limit) => {
+        write!(f, "Command timed out after {}s", limit.as_secs())
+      }
+      ExecutionError::ServerBusy(running) => write!(
+        f,
+        "Server busy: {running} commands already running. Try again shortly."
+      ),
+      ExecutionError::RateLimited(retry_after) => write!(
+        f,
+        "Rate limited, try again in {}s",
+        retry_after.as_secs_f64().ceil() as u64
+      ),
+      ExecutionError::NoCommandsAvailable => write!(
+        f,
+        "No commands are available on this host after applying requires_os and when"
+      ),
+      ExecutionError::UnresolvedPrograms(commands) => write!(
+        f,
+        "Commands whose program isn't an executable on PATH: {}",
+        commands.join(", ")
+      ),
+      ExecutionError::Cancelled => write!(f, "Command was cancelled"),
+    }
+  }
+}
+
+impl Error for ExecutionError {}
+
+/// Runs the resolved command string with the configured shell, refusing to spawn the shell at all
+/// when the command is blank, since most shells treat an empty script as interactive mode.
+///
+/// If `input` is given, it's written to the command's stdin followed by a newline. If the command
+/// doesn't finish within `timeout`, it's killed and [`ExecutionError::TimedOut`] is returned.
+pub async fn run_shell_command(
+  server_config: &Config,
+  command: &str,
+  envs: &HashMap<String, String>,
+  input: Option<&str>,
+  timeout: Option<Duration>,
+) -> Result<Output> {
+  if command.trim().is_empty() {
+    return Err(anyhow!(ExecutionError::EmptyCommand));
+  }
+
+  let mut child = Command::new(&server_config.shell)
+    .args(&server_config.shell_args)
+    .arg(command)
+    .envs(envs)
+    .stdin(if input.is_some() {
+      Stdio::piped()
+    } else {
+      Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .spawn()?;
+
+  // Dropping this future (on timeout) drops the child, which kills it
+  let run = async move {
+    if let Some(input) = input
+      && let Some(mut stdin) = child.stdin.take()
+    {
+      stdin.write_all(format!("{input}\n").as_bytes()).await?;
+    }
+    child.wait_with_output().await
+  };
+
+  let output = match timeout {
+    Some(limit) => tokio::time::timeout(limit, run)
+      .await
+      .map_err(|_| anyhow!(ExecutionError::TimedOut(limit)))?,
+    None => run.await,
+  };
+
+  Ok(output?)
+}
+
+/// Runs a command's `steps` one after another with [`run_shell_command`], stopping at the first
+/// one that exits unsuccessfully. The steps' stdout and stderr are concatenated in order, and the
+/// exit status is that of the last step run. The timeout applies to the steps as a whole.
+pub async fn run_steps(
+  server_config: &Config,
+  steps: &[String],
+  envs: &HashMap<String, String>,
+  input: Option<&str>,
+  timeout: Option<Duration>,
+) -> Result<Output> {
+  // Dropping this future (on timeout) drops the running step, which kills it
+  let run = async {
+    let mut combined = Output {
+      status: ExitStatus::default(),
+      stdout: Vec::new(),
+      stderr: Vec::new(),
+    };
+
+    for (index, step) in steps.iter().enumerate() {
+      let output = run_shell_command(server_config, step, envs, input, None).await?;
+      combined.stdout.extend(output.stdout);
+      combined.status = output.status;
+
+      if !output.status.success() {
+        combined
+          .stderr
+          .extend(format!("Step {}/{} failed\n", index + 1, steps.len()).bytes());
+        combined.stderr.extend(output.stderr);
+        break;
+      }
+      combined.stderr.extend(output.stderr);
+    }
+
+    Ok(combined)
+  };
+
+  match timeout {
+    Some(limit) => tokio::time::timeout(limit, run)
+      .await
+      .map_err(|_| anyhow!(ExecutionError::TimedOut(limit)))?,
+    None => run.await,
+  }
+}
+
+/// Runs a command's `steps` as a pipeline, each step's stdout feeding the next one's stdin like
+/// `step1 | step2`, with `input` (if given) written to the first step. The result has the last
+/// step's stdout and every step's stderr in order. If any step exits unsuccessfully, the first one
+/// to do so is reported as `Step N/M failed` and its exit status is returned. The timeout applies
+/// to the pipeline as a whole.
+pub async fn run_pipeline(
+  server_config: &Config,
+  steps: &[String],
+  envs: &HashMap<String, String>,
+  input: Option<&str>,
+  timeout: Option<Duration>,
+) -> Result<Output> {
+  let mut children = Vec::with_capacity(steps.len());
+  let mut previous_stdout: Option<Stdio> = None;
+  for step in steps {
+    if step.trim().is_empty() {
+      return Err(anyhow!(ExecutionError::EmptyCommand));
+    }
+
+    let stdin = match previous_stdout.take() {
+      Some(stdout) => stdout,
+      None if input.is_some() => Stdio::piped(),
+      None => Stdio::null(),
+    };
+    let mut child = Command::new(&server_config.shell)
+      .args(&server_config.shell_args)
+      .arg(step)
+      .envs(envs)
+      .stdin(stdin)
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()?;
+
+    if children.len() + 1 < steps.len()
+      && let Some(stdout) = child.stdout.take()
+    {
+      previous_stdout = Some(stdout.try_into()?);
+    }
+    children.push(child);
+  }
+
+  // Every step is waited on at once, as a step blocks once the pipe to the next one fills up.
+  // Dropping the set (on timeout) drops the children, which kills them.
+  let run = async move {
+    if let Some(input) = input
+      && let Some(mut stdin) = children.first_mut().and_then(|child| child.stdin.take())
+    {
+      stdin.write_all(format!("{input}\n").as_bytes()).await?;
+    }
+
+    let mut running = tokio::task::JoinSet::new();
+    for (index, child) in children.into_iter().enumerate() {
+      running.spawn(async move { (index, child.wait_with_output().await) });
+    }
+    let mut outputs = vec![None; steps.len()];
+    while let Some(finished) = running.join_next().await {
+      let (index, output) = finished?;
+      outputs[index] = Some(output?);
+    }
+    Ok::<_, anyhow::Error>(outputs.into_iter().flatten().collect::<Vec<_>>())
+  };
+
+  let outputs = match timeout {
+    Some(limit) => tokio::time::timeout(limit, run)
+      .await
+      .map_err(|_| anyhow!(ExecutionError::TimedOut(limit)))?,
+    None => run.await,
+  }?;
+
+  let failed = outputs.iter().position(|output| !output.status.success());
+  let mut combined = Output {
+    status: outputs[failed.unwrap_or(outputs.len() - 1)].status,
+    stdout: Vec::new(),
+    stderr: Vec::new(),
+  };
+  for (index, output) in outputs.into_iter().enumerate() {
+    if failed == Some(index) {
+      combined
+        .stderr
+        .extend(format!("Step {}/{} failed\n", index + 1, steps.len()).bytes());
+    }
+    combined.stderr.extend(output.stderr);
+    if index + 1 == steps.len() {
+      combined.stdout = output.stdout;
+    }
+  }
+
+  Ok(combined)
+}
+
+/// The command's own `timeout`, else the global `command_timeout`
+pub fn command_timeout(server_config: &Config, definition: &CommandDefinition) -> Option<Duration> {
+  definition
+    .timeout
+    .or(server_config.command_timeout)
+    .map(Duration::from_secs)
+}
+
+/// Runs the command's `precondition`, if any, with the same environment as the command itself.
+/// Returns the reply to send instead of running the command when the precondition fails: the
+/// configured `precondition_message`, else the precondition's stderr.
+pub async fn check_precondition(
+  server_config: &Config,
+  definition: &CommandDefinition,
+  envs: &HashMap<String, String>,
+  context: &RequestContext,
+) -> Result<Option<String>> {
+  let Some(precondition) = definition.precondition.as_deref() else {
+    return Ok(None);
+  };
+
+  let out = run_shell_command(
+    server_config,
+    &interpolate_reserved(precondition, context),
+    envs,
+    None,
+    command_timeout(server_config, definition),
+  )
+  .await?;
+  if out.status.success() {
+    return Ok(None);
+  }
+
+  let stderr = String::from_utf8_lossy(&out.stderr);
+  let reply = match definition.precondition_message.as_deref() {
+    Some(message) => message.to_owned(),
+    None if !stderr.trim().is_empty() => stderr.into_owned(),
+    None => format!("Precondition failed ({})", out.status),
+  };
+  Ok(Some(reply))
+}
+
+/// Drops the commands that aren't available on this host, so they're neither resolvable nor shown
+/// in help: those whose `requires_os` doesn't include the current OS, and those whose `when`
+/// predicate exits non-zero (or can't be run). Groups left without subcommands are dropped too.
+pub fn retain_available_commands(
+  server_config: &Config,
+  commands: Vec<CommandDefinition>,
+) -> Vec<CommandDefinition> {
+  commands
+    .into_iter()
+    .filter(|command| is_available_on_host(server_config, command))
+    .filter_map(|mut command| {
+      if command.commands.is_empty() {
+        return Some(command);
+      }
+
+      command.commands = retain_available_commands(server_config, command.commands);
+      if command.is_group() {
+        Some(command)
+      } else {
+        info!(
+          "Excluding group '{}': no available subcommands",
+          command.name
+        );
+        None
+      }
+    })
+    .collect()
+}
+
+/// Catches a config whose commands were all filtered out by [`retain_available_commands`]: logs a
+/// warning, or fails when `require_commands` is set, so the node doesn't silently start with
+/// nothing to run.
+pub fn check_commands_available(server_config: &Config) -> Result<()> {
+  if !server_config.commands.is_empty() {
+    return Ok(());
+  }
+
+  if server_config.require_commands {
+    return Err(anyhow!(ExecutionError::NoCommandsAvailable));
+  }
+  warn!("{}", ExecutionError::NoCommandsAvailable);
+  Ok(())
+}
+
+/// With `check_command_paths` enabled, warns about each leaf command whose program (the first word
+/// of its `command`, or of any of its `steps`) doesn't resolve to an executable on `PATH`, and
+/// fails in `strict` mode. This is best-effort: programs that are placeholders, expansions or shell
+/// builtins are skipped, since they can only be known when the command runs.
+pub fn check_command_programs(server_config: &Config) -> Result<()> {
+  if server_config.check_command_paths == CommandPathCheck::Off {
+    return Ok(());
+  }
+
+  let path = std::env::var("PATH").unwrap_or_default();
+  let mut unresolved = Vec::new();
+  collect_unresolved_programs(&server_config.commands, "", &path, &mut unresolved);
+  if unresolved.is_empty() {
+    return Ok(());
+  }
+
+  for (command, program) in &unresolved {
+    warn!("Command '{command}' runs '{program}', which isn't an executable on PATH");
+  }
+  if server_config.check_command_paths == CommandPathCheck::Strict {
+    let commands = unresolved.into_iter().map(|(command, _)| command).collect();
+    return Err(anyhow!(ExecutionError::UnresolvedPrograms(commands)));
+  }
+  Ok(())
+}
+
+/// Adds `(command path, program)` for each unresolvable program in the tree. A command's own
+/// `PATH` in its `env` is searched instead of the server's, as that's what it runs with.
+fn collect_unresolved_programs(
+  commands: &[CommandDefinition],
+  parent: &str,
+  path: &str,
+  unresolved: &mut Vec<(String, String)>,
+) {
+  for command in commands {
+    let name = if parent.is_empty() {
+      command.name.clone()
+    } else {
+      format!("{parent} {}", command.name)
+    };
+    let path = command.env.get("PATH").map_or(path, String::as_str);
+    let scripts = std::iter::once(&command.command)
+      .chain(&command.steps)
+      .filter(|script| !script.trim().is_empty());
+    for program in scripts.filter_map(|script| first_program(script)) {
+      if !resolves_on_path(program, path) {
+        unresolved.push((name.clone(), program.to_owned()));
+      }
+    }
+    collect_unresolved_programs(&command.commands, &name, path, unresolved);
+  }
+}
+
+/// The program a shell script starts by running, skipping leading `NAME=value` assignments.
+/// `None` when it can't be checked before the script runs.
+fn first_program(script: &str) -> Option<&str> {
+  let word = script
+    .split_whitespace()
+    .find(|word| !is_env_assignment(word))?
+    .trim_end_matches([';', '&', '|']);
+  let dynamic = word.contains(['{', '$', '`', '(', '"', '\'', '*', '?']);
+  (!word.is_empty() && !dynamic && !SHELL_BUILTINS.contains(&word)).then_some(word)
+}
+
+fn is_env_assignment(word: &str) -> bool {
+  word.split_once('=').is_some_and(|(name, _)| {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+  })
+}
+
+/// A program containing a `/` is checked as a path, anything else is searched for in `path`
+fn resolves_on_path(program: &str, path: &str) -> bool {
+  if program.contains('/') {
+    return is_executable(Path::new(program));
+  }
+  std::env::split_paths(path).any(|dir| is_executable(&dir.join(program)))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+  use std::os::unix::fs::PermissionsExt;
+  path
+    .metadata()
+    .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+  path.is_file() || path.with_extension("exe").is_file()
+}
+
+/// The `on_connect` hook to run once the node connection is established, if one is configured
+pub fn on_connect_hook(server_config: &Config) -> Option<&str> {
+  server_config
+    .on_connect
+    .as_deref()
+    .filter(|hook| !hook.trim().is_empty())
+}
+
+/// Runs the `on_connect` hook, if any, under `command_timeout`. Its output is logged rather than
+/// sent to the mesh, and a failing hook is only logged, so it can't keep the runner from starting.
+pub async fn run_on_connect_hook(server_config: &Config) {
+  let Some(hook) = on_connect_hook(server_config) else {
+    return;
+  };
+
+  info!("Running on_connect hook: {hook}");
+  let path = std::env::var("PATH").unwrap_or_default();
+  let envs = build_command_env(server_config, path, &HashMap::new(), &HashMap::new());
+  let timeout = server_config.command_timeout.map(Duration::from_secs);
+  match run_shell_command(server_config, hook, &envs, None, timeout).await {
+    Ok(output) => {
+      let stdout = String::from_utf8_lossy(&output.stdout);
+      let stderr = String::from_utf8_lossy(&output.stderr);
+      if output.status.success() {
+        info!("on_connect hook finished: {}", stdout.trim_end());
+      } else {
+        warn!(
+          "on_connect hook failed ({}): {}{}",
+          output.status,
+          stdout.trim_end(),
+          stderr.trim_end()
+        );
+      }
+    }
+    Err(e) => warn!("on_connect hook failed: {e:#}"),
+  }
+}
+
+fn is_available_on_host(server_config: &Config, command: &CommandDefinition) -> bool {
+  if !command.requires_os.is_empty()
+    && !command
+      .requires_os
+      .iter()
+      .any(|os| os.eq_ignore_ascii_case(std::env::consts::OS))
+  {
+    info!(
+      "Excluding command '{}': requires {}, running on {}",
+      command.name,
+      command.requires_os.join("/"),
+      std::env::consts::OS
+    );
+    return false;
+  }
+
+  let Some(predicate) = command.when.as_deref() else {
+    return true;
+  };
+
+  let path = std::env::var("PATH").unwrap_or_default();
+  let envs = build_command_env(server_config, path, &command.env, &HashMap::new());
+  match run_predicate(server_config, predicate, &envs, WHEN_TIMEOUT) {
+    Ok(Some(status)) if status.success() => true,
+    Ok(Some(status)) => {
+      info!(
+        "Excluding command '{}': 'when' predicate failed ({status})",
+        command.name
+      );
+      false
+    }
+    Ok(None) => {
+      warn!(
+        "Excluding command '{}': 'when' predicate timed out after {}s",
+        command.name,
+        WHEN_TIMEOUT.as_secs()
+      );
+      false
+    }
+    Err(e) => {
+      warn!(
+        "Excluding command '{}': failed to run 'when' predicate: {e}",
+        command.name
+      );
+      false
+    }
+  }
+}
+
+/// Runs a `when` predicate with the configured shell, killing it if it's still running after
+/// `timeout`. Returns its exit status, or `None` if it timed out.
+fn run_predicate(
+  server_config: &Config,
+  predicate: &str,
+  envs: &HashMap<String, String>,
+  timeout: Duration,
+) -> std::io::Result<Option<ExitStatus>> {
+  let mut child = std::process::Command::new(&server_config.shell)
+    .args(&server_config.shell_args)
+    .arg(predicate)
+    .envs(envs)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()?;
+
+  let deadline = Instant::now() + timeout;
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(Some(status));
+    }
+    if Instant::now() >= deadline {
+      child.kill()?;
+      child.wait()?;
+      return Ok(None);
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+}
+
+/// Pipes a command's output through its `output_filter`, run with the same shell and environment.
+/// The output is written concurrently with reading the filter's output so a filter that starts
+/// writing before it has read all of its input can't deadlock on a full pipe.
+pub async fn apply_output_filter(
+  server_config: &Config,
+  filter: &str,
+  envs: &HashMap<String, String>,
+  output: &str,
+) -> Result<String> {
+  if filter.trim().is_empty() {
+    return Err(anyhow!(ExecutionError::EmptyCommand));
+  }
+
+  let mut child = Command::new(&server_config.shell)
+    .args(&server_config.shell_args)
+    .arg(filter)
+    .envs(envs)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .spawn()?;
+
+  let mut stdin = child
+    .stdin
+    .take()
+    .ok_or_else(|| anyhow!("Failed to open the output filter's stdin"))?;
+  let write = async move {
+    // A filter like `head` may exit without reading everything, so write errors are expected
+    let _ = stdin.write_all(output.as_bytes()).await;
+  };
+
+  let ((), filtered) = tokio::join!(write, child.wait_with_output());
+  let filtered = filtered?;
+
+  if !filtered.status.success() {
+    let stderr = String::from_utf8_lossy(&filtered.stderr);
+    let reason = if stderr.trim().is_empty() {
+      filtered.status.to_string()
+    } else {
+      stderr.trim().to_owned()
+    };
+    return Err(anyhow!(ExecutionError::OutputFilterFailed(reason)));
+  }
+
+  Ok(String::from_utf8_lossy(&filtered.stdout).into_owned())
+}
+
+pub const DEFAULT_MAX_CONCURRENT_COMMANDS: usize = 4;
+
+/// Caps how many command processes run at once (`max_concurrent_commands`), so a flood of slow
+/// commands can't exhaust the host. A request that finds every slot taken is turned away rather
+/// than queued. A limit of `0` disables the cap.
+#[derive(Debug, Clone)]
+pub struct CommandSlots {
+  limit: usize,
+  semaphore: Arc<Semaphore>,
+}
+
+impl CommandSlots {
+  pub fn new(limit: usize) -> Self {
+    Self {
+      limit,
+      semaphore: Arc::new(Semaphore::new(if limit == 0 {
+        Semaphore::MAX_PERMITS
+      } else {
+        limit
+      })),
+    }
+  }
+
+  /// Claims a slot, held until the returned permit is dropped, or `None` if all are in use
+  pub fn try_claim(&self) -> Option<OwnedSemaphorePermit> {
+    Arc::clone(&self.semaphore).try_acquire_owned().ok()
+  }
+
+  pub fn limit(&self) -> usize {
+    self.limit
+  }
+}
+
+/// A resolved command to run off the receive loop, along with what's needed to reply to it
+#[derive(Debug, Clone)]
+pub struct CommandRequest {
+  pub sender: u32,
+  /// The resolved command, before the reserved placeholders are substituted
+  pub command: String,
+  /// The resolved arg and flag values
+  pub env: HashMap<String, String>,
+  /// The full environment the command runs with
+  pub envs: HashMap<String, String>,
+  pub definition: CommandDefinition,
+  pub context: RequestContext,
+  /// The sender's follow-up message for a command that was awaiting input
+  pub input: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum CommandOutcome {
+  /// The precondition failed, so the command wasn't run; holds the reply to send instead
+  Blocked(String),
+  /// The command ran. Unless it's awaiting input, its stdout has been through the output filter.
+  Finished {
+    output: Output,
+    elapsed: Duration,
+  },
+  Failed(anyhow::Error),
+}
+
+impl CommandOutcome {
+  /// Whether the command succeeded, or `None` if it hasn't finished: its precondition failed, or
+  /// it's awaiting input
+  pub fn succeeded(&self) -> Option<bool> {
+    match self {
+      Self::Blocked(_) => None,
+      Self::Finished { output, .. } if is_awaiting_input(&output.status) => None,
+      Self::Finished { output, .. } => Some(output.status.success()),
+      Self::Failed(_) => Some(false),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct CommandCompletion {
+  /// The ID the command was registered under in [`RunningCommands`]
+  pub run_id: u64,
+  pub request: CommandRequest,
+  pub outcome: CommandOutcome,
+}
+
+/// The commands running off the receive loop, by sender, so `!cancel` can stop them
+#[derive(Debug, Default)]
+pub struct RunningCommands {
+  next_id: u64,
+  commands: HashMap<u64, RunningCommand>,
+}
+
+#[derive(Debug)]
+struct RunningCommand {
+  sender: u32,
+  name: String,
+  token: CancellationToken,
+}
+
+impl RunningCommands {
+  /// Registers a command about to run, returning its ID and the token that cancels it
+  pub fn start(&mut self, sender: u32, name: &str) -> (u64, CancellationToken) {
+    self.next_id += 1;
+    let token = CancellationToken::new();
+    self.commands.insert(
+      self.next_id,
+      RunningCommand {
+        sender,
+        name: name.to_owned(),
+        token: token.clone(),
+      },
+    );
+    (self.next_id, token)
+  }
+
+  pub fn finish(&mut self, id: u64) {
+    self.commands.remove(&id);
+  }
+
+  /// Cancels every command the sender has running, returning their names
+  pub fn cancel(&mut self, sender: u32) -> Vec<String> {
+    let mut cancelled: Vec<(u64, String)> = Vec::new();
+    self.commands.retain(|&id, command| {
+      if command.sender != sender {
+        return true;
+      }
+      command.token.cancel();
+      cancelled.push((id, mem::take(&mut command.name)));
+      false
+    });
+    cancelled.sort();
+    cancelled.into_iter().map(|(_, name)| name).collect()
+  }
+}
+
+/// The reply to `!cancel`, given the names of the commands it cancelled
+pub fn format_cancelled(cancelled: &[String]) -> String {
+  if cancelled.is_empty() {
+    "Nothing to cancel".to_owned()
+  } else {
+    format!("Cancelled: {}", cancelled.join(", "))
+  }
+}
+
+/// Awaits `execution` unless `token` is cancelled first, in which case the execution is dropped,
+/// killing any child it's running, and [`ExecutionError::Cancelled`] is returned
+pub async fn cancellable(
+  execution: impl Future<Output = CommandOutcome>,
+  token: CancellationToken,
+) -> CommandOutcome {
+  tokio::select! {
+    biased;
+    () = token.cancelled() => CommandOutcome::Failed(anyhow!(ExecutionError::Cancelled)),
+    outcome = execution => outcome,
+  }
+}
+
+/// Notice that a command with a `progress_interval_secs` is still running
+#[derive(Debug, Clone)]
+pub struct CommandProgress {
+  pub sender: u32,
+  pub channel: u32,
+  pub elapsed: Duration,
+}
+
+impl CommandProgress {
+  pub fn message(&self) -> String {
+    format!("Still running ({}s)...", self.elapsed.as_secs())
+  }
+}
+
+/// Awaits `execution`, calling `on_progress` with the elapsed time every `interval` until it
+/// completes. Nothing is reported after completion, and without an interval it's a plain await.
+pub async fn with_progress<T>(
+  execution: impl Future<Output = T>,
+  interval: Option<Duration>,
+  mut on_progress: impl FnMut(Duration),
+) -> T {
+  let Some(interval) = interval.filter(|interval| !interval.is_zero()) else {
+    return execution.await;
+  };
+
+  let started = tokio::time::Instant::now();
+  let mut ticks = tokio::time::interval_at(started + interval, interval);
+  tokio::pin!(execution);
+  loop {
+    tokio::select! {
+      output = &mut execution => return output,
+      _ = ticks.tick() => on_progress(started.elapsed()),
+    }
+  }
+}
+
+/// Runs a request's precondition (unless it's a follow-up to a prompt), then the command itself
+/// with its timeout, then its output filter
+pub async fn execute_request(server_config: &Config, request: &CommandRequest) -> CommandOutcome {
+  let definition = &request.definition;
+  if request.input.is_none() {
+    match check_precondition(server_config, definition, &request.envs, &request.context).await {
+      Ok(None) => {}
+      Ok(Some(reply)) => return CommandOutcome::Blocked(reply),
+      Err(e) => return CommandOutcome::Failed(e),
+    }
+  }
+
+  let timeout = command_timeout(server_config, definition);
+  let started = Instant::now();
+  let result = if definition.steps.is_empty() {
+    let command_line = interpolate_reserved(&request.command, &request.context);
+    run_shell_command(
+      server_config,
+      &command_line,
+      &request.envs,
+      request.input.as_deref(),
+      timeout,
+    )
+    .await
+  } else {
+    let steps: Vec<String> = definition
+      .steps
+      .iter()
+      .map(|step| interpolate_reserved(step, &request.context))
+      .collect();
+    let input = request.input.as_deref();
+    if definition.pipe_steps {
+      run_pipeline(server_config, &steps, &request.envs, input, timeout).await
+    } else {
+      run_steps(server_config, &steps, &request.envs, input, timeout).await
+    }
+  };
+  let mut output = match result {
+    Ok(output) => output,
+    Err(e) => return CommandOutcome::Failed(e),
+  };
+  let elapsed = started.elapsed();
+
+  if let Some(filter) = definition.output_filter.as_deref()
+    && !is_awaiting_input(&output.status)
+  {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let filter = interpolate_reserved(filter, &request.context);
+    match apply_output_filter(server_config, &filter, &request.envs, &stdout).await {
+      Ok(filtered) => output.stdout = filtered.into_bytes(),
+      Err(e) => return CommandOutcome::Failed(e),
+    }
+  }
+
+  CommandOutcome::Finished { output, elapsed }
+}
+
+/// Environment for a command: `PATH`, then the secrets, then the command's own `env` (with any
+/// `${SECRET}` in its values expanded), then the resolved arg and flag values
+pub fn build_command_env(
+  server_config: &Config,
+  path: String,
+  command_env: &HashMap<String, String>,
+  alias_env: &HashMap<String, String>,
+) -> HashMap<String, String> {
+  let mut envs = HashMap::from([("PATH".to_owned(), path)]);
+  envs.extend(
+    server_config
+      .secrets
+      .iter()
+      .map(|(name, value)| (name.clone(), value.clone())),
+  );
+  envs.extend(
+    command_env
+      .iter()
+      .map(|(name, value)| (name.clone(), server_config.secrets.interpolate(value))),
+  );
+  envs.extend(alias_env.clone());
+  envs
+}
+
+/// Variables describing who sent the command, set on every command's environment after the arg
+/// and flag values so they can't be overridden. `MESHEXEC_FROM_NAME` is only set once the sender's
+/// long name has been learned from its `NodeInfo`.
+pub fn add_sender_env(
+  envs: &mut HashMap<String, String>,
+  context: &RequestContext,
+  sender_name: Option<&str>,
+) {
+  envs.insert(FROM_NODE_ENV.to_owned(), context.sender.to_string());
+  envs.insert(CHANNEL_ENV.to_owned(), context.channel.to_string());
+  match sender_name {
+    Some(name) => envs.insert(FROM_NAME_ENV.to_owned(), name.to_owned()),
+    None => envs.remove(FROM_NAME_ENV),
+  };
+}
+
+/// Runtime context for the reserved `${__channel}`, `${__node}`, `${__sender}` and `${__hops}`
+/// placeholders
+#[derive(Debug, Clone, Copy)]
+pub struct RequestContext {
+  pub channel: u32,
+  pub node: u32,
+  pub sender: u32,
+  /// ID of the packet the command arrived in, for reacting to it
+  pub packet_id: u32,
+  /// Hops the command's packet took to get here, if the sender reported it
+  pub hops: Option<u32>,
+}
+
+impl RequestContext {
+  /// The hop count, or `?` if it isn't known
+  pub fn hops_display(&self) -> String {
+    self
+      .hops
+      .map_or_else(|| "?".to_owned(), |hops| hops.to_string())
+  }
+}
+
+/// Substitutes the reserved placeholders directly into the command string. Arg and flag names
+/// can't start with `__`, so these never collide with user-defined variables.
+pub fn interpolate_reserved(command: &str, context: &RequestContext) -> String {
+  command
+    .replace("${__channel}", &context.channel.to_string())
+    .replace("${__node}", &context.node.to_string())
+    .replace("${__sender}", &context.sender.to_string())
+    .replace("${__hops}", &context.hops_display())
+}
+
+pub fn is_awaiting_input(status: &ExitStatus) -> bool {
+  status.code() == Some(AWAITING_INPUT_EXIT_CODE)
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingInput {
+  pub command: String,
+  pub env: HashMap<String, String>,
+  pub definition: CommandDefinition,
+  pub created_at: Instant,
+}
+
+/// Commands waiting on a follow-up message, keyed by the node that sent the original command.
+/// Ones that go unanswered for longer than the TTL are dropped by [`Continuations::expire`], so
+/// senders who never follow up don't accumulate; a zero TTL keeps them until they're answered.
+#[derive(Debug, Default)]
+pub struct Continuations {
+  ttl: Duration,
+  pending: HashMap<u32, PendingInput>,
+}
+
+impl Continuations {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      pending: HashMap::new(),
+    }
+  }
+
+  pub fn await_input(
+    &mut self,
+    sender: u32,
+    command: String,
+    env: HashMap<String, String>,
+    definition: CommandDefinition,
+  ) {
+    self.pending.insert(
+      sender,
+      PendingInput {
+        command,
+        env,
+        definition,
+        created_at: Instant::now(),
+      },
+    );
+  }
+
+  pub fn take(&mut self, sender: u32) -> Option<PendingInput> {
+    self.pending.remove(&sender)
+  }
+
+  pub fn is_pending(&self, sender: u32) -> bool {
+    self.pending.contains_key(&sender)
+  }
+
+  /// Drops the continuations older than the TTL, returning how many were dropped
+  pub fn expire(&mut self) -> usize {
+    self.expire_at(Instant::now())
+  }
+
+  fn expire_at(&mut self, now: Instant) -> usize {
+    if self.ttl.is_zero() {
+      return 0;
+    }
+
+    let before = self.pending.len();
+    self
+      .pending
+      .retain(|_, pending| now.saturating_duration_since(pending.created_at) < self.ttl);
+    before - self.pending.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pending(continuations: &mut Continuations, sender: u32, command: &str) {
+    continuations.await_input(
+      sender,
+      command.to_owned(),
+      HashMap::new(),
+      CommandDefinition::default(),
+    );
+  }
+
+  fn config_with_shell(shell: &str, shell_args: &[&str]) -> Config {
+    Config {
+      shell: shell.to_owned(),
+      shell_args: shell_args.iter().map(|a| a.to_string()).collect(),
+      ..Config::default()
+    }
+  }
+
+  #[test]
+  fn command_env_is_overridden_by_args_and_flags() {
+    let config = config_with_shell("sh", &["-c"]);
+    let command_env = HashMap::from([
+      ("AWS_PROFILE".to_owned(), "ops".to_owned()),
+      ("region".to_owned(), "us-east-1".to_owned()),
+      ("PATH".to_owned(), "/opt/tools/bin".to_owned()),
+    ]);
+    let alias_env = HashMap::from([("region".to_owned(), "eu-west-1".to_owned())]);
+
+    let envs = build_command_env(&config, "/usr/bin".to_owned(), &command_env, &alias_env);
+
+    assert_eq!(envs["AWS_PROFILE"], "ops");
+    assert_eq!(envs["region"], "eu-west-1");
+    assert_eq!(envs["PATH"], "/opt/tools/bin");
+  }
+
+  #[test]
+  fn secrets_are_expanded_in_command_env() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let secrets_path = dir.path().join("secrets.env");
+    std::fs::write(&secrets_path, "KUBE_SECRET=/etc/kube/prod\n").unwrap();
+    let config = Config {
+      secrets: crate::secrets::Secrets::load(&secrets_path).unwrap(),
+      ..config_with_shell("sh", &["-c"])
+    };
+    let command_env = HashMap::from([
+      ("KUBECONFIG".to_owned(), "${KUBE_SECRET}".to_owned()),
+      ("OTHER".to_owned(), "${UNSET}".to_owned()),
+    ]);
+
+    let envs = build_command_env(
+      &config,
+      "/usr/bin".to_owned(),
+      &command_env,
+      &HashMap::new(),
+    );
+
+    assert_eq!(envs["KUBECONFIG"], "/etc/kube/prod");
+    assert_eq!(envs["OTHER"], "${UNSET}");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn sender_env_is_visible_to_the_command() {
+    let config = config_with_shell("sh", &["-c"]);
+    let alias_env = HashMap::from([(FROM_NODE_ENV.to_owned(), "spoofed".to_owned())]);
+    let mut envs = build_command_env(
+      &config,
+      std::env::var("PATH").unwrap(),
+      &HashMap::new(),
+      &alias_env,
+    );
+    add_sender_env(&mut envs, &context(), Some("Garage Pi"));
+
+    let out = run_shell_command(
+      &config,
+      "echo \"$MESHEXEC_FROM_NODE $MESHEXEC_CHANNEL $MESHEXEC_FROM_NAME\"",
+      &envs,
+      None,
+      None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "5678 2 Garage Pi\n");
+  }
+
+  #[test]
+  fn unknown_sender_name_is_left_unset() {
+    let mut envs = HashMap::new();
+    add_sender_env(&mut envs, &context(), None);
+    assert_eq!(envs[FROM_NODE_ENV], "5678");
+    assert_eq!(envs[CHANNEL_ENV], "2");
+    assert!(!envs.contains_key(FROM_NAME_ENV));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn secrets_are_interpolated_by_the_shell() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let secrets_path = dir.path().join("secrets.env");
+    std::fs::write(&secrets_path, "API_TOKEN=abc123\n").unwrap();
+    let config = Config {
+      secrets: crate::secrets::Secrets::load(&secrets_path).unwrap(),
+      ..config_with_shell("sh", &["-c"])
+    };
+    let alias_env = HashMap::from([("name".to_owned(), "Alice".to_owned())]);
+
+    let envs = build_command_env(
+      &config,
+      std::env::var("PATH").unwrap(),
+      &HashMap::new(),
+      &alias_env,
+    );
+    let out = run_shell_command(&config, "echo \"${name}:${API_TOKEN}\"", &envs, None, None)
+      .await
+      .unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Alice:abc123\n");
+  }
+
+  fn context() -> RequestContext {
+    RequestContext {
+      channel: 2,
+      node: 1234,
+      sender: 5678,
+      packet_id: 99,
+      hops: Some(2),
+    }
+  }
+
+  fn with_precondition(precondition: &str, message: Option<&str>) -> CommandDefinition {
+    CommandDefinition {
+      name: "deploy".to_owned(),
+      command: "deploy.sh".to_owned(),
+      precondition: Some(precondition.to_owned()),
+      precondition_message: message.map(str::to_owned),
+      ..CommandDefinition::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn no_precondition_always_passes() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let definition = CommandDefinition::default();
+    let result = check_precondition(&config, &definition, &HashMap::new(), &context()).await;
+    assert_eq!(result.unwrap(), None);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn passing_precondition_allows_command() {
+    let config = config_with_shell("sh", &["-c"]);
+    let envs = HashMap::from([("lock".to_owned(), "/nonexistent/deploy.lock".to_owned())]);
+    let definition = with_precondition("test ! -e \"${lock}\"", None);
+    let result = check_precondition(&config, &definition, &envs, &context()).await;
+    assert_eq!(result.unwrap(), None);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn failing_precondition_replies_with_stderr() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = with_precondition("echo \"locked by ${__sender}\" >&2; exit 1", None);
+    let result = check_precondition(&config, &definition, &HashMap::new(), &context()).await;
+    assert_eq!(result.unwrap().as_deref(), Some("locked by 5678\n"));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn failing_precondition_prefers_configured_message() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = with_precondition("echo busy >&2; exit 3", Some("Deploy already running"));
+    let result = check_precondition(&config, &definition, &HashMap::new(), &context()).await;
+    assert_eq!(result.unwrap().as_deref(), Some("Deploy already running"));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn failing_precondition_without_stderr_reports_status() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = with_precondition("exit 2", None);
+    let reply = check_precondition(&config, &definition, &HashMap::new(), &context())
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(
+      reply.starts_with("Precondition failed"),
+      "unexpected reply: {reply}"
+    );
+  }
+
+  fn conditional(name: &str, requires_os: &[&str], when: Option<&str>) -> CommandDefinition {
+    CommandDefinition {
+      name: name.to_owned(),
+      command: "true".to_owned(),
+      requires_os: requires_os.iter().map(|os| os.to_string()).collect(),
+      when: when.map(str::to_owned),
+      ..CommandDefinition::default()
+    }
+  }
+
+  fn names(commands: &[CommandDefinition]) -> Vec<&str> {
+    commands.iter().map(|c| c.name.as_str()).collect()
+  }
+
+  #[test]
+  fn requires_os_keeps_commands_for_this_os() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let commands = vec![
+      conditional("here", &["plan9", std::env::consts::OS], None),
+      conditional("elsewhere", &["plan9"], None),
+      conditional("anywhere", &[], None),
+    ];
+
+    let available = retain_available_commands(&config, commands);
+
+    assert_eq!(names(&available), vec!["here", "anywhere"]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn when_predicate_decides_availability() {
+    let config = config_with_shell("sh", &["-c"]);
+    let commands = vec![
+      conditional("passes", &[], Some("true")),
+      conditional("fails", &[], Some("exit 1")),
+    ];
+
+    let available = retain_available_commands(&config, commands);
+
+    assert_eq!(names(&available), vec!["passes"]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn hung_when_predicate_times_out() {
+    let config = config_with_shell("sh", &["-c"]);
+    let started = Instant::now();
+
+    let status = run_predicate(
+      &config,
+      "sleep 5",
+      &HashMap::new(),
+      Duration::from_millis(100),
+    )
+    .unwrap();
+
+    assert!(status.is_none());
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn when_predicate_that_cannot_run_excludes_command() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let commands = vec![conditional("broken", &[], Some("true"))];
+    assert!(retain_available_commands(&config, commands).is_empty());
+  }
+
+  #[test]
+  fn all_commands_filtered_out_is_only_an_error_when_required() {
+    let mut config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    config.commands = retain_available_commands(
+      &config,
+      vec![
+        conditional("elsewhere", &["plan9"], None),
+        conditional("broken", &[], Some("true")),
+      ],
+    );
+    assert!(config.commands.is_empty());
+    assert!(check_commands_available(&config).is_ok());
+
+    config.require_commands = true;
+    let err = check_commands_available(&config).unwrap_err();
+    assert!(err.to_string().starts_with("No commands are available"));
+
+    config.commands = vec![conditional("anywhere", &[], None)];
+    assert!(check_commands_available(&config).is_ok());
+  }
+
+  #[test]
+  fn groups_are_filtered_recursively() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let group = |name: &str, commands| CommandDefinition {
+      name: name.to_owned(),
+      commands,
+      ..CommandDefinition::default()
+    };
+    let commands = vec![
+      group(
+        "mixed",
+        vec![
+          conditional("a", &[], None),
+          conditional("b", &["plan9"], None),
+        ],
+      ),
+      group("empty", vec![conditional("c", &["plan9"], None)]),
+    ];
+
+    let available = retain_available_commands(&config, commands);
+
+    assert_eq!(names(&available), vec!["mixed"]);
+    assert_eq!(names(&available[0].commands), vec!["a"]);
+  }
+
+  #[test]
+  fn on_connect_hook_only_runs_when_configured() {
+    let mut config = config_with_shell("sh", &["-c"]);
+    assert_eq!(on_connect_hook(&config), None);
+
+    config.on_connect = Some("  ".to_owned());
+    assert_eq!(on_connect_hook(&config), None);
+
+    config.on_connect = Some("curl -fsS https://monitor.example/up".to_owned());
+    assert_eq!(
+      on_connect_hook(&config),
+      Some("curl -fsS https://monitor.example/up")
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn on_connect_hook_is_run() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let marker = dir.path().join("connected");
+    let mut config = config_with_shell("sh", &["-c"]);
+    config.on_connect = Some(format!("echo up > '{}'", marker.display()));
+
+    run_on_connect_hook(&config).await;
+
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "up\n");
+  }
+
+  #[test]
+  fn first_program_skips_assignments_builtins_and_placeholders() {
+    assert_eq!(first_program("uptime -p"), Some("uptime"));
+    assert_eq!(first_program("  LC_ALL=C df -h"), Some("df"));
+    assert_eq!(first_program("./deploy.sh; echo done"), Some("./deploy.sh"));
+    assert_eq!(first_program("cd /srv && make"), None);
+    assert_eq!(first_program("{tool} --version"), None);
+    assert_eq!(first_program("$EDITOR notes.txt"), None);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn command_programs_are_resolved_on_path() {
+    let leaf = |name: &str, command: &str| CommandDefinition {
+      name: name.to_owned(),
+      command: command.to_owned(),
+      ..CommandDefinition::default()
+    };
+    let mut config = config_with_shell("sh", &["-c"]);
+    config.commands = vec![
+      leaf("check", "sh -c 'exit 0'"),
+      leaf("deploy", "dpeloy.sh --prod"),
+      CommandDefinition {
+        name: "net".to_owned(),
+        commands: vec![CommandDefinition {
+          name: "dns".to_owned(),
+          steps: vec!["true".to_owned(), "meshexec-nonexistent-tool".to_owned()],
+          ..CommandDefinition::default()
+        }],
+        ..CommandDefinition::default()
+      },
+    ];
+
+    assert!(check_command_programs(&config).is_ok());
+
+    config.check_command_paths = CommandPathCheck::Warn;
+    assert!(check_command_programs(&config).is_ok());
+
+    config.check_command_paths = CommandPathCheck::Strict;
+    let err = check_command_programs(&config).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Commands whose program isn't an executable on PATH: deploy, net dns"
+    );
+
+    config.commands.truncate(1);
+    assert!(check_command_programs(&config).is_ok());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn command_env_path_is_searched_for_its_program() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::TempDir::new().unwrap();
+    let script = dir.path().join("deploy.sh");
+    std::fs::write(&script, "#!/bin/sh\n").unwrap();
+    let mut config = config_with_shell("sh", &["-c"]);
+    config.check_command_paths = CommandPathCheck::Strict;
+    config.commands = vec![CommandDefinition {
+      name: "deploy".to_owned(),
+      command: "deploy.sh".to_owned(),
+      env: HashMap::from([("PATH".to_owned(), dir.path().display().to_string())]),
+      ..CommandDefinition::default()
+    }];
+
+    // Not executable yet
+    assert!(check_command_programs(&config).is_err());
+
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(check_command_programs(&config).is_ok());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn output_filter_transforms_output() {
+    let config = config_with_shell("sh", &["-c"]);
+    let envs = HashMap::from([("level".to_owned(), "ERROR".to_owned())]);
+    let output = "INFO started\nERROR disk full\nINFO retrying\nERROR disk still full\n";
+
+    let filtered = apply_output_filter(&config, "grep \"${level}\" | head -n 1", &envs, output)
+      .await
+      .unwrap();
+
+    assert_eq!(filtered, "ERROR disk full\n");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn output_filter_that_exits_early_on_large_input() {
+    let config = config_with_shell("sh", &["-c"]);
+    let output = "line\n".repeat(100_000);
+    let filtered = apply_output_filter(&config, "head -n 2", &HashMap::new(), &output)
+      .await
+      .unwrap();
+    assert_eq!(filtered, "line\nline\n");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn failing_output_filter_reports_stderr() {
+    let config = config_with_shell("sh", &["-c"]);
+    let err = apply_output_filter(&config, "echo bad filter >&2; exit 2", &HashMap::new(), "x")
+      .await
+      .unwrap_err();
+    assert_eq!(err.to_string(), "Output filter failed: bad filter");
+  }
+
+  #[tokio::test]
+  async fn output_filter_with_missing_shell_errors() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    assert!(
+      apply_output_filter(&config, "cat", &HashMap::new(), "x")
+        .await
+        .is_err()
+    );
+  }
+
+  fn request(definition: CommandDefinition, input: Option<&str>) -> CommandRequest {
+    CommandRequest {
+      sender: 5678,
+      command: definition.command.clone(),
+      env: HashMap::new(),
+      envs: HashMap::new(),
+      definition,
+      context: context(),
+      input: input.map(str::to_owned),
+    }
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_filters_output() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      command: "echo one; echo two; echo \"from ${__sender}\" >&2; exit 3".to_owned(),
+      output_filter: Some("tail -n 1".to_owned()),
+      ..CommandDefinition::default()
+    };
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, None)).await
+    else {
+      panic!("expected the command to run");
+    };
+
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "two\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "from 5678\n");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_runs_every_step_in_order() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      steps: vec![
+        "echo one".to_owned(),
+        "echo warning >&2".to_owned(),
+        "echo \"from ${__sender}\"".to_owned(),
+      ],
+      ..CommandDefinition::default()
+    };
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, None)).await
+    else {
+      panic!("expected the steps to run");
+    };
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "one\nfrom 5678\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "warning\n");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_stops_at_the_first_failing_step() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      steps: vec![
+        "echo one".to_owned(),
+        "echo broken >&2; exit 4".to_owned(),
+        "echo three".to_owned(),
+      ],
+      ..CommandDefinition::default()
+    };
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, None)).await
+    else {
+      panic!("expected the steps to run");
+    };
+
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "one\n");
+    assert_eq!(
+      String::from_utf8_lossy(&output.stderr),
+      "Step 2/3 failed\nbroken\n"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn outcome_succeeded_follows_the_exit_status() {
+    let config = &config_with_shell("sh", &["-c"]);
+    let outcome = |command: &str| {
+      let definition = CommandDefinition {
+        command: command.to_owned(),
+        ..CommandDefinition::default()
+      };
+      let request = request(definition, None);
+      async move { execute_request(config, &request).await }
+    };
+
+    assert_eq!(outcome("true").await.succeeded(), Some(true));
+    assert_eq!(outcome("exit 1").await.succeeded(), Some(false));
+    let prompt = format!("echo 'Which environment?'; exit {AWAITING_INPUT_EXIT_CODE}");
+    assert_eq!(outcome(&prompt).await.succeeded(), None);
+    assert_eq!(
+      CommandOutcome::Blocked("Not now".to_owned()).succeeded(),
+      None
+    );
+    assert_eq!(
+      CommandOutcome::Failed(anyhow!("timed out")).succeeded(),
+      Some(false)
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_pipes_steps_into_each_other() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      steps: vec![
+        "seq 1 100000".to_owned(),
+        "grep 7".to_owned(),
+        "tail -n 1".to_owned(),
+      ],
+      pipe_steps: true,
+      ..CommandDefinition::default()
+    };
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, None)).await
+    else {
+      panic!("expected the pipeline to run");
+    };
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "99997\n");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_reports_the_failing_pipeline_step() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      steps: vec!["echo broken >&2; exit 3".to_owned(), "cat".to_owned()],
+      pipe_steps: true,
+      ..CommandDefinition::default()
+    };
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, None)).await
+    else {
+      panic!("expected the pipeline to run");
+    };
+
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+      String::from_utf8_lossy(&output.stderr),
+      "Step 1/2 failed\nbroken\n"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_does_not_filter_a_prompt() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      command: "echo 'Which environment?'; exit 42".to_owned(),
+      output_filter: Some("tr a-z A-Z".to_owned()),
+      ..CommandDefinition::default()
+    };
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, None)).await
+    else {
+      panic!("expected the command to run");
+    };
+
+    assert!(is_awaiting_input(&output.status));
+    assert_eq!(
+      String::from_utf8_lossy(&output.stdout),
+      "Which environment?\n"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn execute_request_checks_precondition_only_for_new_commands() {
+    let config = config_with_shell("sh", &["-c"]);
+    let definition = CommandDefinition {
+      command: "read -r answer; echo \"got $answer\"".to_owned(),
+      precondition: Some("exit 1".to_owned()),
+      precondition_message: Some("Locked".to_owned()),
+      ..CommandDefinition::default()
+    };
+
+    let outcome = execute_request(&config, &request(definition.clone(), None)).await;
+    assert!(matches!(outcome, CommandOutcome::Blocked(reply) if reply == "Locked"));
+
+    let CommandOutcome::Finished { output, .. } =
+      execute_request(&config, &request(definition, Some("yes"))).await
+    else {
+      panic!("expected the follow-up to run");
+    };
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "got yes\n");
+  }
+
+  #[tokio::test]
+  async fn execute_request_reports_spawn_failure() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let definition = CommandDefinition {
+      command: "uptime".to_owned(),
+      ..CommandDefinition::default()
+    };
+    let outcome = execute_request(&config, &request(definition, None)).await;
+    assert!(matches!(outcome, CommandOutcome::Failed(_)));
+  }
+
+  #[test]
+  fn command_beyond_the_limit_is_rejected_until_a_slot_frees_up() {
+    let slots = CommandSlots::new(2);
+    let first = slots.try_claim().unwrap();
+    let _second = slots.try_claim().unwrap();
+
+    assert!(slots.try_claim().is_none());
+
+    drop(first);
+    assert!(slots.try_claim().is_some());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn cancelling_kills_a_running_command() {
+    let config = Arc::new(config_with_shell("sh", &["-c"]));
+    let mut running = RunningCommands::default();
+    let (_, token) = running.start(5678, "backup");
+    let definition = CommandDefinition {
+      command: "sleep 30".to_owned(),
+      ..CommandDefinition::default()
+    };
+    let request = request(definition, None);
+    let started = Instant::now();
+    let task =
+      tokio::spawn(async move { cancellable(execute_request(&config, &request), token).await });
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(running.cancel(5678), vec!["backup"]);
+
+    let CommandOutcome::Failed(e) = task.await.unwrap() else {
+      panic!("expected the command to be cancelled");
+    };
+    assert!(matches!(
+      e.downcast_ref::<ExecutionError>(),
+      Some(ExecutionError::Cancelled)
+    ));
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(running.cancel(5678), Vec::<String>::new());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn command_cancelled_before_it_starts_never_runs() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let marker = dir.path().join("ran");
+    let config = config_with_shell("sh", &["-c"]);
+    let mut running = RunningCommands::default();
+    let (_, token) = running.start(5678, "backup");
+    let (_, other) = running.start(1111, "status");
+
+    assert_eq!(running.cancel(5678), vec!["backup"]);
+    let definition = CommandDefinition {
+      command: format!("touch {}", marker.display()),
+      ..CommandDefinition::default()
+    };
+    let outcome = cancellable(execute_request(&config, &request(definition, None)), token).await;
+
+    assert!(matches!(outcome, CommandOutcome::Failed(_)));
+    assert!(!marker.exists());
+    assert!(!other.is_cancelled());
+  }
+
+  #[test]
+  fn cancel_reply_lists_the_cancelled_commands() {
+    assert_eq!(format_cancelled(&[]), "Nothing to cancel");
+    assert_eq!(
+      format_cancelled(&["backup".to_owned(), "sync".to_owned()]),
+      "Cancelled: backup, sync"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn slots_are_held_while_commands_run() {
+    let config = Arc::new(config_with_shell("sh", &["-c"]));
+    let slots = CommandSlots::new(2);
+    let definition = CommandDefinition {
+      command: "sleep 1".to_owned(),
+      ..CommandDefinition::default()
+    };
+
+    let running: Vec<_> = (0..slots.limit())
+      .map(|_| {
+        let slot = slots.try_claim().unwrap();
+        let config = Arc::clone(&config);
+        let request = request(definition.clone(), None);
+        tokio::spawn(async move {
+          let _slot = slot;
+          execute_request(&config, &request).await
+        })
+      })
+      .collect();
+
+    assert!(slots.try_claim().is_none());
+    for command in running {
+      assert!(matches!(
+        command.await.unwrap(),
+        CommandOutcome::Finished { .. }
+      ));
+    }
+    assert!(slots.try_claim().is_some());
+  }
+
+  #[test]
+  fn zero_limit_never_rejects() {
+    let slots = CommandSlots::new(0);
+    let claimed: Vec<_> = (0..100).map(|_| slots.try_claim()).collect();
+    assert!(claimed.iter().all(Option::is_some));
+  }
+
+  #[tokio::test]
+  async fn progress_ticks_every_interval_until_completion() {
+    let mut ticks = Vec::new();
+
+    let output = with_progress(
+      async {
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        "done"
+      },
+      Some(Duration::from_millis(200)),
+      |elapsed| ticks.push(elapsed),
+    )
+    .await;
+
+    assert_eq!(output, "done");
+    assert_eq!(ticks.len(), 3, "{ticks:?}");
+    for (tick, elapsed) in ticks.iter().enumerate() {
+      assert!(*elapsed >= Duration::from_millis(200) * (tick as u32 + 1));
+    }
+  }
+
+  #[tokio::test]
+  async fn no_progress_without_an_interval() {
+    let mut ticks = 0;
+    let output = with_progress(
+      async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        "done"
+      },
+      None,
+      |_| ticks += 1,
+    )
+    .await;
+    assert_eq!(output, "done");
+    assert_eq!(ticks, 0);
+  }
+
+  #[tokio::test]
+  async fn no_progress_for_command_finishing_within_the_interval() {
+    let mut ticks = 0;
+    with_progress(async {}, Some(Duration::from_secs(1)), |_| ticks += 1).await;
+    assert_eq!(ticks, 0);
+  }
+
+  #[test]
+  fn progress_message_shows_elapsed_seconds() {
+    let progress = CommandProgress {
+      sender: 1,
+      channel: 0,
+      elapsed: Duration::from_millis(30_400),
+    };
+    assert_eq!(progress.message(), "Still running (30s)...");
+  }
+
+  #[test]
+  fn server_busy_error_message() {
+    assert_eq!(
+      ExecutionError::ServerBusy(4).to_string(),
+      "Server busy: 4 commands already running. Try again shortly."
+    );
+  }
+
+  #[test]
+  fn rate_limited_error_rounds_up_the_wait() {
+    assert_eq!(
+      ExecutionError::RateLimited(Duration::from_millis(12_300)).to_string(),
+      "Rate limited, try again in 13s"
+    );
+  }
+
+  #[test]
+  fn interpolate_reserved_channel() {
+    assert_eq!(
+      interpolate_reserved("echo ch=${__channel}", &context()),
+      "echo ch=2"
+    );
+  }
+
+  #[test]
+  fn interpolate_reserved_node() {
+    assert_eq!(
+      interpolate_reserved("echo ${__node} ${__node}", &context()),
+      "echo 1234 1234"
+    );
+  }
+
+  #[test]
+  fn interpolate_reserved_sender() {
+    assert_eq!(
+      interpolate_reserved("notify ${__sender}", &context()),
+      "notify 5678"
+    );
+  }
+
+  #[test]
+  fn interpolate_reserved_hops() {
+    assert_eq!(
+      interpolate_reserved("echo ${__hops} hops", &context()),
+      "echo 2 hops"
+    );
+    let unknown = RequestContext {
+      hops: None,
+      ..context()
+    };
+    assert_eq!(
+      interpolate_reserved("echo ${__hops} hops", &unknown),
+      "echo ? hops"
+    );
+  }
+
+  #[test]
+  fn interpolate_reserved_leaves_user_variables() {
+    assert_eq!(
+      interpolate_reserved("echo ${name} $__sender ${__other}", &context()),
+      "echo ${name} $__sender ${__other}"
+    );
+  }
+
+  #[tokio::test]
+  async fn empty_command_is_rejected_without_spawning_shell() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let err = run_shell_command(&config, "", &HashMap::new(), None, None)
+      .await
+      .unwrap_err();
+    assert!(
+      matches!(
+        err.downcast_ref::<ExecutionError>(),
+        Some(ExecutionError::EmptyCommand)
+      ),
+      "unexpected error: {err}"
+    );
+  }
+
+  #[tokio::test]
+  async fn whitespace_only_command_is_rejected() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let err = run_shell_command(&config, " \n\t ", &HashMap::new(), None, None)
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Command resolved to an empty command string"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn command_exceeding_timeout_is_killed() {
+    let config = config_with_shell("sh", &["-c"]);
+    let started = Instant::now();
+    let err = run_shell_command(
+      &config,
+      "sleep 30",
+      &HashMap::new(),
+      None,
+      Some(Duration::from_millis(200)),
+    )
+    .await
+    .unwrap_err();
+
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(matches!(
+      err.downcast_ref::<ExecutionError>(),
+      Some(ExecutionError::TimedOut(_))
+    ));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn timeout_covers_all_steps_together() {
+    let config = config_with_shell("sh", &["-c"]);
+    let steps = vec!["sleep 0.4".to_string(), "sleep 0.4".to_string()];
+    let err = run_steps(
+      &config,
+      &steps,
+      &HashMap::new(),
+      None,
+      Some(Duration::from_millis(600)),
+    )
+    .await
+    .unwrap_err();
+
+    assert!(matches!(
+      err.downcast_ref::<ExecutionError>(),
+      Some(ExecutionError::TimedOut(_))
+    ));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn command_within_timeout_completes() {
+    let config = config_with_shell("sh", &["-c"]);
+    let output = run_shell_command(
+      &config,
+      "echo done",
+      &HashMap::new(),
+      None,
+      Some(Duration::from_secs(10)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+  }
+
+  #[test]
+  fn timed_out_error_message() {
+    let err = ExecutionError::TimedOut(Duration::from_secs(30));
+    assert_eq!(err.to_string(), "Command timed out after 30s");
+  }
+
+  #[test]
+  fn command_timeout_prefers_command_over_global() {
+    let config = Config {
+      command_timeout: Some(60),
+      ..Config::default()
+    };
+    let mut definition = CommandDefinition::default();
+    assert_eq!(
+      command_timeout(&config, &definition),
+      Some(Duration::from_secs(60))
+    );
+
+    definition.timeout = Some(5);
+    assert_eq!(
+      command_timeout(&config, &definition),
+      Some(Duration::from_secs(5))
+    );
+    assert_eq!(
+      command_timeout(&Config::default(), &CommandDefinition::default()),
+      None
+    );
+  }
+
+  #[tokio::test]
+  async fn missing_shell_returns_spawn_error() {
+    let config = config_with_shell("meshexec-nonexistent-shell", &[]);
+    let err = run_shell_command(&config, "echo hi", &HashMap::new(), None, None)
+      .await
+      .unwrap_err();
+    assert!(err.downcast_ref::<ExecutionError>().is_none());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn non_empty_command_runs_in_shell() {
+    let config = config_with_shell("sh", &["-c"]);
+    let mut envs = HashMap::new();
+    envs.insert("name".to_owned(), "mesh".to_owned());
+    let output = run_shell_command(&config, "echo \"hello ${name}\"", &envs, None, None)
+      .await
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello mesh\n");
+  }
+
+  #[test]
+  fn continuation_is_taken_once() {
+    let mut continuations = Continuations::default();
+    pending(&mut continuations, 7, "deploy");
+    assert!(continuations.is_pending(7));
+
+    let taken = continuations.take(7).unwrap();
+    assert_eq!(taken.command, "deploy");
+    assert!(!continuations.is_pending(7));
+    assert!(continuations.take(7).is_none());
+  }
+
+  #[test]
+  fn continuations_are_kept_per_sender() {
+    let mut continuations = Continuations::default();
+    pending(&mut continuations, 1, "first");
+    pending(&mut continuations, 2, "second");
+
+    assert_eq!(continuations.take(2).unwrap().command, "second");
+    assert!(continuations.is_pending(1));
+    assert!(continuations.take(3).is_none());
+  }
+
+  #[test]
+  fn stale_continuations_expire_and_fresh_ones_survive() {
+    let mut continuations = Continuations::new(Duration::from_secs(60));
+    pending(&mut continuations, 1, "stale");
+    pending(&mut continuations, 2, "fresh");
+    let now = Instant::now();
+    continuations.pending.get_mut(&1).unwrap().created_at = now - Duration::from_secs(61);
+
+    assert_eq!(continuations.expire_at(now), 1);
+    assert!(!continuations.is_pending(1));
+    assert!(continuations.is_pending(2));
+    assert_eq!(continuations.expire_at(now + Duration::from_secs(30)), 0);
+    assert_eq!(continuations.expire_at(now + Duration::from_secs(60)), 1);
+    assert!(!continuations.is_pending(2));
+  }
+
+  #[test]
+  fn zero_ttl_never_expires_continuations() {
+    let mut continuations = Continuations::default();
+    pending(&mut continuations, 1, "first");
+    let later = Instant::now() + Duration::from_secs(86_400);
+    assert_eq!(continuations.expire_at(later), 0);
+    assert!(continuations.is_pending(1));
+  }
+
+  #[test]
+  fn new_prompt_replaces_previous_continuation_for_sender() {
+    let mut continuations = Continuations::default();
+    pending(&mut continuations, 1, "first");
+    pending(&mut continuations, 1, "second");
+
+    assert_eq!(continuations.take(1).unwrap().command, "second");
+    assert!(!continuations.is_pending(1));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn prompt_and_answer_cycle() {
+    let config = config_with_shell("sh", &["-c"]);
+    let script = indoc::indoc! {r#"
+      read -r answer
+      if [ -z "$answer" ]; then
+        echo "Which environment?"
+        exit 42
+      fi
+      echo "deploying to $answer"
+    "#};
+    let mut continuations = Continuations::default();
+
+    let prompt = run_shell_command(&config, script, &HashMap::new(), None, None)
+      .await
+      .unwrap();
+    assert!(is_awaiting_input(&prompt.status));
+    assert_eq!(
+      String::from_utf8_lossy(&prompt.stdout),
+      "Which environment?\n"
+    );
+    pending(&mut continuations, 42, script);
+
+    let pending_input = continuations.take(42).unwrap();
+    let answer = run_shell_command(
+      &config,
+      &pending_input.command,
+      &pending_input.env,
+      Some("staging"),
+      None,
+    )
+    .await
+    .unwrap();
+    assert!(!is_awaiting_input(&answer.status));
+    assert!(answer.status.success());
+    assert_eq!(
+      String::from_utf8_lossy(&answer.stdout),
+      "deploying to staging\n"
+    );
+  }
+}
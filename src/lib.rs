@@ -1,5 +1,20 @@
+pub mod acks;
+pub mod airtime;
+pub mod builtins;
 pub mod cli;
 pub mod command;
+pub mod compress;
 pub mod config;
+pub mod dedup;
+pub mod executor;
+pub mod limit_state;
 pub mod logging;
+pub mod nodes;
+pub mod output;
+pub mod rate_limit;
+pub mod recent_errors;
+pub mod replay;
+pub mod secrets;
+pub mod shutdown;
+pub mod template;
 pub mod transport;
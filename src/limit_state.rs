@@ -0,0 +1,301 @@
+use crate::airtime::{AIRTIME_WINDOW, AirtimeBudget};
+use crate::dedup::ErrorReplyThrottle;
+use crate::rate_limit::RateLimiter;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the limit state is saved while running, in addition to on shutdown
+pub const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The rate-limit state saved to the `state_file`, so restarting the server (e.g. in a crash loop)
+/// doesn't reset the airtime budget, the error reply throttle or each node's rate limit. Times are
+/// Unix milliseconds, since `Instant`s don't survive a restart.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LimitState {
+  #[serde(default)]
+  pub airtime: Vec<AirtimeRecord>,
+  #[serde(default)]
+  pub error_replies: Vec<ErrorReplyRecord>,
+  #[serde(default)]
+  pub rate_limits: Vec<RateLimitRecord>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AirtimeRecord {
+  pub sent_at: u64,
+  pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorReplyRecord {
+  pub sender: u32,
+  pub replied_at: u64,
+  pub suppressed: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitRecord {
+  pub sender: u32,
+  pub tokens: f64,
+  pub updated_at: u64,
+}
+
+impl LimitState {
+  /// Loads the saved state, or an empty state if the file doesn't exist yet
+  pub fn load(path: &Path) -> Result<Self> {
+    let content = match fs::read_to_string(path) {
+      Ok(content) => content,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+      Err(e) => {
+        return Err(e).with_context(|| format!("Failed to read state from '{}'", path.display()));
+      }
+    };
+
+    serde_json::from_str(&content)
+      .with_context(|| format!("Failed to parse state from '{}'", path.display()))
+  }
+
+  /// Writes the state to a temporary file first, so a crash mid-write can't corrupt it
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_string(self)?)
+      .with_context(|| format!("Failed to write state to '{}'", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+      .with_context(|| format!("Failed to write state to '{}'", path.display()))
+  }
+
+  pub fn capture(
+    airtime: &AirtimeBudget,
+    error_throttle: &ErrorReplyThrottle,
+    rate_limiter: &RateLimiter,
+  ) -> Self {
+    Self::capture_at(
+      airtime,
+      error_throttle,
+      rate_limiter,
+      Instant::now(),
+      SystemTime::now(),
+    )
+  }
+
+  /// Applies the saved state to freshly created limiters
+  pub fn restore(
+    self,
+    airtime: &mut AirtimeBudget,
+    error_throttle: &mut ErrorReplyThrottle,
+    rate_limiter: &mut RateLimiter,
+  ) {
+    self.restore_at(
+      airtime,
+      error_throttle,
+      rate_limiter,
+      Instant::now(),
+      SystemTime::now(),
+    );
+  }
+
+  fn capture_at(
+    airtime: &AirtimeBudget,
+    error_throttle: &ErrorReplyThrottle,
+    rate_limiter: &RateLimiter,
+    now: Instant,
+    wall_now: SystemTime,
+  ) -> Self {
+    Self {
+      airtime: airtime
+        .sent()
+        .map(|(sent_at, bytes)| AirtimeRecord {
+          sent_at: to_unix_millis(sent_at, now, wall_now),
+          bytes,
+        })
+        .collect(),
+      error_replies: error_throttle
+        .replies()
+        .map(|(sender, replied_at, suppressed)| ErrorReplyRecord {
+          sender,
+          replied_at: to_unix_millis(replied_at, now, wall_now),
+          suppressed,
+        })
+        .collect(),
+      rate_limits: rate_limiter
+        .buckets()
+        .map(|(sender, tokens, updated_at)| RateLimitRecord {
+          sender,
+          tokens,
+          updated_at: to_unix_millis(updated_at, now, wall_now),
+        })
+        .collect(),
+    }
+  }
+
+  fn restore_at(
+    self,
+    airtime: &mut AirtimeBudget,
+    error_throttle: &mut ErrorReplyThrottle,
+    rate_limiter: &mut RateLimiter,
+    now: Instant,
+    wall_now: SystemTime,
+  ) {
+    airtime.restore(self.airtime.into_iter().filter_map(|record| {
+      let sent_at = to_instant(record.sent_at, now, wall_now)?;
+      (now.duration_since(sent_at) < AIRTIME_WINDOW).then_some((sent_at, record.bytes))
+    }));
+    error_throttle.restore(self.error_replies.into_iter().filter_map(|record| {
+      let replied_at = to_instant(record.replied_at, now, wall_now)?;
+      Some((record.sender, replied_at, record.suppressed))
+    }));
+    rate_limiter.restore(self.rate_limits.into_iter().filter_map(|record| {
+      let updated_at = to_instant(record.updated_at, now, wall_now)?;
+      Some((record.sender, record.tokens, updated_at))
+    }));
+  }
+}
+
+fn to_unix_millis(at: Instant, now: Instant, wall_now: SystemTime) -> u64 {
+  let at = wall_now - now.saturating_duration_since(at);
+  at.duration_since(UNIX_EPOCH)
+    .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// `None` for times too far in the past to be represented as an `Instant` on this host, which are
+/// long expired anyway
+fn to_instant(unix_millis: u64, now: Instant, wall_now: SystemTime) -> Option<Instant> {
+  let at = UNIX_EPOCH + Duration::from_millis(unix_millis);
+  now.checked_sub(wall_now.duration_since(at).unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::RateLimit;
+  use tempfile::TempDir;
+
+  #[test]
+  fn state_round_trips_through_the_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("state.json");
+    let state = LimitState {
+      airtime: vec![AirtimeRecord {
+        sent_at: 1_700_000_000_000,
+        bytes: 180,
+      }],
+      error_replies: vec![ErrorReplyRecord {
+        sender: 42,
+        replied_at: 1_700_000_001_000,
+        suppressed: 3,
+      }],
+      rate_limits: vec![RateLimitRecord {
+        sender: 42,
+        tokens: 0.5,
+        updated_at: 1_700_000_002_000,
+      }],
+    };
+
+    state.save(&path).unwrap();
+
+    assert_eq!(LimitState::load(&path).unwrap(), state);
+  }
+
+  #[test]
+  fn missing_state_file_is_empty_state() {
+    let dir = TempDir::new().unwrap();
+    let state = LimitState::load(&dir.path().join("state.json")).unwrap();
+    assert_eq!(state, LimitState::default());
+  }
+
+  #[test]
+  fn corrupt_state_file_is_an_error() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("state.json");
+    fs::write(&path, "{not json").unwrap();
+    assert!(LimitState::load(&path).is_err());
+  }
+
+  #[test]
+  fn limiters_round_trip_through_the_state() {
+    let mut airtime = AirtimeBudget::new(500);
+    let mut error_throttle = ErrorReplyThrottle::new(Duration::from_secs(60));
+    let limit = Some(RateLimit {
+      max_commands: 1,
+      per_secs: 3600,
+    });
+    let mut rate_limiter = RateLimiter::new(limit);
+    assert!(airtime.try_consume(400));
+    assert_eq!(error_throttle.throttle(7), None);
+    assert_eq!(error_throttle.throttle(7), Some(1));
+    assert_eq!(rate_limiter.check(7), None);
+
+    let state = LimitState::capture(&airtime, &error_throttle, &rate_limiter);
+    let mut restored_airtime = AirtimeBudget::new(500);
+    let mut restored_throttle = ErrorReplyThrottle::new(Duration::from_secs(60));
+    let mut restored_limiter = RateLimiter::new(limit);
+    state.restore(
+      &mut restored_airtime,
+      &mut restored_throttle,
+      &mut restored_limiter,
+    );
+
+    assert!(!restored_airtime.try_consume(200));
+    assert!(restored_airtime.try_consume(100));
+    assert_eq!(restored_throttle.throttle(7), Some(2));
+    assert_eq!(restored_throttle.throttle(8), None);
+    assert!(restored_limiter.check(7).is_some());
+    assert_eq!(restored_limiter.check(8), None);
+  }
+
+  #[test]
+  fn expired_airtime_is_not_restored() {
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+    let wall_millis = to_unix_millis(now, now, wall_now);
+    let state = LimitState {
+      airtime: vec![
+        AirtimeRecord {
+          sent_at: wall_millis - AIRTIME_WINDOW.as_millis() as u64 - 1000,
+          bytes: 400,
+        },
+        AirtimeRecord {
+          sent_at: wall_millis - 1000,
+          bytes: 100,
+        },
+      ],
+      ..LimitState::default()
+    };
+    let mut airtime = AirtimeBudget::new(500);
+    let mut error_throttle = ErrorReplyThrottle::new(Duration::from_secs(60));
+    let mut rate_limiter = RateLimiter::new(None);
+
+    state.restore_at(
+      &mut airtime,
+      &mut error_throttle,
+      &mut rate_limiter,
+      now,
+      wall_now,
+    );
+
+    assert_eq!(
+      airtime.sent().map(|(_, bytes)| bytes).collect::<Vec<_>>(),
+      vec![100]
+    );
+  }
+
+  #[test]
+  fn times_convert_between_clocks() {
+    let now = Instant::now();
+    let wall_now = SystemTime::now();
+    let earlier = now - Duration::from_secs(90);
+
+    let millis = to_unix_millis(earlier, now, wall_now);
+
+    assert_eq!(
+      to_instant(millis, now, wall_now)
+        .map(|at| now - at)
+        .map(|age| age.as_secs()),
+      Some(90)
+    );
+  }
+}
@@ -1,16 +1,30 @@
-use crate::cli::LogLevel;
+use crate::cli::{LogFormat, LogLevel};
+use crate::recent_errors::RecentErrorsAppender;
+use crate::shutdown::wait_for_shutdown_signal;
 use anyhow::{Context, Result};
 use colored::Colorize;
-use log::LevelFilter;
+use log::{LevelFilter, Record};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::writer::simple::SimpleWriter;
+use log4rs::encode::{self, Encode};
 use regex::Regex;
+use serde_json::{Map, Value};
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Lines sent for `!log` without a count, and the most it will send
+pub const DEFAULT_LOG_TAIL_LINES: usize = 10;
+pub const MAX_LOG_TAIL_LINES: usize = 50;
+/// How much of the end of the log file `!log` reads, so a huge log is never read whole
+const LOG_TAIL_MAX_BYTES: u64 = 16 * 1024;
+/// How often `tail-logs` checks the log file for new lines once it has caught up
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 pub fn get_log_path() -> PathBuf {
   let mut log_path = if cfg!(target_os = "linux") {
@@ -31,78 +45,282 @@ pub fn get_log_path() -> PathBuf {
   log_path
 }
 
-pub fn init_logging_config(log_level: LogLevel) -> log4rs::Config {
-  let encoder = Box::new(PatternEncoder::new(
-    "{d(%Y-%m-%d %H:%M:%S%.3f)(utc)} <{i}> [{l}] {f}:{L} - {m}{n}",
-  ));
-  let logfile = FileAppender::builder()
-    .encoder(encoder.clone())
-    .build(get_log_path())
-    .unwrap();
-  let stdout = ConsoleAppender::builder().encoder(encoder.clone()).build();
+/// The log line pattern. An instance label, if given, follows the timestamp in square brackets so
+/// logs from several runners can be told apart once aggregated.
+fn log_pattern(instance: Option<&str>) -> String {
+  let instance = match instance {
+    Some(label) => {
+      let escaped: String = label
+        .chars()
+        .flat_map(|c| match c {
+          '{' | '}' | '(' | ')' | '\\' => vec!['\\', c],
+          _ => vec![c],
+        })
+        .collect();
+      format!("[{escaped}] ")
+    }
+    None => String::new(),
+  };
+
+  format!("{{d(%Y-%m-%d %H:%M:%S%.3f)(utc)}} {instance}<{{i}}> [{{l}}] {{f}}:{{L}} - {{m}}{{n}}")
+}
+
+/// Encodes each record as a single-line JSON object with `timestamp`, `level`, `target`, `file`,
+/// `line` and `message` fields, plus `instance` when an instance label is set
+#[derive(Debug)]
+struct JsonLineEncoder {
+  timestamp: PatternEncoder,
+  instance: Option<String>,
+}
+
+impl JsonLineEncoder {
+  fn new(instance: Option<&str>) -> Self {
+    Self {
+      timestamp: PatternEncoder::new("{d(%Y-%m-%dT%H:%M:%S%.3fZ)(utc)}"),
+      instance: instance.map(str::to_owned),
+    }
+  }
+}
+
+impl Encode for JsonLineEncoder {
+  fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> anyhow::Result<()> {
+    let mut timestamp = Vec::new();
+    self
+      .timestamp
+      .encode(&mut SimpleWriter(&mut timestamp), record)?;
+
+    let mut fields = Map::new();
+    fields.insert(
+      "timestamp".to_owned(),
+      String::from_utf8_lossy(&timestamp).into(),
+    );
+    if let Some(instance) = &self.instance {
+      fields.insert("instance".to_owned(), instance.as_str().into());
+    }
+    fields.insert("level".to_owned(), record.level().as_str().into());
+    fields.insert("target".to_owned(), record.target().into());
+    fields.insert("file".to_owned(), record.file().into());
+    fields.insert("line".to_owned(), record.line().into());
+    fields.insert("message".to_owned(), record.args().to_string().into());
+
+    serde_json::to_writer(&mut *w, &Value::Object(fields))?;
+    w.write_all(b"\n")?;
+    Ok(())
+  }
+}
+
+fn log_encoder(format: LogFormat, instance: Option<&str>) -> Box<dyn Encode> {
+  match format {
+    LogFormat::Text => Box::new(PatternEncoder::new(&log_pattern(instance))),
+    LogFormat::Json => Box::new(JsonLineEncoder::new(instance)),
+  }
+}
 
-  log4rs::Config::builder()
-    .appender(Appender::builder().build("logfile", Box::new(logfile)))
+pub fn init_logging_config(
+  log_level: LogLevel,
+  log_to_file: bool,
+  instance: Option<&str>,
+  format: LogFormat,
+) -> log4rs::Config {
+  let stdout = ConsoleAppender::builder()
+    .encoder(log_encoder(format, instance))
+    .build();
+
+  let mut config_builder = log4rs::Config::builder()
     .appender(Appender::builder().build("stdout", Box::new(stdout)))
-    .logger(Logger::builder().build("meshtastic::connections::stream_buffer", LevelFilter::Off))
-    .build(
-      Root::builder()
-        .appender("logfile")
-        .appender("stdout")
-        .build(log_level.into()),
-    )
+    .appender(Appender::builder().build("recent_errors", Box::new(RecentErrorsAppender)))
+    .logger(Logger::builder().build("meshtastic::connections::stream_buffer", LevelFilter::Off));
+  let mut root_builder = Root::builder().appender("stdout").appender("recent_errors");
+
+  if log_to_file {
+    let logfile = FileAppender::builder()
+      .encoder(log_encoder(format, instance))
+      .build(get_log_path())
+      .unwrap();
+    config_builder =
+      config_builder.appender(Appender::builder().build("logfile", Box::new(logfile)));
+    root_builder = root_builder.appender("logfile");
+  }
+
+  config_builder
+    .build(root_builder.build(log_level.into()))
     .unwrap()
 }
 
 pub async fn tail_logs(no_color: bool) -> Result<()> {
   let re = Regex::new(
-    r"^(?P<timestamp>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3})\s+<(?P<opid>[^\s>]+)>\s+\[(?P<level>[A-Z]+)]\s+(?P<logger>[^:]+):(?P<line>\d+)\s+-\s+(?P<message>.*)$",
+    r"^(?P<timestamp>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3})\s+(?:\[(?P<instance>[^\]]+)]\s+)?<(?P<opid>[^\s>]+)>\s+\[(?P<level>[A-Z]+)]\s+(?P<logger>[^:]+):(?P<line>\d+)\s+-\s+(?P<message>.*)$",
   )?;
   let file_path = get_log_path();
-  let file = File::open(&file_path).expect("Cannot open file");
+  let file = File::open(&file_path)
+    .with_context(|| format!("Cannot open log file '{}'", file_path.display()))?;
   let mut reader = BufReader::new(file);
 
   reader
     .seek(SeekFrom::End(0))
     .with_context(|| "Unable to tail log file")?;
 
-  let mut lines = reader.lines();
-
-  tokio::spawn(async move {
-    loop {
-      if let Some(Ok(line)) = lines.next() {
-        if no_color {
-          println!("{line}");
-        } else {
-          let colored_line = colorize_log_line(&line, &re);
-          println!("{colored_line}");
-        }
-      }
+  let print_line = |line: &str| {
+    if no_color {
+      println!("{line}");
+    } else {
+      let colored_line =
+        colorize_json_log_line(line).unwrap_or_else(|| colorize_log_line(line, &re));
+      println!("{colored_line}");
+    }
+  };
+
+  tokio::select! {
+    result = follow_lines(reader, TAIL_POLL_INTERVAL, print_line) => result,
+    signal = wait_for_shutdown_signal() => signal.map(|_| ()),
+  }
+}
+
+/// Calls `on_line` with each complete line appended to the file, without its line ending. At the
+/// end of the file it waits `poll_interval` before reading again, and starts over from the
+/// beginning if the file has shrunk (e.g. it was truncated).
+async fn follow_lines<R: BufRead + Seek>(
+  mut reader: R,
+  poll_interval: Duration,
+  mut on_line: impl FnMut(&str),
+) -> Result<()> {
+  let mut line = String::new();
+  loop {
+    if reader.read_line(&mut line)? > 0 && line.ends_with('\n') {
+      on_line(line.trim_end_matches(['\r', '\n']));
+      line.clear();
+      continue;
+    }
+
+    // At the end of the file, possibly partway through a line that's still being written
+    let position = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < position {
+      reader.rewind()?;
+      line.clear();
+    } else {
+      reader.seek(SeekFrom::Start(position))?;
     }
-  })
-  .await?
+
+    tokio::time::sleep(poll_interval).await;
+  }
+}
+
+/// Builds the `!log [count]` reply: the last lines of the log file, up to `MAX_LOG_TAIL_LINES`
+pub fn format_log_tail(path: &Path, arg: &str) -> String {
+  let count = match arg {
+    "" => DEFAULT_LOG_TAIL_LINES,
+    count => match count.parse::<usize>() {
+      Ok(count) => count.min(MAX_LOG_TAIL_LINES),
+      Err(_) => return "Usage: !log [count]".to_owned(),
+    },
+  };
+
+  let content = match read_log_tail(path, LOG_TAIL_MAX_BYTES) {
+    Ok(content) => content,
+    Err(e) => return format!("Unable to read the log: {e}"),
+  };
+
+  let lines = last_lines(&content, count);
+  if lines.is_empty() {
+    return "The log is empty".to_owned();
+  }
+
+  lines.join("\n")
+}
+
+/// Reads at most the last `max_bytes` of the file. When that starts mid-file, the first (likely
+/// partial) line is dropped.
+fn read_log_tail(path: &Path, max_bytes: u64) -> std::io::Result<String> {
+  let mut file = File::open(path)?;
+  let len = file.metadata()?.len();
+  let start = len.saturating_sub(max_bytes);
+  file.seek(SeekFrom::Start(start))?;
+
+  let mut bytes = Vec::new();
+  file.take(max_bytes).read_to_end(&mut bytes)?;
+  let content = String::from_utf8_lossy(&bytes);
+
+  if start == 0 {
+    return Ok(content.into_owned());
+  }
+
+  Ok(
+    content
+      .split_once('\n')
+      .map_or_else(String::new, |(_, rest)| rest.to_owned()),
+  )
+}
+
+/// The last `count` non-blank lines, oldest first
+fn last_lines(content: &str, count: usize) -> Vec<&str> {
+  let mut lines: Vec<&str> = content
+    .lines()
+    .rev()
+    .filter(|line| !line.trim().is_empty())
+    .take(count)
+    .collect();
+  lines.reverse();
+  lines
+}
+
+fn colorize_message(level: &str, message: &str) -> colored::ColoredString {
+  match level {
+    "ERROR" => message.red(),
+    "WARN" => message.yellow(),
+    "INFO" => message.green(),
+    "DEBUG" => message.blue(),
+    _ => message.normal(),
+  }
+}
+
+/// Pretty-prints a line written with `--log-format json` like a colored text line, or returns
+/// `None` if it isn't one
+fn colorize_json_log_line(line: &str) -> Option<String> {
+  if !line.starts_with('{') {
+    return None;
+  }
+  let fields: Map<String, Value> = serde_json::from_str(line).ok()?;
+  let field = |name: &str| fields.get(name).and_then(Value::as_str).unwrap_or_default();
+
+  let level = field("level");
+  let instance = fields
+    .get("instance")
+    .and_then(Value::as_str)
+    .map(|instance| format!("[{}] ", instance.yellow()))
+    .unwrap_or_default();
+  let line_number = fields
+    .get("line")
+    .and_then(Value::as_u64)
+    .map(|line| line.to_string())
+    .unwrap_or_default();
+
+  Some(format!(
+    "{} {instance}[{}] {}:{} - {}",
+    field("timestamp").white(),
+    level.bold(),
+    field("file").magenta(),
+    line_number.bold(),
+    colorize_message(level, field("message"))
+  ))
 }
 
 fn colorize_log_line(line: &str, re: &Regex) -> String {
   if let Some(caps) = re.captures(line) {
     let level = &caps["level"];
-    let message = &caps["message"];
-
-    let colored_message = match level {
-      "ERROR" => message.red(),
-      "WARN" => message.yellow(),
-      "INFO" => message.green(),
-      "DEBUG" => message.blue(),
-      _ => message.normal(),
-    };
+    let colored_message = colorize_message(level, &caps["message"]);
 
     let timestamp = &caps["timestamp"];
+    let instance = caps
+      .name("instance")
+      .map(|instance| format!("[{}] ", instance.as_str().yellow()))
+      .unwrap_or_default();
     let opid = &caps["opid"];
     let logger = &caps["logger"];
     let line_number = &caps["line"];
 
     format!(
-      "{} <{}> [{}] {}:{} - {}",
+      "{} {instance}<{}> [{}] {}:{} - {}",
       timestamp.white(),
       opid.cyan(),
       level.bold(),
@@ -121,7 +339,7 @@ mod tests {
 
   fn log_regex() -> Regex {
     Regex::new(
-            r"^(?P<timestamp>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3})\s+<(?P<opid>[^\s>]+)>\s+\[(?P<level>[A-Z]+)]\s+(?P<logger>[^:]+):(?P<line>\d+)\s+-\s+(?P<message>.*)$",
+            r"^(?P<timestamp>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3})\s+(?:\[(?P<instance>[^\]]+)]\s+)?<(?P<opid>[^\s>]+)>\s+\[(?P<level>[A-Z]+)]\s+(?P<logger>[^:]+):(?P<line>\d+)\s+-\s+(?P<message>.*)$",
         )
         .unwrap()
   }
@@ -155,6 +373,191 @@ mod tests {
     assert_eq!(colored, "");
   }
 
+  #[test]
+  fn logging_config_without_file_has_no_logfile_appender() {
+    let config = init_logging_config(LogLevel::Info, false, None, LogFormat::Text);
+    let appenders: Vec<_> = config.appenders().iter().map(|a| a.name()).collect();
+    assert_eq!(appenders, vec!["stdout", "recent_errors"]);
+    assert_eq!(config.root().appenders(), ["stdout", "recent_errors"]);
+  }
+
+  fn format_with_pattern(instance: Option<&str>) -> String {
+    let mut buffer = Vec::new();
+    let record = log::Record::builder()
+      .args(format_args!("Connected"))
+      .level(log::Level::Info)
+      .file(Some("src/main.rs"))
+      .line(Some(7))
+      .build();
+    PatternEncoder::new(&log_pattern(instance))
+      .encode(&mut SimpleWriter(&mut buffer), &record)
+      .unwrap();
+    String::from_utf8(buffer).unwrap()
+  }
+
+  #[test]
+  fn log_pattern_includes_instance_label() {
+    let line = format_with_pattern(Some("garage-bot"));
+    assert!(
+      line.contains(" [garage-bot] <") && line.ends_with("[INFO] src/main.rs:7 - Connected\n"),
+      "unexpected line: {line}"
+    );
+
+    let caps = log_regex().captures(line.trim_end()).unwrap();
+    assert_eq!(&caps["instance"], "garage-bot");
+    assert_eq!(&caps["message"], "Connected");
+  }
+
+  #[test]
+  fn log_pattern_escapes_special_characters_in_label() {
+    let line = format_with_pattern(Some("bot{1}(a)"));
+    assert!(line.contains(" [bot{1}(a)] <"), "unexpected line: {line}");
+  }
+
+  #[test]
+  fn log_pattern_without_instance_label() {
+    let line = format_with_pattern(None);
+    let caps = log_regex().captures(line.trim_end()).unwrap();
+    assert!(caps.name("instance").is_none());
+    assert_eq!(&caps["level"], "INFO");
+  }
+
+  fn format_as_json(instance: Option<&str>, message: std::fmt::Arguments) -> String {
+    let mut buffer = Vec::new();
+    let record = Record::builder()
+      .args(message)
+      .level(log::Level::Warn)
+      .target("meshexec::executor")
+      .file(Some("src/executor.rs"))
+      .line(Some(42))
+      .build();
+    JsonLineEncoder::new(instance)
+      .encode(&mut SimpleWriter(&mut buffer), &record)
+      .unwrap();
+    String::from_utf8(buffer).unwrap()
+  }
+
+  #[test]
+  fn json_log_line_has_the_record_fields() {
+    let line = format_as_json(Some("garage-bot"), format_args!("Said \"hi\"\nthen left"));
+    assert!(line.ends_with('\n') && line.trim_end().lines().count() == 1);
+
+    let fields: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(fields["level"], "WARN");
+    assert_eq!(fields["target"], "meshexec::executor");
+    assert_eq!(fields["file"], "src/executor.rs");
+    assert_eq!(fields["line"], 42);
+    assert_eq!(fields["message"], "Said \"hi\"\nthen left");
+    assert_eq!(fields["instance"], "garage-bot");
+    let timestamp = fields["timestamp"].as_str().unwrap();
+    assert!(
+      timestamp.len() == 24 && timestamp.ends_with('Z'),
+      "{timestamp}"
+    );
+  }
+
+  #[test]
+  fn json_log_line_without_instance_omits_it() {
+    let fields: Value = serde_json::from_str(&format_as_json(None, format_args!("Hi"))).unwrap();
+    assert!(fields.get("instance").is_none());
+  }
+
+  #[test]
+  fn json_log_lines_are_pretty_printed() {
+    let line = format_as_json(Some("garage-bot"), format_args!("Disk low"));
+    let pretty = colorize_json_log_line(line.trim_end()).unwrap();
+    assert!(pretty.contains("garage-bot"));
+    assert!(pretty.contains("src/executor.rs"));
+    assert!(pretty.contains("Disk low"));
+
+    assert!(colorize_json_log_line("2025-01-15 12:00:00.000 <main> [INFO] a:1 - x").is_none());
+    assert!(colorize_json_log_line("{not json").is_none());
+  }
+
+  #[tokio::test]
+  async fn follow_lines_waits_for_complete_lines_and_restarts_after_truncation() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("meshexec.log");
+    fs::write(&path, "old line\n").unwrap();
+    let mut reader = BufReader::new(File::open(&path).unwrap());
+    reader.seek(SeekFrom::End(0)).unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let follower = tokio::spawn(follow_lines(
+      reader,
+      Duration::from_millis(5),
+      move |line: &str| tx.send(line.to_owned()).unwrap(),
+    ));
+    let mut next_line = async || {
+      tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap()
+    };
+
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    std::io::Write::write_all(&mut file, b"first\nsec").unwrap();
+    assert_eq!(next_line().await, "first");
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    std::io::Write::write_all(&mut file, b"ond\r\n").unwrap();
+    assert_eq!(next_line().await, "second");
+
+    fs::write(&path, "new\n").unwrap();
+    assert_eq!(next_line().await, "new");
+
+    follower.abort();
+  }
+
+  const SAMPLE_LOG: &str = "2024-01-01 00:00:01.000 <1> [INFO] meshexec:10 - Connected\n\
+    2024-01-01 00:00:02.000 <1> [INFO] meshexec:20 - Executing for Alice: uptime\n\
+    \n\
+    2024-01-01 00:00:03.000 <1> [WARN] meshexec:30 - Alias error: Unknown command: !nope\n";
+
+  #[test]
+  fn last_lines_returns_the_newest_lines_in_order() {
+    assert_eq!(
+      last_lines(SAMPLE_LOG, 2),
+      vec![
+        "2024-01-01 00:00:02.000 <1> [INFO] meshexec:20 - Executing for Alice: uptime",
+        "2024-01-01 00:00:03.000 <1> [WARN] meshexec:30 - Alias error: Unknown command: !nope",
+      ]
+    );
+    assert_eq!(last_lines(SAMPLE_LOG, 10).len(), 3);
+    assert!(last_lines(SAMPLE_LOG, 0).is_empty());
+    assert!(last_lines("", 5).is_empty());
+  }
+
+  #[test]
+  fn read_log_tail_drops_the_partial_first_line() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("meshexec.log");
+    fs::write(&path, "first line\nsecond line\nthird\n").unwrap();
+
+    assert_eq!(
+      read_log_tail(&path, 1024).unwrap(),
+      "first line\nsecond line\nthird\n"
+    );
+    assert_eq!(read_log_tail(&path, 15).unwrap(), "third\n");
+  }
+
+  #[test]
+  fn format_log_tail_replies() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("meshexec.log");
+    fs::write(&path, SAMPLE_LOG).unwrap();
+
+    assert_eq!(
+      format_log_tail(&path, "1"),
+      "2024-01-01 00:00:03.000 <1> [WARN] meshexec:30 - Alias error: Unknown command: !nope"
+    );
+    assert_eq!(format_log_tail(&path, "").lines().count(), 3);
+    assert_eq!(format_log_tail(&path, "lots"), "Usage: !log [count]");
+
+    fs::write(&path, "").unwrap();
+    assert_eq!(format_log_tail(&path, ""), "The log is empty");
+    assert!(format_log_tail(&dir.path().join("missing.log"), "").starts_with("Unable to read"));
+  }
+
   #[test]
   fn get_log_path_has_expected_suffix_and_is_absolute() {
     let path = get_log_path();
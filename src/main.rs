@@ -1,32 +1,52 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use crossterm::execute;
 use crossterm::terminal::{LeaveAlternateScreen, disable_raw_mode};
 use indoc::formatdoc;
 use log::{debug, error, info, warn};
+use meshexec::acks::acks;
+use meshexec::airtime::AirtimeBudget;
+use meshexec::builtins::{
+  Builtin, MaintenanceMode, NodeIdentity, format_config_summary, format_explanation,
+  reload_commands,
+};
 use meshexec::cli::{Args, Commands};
-use meshexec::command::{self, AliasResult};
-use meshexec::config::{Config, find_config_file, load_config};
-use meshexec::logging::{init_logging_config, tail_logs};
-use meshexec::transport::{send_split_text, wait_for_my_node_num};
+use meshexec::command::{self, AliasResult, ResolveOptions};
+use meshexec::compress::compress_reply;
+use meshexec::config::{Config, Connection, find_config_file, load_config};
+use meshexec::dedup::{ErrorReplyThrottle, PacketDeduplicator, ReplyDeduplicator};
+use meshexec::executor::{
+  CommandCompletion, CommandOutcome, CommandProgress, CommandRequest, CommandSlots, Continuations,
+  ExecutionError, RequestContext, RunningCommands, add_sender_env, build_command_env, cancellable,
+  check_command_programs, check_commands_available, execute_request, format_cancelled,
+  is_awaiting_input, retain_available_commands, run_on_connect_hook, with_progress,
+};
+use meshexec::limit_state::{LimitState, STATE_SAVE_INTERVAL};
+use meshexec::logging::{format_log_tail, get_log_path, init_logging_config, tail_logs};
+use meshexec::nodes::NodeNames;
+use meshexec::output::{collapse_blank_lines, command_replies};
+use meshexec::rate_limit::RateLimiter;
+use meshexec::recent_errors::{format_recent_errors, recent_errors};
+use meshexec::replay::replay_messages;
+use meshexec::shutdown::{cancel_on_shutdown, wait_for_shutdown_signal};
+use meshexec::template::format_duration_ms;
+use meshexec::transport::{
+  ReplyKind, ReplyTarget, UNAUTHORIZED_MESSAGE, WEAK_SIGNAL_MESSAGE, connect_to_node,
+  detect_serial_device, forward_packets, has_sufficient_signal, hops_away, is_addressed_to_node,
+  is_from_listened_peer, retry_with_backoff, send_reaction, send_split_text,
+};
 use meshtastic::packet::PacketRouter;
 use meshtastic::protobufs::{FromRadio, MeshPacket};
+use meshtastic::protobufs::{PortNum, from_radio, mesh_packet};
 use meshtastic::types::NodeId;
-use meshtastic::utils::generate_rand_id;
-use meshtastic::{
-  api::StreamApi,
-  protobufs::{PortNum, from_radio, mesh_packet},
-  utils::stream::build_serial_stream,
-};
-use std::collections::HashMap;
 use std::convert::Infallible;
 use std::panic::PanicHookInfo;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::{env, io, panic, process};
-use tokio::signal;
+use std::time::Duration;
+use std::{env, fs, io, mem, panic, process};
+use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
@@ -35,86 +55,173 @@ async fn main() -> Result<()> {
     panic_hook(info);
   }));
   let args = Args::parse();
-  log4rs::init_config(init_logging_config(args.global.log_level))?;
+  log4rs::init_config(init_logging_config(
+    args.global.log_level,
+    args.global.log_to_file,
+    args.global.instance_name.as_deref(),
+    args.global.log_format,
+  ))?;
 
   match args.command {
     Commands::ConfigPath => {
       let config_dir =
-        dirs_next::config_dir().expect("Could not determine config directory for this system");
+        dirs_next::config_dir().context("Could not determine config directory for this system")?;
       println!(
         "{}",
         config_dir.join("meshexec").join("config.yaml").display()
       );
       return Ok(());
     }
+    Commands::Completions { shell } => {
+      clap_complete::generate(shell, &mut Args::command(), "meshexec", &mut io::stdout());
+    }
     Commands::TailLogs { no_color } => tail_logs(no_color).await?,
+    Commands::Validate => match resolve_config_path(args.global.config_file)
+      .and_then(|config_path| load_server_config(&config_path))
+    {
+      Ok(config) => println!("config OK: {} commands", config.commands.len()),
+      Err(e) => {
+        eprintln!("config invalid: {e:#}");
+        process::exit(1);
+      }
+    },
+    Commands::Replay { file, export } => {
+      let config = load_server_config(&resolve_config_path(args.global.config_file)?)?;
+      let messages = fs::read_to_string(&file)
+        .with_context(|| format!("Failed to read messages from '{}'", file.display()))?;
+      print!("{}", replay_messages(&config, &messages, export));
+    }
     Commands::Serve => {
-      let config_path = match args.global.config_file {
-        Some(path) => path,
-        None => find_config_file()?,
-      };
-      let config = load_config(&config_path)?;
-      debug!("Loaded config: {config:?}");
-
-      let running = Arc::new(AtomicBool::new(true));
-      let r = running.clone();
+      let config_path = resolve_config_path(args.global.config_file)?;
+      let config = load_server_config(&config_path)?;
 
       let cancellation_token = CancellationToken::new();
-      let ctrlc_cancellation_token = cancellation_token.clone();
-      ctrlc::set_handler(move || {
-        ctrlc_cancellation_token.cancel();
-        r.store(false, Ordering::SeqCst);
-        process::exit(1);
-      })
-      .expect("Error setting Ctrl-C handler");
+      let shutdown_token = cancellation_token.clone();
+      tokio::spawn(async move {
+        if let Err(e) = cancel_on_shutdown(shutdown_token, wait_for_shutdown_signal()).await {
+          error!("Failed to install shutdown signal handlers: {e}");
+        }
+      });
 
-      start_runner_server(config).await?
+      start_runner_server(config, config_path, cancellation_token).await?
     }
   }
 
   Ok(())
 }
 
-async fn start_runner_server(server_config: Config) -> Result<()> {
-  let serial = build_serial_stream(server_config.device.clone(), server_config.baud, None, None)?;
+fn resolve_config_path(config_file: Option<PathBuf>) -> Result<PathBuf> {
+  match config_file {
+    Some(path) => Ok(path),
+    None => find_config_file(),
+  }
+}
 
-  let (mut rx, api) = StreamApi::new().connect(serial).await;
-  let config_id = generate_rand_id();
-  let mut api = api.configure(config_id).await?;
-  let node_id = wait_for_my_node_num(&mut rx).await?;
-  let mut router = NoopRouter::new(NodeId::new(node_id));
+fn load_server_config(config_path: &Path) -> Result<Config> {
+  let config = load_config(config_path)?;
+  debug!("Loaded config: {config:?}");
+
+  Ok(config)
+}
 
-  info!("Connected to {}", server_config.device);
+async fn start_runner_server(
+  mut server_config: Config,
+  config_path: PathBuf,
+  cancellation_token: CancellationToken,
+) -> Result<()> {
+  // Kept unfiltered so `!reload` can splice an imported file's commands back into place
+  let mut loaded_commands = mem::take(&mut server_config.commands);
+  server_config.commands = retain_available_commands(&server_config, loaded_commands.clone());
+  check_commands_available(&server_config)?;
+  check_command_programs(&server_config)?;
+  if server_config.connection == Connection::Serial && server_config.device.trim().is_empty() {
+    server_config.device = detect_serial_device()?;
+    info!("No device configured; using {}", server_config.device);
+  }
+  let mut server_config = Arc::new(server_config);
+  let config = &*server_config;
+  let (rx, mut api, node_id) = retry_with_backoff(&server_config.connect_retry, move || {
+    connect_to_node(config)
+  })
+  .await?;
+  run_on_connect_hook(&server_config).await;
+  let (packet_tx, mut packets) = mpsc::unbounded_channel();
+  tokio::spawn(forward_packets(rx, packet_tx));
+  let mut router = NoopRouter::new(NodeId::new(node_id));
+  let mut maintenance = MaintenanceMode::new(&server_config);
+  let mut identity = NodeIdentity::new(node_id);
+  let mut node_names = NodeNames::default();
+  let mut continuations =
+    Continuations::new(Duration::from_secs(server_config.pending_input_ttl_secs));
+  recent_errors().set_capacity(server_config.error_buffer_size);
+  let resolve_options = ResolveOptions::from(&*server_config);
+  let mut airtime = AirtimeBudget::new(server_config.airtime_budget_bytes);
+  let mut packet_dedup =
+    PacketDeduplicator::new(Duration::from_secs(server_config.packet_dedup_window_secs));
+  let mut reply_dedup =
+    ReplyDeduplicator::new(Duration::from_secs(server_config.dedup_window_secs));
+  let mut error_throttle =
+    ErrorReplyThrottle::new(Duration::from_secs(server_config.error_reply_window_secs));
+  let mut rate_limiter = RateLimiter::new(server_config.rate_limit);
+  if let Some(state_file) = server_config.state_file.as_deref() {
+    match LimitState::load(state_file) {
+      Ok(state) => state.restore(&mut airtime, &mut error_throttle, &mut rate_limiter),
+      Err(e) => warn!("Not restoring rate-limit state: {e:#}"),
+    }
+  }
+  let mut state_save = tokio::time::interval(STATE_SAVE_INTERVAL);
+  // Commands run on their own tasks so the loop keeps receiving packets while they do
+  let command_slots = CommandSlots::new(server_config.max_concurrent_commands);
+  let mut running_commands = RunningCommands::default();
+  let (done_tx, mut done_rx) = mpsc::channel(command_slots.limit().max(1));
+  let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+
+  info!("Connected to {}", server_config.node_address());
   warn!(
     "\n{}",
     formatdoc! {"
         *****************************************
-        CAUTION: Be sure channel {} is private!
+        CAUTION: Be sure channel(s) {} are private!
         *****************************************
         ",
-        server_config.channel
+        server_config.channel_list()
     }
   );
   info!(
-    "Listening for commands in channel {}... \n(Ctrl+C to stop)",
-    server_config.channel
+    "Listening for commands in channel(s) {}... \n(Ctrl+C to stop)",
+    server_config.channel_list()
   );
 
   loop {
     tokio::select! {
-        _ = signal::ctrl_c() => {
-            error!("Ctrl+C received, exiting.");
+        _ = cancellation_token.cancelled() => {
+            info!("Exiting.");
             break;
         }
 
-        maybe = rx.recv() => {
+        _ = state_save.tick(), if server_config.state_file.is_some() => {
+            save_limit_state(&server_config, &airtime, &error_throttle, &rate_limiter);
+        }
+
+        maybe = packets.recv() => {
             let Some(from_radio_msg) = maybe else { break; };
 
+            if let Some(from_radio::PayloadVariant::NodeInfo(node_info)) = &from_radio_msg.payload_variant {
+                node_names.record(node_info);
+                if node_info.num == node_id
+                    && let Some(user) = &node_info.user
+                {
+                    identity.set_names(&user.short_name, &user.long_name);
+                    debug!("This node is {}", identity.describe());
+                }
+                continue;
+            }
+
             let Some(from_radio::PayloadVariant::Packet(packet)) = from_radio_msg.payload_variant else {
                 continue;
             };
 
-            if packet.channel != server_config.channel {
+            if !is_from_listened_peer(packet.from, packet.channel, node_id, &server_config.channels) {
                 continue;
             }
 
@@ -122,11 +229,18 @@ async fn start_runner_server(server_config: Config) -> Result<()> {
                 continue;
             };
 
-            if packet.from == node_id {
+            if !is_addressed_to_node(packet.to, node_id, server_config.require_direct) {
+                debug!("Ignoring packet from {} not addressed to this node", packet.from);
+                continue;
+            }
+
+            if packet_dedup.is_duplicate(packet.from, packet.id) {
+                debug!("Ignoring duplicate packet {} from {}", packet.id, packet.from);
                 continue;
             }
 
             let portnum = PortNum::try_from(data.portnum).ok();
+            let target = ReplyTarget::new(server_config.reply_mode, packet.from, packet.channel);
 
             let message = match from_utf8(&data.payload) {
                 Ok(s) => s.trim_end(),
@@ -141,61 +255,274 @@ async fn start_runner_server(server_config: Config) -> Result<()> {
                 }
             };
 
-            if !message.starts_with('!') {
-                debug!("Ignoring non-alias message.");
+            let expired = continuations.expire();
+            if expired > 0 {
+                debug!("Expired {expired} pending input request(s) after {}s", server_config.pending_input_ttl_secs);
+            }
+
+            let is_command = message.starts_with(server_config.prefix.as_str()) || continuations.is_pending(packet.from);
+            if is_command && !server_config.is_allowed(packet.from) {
+                debug!("Ignoring command from {}, which isn't in allowed_nodes", packet.from);
+                if server_config.reply_unauthorized && error_throttle.throttle(packet.from).is_none() {
+                    send_split_text(&mut api, &mut router, target, UNAUTHORIZED_MESSAGE, &server_config, &mut airtime, ReplyKind::Output).await?;
+                }
                 continue;
             }
 
-            let (resolved, alias_env) = match command::resolve_alias(message, &server_config.commands) {
-                Ok(AliasResult::HelpText(text)) => {
-                    send_split_text(&mut api, &mut router, &text, &server_config).await?;
+            if is_command && !has_sufficient_signal(packet.rx_snr, packet.rx_rssi, server_config.min_snr, server_config.min_rssi) {
+                warn!("Ignoring command from {} with weak signal (SNR {}, RSSI {})", packet.from, packet.rx_snr, packet.rx_rssi);
+                if server_config.reply_weak_signal && error_throttle.throttle(packet.from).is_none() {
+                    send_split_text(&mut api, &mut router, target, WEAK_SIGNAL_MESSAGE, &server_config, &mut airtime, ReplyKind::Output).await?;
+                }
+                continue;
+            }
+
+            let message = if is_command {
+                match command::enforce_command_size(message, server_config.max_command_bytes, server_config.command_size_policy) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Rejecting {} byte message from {}: {e}", message.len(), packet.from);
+                        if let Some(suppressed) = error_throttle.throttle(packet.from) {
+                            info!("Suppressed error reply to {} ({suppressed} in the current window)", packet.from);
+                            continue;
+                        }
+                        send_split_text(&mut api, &mut router, target, &e.to_string(), &server_config, &mut airtime, ReplyKind::Output).await?;
+                        continue;
+                    }
+                }
+            } else {
+                message
+            };
+
+            let pending_input = if message.starts_with(server_config.prefix.as_str()) {
+                if continuations.take(packet.from).is_some() {
+                    debug!("Discarding pending input request for {}", packet.from);
+                }
+                None
+            } else {
+                match continuations.take(packet.from) {
+                    Some(pending_input) => Some(pending_input),
+                    None => {
+                        debug!("Ignoring non-alias message.");
+                        continue;
+                    }
+                }
+            };
+
+            let (resolved, alias_env, definition, input) = if let Some(pending_input) = pending_input {
+                info!("Received input from {} for pending command", packet.from);
+                (pending_input.command, pending_input.env, pending_input.definition, Some(message))
+            } else {
+                let is_admin = server_config.admin_nodes.contains(&packet.from);
+                if let Some(builtin) = Builtin::parse(&message[server_config.prefix.len()..])
+                    && builtin.is_available(&server_config, is_admin)
+                {
+                    let reply = match builtin {
+                        Builtin::Maintenance(arg) => maintenance.toggle(arg, &server_config.prefix),
+                        Builtin::Errors(arg) => format_recent_errors(&recent_errors(), arg),
+                        Builtin::Config => format_config_summary(&server_config),
+                        Builtin::Who => identity.describe(),
+                        Builtin::Explain(alias) => format_explanation(&server_config, alias),
+                        Builtin::Log(arg) => format_log_tail(&get_log_path(), arg),
+                        Builtin::Cancel => format_cancelled(&running_commands.cancel(packet.from)),
+                        Builtin::Reload("") => format!("Usage: {}reload <file>", server_config.prefix),
+                        Builtin::Reload(file) => match reload_commands(&server_config, &config_path, &mut loaded_commands, file).await {
+                            Ok((config, count)) => {
+                                server_config = Arc::new(config);
+                                format!("Reloaded {count} commands from {file}")
+                            }
+                            Err(e) => format!("Reload failed: {e:#}"),
+                        },
+                    };
+                    info!("{} ran built-in {message}: {reply}", packet.from);
+                    send_split_text(&mut api, &mut router, target, &reply, &server_config, &mut airtime, ReplyKind::Output).await?;
                     continue;
                 }
-                Ok(AliasResult::Command { command, env }) => (command, env),
-                Err(e) => {
-                    warn!("Alias error: {e}");
-                    send_split_text(&mut api, &mut router, &e.to_string(), &server_config).await?;
+
+                let resolution = command::resolve_alias_with(message, &server_config.commands, &resolve_options);
+                if let Some(notice) = maintenance.gate(&resolution) {
+                    info!("Maintenance mode active, not running: {message}");
+                    send_split_text(&mut api, &mut router, target, notice, &server_config, &mut airtime, ReplyKind::Output).await?;
                     continue;
                 }
+
+                match resolution {
+                    Ok(AliasResult::HelpText(text)) => {
+                        send_split_text(&mut api, &mut router, target, &text, &server_config, &mut airtime, ReplyKind::Help).await?;
+                        continue;
+                    }
+                    Ok(AliasResult::Reply(text)) => {
+                        send_split_text(&mut api, &mut router, target, &text, &server_config, &mut airtime, ReplyKind::Output).await?;
+                        continue;
+                    }
+                    Ok(AliasResult::Command { command, env, definition }) => (command, env, *definition, None),
+                    Err(e) => {
+                        warn!("Alias error: {e}");
+                        if let Some(suppressed) = error_throttle.throttle(packet.from) {
+                            info!("Suppressed error reply to {} ({suppressed} in the current window)", packet.from);
+                            continue;
+                        }
+                        send_split_text(&mut api, &mut router, target, &e.to_string(), &server_config, &mut airtime, ReplyKind::Output).await?;
+                        continue;
+                    }
+                }
             };
 
-            info!("Executing: {resolved}");
+            if input.is_none() && let Some(retry_after) = rate_limiter.check(packet.from) {
+                let limited = ExecutionError::RateLimited(retry_after);
+                warn!("Not running '{}' from {}: {limited}", definition.name, packet.from);
+                if let Some(suppressed) = error_throttle.throttle(packet.from) {
+                    info!("Suppressed error reply to {} ({suppressed} in the current window)", packet.from);
+                    continue;
+                }
+                send_split_text(&mut api, &mut router, target, &limited.to_string(), &server_config, &mut airtime, ReplyKind::Output).await?;
+                continue;
+            }
+
+            let Some(slot) = command_slots.try_claim() else {
+                let busy = ExecutionError::ServerBusy(command_slots.limit());
+                warn!("Not running '{}' from {}: {busy}", definition.name, packet.from);
+                if let Some(suppressed) = error_throttle.throttle(packet.from) {
+                    info!("Suppressed error reply to {} ({suppressed} in the current window)", packet.from);
+                    continue;
+                }
+                send_split_text(&mut api, &mut router, target, &busy.to_string(), &server_config, &mut airtime, ReplyKind::Output).await?;
+                continue;
+            };
+
+            info!("Executing for {}: {resolved}", node_names.display_name(packet.from));
             let path = env::var("PATH").context("No PATH environment variable")?;
-            let mut envs: HashMap<String, String> = HashMap::new();
-            envs.insert("PATH".into(), path);
-            envs.extend(alias_env);
-            let output = Command::new(&server_config.shell)
-                .args(&server_config.shell_args)
-                .arg(&resolved)
-                .envs(envs)
-                .output();
-            match output {
-                Ok(out) => {
+            let context = RequestContext {
+                channel: packet.channel,
+                node: node_id,
+                sender: packet.from,
+                packet_id: packet.id,
+                hops: hops_away(packet.hop_start, packet.hop_limit),
+            };
+            let mut envs = build_command_env(&server_config, path, &definition.env, &alias_env);
+            add_sender_env(&mut envs, &context, node_names.long_name(packet.from));
+            let request = CommandRequest {
+                sender: packet.from,
+                command: resolved,
+                env: alias_env,
+                envs,
+                definition,
+                context,
+                input: input.map(str::to_owned),
+            };
+            let config = Arc::clone(&server_config);
+            let done_tx = done_tx.clone();
+            let progress_tx = progress_tx.clone();
+            let (run_id, cancel_token) = running_commands.start(request.sender, &request.definition.name);
+            tokio::spawn(async move {
+                let _slot = slot;
+                let interval = request.definition.progress_interval_secs.map(Duration::from_secs);
+                let execution = cancellable(execute_request(&config, &request), cancel_token);
+                let outcome = with_progress(execution, interval, |elapsed| {
+                    let _ = progress_tx.send(CommandProgress { sender: request.sender, channel: request.context.channel, elapsed });
+                })
+                .await;
+                let _ = done_tx.send(CommandCompletion { run_id, request, outcome }).await;
+            });
+        }
+
+        Some(progress) = progress_rx.recv() => {
+            let target = ReplyTarget::new(server_config.reply_mode, progress.sender, progress.channel);
+            send_split_text(&mut api, &mut router, target, &progress.message(), &server_config, &mut airtime, ReplyKind::Output).await?;
+        }
+
+        Some(CommandCompletion { run_id, request, outcome }) = done_rx.recv() => {
+            running_commands.finish(run_id);
+            let CommandRequest { sender, command: resolved, env: alias_env, definition, context, .. } = request;
+            let target = ReplyTarget::new(server_config.reply_mode, sender, context.channel);
+            if let Some(reaction) = outcome.succeeded().and_then(|succeeded| definition.reaction(succeeded)) {
+                send_reaction(&mut api, &mut router, target, context.packet_id, reaction, &mut airtime).await?;
+            }
+            match outcome {
+                CommandOutcome::Blocked(_) if !definition.replies(true) => {
+                    info!("Precondition for '{}' failed, not running it (no reply sent)", definition.name);
+                }
+                CommandOutcome::Blocked(reply) => {
+                    info!("Precondition for '{}' failed, not running it", definition.name);
+                    send_split_text(&mut api, &mut router, target, &reply, &server_config, &mut airtime, ReplyKind::Output).await?;
+                }
+                CommandOutcome::Finished { output: out, .. } if is_awaiting_input(&out.status) => {
+                    let prompt = String::from_utf8_lossy(&out.stdout);
+                    info!("Command is awaiting input from {sender}");
+                    continuations.await_input(sender, resolved, alias_env, definition);
+                    send_split_text(&mut api, &mut router, target, &prompt, &server_config, &mut airtime, ReplyKind::Output).await?;
+                }
+                CommandOutcome::Finished { output: out, elapsed } => {
                     let status = out.status;
-                    let stdout = from_utf8(&out.stdout).context("Invalid UTF-8 in stdout")?;
-                    let stderr = from_utf8(&out.stderr).context("Invalid UTF-8 in stderr")?;
-
-                    if !status.success() {
-                        let err = if !stderr.is_empty() {
-                            stderr.to_owned()
-                        } else {
-                            "Command exited with non-zero status.".into()
-                        };
-                        send_split_text(&mut api, &mut router, &err, &server_config).await?;
+                    let duration_ms = format_duration_ms(elapsed);
+                    let sender_name = node_names.display_name(sender);
+                    info!("Command '{}' from {sender_name} finished ({status}) in {duration_ms}ms", definition.name);
+                    let replies = command_replies(&server_config, &definition, &out, &duration_ms, &sender_name, &context.hops_display());
+                    if replies.is_empty() {
+                        debug!("Not replying to {sender_name}: '{}' has no_reply set", definition.name);
+                        continue;
+                    }
+
+                    let replies: Vec<String> = if server_config.tag_replies {
+                        replies.iter().map(|reply| format!("[{}] {reply}", identity.label())).collect()
+                    } else {
+                        replies
+                    };
+                    let replies: Vec<String> = if server_config.collapse_blank_lines {
+                        replies.iter().map(|reply| collapse_blank_lines(reply).into_owned()).collect()
+                    } else {
+                        replies
+                    };
+
+                    if reply_dedup.is_duplicate(sender, &replies.concat()) {
+                        info!("Suppressing duplicate reply to {sender}");
+                        continue;
                     }
-                    send_split_text(&mut api, &mut router, stdout, &server_config).await?;
+
+                    for reply in &replies {
+                        let reply = compress_reply(reply, &server_config);
+                        send_split_text(&mut api, &mut router, target, &reply, &server_config, &mut airtime, ReplyKind::Output).await?;
+                    }
+                }
+                CommandOutcome::Failed(e) if matches!(e.downcast_ref::<ExecutionError>(), Some(ExecutionError::Cancelled)) => {
+                    info!("Command '{}' from {sender} was cancelled", definition.name);
+                }
+                CommandOutcome::Failed(e) if !definition.replies(true) => {
+                    error!("Failed to run {resolved} (no reply sent): {e}");
+                }
+                CommandOutcome::Failed(e) if matches!(e.downcast_ref::<ExecutionError>(), Some(ExecutionError::TimedOut(_))) => {
+                    warn!("Command '{}' from {sender} was killed: {e}", definition.name);
+                    send_split_text(&mut api, &mut router, target, &e.to_string(), &server_config, &mut airtime, ReplyKind::Output).await?;
                 }
-                Err(e) => {
-                    send_split_text(&mut api, &mut router, &format!("Error: {e:?}"), &server_config).await?;
+                CommandOutcome::Failed(e) => {
+                    error!("Failed to run {resolved}: {e}");
+                    send_split_text(&mut api, &mut router, target, &format!("Error: {e}"), &server_config, &mut airtime, ReplyKind::Output).await?;
                 }
             }
         }
     }
   }
 
+  save_limit_state(&server_config, &airtime, &error_throttle, &rate_limiter);
+
   Ok(())
 }
 
+fn save_limit_state(
+  config: &Config,
+  airtime: &AirtimeBudget,
+  error_throttle: &ErrorReplyThrottle,
+  rate_limiter: &RateLimiter,
+) {
+  if let Some(state_file) = config.state_file.as_deref()
+    && let Err(e) = LimitState::capture(airtime, error_throttle, rate_limiter).save(state_file)
+  {
+    warn!("Failed to save rate-limit state: {e:#}");
+  }
+}
+
+/// Handles nothing from the radio, but notes the packets sent with `want_ack` so replies can wait
+/// for their acknowledgement
 pub struct NoopRouter {
   source: NodeId,
 }
@@ -211,7 +538,10 @@ impl PacketRouter<(), Infallible> for NoopRouter {
     Ok(())
   }
 
-  fn handle_mesh_packet(&mut self, _packet: MeshPacket) -> Result<(), Infallible> {
+  fn handle_mesh_packet(&mut self, packet: MeshPacket) -> Result<(), Infallible> {
+    if packet.want_ack {
+      acks().sent(packet.id);
+    }
     Ok(())
   }
 
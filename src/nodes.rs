@@ -0,0 +1,89 @@
+use meshtastic::protobufs::NodeInfo;
+use std::collections::HashMap;
+
+/// Long names of the nodes on the mesh, learned from the `NodeInfo` packets the device streams
+/// (its node database on connect, then updates as nodes announce themselves)
+#[derive(Debug, Default)]
+pub struct NodeNames {
+  long_names: HashMap<u32, String>,
+}
+
+impl NodeNames {
+  /// Remembers the node's long name, replacing any previously seen one. Nodes without a name are
+  /// ignored, so a partial update doesn't erase a known name.
+  pub fn record(&mut self, node_info: &NodeInfo) {
+    let Some(user) = &node_info.user else {
+      return;
+    };
+
+    let long_name = user.long_name.trim();
+    if !long_name.is_empty() {
+      self.long_names.insert(node_info.num, long_name.to_owned());
+    }
+  }
+
+  pub fn long_name(&self, num: u32) -> Option<&str> {
+    self.long_names.get(&num).map(String::as_str)
+  }
+
+  /// Long name if known, else the node ID in Meshtastic's `!xxxxxxxx` notation
+  pub fn display_name(&self, num: u32) -> String {
+    self
+      .long_name(num)
+      .map_or_else(|| format!("!{num:08x}"), str::to_owned)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use meshtastic::protobufs::User;
+
+  fn node_info(num: u32, long_name: &str) -> NodeInfo {
+    NodeInfo {
+      num,
+      user: Some(User {
+        long_name: long_name.to_owned(),
+        ..Default::default()
+      }),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn resolves_names_from_captured_node_info() {
+    let mut names = NodeNames::default();
+    names.record(&node_info(0x2a, "Garage Pi"));
+    names.record(&node_info(0x2b, " Alice's T-Echo "));
+
+    assert_eq!(names.long_name(0x2a), Some("Garage Pi"));
+    assert_eq!(names.display_name(0x2b), "Alice's T-Echo");
+  }
+
+  #[test]
+  fn unknown_node_is_shown_by_id() {
+    let names = NodeNames::default();
+    assert_eq!(names.long_name(0xa1b2c3d4), None);
+    assert_eq!(names.display_name(0xa1b2c3d4), "!a1b2c3d4");
+  }
+
+  #[test]
+  fn latest_name_wins() {
+    let mut names = NodeNames::default();
+    names.record(&node_info(0x2a, "Garage Pi"));
+    names.record(&node_info(0x2a, "Shed Pi"));
+    assert_eq!(names.long_name(0x2a), Some("Shed Pi"));
+  }
+
+  #[test]
+  fn node_info_without_name_keeps_known_name() {
+    let mut names = NodeNames::default();
+    names.record(&node_info(0x2a, "Garage Pi"));
+    names.record(&node_info(0x2a, ""));
+    names.record(&NodeInfo {
+      num: 0x2a,
+      ..Default::default()
+    });
+    assert_eq!(names.long_name(0x2a), Some("Garage Pi"));
+  }
+}
@@ -0,0 +1,640 @@
+use crate::config::{Command, Config, LinePrefix, OutputStream};
+use crate::template::render_template;
+use regex::Regex;
+use std::borrow::Cow;
+use std::process::Output;
+
+/// Picks the output forwarded to the mesh for a command that exited successfully
+pub fn select_output(stream: OutputStream, stdout: &str, stderr: &str) -> String {
+  match stream {
+    OutputStream::Stdout => stdout.to_owned(),
+    OutputStream::Stderr => stderr.to_owned(),
+    OutputStream::Both => {
+      if stdout.is_empty() || stderr.is_empty() {
+        return format!("{stdout}{stderr}");
+      }
+
+      let separator = if stdout.ends_with('\n') { "" } else { "\n" };
+      format!("{stdout}{separator}{stderr}")
+    }
+  }
+}
+
+/// Marks where stderr starts in `both` output for commands with `label_streams`
+pub const STDERR_LABEL: &str = "[stderr]";
+
+/// Prefixes each line of stderr with [`STDERR_LABEL`], so it can be told apart from stdout once
+/// the two are combined
+pub fn label_stderr(stderr: &str) -> String {
+  stderr
+    .split_inclusive('\n')
+    .map(|line| format!("{STDERR_LABEL} {line}"))
+    .collect()
+}
+
+pub const DEFAULT_FAILURE_MESSAGE: &str = "Command exited with non-zero status.";
+
+/// Builds the single reply for a command that exited unsuccessfully: its stderr, or the failure
+/// notice when it wrote nothing to stderr, followed by any stdout
+pub fn failure_reply(stdout: &str, stderr: &str, failure_message: &str) -> String {
+  let error = if stderr.trim().is_empty() {
+    failure_message
+  } else {
+    stderr
+  };
+
+  if stdout.trim().is_empty() {
+    return error.to_owned();
+  }
+
+  let separator = if error.ends_with('\n') { "" } else { "\n" };
+  format!("{error}{separator}{stdout}")
+}
+
+/// Builds the replies for a finished command: its selected output (through `on_success`, which may
+/// use `{output}`, `{duration_ms}`, `{sender_name}` and `{hops}`) if it succeeded, else the failure
+/// reply. There are no replies if the command has `no_reply` set (unless it failed and
+/// `reply_on_error` is set). The command's `strip_line_prefix` is removed from both streams, and
+/// the reply is truncated to the output limits. Output that isn't valid UTF-8 is decoded lossily,
+/// so a command printing binary data still gets a reply rather than an error.
+pub fn command_replies(
+  server_config: &Config,
+  definition: &Command,
+  output: &Output,
+  duration_ms: &str,
+  sender_name: &str,
+  hops: &str,
+) -> Vec<String> {
+  if !definition.replies(!output.status.success()) {
+    return Vec::new();
+  }
+
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  let (stdout, stderr) = match &definition.strip_line_prefix {
+    Some(prefix) => (
+      Cow::Owned(strip_line_prefix(&stdout, prefix).into_owned()),
+      Cow::Owned(strip_line_prefix(&stderr, prefix).into_owned()),
+    ),
+    None => (stdout, stderr),
+  };
+
+  let reply = if output.status.success() {
+    let output = if definition.label_streams && definition.output_stream == OutputStream::Both {
+      select_output(definition.output_stream, &stdout, &label_stderr(&stderr))
+    } else {
+      select_output(definition.output_stream, &stdout, &stderr)
+    };
+    match definition.on_success.as_deref() {
+      Some(template) => render_template(
+        template,
+        &[
+          ("output", &output),
+          ("duration_ms", duration_ms),
+          ("sender_name", sender_name),
+          ("hops", hops),
+        ],
+      ),
+      None => output,
+    }
+  } else {
+    let failure_message = server_config
+      .silent_failure_message
+      .as_deref()
+      .unwrap_or(DEFAULT_FAILURE_MESSAGE);
+    failure_reply(&stdout, &stderr, failure_message)
+  };
+
+  let reply = truncate_output(
+    &reply,
+    definition
+      .max_output_lines
+      .or(server_config.max_output_lines),
+    definition
+      .max_output_bytes
+      .or(server_config.max_output_bytes),
+    server_config.max_content_bytes,
+  )
+  .into_owned();
+  vec![reply]
+}
+
+/// Keeps the start of the output within `max_lines` lines and `max_bytes` bytes, replacing the
+/// rest with a `...(truncated, N more lines)` marker. The marker is shortened if it wouldn't fit in
+/// a single chunk of `max_content_bytes`.
+pub fn truncate_output(
+  output: &str,
+  max_lines: Option<usize>,
+  max_bytes: Option<usize>,
+  max_content_bytes: usize,
+) -> Cow<'_, str> {
+  let max_lines = max_lines.unwrap_or(usize::MAX);
+  let max_bytes = max_bytes.unwrap_or(usize::MAX);
+  let total = output.lines().count();
+  if total <= max_lines && output.len() <= max_bytes {
+    return Cow::Borrowed(output);
+  }
+
+  let mut truncated = String::new();
+  let mut shown = 0;
+  for line in output.split_inclusive('\n').take(max_lines) {
+    if truncated.len() + line.len() > max_bytes {
+      if truncated.is_empty() {
+        let mut end = max_bytes;
+        while !line.is_char_boundary(end) {
+          end -= 1;
+        }
+        if end > 0 {
+          truncated.push_str(&line[..end]);
+          shown += 1;
+        }
+      }
+      break;
+    }
+    truncated.push_str(line);
+    shown += 1;
+  }
+
+  if !truncated.is_empty() && !truncated.ends_with('\n') {
+    truncated.push('\n');
+  }
+  truncated.push_str(&truncation_marker(total - shown, max_content_bytes));
+
+  Cow::Owned(truncated)
+}
+
+fn truncation_marker(remaining_lines: usize, max_content_bytes: usize) -> String {
+  let marker = if remaining_lines == 0 {
+    "...(truncated)".to_owned()
+  } else {
+    format!("...(truncated, {remaining_lines} more lines)")
+  };
+
+  if max_content_bytes == 0 || marker.len() <= max_content_bytes {
+    marker
+  } else {
+    "...".chars().take(max_content_bytes).collect()
+  }
+}
+
+/// Removes the prefix from the start of every line that begins with it. A regex prefix only
+/// matches at the start of a line, and one that doesn't compile (config validation rejects those)
+/// leaves the output unchanged.
+pub fn strip_line_prefix<'a>(output: &'a str, prefix: &LinePrefix) -> Cow<'a, str> {
+  let pattern = match prefix {
+    LinePrefix::Literal(literal) => regex::escape(literal),
+    LinePrefix::Regex { regex } => format!("(?:{regex})"),
+  };
+  let Ok(re) = Regex::new(&format!("^{pattern}")) else {
+    return Cow::Borrowed(output);
+  };
+
+  let mut stripped = String::with_capacity(output.len());
+  let mut changed = false;
+  for line in output.split_inclusive('\n') {
+    let content = line.trim_end_matches(['\r', '\n']);
+    let prefix_len = re.find(content).map_or(0, |m| m.end());
+    changed |= prefix_len > 0;
+    stripped.push_str(&line[prefix_len..]);
+  }
+
+  if changed {
+    Cow::Owned(stripped)
+  } else {
+    Cow::Borrowed(output)
+  }
+}
+
+/// Reduces every run of consecutive blank (or whitespace-only) lines to a single blank line
+pub fn collapse_blank_lines(output: &str) -> Cow<'_, str> {
+  let mut collapsed = String::with_capacity(output.len());
+  let mut previous_blank = false;
+  let mut changed = false;
+
+  for line in output.split_inclusive('\n') {
+    let blank = line.trim().is_empty();
+    if blank && previous_blank {
+      changed = true;
+      continue;
+    }
+    previous_blank = blank;
+    collapsed.push_str(line);
+  }
+
+  if changed {
+    Cow::Owned(collapsed)
+  } else {
+    Cow::Borrowed(output)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn truncate_output_under_limit_is_unchanged() {
+    assert_eq!(truncate_output("a\nb\n", Some(2), None, 200), "a\nb\n");
+    assert_eq!(truncate_output("a\nb", Some(5), Some(3), 200), "a\nb");
+    assert!(matches!(
+      truncate_output("a\n", Some(1), None, 200),
+      Cow::Borrowed(_)
+    ));
+    assert!(matches!(
+      truncate_output("a\n", None, None, 200),
+      Cow::Borrowed(_)
+    ));
+  }
+
+  #[test]
+  fn truncate_output_over_line_limit_appends_marker() {
+    assert_eq!(
+      truncate_output("a\nb\nc\nd\n", Some(2), None, 200),
+      "a\nb\n...(truncated, 2 more lines)"
+    );
+    assert_eq!(
+      truncate_output("a\nb\nc", Some(1), None, 200),
+      "a\n...(truncated, 2 more lines)"
+    );
+  }
+
+  #[test]
+  fn truncate_output_to_zero_lines_keeps_only_marker() {
+    assert_eq!(
+      truncate_output("a\nb\n", Some(0), None, 200),
+      "...(truncated, 2 more lines)"
+    );
+  }
+
+  #[test]
+  fn truncate_output_over_byte_limit_keeps_whole_lines() {
+    assert_eq!(
+      truncate_output("one\ntwo\nthree\n", None, Some(9), 200),
+      "one\ntwo\n...(truncated, 1 more lines)"
+    );
+    assert_eq!(
+      truncate_output("one\ntwo\nthree\n", Some(1), Some(100), 200),
+      "one\n...(truncated, 2 more lines)"
+    );
+  }
+
+  #[test]
+  fn truncate_output_cuts_an_oversized_first_line() {
+    assert_eq!(
+      truncate_output("h\u{e9}llo world\nbye\n", None, Some(2), 200),
+      "h\n...(truncated, 1 more lines)"
+    );
+    assert_eq!(
+      truncate_output("abcdef", None, Some(3), 200),
+      "abc\n...(truncated)"
+    );
+  }
+
+  #[test]
+  fn truncation_marker_fits_in_a_chunk() {
+    let truncated = truncate_output("a\nb\nc\n", Some(1), None, 10);
+    assert_eq!(truncated, "a\n...");
+    assert!(truncated.lines().all(|line| line.len() <= 10));
+  }
+
+  #[test]
+  fn failure_without_any_output_is_one_reply_with_the_notice() {
+    assert_eq!(
+      failure_reply("", "", DEFAULT_FAILURE_MESSAGE),
+      DEFAULT_FAILURE_MESSAGE
+    );
+    assert_eq!(
+      failure_reply("\n", " ", "Failed silently"),
+      "Failed silently"
+    );
+  }
+
+  #[test]
+  fn failure_with_stderr_only_replies_with_stderr() {
+    assert_eq!(
+      failure_reply("", "disk full\n", DEFAULT_FAILURE_MESSAGE),
+      "disk full\n"
+    );
+  }
+
+  #[test]
+  fn failure_with_stdout_only_prefixes_the_notice() {
+    assert_eq!(
+      failure_reply("partial\n", "", "Failed"),
+      "Failed\npartial\n"
+    );
+  }
+
+  #[test]
+  fn failure_with_both_streams_puts_stderr_first() {
+    assert_eq!(
+      failure_reply("partial\n", "disk full\n", DEFAULT_FAILURE_MESSAGE),
+      "disk full\npartial\n"
+    );
+  }
+
+  #[test]
+  fn strip_literal_line_prefix() {
+    let prefix = LinePrefix::Literal("[app] ".to_owned());
+    assert_eq!(
+      strip_line_prefix("[app] starting\n[app] ready\nno prefix [app] \n", &prefix),
+      "starting\nready\nno prefix [app] \n"
+    );
+  }
+
+  #[test]
+  fn strip_regex_line_prefix() {
+    let prefix = LinePrefix::Regex {
+      regex: r"\d{2}:\d{2}:\d{2} |WARN: ".to_owned(),
+    };
+    assert_eq!(
+      strip_line_prefix("12:00:01 up\nWARN: disk 91%\nat 12:00:02 done", &prefix),
+      "up\ndisk 91%\nat 12:00:02 done"
+    );
+  }
+
+  #[test]
+  fn strip_line_prefix_without_matches_is_unchanged() {
+    let prefix = LinePrefix::Literal("[app] ".to_owned());
+    assert!(matches!(
+      strip_line_prefix("plain\noutput\n", &prefix),
+      Cow::Borrowed(_)
+    ));
+  }
+
+  #[test]
+  fn collapse_blank_lines_reduces_each_run() {
+    assert_eq!(
+      collapse_blank_lines("a\n\n\n\nb\n \n\t\nc\n\n\n"),
+      "a\n\nb\n \nc\n\n"
+    );
+  }
+
+  #[test]
+  fn collapse_blank_lines_keeps_single_blank_line() {
+    let output = "a\n\nb\n";
+    assert!(matches!(
+      collapse_blank_lines(output),
+      Cow::Borrowed("a\n\nb\n")
+    ));
+  }
+
+  #[test]
+  fn collapse_blank_lines_without_blank_lines_is_unchanged() {
+    assert!(matches!(
+      collapse_blank_lines("a\nb\nc"),
+      Cow::Borrowed("a\nb\nc")
+    ));
+    assert_eq!(collapse_blank_lines(""), "");
+  }
+
+  #[test]
+  fn select_stdout_ignores_stderr() {
+    assert_eq!(
+      select_output(OutputStream::Stdout, "out\n", "err\n"),
+      "out\n"
+    );
+  }
+
+  #[test]
+  fn select_stderr_ignores_stdout() {
+    assert_eq!(
+      select_output(OutputStream::Stderr, "out\n", "err\n"),
+      "err\n"
+    );
+  }
+
+  #[test]
+  fn select_both_appends_stderr_after_stdout() {
+    assert_eq!(
+      select_output(OutputStream::Both, "out\n", "err\n"),
+      "out\nerr\n"
+    );
+  }
+
+  #[test]
+  fn select_both_separates_streams_without_trailing_newline() {
+    assert_eq!(select_output(OutputStream::Both, "out", "err"), "out\nerr");
+  }
+
+  #[test]
+  fn select_both_with_one_empty_stream() {
+    assert_eq!(select_output(OutputStream::Both, "", "err\n"), "err\n");
+    assert_eq!(select_output(OutputStream::Both, "out\n", ""), "out\n");
+  }
+
+  #[cfg(unix)]
+  async fn run(command: &str) -> Output {
+    let config = Config {
+      shell: "sh".to_owned(),
+      shell_args: vec!["-c".to_owned()],
+      ..Config::default()
+    };
+    crate::executor::run_shell_command(&config, command, &Default::default(), None, None)
+      .await
+      .unwrap()
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn non_utf8_output_is_replied_lossily() {
+    let output = run("printf 'ok \\377\\376\\n'").await;
+    let replies = command_replies(
+      &Config::default(),
+      &Command::default(),
+      &output,
+      "5",
+      "Alice",
+      "?",
+    );
+    assert_eq!(replies, vec!["ok \u{FFFD}\u{FFFD}\n"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn non_utf8_stderr_of_failed_command_is_replied_lossily() {
+    let output = run("printf '\\377\\376' >&2; exit 1").await;
+    let replies = command_replies(
+      &Config::default(),
+      &Command::default(),
+      &output,
+      "5",
+      "Alice",
+      "?",
+    );
+    assert_eq!(replies, vec!["\u{FFFD}\u{FFFD}"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn replies_render_on_success_template() {
+    let output = run("echo up").await;
+    let definition = Command {
+      on_success: Some("{sender_name} ({hops} hops): {output} in {duration_ms}ms".to_owned()),
+      max_output_lines: Some(2),
+      ..Command::default()
+    };
+    let replies = command_replies(&Config::default(), &definition, &output, "5", "Alice", "2");
+    assert_eq!(replies, vec!["Alice (2 hops): up\n in 5ms"]);
+  }
+
+  #[test]
+  fn label_stderr_marks_each_line() {
+    assert_eq!(label_stderr("a\nb\n"), "[stderr] a\n[stderr] b\n");
+    assert_eq!(label_stderr("a"), "[stderr] a");
+    assert_eq!(label_stderr(""), "");
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn combined_output_is_labeled_only_with_label_streams() {
+    let output = run("echo out; echo err >&2").await;
+    let definition = Command {
+      output_stream: OutputStream::Both,
+      ..Command::default()
+    };
+    let unlabeled = command_replies(&Config::default(), &definition, &output, "5", "Alice", "?");
+    assert_eq!(unlabeled, vec!["out\nerr\n"]);
+
+    let definition = Command {
+      label_streams: true,
+      ..definition
+    };
+    let labeled = command_replies(&Config::default(), &definition, &output, "5", "Alice", "?");
+    assert_eq!(labeled, vec!["out\n[stderr] err\n"]);
+
+    let stdout_only = Command {
+      output_stream: OutputStream::Stdout,
+      ..definition
+    };
+    let replies = command_replies(&Config::default(), &stdout_only, &output, "5", "Alice", "?");
+    assert_eq!(replies, vec!["out\n"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn no_reply_command_sends_nothing_on_success() {
+    let definition = Command {
+      no_reply: true,
+      ..Command::default()
+    };
+    let output = run("echo started").await;
+    assert!(
+      command_replies(&Config::default(), &definition, &output, "5", "Alice", "?").is_empty()
+    );
+
+    let output = run("echo broken >&2; exit 1").await;
+    assert!(
+      command_replies(&Config::default(), &definition, &output, "5", "Alice", "?").is_empty()
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn no_reply_command_can_still_report_failures() {
+    let definition = Command {
+      no_reply: true,
+      reply_on_error: true,
+      ..Command::default()
+    };
+    let output = run("echo started").await;
+    assert!(
+      command_replies(&Config::default(), &definition, &output, "5", "Alice", "?").is_empty()
+    );
+
+    let output = run("echo broken >&2; exit 1").await;
+    let replies = command_replies(&Config::default(), &definition, &output, "5", "Alice", "?");
+    assert_eq!(replies, vec!["broken\n"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn command_limits_override_global_output_limits() {
+    let output = run("printf 'a\\nb\\nc\\n'").await;
+    let config = Config {
+      max_output_lines: Some(1),
+      max_content_bytes: 200,
+      ..Config::default()
+    };
+
+    let replies = command_replies(&config, &Command::default(), &output, "5", "Alice", "?");
+    assert_eq!(replies, vec!["a\n...(truncated, 2 more lines)"]);
+
+    let definition = Command {
+      max_output_lines: Some(2),
+      ..Command::default()
+    };
+    let replies = command_replies(&config, &definition, &output, "5", "Alice", "?");
+    assert_eq!(replies, vec!["a\nb\n...(truncated, 1 more lines)"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn output_limits_apply_to_stderr_output() {
+    let output = run("printf 'a\\nb\\nc\\n' >&2").await;
+    let config = Config {
+      max_output_lines: Some(1),
+      max_content_bytes: 200,
+      ..Config::default()
+    };
+    let definition = Command {
+      output_stream: OutputStream::Stderr,
+      ..Command::default()
+    };
+
+    let replies = command_replies(&config, &definition, &output, "5", "Alice", "?");
+    assert_eq!(replies, vec!["a\n...(truncated, 2 more lines)"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn output_limits_apply_to_the_failure_reply() {
+    let output = run("printf 'a\\nb\\nc\\n' >&2; exit 1").await;
+    let config = Config {
+      max_output_bytes: Some(4),
+      max_content_bytes: 200,
+      ..Config::default()
+    };
+
+    let replies = command_replies(&config, &Command::default(), &output, "5", "Alice", "?");
+    assert_eq!(replies, vec!["a\nb\n...(truncated, 1 more lines)"]);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn select_output_for_command_writing_to_both_streams() {
+    use crate::config::Config;
+    use crate::executor::run_shell_command;
+    use std::collections::HashMap;
+
+    let config = Config {
+      shell: "sh".to_owned(),
+      shell_args: vec!["-c".to_owned()],
+      ..Config::default()
+    };
+    let output = run_shell_command(
+      &config,
+      "echo out; echo err >&2",
+      &HashMap::new(),
+      None,
+      None,
+    )
+    .await
+    .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(
+      select_output(OutputStream::Stdout, &stdout, &stderr),
+      "out\n"
+    );
+    assert_eq!(
+      select_output(OutputStream::Stderr, &stdout, &stderr),
+      "err\n"
+    );
+    assert_eq!(
+      select_output(OutputStream::Both, &stdout, &stderr),
+      "out\nerr\n"
+    );
+  }
+}
@@ -0,0 +1,143 @@
+use crate::config::RateLimit;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Per-node token bucket limiting how many commands each node can run. Every node starts with
+/// `max_commands` tokens, one is spent per command, and they refill evenly over `per_secs`, so a
+/// node can burst up to the limit but not sustain more than it.
+#[derive(Debug)]
+pub struct RateLimiter {
+  limit: Option<RateLimit>,
+  buckets: HashMap<u32, Bucket>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+impl RateLimiter {
+  /// Without a limit every command is allowed
+  pub fn new(limit: Option<RateLimit>) -> Self {
+    Self {
+      limit,
+      buckets: HashMap::new(),
+    }
+  }
+
+  /// Each node's tokens and when they were last counted
+  pub fn buckets(&self) -> impl Iterator<Item = (u32, f64, Instant)> + '_ {
+    self
+      .buckets
+      .iter()
+      .map(|(sender, bucket)| (*sender, bucket.tokens, bucket.updated_at))
+  }
+
+  /// Restores previously recorded buckets (e.g. from before a restart)
+  pub fn restore(&mut self, buckets: impl IntoIterator<Item = (u32, f64, Instant)>) {
+    self.buckets.extend(
+      buckets
+        .into_iter()
+        .map(|(sender, tokens, updated_at)| (sender, Bucket { tokens, updated_at })),
+    );
+  }
+
+  /// Spends one of the sender's tokens. Returns `None` if the command may run, or how long until
+  /// the sender has a token again if it may not.
+  pub fn check(&mut self, sender: u32) -> Option<Duration> {
+    self.check_at(sender, Instant::now())
+  }
+
+  fn check_at(&mut self, sender: u32, now: Instant) -> Option<Duration> {
+    let limit = self.limit?;
+    let capacity = f64::from(limit.max_commands);
+    let refill_per_sec = capacity / limit.per_secs as f64;
+
+    let bucket = self.buckets.entry(sender).or_insert(Bucket {
+      tokens: capacity,
+      updated_at: now,
+    });
+    let elapsed = now
+      .saturating_duration_since(bucket.updated_at)
+      .as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+    bucket.updated_at = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      None
+    } else {
+      Some(Duration::from_secs_f64(
+        (1.0 - bucket.tokens) / refill_per_sec,
+      ))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limiter(max_commands: u32, per_secs: u64) -> RateLimiter {
+    RateLimiter::new(Some(RateLimit {
+      max_commands,
+      per_secs,
+    }))
+  }
+
+  #[test]
+  fn no_limit_allows_everything() {
+    let mut limiter = RateLimiter::new(None);
+    let now = Instant::now();
+    assert!((0..100).all(|_| limiter.check_at(1, now).is_none()));
+  }
+
+  #[test]
+  fn burst_up_to_the_limit_is_allowed() {
+    let mut limiter = limiter(3, 60);
+    let now = Instant::now();
+    assert_eq!(limiter.check_at(1, now), None);
+    assert_eq!(limiter.check_at(1, now), None);
+    assert_eq!(limiter.check_at(1, now), None);
+    assert_eq!(limiter.check_at(1, now), Some(Duration::from_secs(20)));
+  }
+
+  #[test]
+  fn tokens_refill_over_the_period() {
+    let mut limiter = limiter(2, 60);
+    let now = Instant::now();
+    assert_eq!(limiter.check_at(1, now), None);
+    assert_eq!(limiter.check_at(1, now), None);
+
+    let later = now + Duration::from_secs(20);
+    assert_eq!(limiter.check_at(1, later), Some(Duration::from_secs(10)));
+    assert_eq!(limiter.check_at(1, later + Duration::from_secs(10)), None);
+    assert!(
+      limiter
+        .check_at(1, later + Duration::from_secs(10))
+        .is_some()
+    );
+  }
+
+  #[test]
+  fn refill_is_capped_at_the_limit() {
+    let mut limiter = limiter(2, 60);
+    let now = Instant::now();
+    assert_eq!(limiter.check_at(1, now), None);
+
+    let later = now + Duration::from_secs(3600);
+    assert_eq!(limiter.check_at(1, later), None);
+    assert_eq!(limiter.check_at(1, later), None);
+    assert!(limiter.check_at(1, later).is_some());
+  }
+
+  #[test]
+  fn nodes_are_limited_independently() {
+    let mut limiter = limiter(1, 60);
+    let now = Instant::now();
+    assert_eq!(limiter.check_at(1, now), None);
+    assert!(limiter.check_at(1, now).is_some());
+    assert_eq!(limiter.check_at(2, now), None);
+  }
+}
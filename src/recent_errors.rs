@@ -0,0 +1,153 @@
+use log::{Level, Record};
+use log4rs::append::Append;
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex, MutexGuard};
+
+pub const DEFAULT_ERROR_BUFFER_SIZE: usize = 10;
+
+/// Most recent warn/error log lines, shared between the log appender and the `!errors` built-in
+pub static RECENT_ERRORS: LazyLock<Mutex<RecentErrors>> =
+  LazyLock::new(|| Mutex::new(RecentErrors::new(DEFAULT_ERROR_BUFFER_SIZE)));
+
+pub fn recent_errors() -> MutexGuard<'static, RecentErrors> {
+  RECENT_ERRORS
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Fixed-size ring buffer that evicts the oldest entry once full
+#[derive(Debug)]
+pub struct RecentErrors {
+  capacity: usize,
+  entries: VecDeque<String>,
+}
+
+impl RecentErrors {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      entries: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+    while self.entries.len() > capacity {
+      self.entries.pop_front();
+    }
+  }
+
+  pub fn push(&mut self, entry: String) {
+    if self.capacity == 0 {
+      return;
+    }
+
+    if self.entries.len() == self.capacity {
+      self.entries.pop_front();
+    }
+    self.entries.push_back(entry);
+  }
+
+  /// Returns up to `count` of the newest entries, oldest first
+  pub fn recent(&self, count: usize) -> Vec<&str> {
+    let skip = self.entries.len().saturating_sub(count);
+    self.entries.iter().skip(skip).map(String::as_str).collect()
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+}
+
+/// Log appender that records warn and error lines into [`RECENT_ERRORS`]
+#[derive(Debug)]
+pub struct RecentErrorsAppender;
+
+impl Append for RecentErrorsAppender {
+  fn append(&self, record: &Record) -> anyhow::Result<()> {
+    if record.level() <= Level::Warn {
+      recent_errors().push(format!("[{}] {}", record.level(), record.args()));
+    }
+    Ok(())
+  }
+
+  fn flush(&self) {}
+}
+
+/// Builds the `!errors [count]` reply from the recorded lines
+pub fn format_recent_errors(errors: &RecentErrors, arg: &str) -> String {
+  let count = match arg {
+    "" => errors.len(),
+    count => match count.parse::<usize>() {
+      Ok(count) => count,
+      Err(_) => return "Usage: !errors [count]".to_owned(),
+    },
+  };
+
+  let recent = errors.recent(count);
+  if recent.is_empty() {
+    return "No recent errors".to_owned();
+  }
+
+  recent.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn push_and_retrieve_in_order() {
+    let mut errors = RecentErrors::new(3);
+    errors.push("a".into());
+    errors.push("b".into());
+    assert_eq!(errors.recent(10), vec!["a", "b"]);
+    assert_eq!(errors.recent(1), vec!["b"]);
+  }
+
+  #[test]
+  fn evicts_oldest_when_full() {
+    let mut errors = RecentErrors::new(2);
+    errors.push("a".into());
+    errors.push("b".into());
+    errors.push("c".into());
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors.recent(10), vec!["b", "c"]);
+  }
+
+  #[test]
+  fn shrinking_capacity_drops_oldest() {
+    let mut errors = RecentErrors::new(3);
+    errors.push("a".into());
+    errors.push("b".into());
+    errors.push("c".into());
+    errors.set_capacity(1);
+    assert_eq!(errors.recent(10), vec!["c"]);
+  }
+
+  #[test]
+  fn zero_capacity_records_nothing() {
+    let mut errors = RecentErrors::new(0);
+    errors.push("a".into());
+    assert!(errors.is_empty());
+  }
+
+  #[test]
+  fn format_recent_errors_replies() {
+    let mut errors = RecentErrors::new(3);
+    assert_eq!(format_recent_errors(&errors, ""), "No recent errors");
+
+    errors.push("[WARN] a".into());
+    errors.push("[ERROR] b".into());
+    assert_eq!(format_recent_errors(&errors, ""), "[WARN] a\n[ERROR] b");
+    assert_eq!(format_recent_errors(&errors, "1"), "[ERROR] b");
+    assert_eq!(
+      format_recent_errors(&errors, "lots"),
+      "Usage: !errors [count]"
+    );
+  }
+}
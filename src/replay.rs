@@ -0,0 +1,169 @@
+use crate::command::{AliasResult, ResolveOptions, resolve_alias_with};
+use crate::config::Config;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Dry-runs recorded incoming messages (one per line) against the config, describing what the
+/// server would reply with or run for each. Nothing is executed or transmitted.
+///
+/// With `export`, resolved commands are printed as shell-sourceable `export name='value'` lines
+/// followed by the command itself, and everything else is commented out, so the output can be
+/// sourced to re-run the commands by hand.
+pub fn replay_messages(config: &Config, messages: &str, export: bool) -> String {
+  let options = ResolveOptions::from(config);
+  let mut output = String::new();
+  let marker = if export { "#" } else { ">" };
+
+  for message in messages.lines().map(str::trim_end) {
+    if message.trim().is_empty() {
+      continue;
+    }
+
+    let _ = writeln!(output, "{marker} {message}");
+    output.push_str(&describe_message(config, message, &options, export));
+    output.push('\n');
+  }
+
+  output
+}
+
+fn describe_message(
+  config: &Config,
+  message: &str,
+  options: &ResolveOptions,
+  export: bool,
+) -> String {
+  let description = if !message.starts_with(options.prefix.as_str()) {
+    "ignored: not a command\n".to_owned()
+  } else {
+    match resolve_alias_with(message, &config.commands, options) {
+      Ok(AliasResult::HelpText(text) | AliasResult::Reply(text)) => indent("reply:", &text),
+      Ok(AliasResult::Command { command, env, .. }) if export => {
+        return export_command(&command, &env);
+      }
+      Ok(AliasResult::Command { command, env, .. }) => {
+        let mut description = format!("run: {command}\n");
+        let mut vars: Vec<_> = env.iter().collect();
+        vars.sort();
+        for (name, value) in vars {
+          let _ = writeln!(description, "  {name}={value}");
+        }
+        description
+      }
+      Err(e) => indent("reply:", &e.to_string()),
+    }
+  };
+
+  if export {
+    description
+      .lines()
+      .map(|line| format!("# {line}\n"))
+      .collect()
+  } else {
+    description
+  }
+}
+
+fn export_command(command: &str, env: &HashMap<String, String>) -> String {
+  let mut vars: Vec<_> = env.iter().collect();
+  vars.sort();
+  let mut exports = String::new();
+  for (name, value) in vars {
+    let _ = writeln!(exports, "export {name}={}", shell_quote(value));
+  }
+  let _ = writeln!(exports, "{command}");
+  exports
+}
+
+/// Single-quotes a value for the shell, so it's taken literally when the output is sourced
+fn shell_quote(value: &str) -> String {
+  format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn indent(label: &str, text: &str) -> String {
+  let mut output = format!("{label}\n");
+  for line in text.lines() {
+    let _ = writeln!(output, "  {line}");
+  }
+  output
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::{Arg, ArgType, Command};
+
+  fn config() -> Config {
+    Config {
+      commands: vec![Command {
+        name: "greet".into(),
+        help: "Say hello".into(),
+        command: "echo \"hello ${name}\"".into(),
+        args: vec![Arg {
+          name: "name".into(),
+          help: String::new(),
+          default: None,
+          greedy: false,
+          kind: ArgType::String,
+          choices: Vec::new(),
+        }],
+        ..Default::default()
+      }],
+      verbose_errors: true,
+      prefix: "!".into(),
+      ..Config::default()
+    }
+  }
+
+  #[test]
+  fn replays_each_message() {
+    let output = replay_messages(&config(), "!greet Alice\nhello there\n\n!nope\n", false);
+    assert_eq!(
+      output,
+      indoc::indoc! {r#"
+        > !greet Alice
+        run: echo "hello ${name}"
+          name=Alice
+
+        > hello there
+        ignored: not a command
+
+        > !nope
+        reply:
+          Unknown command: !nope
+
+      "#}
+    );
+  }
+
+  #[test]
+  fn replays_help() {
+    let output = replay_messages(&config(), "!greet --help", false);
+    assert!(output.starts_with("> !greet --help\nreply:\n  !greet - Say hello\n"));
+  }
+
+  #[test]
+  fn export_prints_sourceable_env_and_command() {
+    let output = replay_messages(&config(), "!greet Alice\n!nope", true);
+    assert_eq!(
+      output,
+      indoc::indoc! {r#"
+        # !greet Alice
+        export name='Alice'
+        echo "hello ${name}"
+
+        # !nope
+        # reply:
+        #   Unknown command: !nope
+
+      "#}
+    );
+  }
+
+  #[test]
+  fn shell_quote_escapes_single_quotes() {
+    assert_eq!(shell_quote("plain"), "'plain'");
+    assert_eq!(shell_quote("it's $HOME"), r"'it'\''s $HOME'");
+    assert_eq!(shell_quote(""), "''");
+  }
+}
@@ -0,0 +1,190 @@
+use crate::config::ConfigError;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::Path;
+
+const REDACTED: &str = "<redacted>";
+
+/// Key-value pairs loaded from the `secrets_file`. They're passed to commands as environment
+/// variables at execution time, expanded in commands' `env` values, and are never included in
+/// `Debug` output or serialized configs.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secrets(HashMap<String, String>);
+
+impl Secrets {
+  /// Loads a `.env` file of `KEY=VALUE` lines, or a YAML mapping for any other extension
+  pub fn load(path: &Path) -> Result<Self> {
+    let content =
+      fs::read_to_string(path).map_err(|e| ConfigError::FileNotFound(path.to_path_buf(), e))?;
+
+    let is_env_file = path.extension().is_some_and(|ext| ext == "env")
+      || path.file_name().is_some_and(|name| name == ".env");
+    if is_env_file {
+      return parse_env_file(&content, path);
+    }
+
+    let values: Option<HashMap<String, String>> =
+      serde_yaml::from_str(&content).map_err(|e| ConfigError::ParseError(path.to_path_buf(), e))?;
+
+    Ok(Self(values.unwrap_or_default()))
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+    self.0.iter()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  /// Replaces each `${NAME}` in `text` naming a secret with its value. Other `${...}` references
+  /// are left as they are.
+  pub fn interpolate(&self, text: &str) -> String {
+    self
+      .0
+      .iter()
+      .fold(text.to_owned(), |interpolated, (name, value)| {
+        interpolated.replace(&format!("${{{name}}}"), value)
+      })
+  }
+
+  /// Replaces every secret value appearing in `text` so it can be logged safely
+  pub fn redact(&self, text: &str) -> String {
+    let mut values: Vec<&String> = self.0.values().filter(|v| !v.is_empty()).collect();
+    values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+
+    values.into_iter().fold(text.to_owned(), |redacted, value| {
+      redacted.replace(value.as_str(), REDACTED)
+    })
+  }
+}
+
+impl Debug for Secrets {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let mut keys: Vec<&String> = self.0.keys().collect();
+    keys.sort();
+    f.debug_map()
+      .entries(keys.into_iter().map(|key| (key, REDACTED)))
+      .finish()
+  }
+}
+
+fn parse_env_file(content: &str, path: &Path) -> Result<Secrets> {
+  let mut values = HashMap::new();
+
+  for (idx, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=').ok_or_else(|| {
+      anyhow!(ConfigError::ValidationError(format!(
+        "Invalid line {} in secrets file '{}': expected KEY=VALUE",
+        idx + 1,
+        path.display()
+      )))
+    })?;
+
+    let value = value.trim();
+    let value = value
+      .strip_prefix('"')
+      .and_then(|v| v.strip_suffix('"'))
+      .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+      .unwrap_or(value);
+    values.insert(key.trim().to_owned(), value.to_owned());
+  }
+
+  Ok(Secrets(values))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[test]
+  fn load_yaml_secrets() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("secrets.yaml");
+    fs::write(&path, "API_TOKEN: abc123\nOTHER: \"x y\"\n").unwrap();
+
+    let secrets = Secrets::load(&path).unwrap();
+    let mut values: Vec<_> = secrets.iter().collect();
+    values.sort();
+    assert_eq!(
+      values,
+      vec![
+        (&"API_TOKEN".to_owned(), &"abc123".to_owned()),
+        (&"OTHER".to_owned(), &"x y".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn load_env_secrets() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("secrets.env");
+    fs::write(
+      &path,
+      "# comment\n\nAPI_TOKEN=abc123\nexport QUOTED=\"x y\"\nSINGLE='z'\n",
+    )
+    .unwrap();
+
+    let secrets = Secrets::load(&path).unwrap();
+    let values: HashMap<_, _> = secrets.iter().collect();
+    assert_eq!(values[&"API_TOKEN".to_owned()], "abc123");
+    assert_eq!(values[&"QUOTED".to_owned()], "x y");
+    assert_eq!(values[&"SINGLE".to_owned()], "z");
+  }
+
+  #[test]
+  fn load_env_secrets_rejects_malformed_line() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("secrets.env");
+    fs::write(&path, "API_TOKEN\n").unwrap();
+
+    let err = Secrets::load(&path).unwrap_err().to_string();
+    assert!(err.contains("Invalid line 1"), "unexpected error: {err}");
+  }
+
+  #[test]
+  fn empty_yaml_secrets_file_is_allowed() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("secrets.yaml");
+    fs::write(&path, "").unwrap();
+
+    assert!(Secrets::load(&path).unwrap().is_empty());
+  }
+
+  #[test]
+  fn debug_output_redacts_values() {
+    let secrets = Secrets(HashMap::from([("API_TOKEN".into(), "abc123".into())]));
+    let debug = format!("{secrets:?}");
+    assert_eq!(debug, "{\"API_TOKEN\": \"<redacted>\"}");
+  }
+
+  #[test]
+  fn interpolate_expands_only_secret_references() {
+    let secrets = Secrets(HashMap::from([("API_TOKEN".into(), "abc123".into())]));
+    assert_eq!(
+      secrets.interpolate("Bearer ${API_TOKEN} ${HOME} $API_TOKEN"),
+      "Bearer abc123 ${HOME} $API_TOKEN"
+    );
+  }
+
+  #[test]
+  fn redact_replaces_secret_values() {
+    let secrets = Secrets(HashMap::from([
+      ("SHORT".into(), "abc".into()),
+      ("LONG".into(), "abc123".into()),
+    ]));
+    assert_eq!(
+      secrets.redact("token=abc123 prefix=abc"),
+      "token=<redacted> prefix=<redacted>"
+    );
+  }
+}
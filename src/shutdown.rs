@@ -0,0 +1,89 @@
+use anyhow::Result;
+use log::info;
+use std::fmt::{Display, Formatter};
+use tokio::signal;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownSignal {
+  Interrupt,
+  Terminate,
+}
+
+impl Display for ShutdownSignal {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ShutdownSignal::Interrupt => write!(f, "SIGINT"),
+      ShutdownSignal::Terminate => write!(f, "SIGTERM"),
+    }
+  }
+}
+
+/// Waits for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+pub async fn wait_for_shutdown_signal() -> Result<ShutdownSignal> {
+  #[cfg(unix)]
+  {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+      result = signal::ctrl_c() => result.map(|_| ShutdownSignal::Interrupt).map_err(Into::into),
+      _ = terminate.recv() => Ok(ShutdownSignal::Terminate),
+    }
+  }
+
+  #[cfg(not(unix))]
+  {
+    signal::ctrl_c().await?;
+    Ok(ShutdownSignal::Interrupt)
+  }
+}
+
+/// Cancels `token` once `shutdown_signal` resolves so the server loop can exit gracefully. If the
+/// signal handlers can't be installed, the token is left alone and the error is returned.
+pub async fn cancel_on_shutdown<F>(
+  token: CancellationToken,
+  shutdown_signal: F,
+) -> Result<ShutdownSignal>
+where
+  F: Future<Output = Result<ShutdownSignal>>,
+{
+  let received = shutdown_signal.await?;
+  info!("{received} received, shutting down");
+  token.cancel();
+
+  Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::anyhow;
+
+  #[tokio::test]
+  async fn shutdown_signal_cancels_token() {
+    let token = CancellationToken::new();
+    let received = cancel_on_shutdown(token.clone(), async { Ok(ShutdownSignal::Terminate) })
+      .await
+      .unwrap();
+
+    assert_eq!(received, ShutdownSignal::Terminate);
+    assert!(token.is_cancelled());
+  }
+
+  #[tokio::test]
+  async fn signal_handler_failure_leaves_token_alone() {
+    let token = CancellationToken::new();
+    let result = cancel_on_shutdown(token.clone(), async {
+      Err(anyhow!("signal handlers unavailable"))
+    })
+    .await;
+
+    assert!(result.is_err());
+    assert!(!token.is_cancelled());
+  }
+
+  #[test]
+  fn shutdown_signal_display() {
+    assert_eq!(ShutdownSignal::Interrupt.to_string(), "SIGINT");
+    assert_eq!(ShutdownSignal::Terminate.to_string(), "SIGTERM");
+  }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+/// Renders a reply template by replacing `{name}` placeholders with the matching value. Unknown
+/// placeholders are left untouched so literal braces in a template survive.
+pub fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+  let mut output = String::with_capacity(template.len());
+  let mut rest = template;
+
+  while let Some(start) = rest.find('{') {
+    output.push_str(&rest[..start]);
+    let candidate = &rest[start..];
+
+    let value = candidate.find('}').and_then(|end| {
+      let name = &candidate[1..end];
+      vars
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| (*value, end))
+    });
+
+    match value {
+      Some((value, end)) => {
+        output.push_str(value);
+        rest = &candidate[end + 1..];
+      }
+      None => {
+        output.push('{');
+        rest = &candidate[1..];
+      }
+    }
+  }
+
+  output.push_str(rest);
+  output
+}
+
+pub fn format_duration_ms(elapsed: Duration) -> String {
+  elapsed.as_millis().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn renders_known_placeholders() {
+    let rendered = render_template(
+      "{output} (took {duration_ms}ms)",
+      &[("output", "ok"), ("duration_ms", "42")],
+    );
+    assert_eq!(rendered, "ok (took 42ms)");
+  }
+
+  #[test]
+  fn leaves_unknown_placeholders_and_braces_untouched() {
+    let rendered = render_template("{nope} {output} {", &[("output", "ok")]);
+    assert_eq!(rendered, "{nope} ok {");
+  }
+
+  #[test]
+  fn does_not_expand_placeholders_inside_values() {
+    let rendered = render_template(
+      "{output} {duration_ms}",
+      &[("output", "{duration_ms}"), ("duration_ms", "7")],
+    );
+    assert_eq!(rendered, "{duration_ms} 7");
+  }
+
+  #[test]
+  fn format_duration_ms_truncates_to_whole_milliseconds() {
+    assert_eq!(format_duration_ms(Duration::ZERO), "0");
+    assert_eq!(format_duration_ms(Duration::from_micros(1999)), "1");
+    assert_eq!(format_duration_ms(Duration::from_secs(3)), "3000");
+  }
+}
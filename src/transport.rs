@@ -1,17 +1,179 @@
-use crate::config::Config;
+use crate::acks::{acknowledged_packet, acks};
+use crate::airtime::AirtimeBudget;
+use crate::config::{Config, ConnectRetry, Connection, OversizeChunkPolicy, ReplyMode};
 use anyhow::{Result, anyhow};
-use log::{error, info};
-use meshtastic::api::ConnectedStreamApi;
+use log::{error, info, warn};
 use meshtastic::api::state::Configured;
+use meshtastic::api::{ConnectedStreamApi, StreamApi};
 use meshtastic::packet::{PacketDestination, PacketReceiver, PacketRouter};
-use meshtastic::protobufs::from_radio;
-use meshtastic::types::MeshChannel;
+use meshtastic::protobufs::{FromRadio, PortNum, from_radio};
+use meshtastic::types::{MeshChannel, NodeId};
+use meshtastic::utils::generate_rand_id;
+use meshtastic::utils::stream::{available_serial_ports, build_serial_stream, build_tcp_stream};
 use std::error::Error;
 use std::fmt::Display;
-use std::mem;
+use std::marker::PhantomData;
 use std::time::Duration;
+use std::{io, mem};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::time::{sleep, timeout};
 
+/// Connects over the configured transport. Once connected, the packet stream is the same for
+/// serial and TCP, so everything downstream is transport-agnostic.
+pub async fn connect_to_node(
+  server_config: &Config,
+) -> Result<(PacketReceiver, ConnectedStreamApi<Configured>, u32)> {
+  let (mut rx, api) = match &server_config.connection {
+    Connection::Serial => {
+      let serial =
+        build_serial_stream(server_config.device.clone(), server_config.baud, None, None)
+          .map_err(|e| serial_open_error(&server_config.device, e))?;
+      StreamApi::new().connect(serial).await
+    }
+    Connection::Tcp { host, port } => {
+      let tcp = build_tcp_stream(format!("{host}:{port}")).await?;
+      StreamApi::new().connect(tcp).await
+    }
+  };
+  let config_id = generate_rand_id();
+  let api = api.configure(config_id).await?;
+  let node_id = wait_for_my_node_num(&mut rx).await?;
+
+  Ok((rx, api, node_id))
+}
+
+/// Turns a failure to open the serial device into an actionable message when it's a permissions
+/// problem, which otherwise surfaces as a bare OS error
+pub fn serial_open_error<E>(device: &str, error: E) -> anyhow::Error
+where
+  E: Error + Send + Sync + 'static,
+{
+  if !is_permission_denied(&error) {
+    return anyhow::Error::new(error);
+  }
+
+  let hint = if cfg!(target_os = "linux") {
+    format!(
+      "Add your user to the group that owns the device (usually 'dialout', or 'uucp' on Arch): \
+       `sudo usermod -aG dialout $USER`, then log out and back in. Check the group with \
+       `ls -l {device}`."
+    )
+  } else {
+    "Make sure your user is allowed to open the device and that no other program is using it."
+      .to_owned()
+  };
+
+  anyhow!("Permission denied opening serial device '{device}'. {hint}")
+}
+
+/// Port names (after the last `/`) that USB serial adapters on Meshtastic boards show up as, on
+/// Linux (`ttyUSB0`, `ttyACM0`), macOS (`cu.usbserial-0001`) and Windows (`COM3`)
+const SERIAL_DEVICE_PREFIXES: &[&str] = &[
+  "ttyUSB",
+  "ttyACM",
+  "cu.usbserial",
+  "cu.usbmodem",
+  "cu.SLAB_USBtoUART",
+  "cu.wchusbserial",
+  "COM",
+];
+
+/// Finds the serial device to use when `device` isn't configured
+pub fn detect_serial_device() -> Result<String> {
+  let ports = available_serial_ports().map_err(|e| anyhow!("Failed to list serial ports: {e}"))?;
+  select_serial_device(&ports)
+}
+
+/// Picks the only port that looks like a Meshtastic device. With none or several, there's no
+/// telling which one is meant, so the error lists the candidates to choose from.
+pub fn select_serial_device(ports: &[String]) -> Result<String> {
+  let candidates: Vec<&String> = ports
+    .iter()
+    .filter(|port| {
+      let name = port.rsplit('/').next().unwrap_or(port);
+      SERIAL_DEVICE_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    })
+    .collect();
+
+  match candidates.as_slice() {
+    [device] => Ok((*device).clone()),
+    [] if ports.is_empty() => Err(anyhow!(
+      "No serial ports found. Is the Meshtastic device plugged in? Set 'device' in the config to \
+       choose one."
+    )),
+    [] => Err(anyhow!(
+      "No Meshtastic serial device found among: {}. Set 'device' in the config to choose one.",
+      ports.join(", ")
+    )),
+    _ => Err(anyhow!(
+      "Found several possible Meshtastic serial devices: {}. Set 'device' in the config to \
+       choose one.",
+      candidates
+        .iter()
+        .map(|c| c.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    )),
+  }
+}
+
+/// Whether the error, or any error that caused it, is a permission error. The serial libraries
+/// don't always keep the `io::Error`, so their messages are checked too.
+fn is_permission_denied(error: &(dyn Error + 'static)) -> bool {
+  let mut current = Some(error);
+  while let Some(error) = current {
+    if error
+      .downcast_ref::<io::Error>()
+      .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+    {
+      return true;
+    }
+
+    let message = error.to_string().to_lowercase();
+    if message.contains("permission denied") || message.contains("access is denied") {
+      return true;
+    }
+
+    current = error.source();
+  }
+
+  false
+}
+
+/// Runs `operation` until it succeeds or the configured number of attempts is exhausted, sleeping
+/// with exponential backoff between attempts. Used for the initial connection at startup only.
+pub async fn retry_with_backoff<T, F, Fut>(retry: &ConnectRetry, mut operation: F) -> Result<T>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T>>,
+{
+  let mut attempt = 1;
+
+  loop {
+    match operation().await {
+      Ok(value) => return Ok(value),
+      Err(e) if attempt < retry.attempts => {
+        let delay = backoff_delay(retry.backoff_ms, retry.max_backoff_ms, attempt);
+        warn!(
+          "Connection attempt {attempt}/{} failed: {e}. Retrying in {}ms",
+          retry.attempts,
+          delay.as_millis()
+        );
+        sleep(delay).await;
+        attempt += 1;
+      }
+      Err(e) => return Err(e.context(format!("Failed to connect after {attempt} attempt(s)"))),
+    }
+  }
+}
+
+pub fn backoff_delay(base_ms: u64, max_ms: u64, attempt: u32) -> Duration {
+  let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+  Duration::from_millis(base_ms.saturating_mul(factor).min(max_ms))
+}
+
 pub async fn wait_for_my_node_num(rx: &mut PacketReceiver) -> Result<u32> {
   let msg = timeout(Duration::from_secs(10), async {
     loop {
@@ -29,44 +191,100 @@ pub async fn wait_for_my_node_num(rx: &mut PacketReceiver) -> Result<u32> {
   Ok(node_num)
 }
 
-pub fn chunk_lines_with_footer(text: &str, max_bytes: usize) -> Vec<String> {
-  assert!(max_bytes > 0);
+/// Whether a packet from `from` on `channel` is worth looking at. Packets this node sent itself are
+/// always ignored, whichever channel they show up on (e.g. a reply relayed back, or read on
+/// another of the listened channels), so the runner never acts on its own output. Anything else
+/// must arrive on one of the listened `channels`.
+pub fn is_from_listened_peer(from: u32, channel: u32, node_id: u32, channels: &[u32]) -> bool {
+  from != node_id && channels.contains(&channel)
+}
 
-  let mut raw_chunks = Vec::new();
-  let mut current = String::new();
-  let mut current_bytes = 0usize;
+/// How many hops a packet took to reach this node: the hop limit it was sent with (`hop_start`)
+/// minus what's left of it (`hop_limit`). `None` if the sender's firmware doesn't report
+/// `hop_start`, which it leaves at 0.
+pub fn hops_away(hop_start: u32, hop_limit: u32) -> Option<u32> {
+  (hop_start > 0).then(|| hop_start.saturating_sub(hop_limit))
+}
 
-  for line in text.split_inclusive('\n') {
-    let line_bytes = line.len();
+/// Whether a packet sent to `to` should be acted on. With `require_direct`, broadcast packets
+/// (and packets meant for other nodes) are ignored and only those sent to this node are accepted.
+pub fn is_addressed_to_node(to: u32, node_id: u32, require_direct: bool) -> bool {
+  !require_direct || to == node_id
+}
 
-    if line_bytes > max_bytes {
-      if !current.is_empty() {
-        raw_chunks.push(mem::take(&mut current));
-        current_bytes = 0;
-      }
+pub const UNAUTHORIZED_MESSAGE: &str = "Not authorized to run commands on this node.";
 
-      let mut end = max_bytes.min(line.len());
-      while end > 0 && !line.is_char_boundary(end) {
-        end -= 1;
-      }
+pub const WEAK_SIGNAL_MESSAGE: &str = "Signal too weak, command ignored.";
 
-      raw_chunks.push(line[..end].to_string());
-      continue;
-    }
+/// Whether a packet was received strongly enough to act on, per the optional `min_snr` and
+/// `min_rssi`. An RSSI of 0 means the radio didn't measure one (e.g. the packet arrived over
+/// MQTT), so only the SNR threshold applies to it.
+pub fn has_sufficient_signal(
+  snr: f32,
+  rssi: i32,
+  min_snr: Option<f32>,
+  min_rssi: Option<i32>,
+) -> bool {
+  min_snr.is_none_or(|min_snr| snr >= min_snr)
+    && min_rssi.is_none_or(|min_rssi| rssi == 0 || rssi >= min_rssi)
+}
 
-    if current_bytes + line_bytes > max_bytes {
-      raw_chunks.push(mem::take(&mut current));
-      current_bytes = 0;
+/// Packs whole lines into chunks of at most `max_bytes`, adding a `[n/total]` footer to each chunk
+/// when there's more than one. A line longer than `max_bytes` is cut off at the limit, unless
+/// `wrap_words` is set, in which case it's split across chunks at the last whitespace that fits
+/// (or hard cut if a single word doesn't fit), leaving room for the footers. The room is
+/// re-measured until the number of chunks, and so the footer width, stops changing.
+pub fn chunk_lines_with_footer(text: &str, max_bytes: usize, wrap_words: bool) -> Vec<String> {
+  assert!(max_bytes > 0);
+
+  let mut footer_reserve = 0;
+  let raw_chunks = loop {
+    let raw_chunks = pack_lines(text, max_bytes, wrap_words, footer_reserve);
+    let footer_bytes = chunk_footer(raw_chunks.len(), raw_chunks.len()).len();
+    if !wrap_words || raw_chunks.len() <= 1 || footer_bytes <= footer_reserve {
+      break raw_chunks;
     }
+    footer_reserve = footer_bytes;
+  };
 
-    current.push_str(line);
-    current_bytes += line_bytes;
-  }
+  add_chunk_footers(raw_chunks, max_bytes)
+}
 
-  if !current.is_empty() {
-    raw_chunks.push(current);
+/// Chunks help so that each chunk lists at most `entries_per_chunk` entries, the indented lines
+/// of a listing. Lines before the first entry stay with the first chunk and lines after the last
+/// one with the last chunk. A page too big for `max_bytes` is split further like any other text.
+pub fn chunk_help_with_footer(
+  text: &str,
+  max_bytes: usize,
+  wrap_words: bool,
+  entries_per_chunk: usize,
+) -> Vec<String> {
+  assert!(max_bytes > 0 && entries_per_chunk > 0);
+
+  let mut pages = vec![String::new()];
+  let mut entries = 0;
+  for line in text.split_inclusive('\n') {
+    if line.starts_with("  ") {
+      if entries == entries_per_chunk {
+        pages.push(String::new());
+        entries = 0;
+      }
+      entries += 1;
+    }
+    pages.last_mut().unwrap().push_str(line);
   }
 
+  let raw_chunks = pages
+    .iter()
+    .filter(|page| !page.is_empty())
+    .flat_map(|page| pack_lines(page, max_bytes, wrap_words, 0))
+    .collect();
+  add_chunk_footers(raw_chunks, max_bytes)
+}
+
+/// Adds a `[n/total]` footer to each chunk when there's more than one, cutting the chunk short
+/// if the footer wouldn't fit. A footer longer than `max_bytes` itself is left off.
+fn add_chunk_footers(raw_chunks: Vec<String>, max_bytes: usize) -> Vec<String> {
   let total = raw_chunks.len();
 
   raw_chunks
@@ -74,8 +292,12 @@ pub fn chunk_lines_with_footer(text: &str, max_bytes: usize) -> Vec<String> {
     .enumerate()
     .map(|(i, mut chunk)| {
       if total > 1 {
-        let footer = format!("\n\n[{}/{}]", i + 1, total);
+        let footer = chunk_footer(i + 1, total);
         let footer_bytes = footer.len();
+        if footer_bytes > max_bytes {
+          chunk.truncate(floor_char_boundary(&chunk, max_bytes));
+          return chunk;
+        }
 
         let available = max_bytes.saturating_sub(footer_bytes);
         if chunk.len() > available {
@@ -93,91 +315,1176 @@ pub fn chunk_lines_with_footer(text: &str, max_bytes: usize) -> Vec<String> {
     .collect()
 }
 
+fn chunk_footer(index: usize, total: usize) -> String {
+  format!("\n\n[{index}/{total}]")
+}
+
+/// Packs lines into chunks of at most `max_bytes - footer_reserve`, before footers are added
+fn pack_lines(
+  text: &str,
+  max_bytes: usize,
+  wrap_words: bool,
+  footer_reserve: usize,
+) -> Vec<String> {
+  let budget = max_bytes.saturating_sub(footer_reserve).max(1);
+  let mut raw_chunks = Vec::new();
+  let mut current = String::new();
+  let mut current_bytes = 0usize;
+
+  for mut line in text.split_inclusive('\n') {
+    if line.len() > budget {
+      if !current.is_empty() {
+        raw_chunks.push(mem::take(&mut current));
+        current_bytes = 0;
+      }
+
+      if !wrap_words {
+        raw_chunks.push(line[..floor_char_boundary(line, budget)].to_string());
+        continue;
+      }
+
+      while line.len() > budget {
+        let (piece, rest) = split_at_word_boundary(line, budget);
+        raw_chunks.push(piece.to_string());
+        line = rest;
+      }
+    }
+
+    let line_bytes = line.len();
+    if current_bytes + line_bytes > budget {
+      raw_chunks.push(mem::take(&mut current));
+      current_bytes = 0;
+    }
+
+    current.push_str(line);
+    current_bytes += line_bytes;
+  }
+
+  if !current.is_empty() {
+    raw_chunks.push(current);
+  }
+
+  raw_chunks
+}
+
+/// Splits off the longest start of `line` within `max_bytes` that ends at whitespace, dropping
+/// the whitespace at the break. Without any, the line is cut at the last char boundary that fits.
+fn split_at_word_boundary(line: &str, max_bytes: usize) -> (&str, &str) {
+  let end = floor_char_boundary(line, max_bytes);
+  let break_at = if line[end..].starts_with(char::is_whitespace) {
+    Some(end)
+  } else {
+    line[..end].rfind(char::is_whitespace)
+  };
+
+  if let Some(idx) = break_at
+    && !line[..idx].trim().is_empty()
+  {
+    return (line[..idx].trim_end(), line[idx..].trim_start());
+  }
+
+  match end {
+    0 => line.split_at(line.chars().next().map_or(line.len(), char::len_utf8)),
+    end => line.split_at(end),
+  }
+}
+
+/// Enforces `max_text_bytes` on chunks that still exceed it after chunking, e.g. because of the
+/// footer, by dropping, truncating or re-splitting them
+pub fn fit_chunks(
+  chunks: Vec<String>,
+  max_bytes: usize,
+  policy: OversizeChunkPolicy,
+) -> Vec<String> {
+  let mut fitted = Vec::with_capacity(chunks.len());
+
+  for (idx, mut chunk) in chunks.into_iter().enumerate() {
+    if chunk.len() <= max_bytes {
+      fitted.push(chunk);
+      continue;
+    }
+
+    match policy {
+      OversizeChunkPolicy::Drop => {
+        error!(
+          "part {} is {} bytes (> {max_bytes}), dropping it",
+          idx + 1,
+          chunk.len()
+        );
+      }
+      OversizeChunkPolicy::Truncate => {
+        warn!(
+          "part {} is {} bytes (> {max_bytes}), truncating it",
+          idx + 1,
+          chunk.len()
+        );
+        chunk.truncate(floor_char_boundary(&chunk, max_bytes));
+        fitted.push(chunk);
+      }
+      OversizeChunkPolicy::Split => {
+        warn!(
+          "part {} is {} bytes (> {max_bytes}), splitting it",
+          idx + 1,
+          chunk.len()
+        );
+        let mut rest = chunk.as_str();
+        while !rest.is_empty() {
+          let end = match floor_char_boundary(rest, max_bytes) {
+            0 => rest.chars().next().map_or(rest.len(), char::len_utf8),
+            end => end,
+          };
+          fitted.push(rest[..end].to_owned());
+          rest = &rest[end..];
+        }
+      }
+    }
+  }
+
+  fitted
+}
+
+/// Sent in place of the chunks a reply couldn't fit within `max_reply_bytes`
+pub const REPLY_TRUNCATED_NOTICE: &str = "[reply truncated]";
+
+/// Keeps a reply within `max_bytes` in total: the chunks are sent while the running total, plus
+/// room for the truncation notice, stays within the cap, and the notice replaces the rest. The
+/// footers of the chunks kept are renumbered to count only those.
+pub fn cap_reply_bytes(chunks: Vec<String>, max_bytes: usize) -> Vec<String> {
+  let total: usize = chunks.iter().map(String::len).sum();
+  if total <= max_bytes {
+    return chunks;
+  }
+
+  let budget = max_bytes.saturating_sub(REPLY_TRUNCATED_NOTICE.len());
+  let mut sent_bytes = 0;
+  let mut capped: Vec<String> = chunks
+    .into_iter()
+    .take_while(|chunk| {
+      sent_bytes += chunk.len();
+      sent_bytes <= budget
+    })
+    .collect();
+  warn!(
+    "Reply is {total} bytes (> max_reply_bytes {max_bytes}); sending the first {} chunk(s)",
+    capped.len()
+  );
+  renumber_chunk_footers(&mut capped);
+  capped.push(REPLY_TRUNCATED_NOTICE.to_owned());
+  capped
+}
+
+/// Rewrites each chunk's `[n/total]` footer for the number of chunks left, leaving it off if
+/// there's only one. The footers only get shorter, so the chunks stay within their limits.
+fn renumber_chunk_footers(chunks: &mut [String]) {
+  let total = chunks.len();
+  for chunk in chunks {
+    let Some((content, index)) = split_chunk_footer(chunk) else {
+      continue;
+    };
+    let footer = if total > 1 {
+      chunk_footer(index, total)
+    } else {
+      String::new()
+    };
+    *chunk = format!("{content}{footer}");
+  }
+}
+
+/// Splits a chunk into its content and the index in its `[n/total]` footer, if it has one
+fn split_chunk_footer(chunk: &str) -> Option<(&str, usize)> {
+  let (content, footer) = chunk.rsplit_once("\n\n[")?;
+  let (index, total) = footer.strip_suffix(']')?.split_once('/')?;
+  total.parse::<usize>().ok()?;
+  Some((content, index.parse().ok()?))
+}
+
+fn floor_char_boundary(text: &str, max_bytes: usize) -> usize {
+  let mut end = max_bytes.min(text.len());
+  while end > 0 && !text.is_char_boundary(end) {
+    end -= 1;
+  }
+  end
+}
+
+/// What a reply contains, which decides the delay between its chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyKind {
+  Help,
+  Output,
+}
+
+impl ReplyKind {
+  pub fn chunk_delay(self, server_config: &Config) -> Duration {
+    let delay_ms = match self {
+      ReplyKind::Help => server_config
+        .help_chunk_delay
+        .unwrap_or(server_config.chunk_delay),
+      ReplyKind::Output => server_config.chunk_delay,
+    };
+    Duration::from_millis(delay_ms)
+  }
+}
+
+/// Where a reply to `sender` goes: the channel the command arrived on, broadcast or as a direct
+/// message depending on the configured `reply_mode`
+#[derive(Debug, Clone, Copy)]
+pub struct ReplyTarget {
+  pub destination: PacketDestination,
+  pub channel: u32,
+}
+
+impl ReplyTarget {
+  pub fn new(reply_mode: ReplyMode, sender: u32, channel: u32) -> Self {
+    let destination = match reply_mode {
+      ReplyMode::Broadcast => PacketDestination::Broadcast,
+      ReplyMode::Direct => PacketDestination::Node(NodeId::new(sender)),
+    };
+
+    Self {
+      destination,
+      channel,
+    }
+  }
+}
+
 pub async fn send_split_text<R, E>(
   api: &mut ConnectedStreamApi<Configured>,
   router: &mut R,
+  target: ReplyTarget,
   text: &str,
   server_config: &Config,
+  airtime: &mut AirtimeBudget,
+  kind: ReplyKind,
 ) -> Result<()>
 where
   E: Display + Error + Send + Sync + 'static,
   R: PacketRouter<(), E>,
 {
-  let chunks = chunk_lines_with_footer(text, server_config.max_content_bytes);
+  let mut sender = RadioSender {
+    api,
+    router,
+    router_error: PhantomData,
+  };
+  let chunks = match (kind, server_config.help_entries_per_chunk) {
+    (ReplyKind::Help, Some(entries_per_chunk)) => chunk_help_with_footer(
+      text,
+      server_config.max_content_bytes,
+      server_config.wrap_words,
+      entries_per_chunk,
+    ),
+    _ => chunk_lines_with_footer(
+      text,
+      server_config.max_content_bytes,
+      server_config.wrap_words,
+    ),
+  };
+  let chunks = fit_chunks(
+    chunks,
+    server_config.max_text_bytes,
+    server_config.oversize_chunk_policy,
+  );
+  let chunks = match server_config.max_reply_bytes {
+    Some(max_reply_bytes) => cap_reply_bytes(chunks, max_reply_bytes),
+    None => chunks,
+  };
+  let chunk_delay = kind.chunk_delay(server_config);
 
-  for (idx, part) in chunks.iter().enumerate() {
-    info!("Sending chunk: {part}");
-    let bytes = part.len();
-    if bytes > server_config.max_text_bytes {
-      error!(
-        "part {} is {bytes} bytes (> {})",
-        idx + 1,
-        server_config.max_text_bytes
-      );
-      continue;
+  let total_bytes = chunks.iter().map(String::len).sum();
+  if !airtime.try_consume(total_bytes) {
+    warn!("Airtime budget exceeded; dropping {total_bytes} byte reply");
+    if airtime.should_notify() {
+      let minutes = airtime.time_until_reset().as_secs().div_ceil(60);
+      let notice = format!("Airtime budget exceeded. Replies paused for ~{minutes} min.");
+      airtime.record(notice.len());
+      sender.send_text(notice, target, false).await?;
     }
+    return Ok(());
+  }
 
-    match api
+  send_chunks(
+    &mut sender,
+    &chunks,
+    target,
+    chunk_delay,
+    server_config,
+    airtime,
+  )
+  .await
+}
+
+/// Reacts to the packet `packet_id` with an emoji tapback, which clients show on the original
+/// message. Counts against the airtime budget like any reply, and is skipped once it's spent.
+pub async fn send_reaction<R, E>(
+  api: &mut ConnectedStreamApi<Configured>,
+  router: &mut R,
+  target: ReplyTarget,
+  packet_id: u32,
+  emoji: &str,
+  airtime: &mut AirtimeBudget,
+) -> Result<()>
+where
+  E: Display + Error + Send + Sync + 'static,
+  R: PacketRouter<(), E>,
+{
+  if !airtime.try_consume(emoji.len()) {
+    warn!("Airtime budget exceeded; not reacting with {emoji}");
+    return Ok(());
+  }
+
+  api
+    .send_mesh_packet(
+      router,
+      emoji.as_bytes().to_vec().into(),
+      PortNum::TextMessageApp,
+      target.destination,
+      MeshChannel::from(target.channel),
+      false,
+      false,
+      true,
+      Some(packet_id),
+      Some(1),
+    )
+    .await?;
+  Ok(())
+}
+
+/// Sends a single text packet. Implemented for the radio connection, and by tests to capture what
+/// would be transmitted.
+pub(crate) trait TextSender {
+  async fn send_text(&mut self, text: String, target: ReplyTarget, want_ack: bool) -> Result<()>;
+
+  /// Waits up to `timeout` for the last packet sent with `want_ack` to be acknowledged. Returns
+  /// whether it was.
+  async fn wait_for_ack(&mut self, timeout: Duration) -> bool;
+}
+
+struct RadioSender<'a, R, E> {
+  api: &'a mut ConnectedStreamApi<Configured>,
+  router: &'a mut R,
+  router_error: PhantomData<E>,
+}
+
+impl<R, E> TextSender for RadioSender<'_, R, E>
+where
+  E: Display + Error + Send + Sync + 'static,
+  R: PacketRouter<(), E>,
+{
+  async fn send_text(&mut self, text: String, target: ReplyTarget, want_ack: bool) -> Result<()> {
+    self
+      .api
       .send_text(
-        router,
-        part.clone(),
-        PacketDestination::Broadcast,
-        false,
-        MeshChannel::from(server_config.channel),
+        self.router,
+        text,
+        target.destination,
+        want_ack,
+        MeshChannel::from(target.channel),
       )
-      .await
-    {
-      Ok(_) => {}
+      .await?;
+    Ok(())
+  }
+
+  async fn wait_for_ack(&mut self, timeout: Duration) -> bool {
+    match acks().last_sent() {
+      Some(packet_id) => acks().wait_for(packet_id, timeout).await,
+      None => false,
+    }
+  }
+}
+
+/// Longest wait between attempts at sending a chunk, however many retries it's taken
+const MAX_RETRY_BACKOFF_MS: u64 = 60_000;
+
+/// Sends each chunk in turn. With `ack_last_chunk`, only the final chunk asks for an
+/// acknowledgement, confirming the whole reply landed without the airtime of acknowledging every
+/// chunk. With `reliable_send`, every chunk asks for one and the next chunk waits for it instead of
+/// `chunk_delay`. Resends of unacknowledged chunks count against `airtime` on top of the reply
+/// itself.
+async fn send_chunks(
+  sender: &mut impl TextSender,
+  chunks: &[String],
+  target: ReplyTarget,
+  chunk_delay: Duration,
+  server_config: &Config,
+  airtime: &mut AirtimeBudget,
+) -> Result<()> {
+  let last = chunks.len().saturating_sub(1);
+  let retry_backoff = server_config
+    .retry_backoff_ms
+    .map_or(chunk_delay, Duration::from_millis);
+
+  for (idx, part) in chunks.iter().enumerate() {
+    info!("Sending chunk: {}", server_config.secrets.redact(part));
+    let want_ack = server_config.reliable_send || (server_config.ack_last_chunk && idx == last);
+
+    let sent = send_with_retries(
+      sender,
+      part,
+      idx + 1,
+      target,
+      want_ack,
+      retry_backoff,
+      server_config,
+    )
+    .await
+    .is_some();
+    let acked = sent
+      && server_config.reliable_send
+      && await_ack(
+        sender,
+        part,
+        idx + 1,
+        target,
+        retry_backoff,
+        server_config,
+        airtime,
+      )
+      .await;
+    if !acked {
+      sleep(chunk_delay).await;
+    }
+  }
+
+  Ok(())
+}
+
+/// Sends a chunk, retrying a failed send up to `send_retries` times with the wait doubling from
+/// `retry_backoff` each time. Returns how many retries it took, or `None` if it was never sent; that
+/// is logged, and the reply carries on with the next chunk.
+async fn send_with_retries(
+  sender: &mut impl TextSender,
+  part: &str,
+  part_number: usize,
+  target: ReplyTarget,
+  want_ack: bool,
+  retry_backoff: Duration,
+  server_config: &Config,
+) -> Option<u32> {
+  let mut retries = 0;
+
+  loop {
+    match sender.send_text(part.to_owned(), target, want_ack).await {
+      Ok(()) => {
+        if retries > 0 {
+          info!("Sent part {part_number} after {retries} retry(s)");
+        }
+        return Some(retries);
+      }
+      Err(e) if retries < server_config.send_retries => {
+        retries += 1;
+        let backoff_ms = u64::try_from(retry_backoff.as_millis()).unwrap_or(u64::MAX);
+        let delay = backoff_delay(backoff_ms, MAX_RETRY_BACKOFF_MS, retries);
+        warn!(
+          "send_text failed on part {part_number}: {e}. Retrying in {}ms ({retries}/{})",
+          delay.as_millis(),
+          server_config.send_retries
+        );
+        sleep(delay).await;
+      }
       Err(e) => {
-        error!("send_text failed on part {}: {e}", idx + 1);
-        sleep(Duration::from_millis(server_config.chunk_delay)).await;
-        api
-          .send_text(
-            router,
-            part.clone(),
-            PacketDestination::Broadcast,
-            false,
-            MeshChannel::from(server_config.channel),
-          )
-          .await?;
+        error!("send_text failed on part {part_number} after {retries} retry(s): {e}. Skipping it");
+        return None;
       }
     }
+  }
+}
 
-    sleep(Duration::from_millis(server_config.chunk_delay)).await;
+/// Waits for the chunk just sent to be acknowledged, resending it each time `ack_timeout_secs`
+/// passes without one, up to `ack_retries` times. Each resend is charged to `airtime`, and
+/// resending stops once it's spent. Returns whether it was acknowledged; if it never is, that's
+/// logged and the reply carries on with the next chunk.
+async fn await_ack(
+  sender: &mut impl TextSender,
+  part: &str,
+  part_number: usize,
+  target: ReplyTarget,
+  retry_backoff: Duration,
+  server_config: &Config,
+  airtime: &mut AirtimeBudget,
+) -> bool {
+  let ack_timeout = Duration::from_secs(server_config.ack_timeout_secs);
+
+  for retry in 1..=server_config.ack_retries {
+    if sender.wait_for_ack(ack_timeout).await {
+      return true;
+    }
+    if !airtime.try_consume(part.len()) {
+      warn!(
+        "Part {part_number} wasn't acknowledged, but the airtime budget is spent; not resending"
+      );
+      return false;
+    }
+    warn!(
+      "Part {part_number} wasn't acknowledged within {}s; resending ({retry}/{})",
+      server_config.ack_timeout_secs, server_config.ack_retries
+    );
+    let resent = send_with_retries(
+      sender,
+      part,
+      part_number,
+      target,
+      true,
+      retry_backoff,
+      server_config,
+    )
+    .await;
+    if resent.is_none() {
+      return false;
+    }
   }
 
-  Ok(())
+  if sender.wait_for_ack(ack_timeout).await {
+    return true;
+  }
+  error!(
+    "Part {part_number} was never acknowledged after {} resend(s); moving on",
+    server_config.ack_retries
+  );
+  false
+}
+
+/// Reads packets off the radio, noting the acknowledgements a reply may be waiting on, and passes
+/// every packet on to `packets`. Runs on its own task so acknowledgements are seen while the main
+/// loop is busy sending.
+pub async fn forward_packets(mut rx: PacketReceiver, packets: UnboundedSender<FromRadio>) {
+  while let Some(message) = rx.recv().await {
+    if let Some(packet_id) = acknowledged_packet(&message) {
+      acks().acknowledge(packet_id);
+    }
+    if packets.send(message).is_err() {
+      break;
+    }
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
   use proptest::prelude::*;
+  use std::cell::Cell;
+  use std::collections::VecDeque;
+
+  const BROADCAST_ADDR: u32 = u32::MAX;
+
+  fn oversize_chunks() -> Vec<String> {
+    vec![
+      "short".to_owned(),
+      "0123456789abcdef\n\n[2/3]".to_owned(),
+      "tail".to_owned(),
+    ]
+  }
+
+  fn port_list(names: &[&str]) -> Vec<String> {
+    names.iter().map(|&n| n.to_owned()).collect()
+  }
+
+  #[test]
+  fn the_only_usb_serial_port_is_selected() {
+    let ports = port_list(&["/dev/ttyS0", "/dev/ttyS1", "/dev/ttyACM0"]);
+    assert_eq!(select_serial_device(&ports).unwrap(), "/dev/ttyACM0");
+    let ports = port_list(&["/dev/tty.Bluetooth", "/dev/cu.usbserial-0001"]);
+    assert_eq!(
+      select_serial_device(&ports).unwrap(),
+      "/dev/cu.usbserial-0001"
+    );
+    assert_eq!(select_serial_device(&port_list(&["COM3"])).unwrap(), "COM3");
+  }
+
+  #[test]
+  fn several_usb_serial_ports_are_listed_in_the_error() {
+    let ports = port_list(&["/dev/ttyS0", "/dev/ttyUSB0", "/dev/ttyACM0"]);
+    let err = select_serial_device(&ports).unwrap_err().to_string();
+    assert!(
+      err.contains("several possible Meshtastic serial devices: /dev/ttyUSB0, /dev/ttyACM0."),
+      "{err}"
+    );
+  }
+
+  #[test]
+  fn no_usb_serial_port_lists_the_ports_found() {
+    let err = select_serial_device(&port_list(&["/dev/ttyS0"]))
+      .unwrap_err()
+      .to_string();
+    assert!(err.contains("found among: /dev/ttyS0."), "{err}");
+
+    let err = select_serial_device(&[]).unwrap_err().to_string();
+    assert!(err.starts_with("No serial ports found."), "{err}");
+  }
+
+  #[test]
+  fn broadcast_mode_replies_to_the_inbound_channel() {
+    let target = ReplyTarget::new(ReplyMode::Broadcast, 42, 3);
+    assert!(matches!(target.destination, PacketDestination::Broadcast));
+    assert_eq!(target.channel, 3);
+  }
+
+  #[test]
+  fn direct_mode_replies_to_the_sender() {
+    let target = ReplyTarget::new(ReplyMode::Direct, 42, 3);
+    assert!(matches!(
+      target.destination,
+      PacketDestination::Node(node) if node.id() == 42
+    ));
+    assert_eq!(target.channel, 3);
+  }
+
+  #[test]
+  fn fit_chunks_leaves_chunks_within_limit() {
+    let chunks = vec!["a".to_owned(), "bc".to_owned()];
+    for policy in [
+      OversizeChunkPolicy::Drop,
+      OversizeChunkPolicy::Truncate,
+      OversizeChunkPolicy::Split,
+    ] {
+      assert_eq!(fit_chunks(chunks.clone(), 2, policy), chunks);
+    }
+  }
+
+  #[test]
+  fn fit_chunks_drop_removes_oversize_chunk() {
+    assert_eq!(
+      fit_chunks(oversize_chunks(), 10, OversizeChunkPolicy::Drop),
+      vec!["short", "tail"]
+    );
+  }
+
+  #[test]
+  fn fit_chunks_truncate_cuts_oversize_chunk() {
+    assert_eq!(
+      fit_chunks(oversize_chunks(), 10, OversizeChunkPolicy::Truncate),
+      vec!["short", "0123456789", "tail"]
+    );
+  }
+
+  #[test]
+  fn fit_chunks_split_rechunks_oversize_chunk() {
+    assert_eq!(
+      fit_chunks(oversize_chunks(), 10, OversizeChunkPolicy::Split),
+      vec!["short", "0123456789", "abcdef\n\n[2", "/3]", "tail"]
+    );
+  }
+
+  #[test]
+  fn reply_within_max_reply_bytes_is_sent_whole() {
+    let chunks = oversize_chunks();
+    assert_eq!(cap_reply_bytes(chunks.clone(), 1000), chunks);
+  }
+
+  #[test]
+  fn sending_stops_at_max_reply_bytes_with_a_notice() {
+    let chunks: Vec<String> = (1..=5)
+      .map(|i| format!("{i:0>12}{}", chunk_footer(i, 5)))
+      .collect();
+
+    let capped = cap_reply_bytes(chunks, 60);
+
+    assert_eq!(
+      capped,
+      vec![
+        "000000000001\n\n[1/2]".to_owned(),
+        "000000000002\n\n[2/2]".to_owned(),
+        REPLY_TRUNCATED_NOTICE.to_owned(),
+      ]
+    );
+    assert!(capped.iter().map(String::len).sum::<usize>() <= 60);
+  }
+
+  #[test]
+  fn single_chunk_left_by_max_reply_bytes_has_no_footer() {
+    let chunks: Vec<String> = (1..=3)
+      .map(|i| format!("{i:0>12}{}", chunk_footer(i, 3)))
+      .collect();
+
+    assert_eq!(
+      cap_reply_bytes(chunks, 40),
+      vec!["000000000001".to_owned(), REPLY_TRUNCATED_NOTICE.to_owned()]
+    );
+  }
+
+  #[test]
+  fn fit_chunks_respects_char_boundaries() {
+    let chunks = vec!["añb".to_owned()];
+    assert_eq!(
+      fit_chunks(chunks.clone(), 2, OversizeChunkPolicy::Truncate),
+      vec!["a"]
+    );
+    assert_eq!(
+      fit_chunks(chunks, 2, OversizeChunkPolicy::Split),
+      vec!["a", "ñ", "b"]
+    );
+  }
+
+  fn no_backoff_retry(attempts: u32) -> ConnectRetry {
+    ConnectRetry {
+      attempts,
+      backoff_ms: 0,
+      max_backoff_ms: 0,
+    }
+  }
+
+  #[test]
+  fn help_replies_use_help_chunk_delay() {
+    let config = Config {
+      chunk_delay: 10000,
+      help_chunk_delay: Some(500),
+      ..Config::default()
+    };
+    assert_eq!(
+      ReplyKind::Help.chunk_delay(&config),
+      Duration::from_millis(500)
+    );
+    assert_eq!(
+      ReplyKind::Output.chunk_delay(&config),
+      Duration::from_millis(10000)
+    );
+  }
+
+  #[test]
+  fn help_replies_fall_back_to_chunk_delay() {
+    let config = Config {
+      chunk_delay: 10000,
+      ..Config::default()
+    };
+    assert_eq!(
+      ReplyKind::Help.chunk_delay(&config),
+      Duration::from_millis(10000)
+    );
+  }
+
+  #[test]
+  fn own_packets_are_ignored_on_every_channel() {
+    let channels = [1, 3];
+    for channel in [0, 1, 2, 3] {
+      assert!(!is_from_listened_peer(1234, channel, 1234, &channels));
+    }
+  }
+
+  #[test]
+  fn peer_packets_are_only_accepted_on_listened_channels() {
+    let channels = [1, 3];
+    assert!(is_from_listened_peer(5678, 1, 1234, &channels));
+    assert!(is_from_listened_peer(5678, 3, 1234, &channels));
+    assert!(!is_from_listened_peer(5678, 2, 1234, &channels));
+  }
+
+  #[test]
+  fn hops_are_counted_from_hop_start() {
+    assert_eq!(hops_away(3, 3), Some(0));
+    assert_eq!(hops_away(3, 1), Some(2));
+    assert_eq!(hops_away(7, 0), Some(7));
+    assert_eq!(hops_away(0, 3), None);
+  }
+
+  #[test]
+  fn broadcast_accepted_without_require_direct() {
+    assert!(is_addressed_to_node(BROADCAST_ADDR, 1234, false));
+    assert!(is_addressed_to_node(1234, 1234, false));
+  }
+
+  #[test]
+  fn broadcast_ignored_with_require_direct() {
+    assert!(!is_addressed_to_node(BROADCAST_ADDR, 1234, true));
+    assert!(!is_addressed_to_node(5678, 1234, true));
+  }
+
+  #[test]
+  fn directed_accepted_with_require_direct() {
+    assert!(is_addressed_to_node(1234, 1234, true));
+  }
+
+  #[test]
+  fn any_signal_accepted_without_thresholds() {
+    assert!(has_sufficient_signal(-20.0, -130, None, None));
+  }
+
+  #[test]
+  fn signal_at_or_above_thresholds_accepted() {
+    assert!(has_sufficient_signal(-5.0, -100, Some(-7.5), Some(-110)));
+    assert!(has_sufficient_signal(-7.5, -110, Some(-7.5), Some(-110)));
+  }
+
+  #[test]
+  fn signal_below_either_threshold_rejected() {
+    assert!(!has_sufficient_signal(-10.0, -100, Some(-7.5), Some(-110)));
+    assert!(!has_sufficient_signal(-5.0, -120, Some(-7.5), Some(-110)));
+    assert!(!has_sufficient_signal(-10.0, -120, Some(-7.5), None));
+  }
+
+  #[test]
+  fn unmeasured_rssi_is_not_rejected() {
+    assert!(has_sufficient_signal(0.0, 0, None, Some(-110)));
+  }
+
+  #[derive(Debug)]
+  struct StreamBuildError(io::Error);
+
+  impl Display for StreamBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      write!(f, "Failed to build serial stream")
+    }
+  }
+
+  impl Error for StreamBuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+      Some(&self.0)
+    }
+  }
+
+  #[test]
+  fn permission_denied_opening_serial_device_is_explained() {
+    let error = StreamBuildError(io::Error::from(io::ErrorKind::PermissionDenied));
+    let message = serial_open_error("/dev/ttyUSB0", error).to_string();
+    assert!(
+      message.starts_with("Permission denied opening serial device '/dev/ttyUSB0'."),
+      "unexpected message: {message}"
+    );
+    #[cfg(target_os = "linux")]
+    assert!(message.contains("dialout"));
+  }
+
+  #[test]
+  fn permission_denied_is_detected_from_message() {
+    let error = io::Error::other("Permission denied (os error 13)");
+    assert!(
+      serial_open_error("/dev/ttyACM0", error)
+        .to_string()
+        .starts_with("Permission denied opening serial device")
+    );
+  }
+
+  #[test]
+  fn other_serial_errors_are_unchanged() {
+    let error = StreamBuildError(io::Error::from(io::ErrorKind::NotFound));
+    assert_eq!(
+      serial_open_error("/dev/ttyUSB0", error).to_string(),
+      "Failed to build serial stream"
+    );
+  }
+
+  #[derive(Default)]
+  struct RecordingSender {
+    sent: Vec<(String, bool)>,
+    /// Whether each wait for an ack gets one, in order. Once used up, every wait does.
+    acks: VecDeque<bool>,
+    /// How many sends fail before they start succeeding
+    failures: u32,
+  }
+
+  impl TextSender for RecordingSender {
+    async fn send_text(
+      &mut self,
+      text: String,
+      _target: ReplyTarget,
+      want_ack: bool,
+    ) -> Result<()> {
+      if self.failures > 0 {
+        self.failures -= 1;
+        return Err(anyhow!("radio busy"));
+      }
+      self.sent.push((text, want_ack));
+      Ok(())
+    }
+
+    async fn wait_for_ack(&mut self, _timeout: Duration) -> bool {
+      self.acks.pop_front().unwrap_or(true)
+    }
+  }
+
+  async fn sent_acks(chunks: &[&str], ack_last_chunk: bool) -> Vec<bool> {
+    let config = Config {
+      ack_last_chunk,
+      ..Config::default()
+    };
+    let chunks: Vec<String> = chunks.iter().map(|chunk| chunk.to_string()).collect();
+    let mut sender = RecordingSender::default();
+    let target = ReplyTarget::new(ReplyMode::Broadcast, 1, 0);
+    let mut airtime = AirtimeBudget::new(0);
+
+    send_chunks(
+      &mut sender,
+      &chunks,
+      target,
+      Duration::ZERO,
+      &config,
+      &mut airtime,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+      sender.sent.iter().map(|(text, _)| text).collect::<Vec<_>>(),
+      chunks.iter().collect::<Vec<_>>()
+    );
+    sender
+      .sent
+      .into_iter()
+      .map(|(_, want_ack)| want_ack)
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn ack_last_chunk_requests_ack_only_for_final_chunk() {
+    assert_eq!(
+      sent_acks(&["[1/3] a", "[2/3] b", "[3/3] c"], true).await,
+      vec![false, false, true]
+    );
+    assert_eq!(sent_acks(&["only"], true).await, vec![true]);
+  }
+
+  async fn reliable_send(
+    chunks: &[&str],
+    acks: &[bool],
+    airtime: &mut AirtimeBudget,
+  ) -> Vec<(String, bool)> {
+    let config = Config {
+      reliable_send: true,
+      ack_retries: 2,
+      ..Config::default()
+    };
+    let chunks: Vec<String> = chunks.iter().map(|chunk| chunk.to_string()).collect();
+    let mut sender = RecordingSender {
+      acks: acks.iter().copied().collect(),
+      ..RecordingSender::default()
+    };
+    let target = ReplyTarget::new(ReplyMode::Broadcast, 1, 0);
+
+    send_chunks(
+      &mut sender,
+      &chunks,
+      target,
+      Duration::ZERO,
+      &config,
+      airtime,
+    )
+    .await
+    .unwrap();
+    sender.sent
+  }
+
+  #[tokio::test]
+  async fn reliable_send_resends_an_unacknowledged_chunk() {
+    assert_eq!(
+      reliable_send(
+        &["[1/2] a", "[2/2] b"],
+        &[false, true, true],
+        &mut AirtimeBudget::new(0)
+      )
+      .await,
+      vec![
+        ("[1/2] a".to_owned(), true),
+        ("[1/2] a".to_owned(), true),
+        ("[2/2] b".to_owned(), true),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn reliable_send_moves_on_after_the_retries() {
+    let sent = reliable_send(
+      &["[1/2] a", "[2/2] b"],
+      &[false, false, false],
+      &mut AirtimeBudget::new(0),
+    )
+    .await;
+    let texts: Vec<&str> = sent.iter().map(|(text, _)| text.as_str()).collect();
+    assert_eq!(texts, ["[1/2] a", "[1/2] a", "[1/2] a", "[2/2] b"]);
+  }
+
+  #[tokio::test]
+  async fn resends_count_against_the_airtime_budget() {
+    let mut airtime = AirtimeBudget::new(100);
+    reliable_send(&["[1/2] a", "[2/2] b"], &[false, true, true], &mut airtime).await;
+    assert_eq!(airtime.sent().map(|(_, bytes)| bytes).sum::<usize>(), 7);
+  }
+
+  #[tokio::test]
+  async fn resending_stops_once_the_airtime_budget_is_spent() {
+    let mut airtime = AirtimeBudget::new(7);
+    let sent = reliable_send(
+      &["[1/2] a", "[2/2] b"],
+      &[false, false, false],
+      &mut airtime,
+    )
+    .await;
+    let texts: Vec<&str> = sent.iter().map(|(text, _)| text.as_str()).collect();
+    assert_eq!(texts, ["[1/2] a", "[1/2] a", "[2/2] b"]);
+  }
+
+  fn retrying_config(send_retries: u32) -> Config {
+    Config {
+      send_retries,
+      retry_backoff_ms: Some(0),
+      ..Config::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn failed_send_is_retried_until_it_goes_through() {
+    let mut sender = RecordingSender {
+      failures: 2,
+      ..RecordingSender::default()
+    };
+    let target = ReplyTarget::new(ReplyMode::Broadcast, 1, 0);
+
+    let retries = send_with_retries(
+      &mut sender,
+      "part",
+      1,
+      target,
+      false,
+      Duration::ZERO,
+      &retrying_config(3),
+    )
+    .await;
+
+    assert_eq!(retries, Some(2));
+    assert_eq!(sender.sent, vec![("part".to_owned(), false)]);
+  }
+
+  #[tokio::test]
+  async fn chunk_that_cannot_be_sent_is_skipped() {
+    let chunks = vec!["[1/2] a".to_owned(), "[2/2] b".to_owned()];
+    let mut sender = RecordingSender {
+      failures: 2,
+      ..RecordingSender::default()
+    };
+    let target = ReplyTarget::new(ReplyMode::Broadcast, 1, 0);
+
+    send_chunks(
+      &mut sender,
+      &chunks,
+      target,
+      Duration::ZERO,
+      &retrying_config(1),
+      &mut AirtimeBudget::new(0),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(sender.sent, vec![("[2/2] b".to_owned(), false)]);
+  }
+
+  #[test]
+  fn retry_backoff_doubles_from_the_configured_delay() {
+    assert_eq!(
+      backoff_delay(500, MAX_RETRY_BACKOFF_MS, 3),
+      Duration::from_millis(2000)
+    );
+    assert_eq!(
+      backoff_delay(500, MAX_RETRY_BACKOFF_MS, 20),
+      Duration::from_millis(MAX_RETRY_BACKOFF_MS)
+    );
+  }
+
+  #[tokio::test]
+  async fn no_chunk_requests_ack_by_default() {
+    assert_eq!(
+      sent_acks(&["[1/2] a", "[2/2] b"], false).await,
+      vec![false, false]
+    );
+  }
+
+  #[test]
+  fn backoff_delay_doubles_each_attempt() {
+    assert_eq!(backoff_delay(100, 10_000, 1), Duration::from_millis(100));
+    assert_eq!(backoff_delay(100, 10_000, 2), Duration::from_millis(200));
+    assert_eq!(backoff_delay(100, 10_000, 3), Duration::from_millis(400));
+    assert_eq!(backoff_delay(100, 10_000, 4), Duration::from_millis(800));
+  }
+
+  #[test]
+  fn backoff_delay_is_capped_at_max() {
+    assert_eq!(backoff_delay(1000, 5000, 4), Duration::from_millis(5000));
+    assert_eq!(backoff_delay(1000, 5000, 64), Duration::from_millis(5000));
+  }
+
+  #[tokio::test]
+  async fn retry_with_backoff_returns_first_success() {
+    let calls = Cell::new(0);
+    let result = retry_with_backoff(&no_backoff_retry(3), || {
+      calls.set(calls.get() + 1);
+      async { Ok::<_, anyhow::Error>(42) }
+    })
+    .await;
+
+    assert_eq!(result.unwrap(), 42);
+    assert_eq!(calls.get(), 1);
+  }
+
+  #[tokio::test]
+  async fn retry_with_backoff_retries_until_success() {
+    let calls = Cell::new(0);
+    let result = retry_with_backoff(&no_backoff_retry(5), || {
+      calls.set(calls.get() + 1);
+      let attempt = calls.get();
+      async move {
+        if attempt < 3 {
+          Err(anyhow!("not ready"))
+        } else {
+          Ok(attempt)
+        }
+      }
+    })
+    .await;
+
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(calls.get(), 3);
+  }
+
+  #[tokio::test]
+  async fn retry_with_backoff_gives_up_after_configured_attempts() {
+    let calls = Cell::new(0);
+    let result: Result<()> = retry_with_backoff(&no_backoff_retry(4), || {
+      calls.set(calls.get() + 1);
+      async { Err(anyhow!("not ready")) }
+    })
+    .await;
+
+    let err = format!("{:#}", result.unwrap_err());
+    assert_eq!(calls.get(), 4);
+    assert!(
+      err.contains("Failed to connect after 4 attempt(s)"),
+      "unexpected error: {err}"
+    );
+    assert!(err.contains("not ready"), "unexpected error: {err}");
+  }
+
+  #[tokio::test]
+  async fn retry_with_backoff_single_attempt_does_not_retry() {
+    let calls = Cell::new(0);
+    let result: Result<()> = retry_with_backoff(&no_backoff_retry(1), || {
+      calls.set(calls.get() + 1);
+      async { Err(anyhow!("not ready")) }
+    })
+    .await;
+
+    assert!(result.is_err());
+    assert_eq!(calls.get(), 1);
+  }
 
   #[test]
   fn chunk_empty_string_returns_empty_vec() {
-    let chunks = chunk_lines_with_footer("", 10);
+    let chunks = chunk_lines_with_footer("", 10, false);
     assert!(chunks.is_empty());
   }
 
   #[test]
   fn chunk_single_short_line_within_budget_no_footer() {
     let text = "hello";
-    let chunks = chunk_lines_with_footer(text, 10);
+    let chunks = chunk_lines_with_footer(text, 10, false);
     assert_eq!(chunks, vec![text.to_string()]);
   }
 
   #[test]
   fn chunk_two_lines_fit_in_one_chunk_no_footer() {
     let text = "alpha\nbeta\n";
-    let chunks = chunk_lines_with_footer(text, 100);
+    let chunks = chunk_lines_with_footer(text, 100, false);
     assert_eq!(chunks, vec![text.to_string()]);
   }
 
   #[test]
   fn chunk_two_lines_split_with_footers() {
     let text = "1234567\nabcdefg\n";
-    let chunks = chunk_lines_with_footer(text, 15);
+    let chunks = chunk_lines_with_footer(text, 15, false);
     assert_eq!(chunks.len(), 2);
     assert!(chunks[0].starts_with("1234567\n"));
     assert!(chunks[0].ends_with("[1/2]"));
@@ -188,7 +1495,7 @@ mod tests {
   #[test]
   fn chunk_single_long_line_truncates_to_max_bytes() {
     let text = "abcdefghij";
-    let chunks = chunk_lines_with_footer(text, 5);
+    let chunks = chunk_lines_with_footer(text, 5, false);
     assert_eq!(chunks, vec!["abcde".to_string()]);
   }
 
@@ -196,7 +1503,7 @@ mod tests {
   fn chunk_footer_accounting_keeps_chunks_within_max_bytes() {
     let text = "1234567\nabcdefg\n";
     let max_bytes = 15;
-    let chunks = chunk_lines_with_footer(text, max_bytes);
+    let chunks = chunk_lines_with_footer(text, max_bytes, false);
     for chunk in &chunks {
       assert!(chunk.len() <= max_bytes);
     }
@@ -205,21 +1512,21 @@ mod tests {
   #[test]
   fn chunk_respects_utf8_char_boundaries() {
     let text = "héllo";
-    let chunks = chunk_lines_with_footer(text, 2);
+    let chunks = chunk_lines_with_footer(text, 2, false);
     assert_eq!(chunks, vec!["h".to_string()]);
   }
 
   #[test]
   fn chunk_handles_trailing_newline() {
     let text = "hello\n";
-    let chunks = chunk_lines_with_footer(text, 100);
+    let chunks = chunk_lines_with_footer(text, 100, false);
     assert_eq!(chunks, vec![text.to_string()]);
   }
 
   #[test]
   fn chunk_three_chunks_have_correct_footers() {
     let text = "1234567\nabcdefg\nqwertyu\n";
-    let chunks = chunk_lines_with_footer(text, 15);
+    let chunks = chunk_lines_with_footer(text, 15, false);
     assert_eq!(chunks.len(), 3);
     assert!(chunks[0].ends_with("[1/3]"));
     assert!(chunks[1].ends_with("[2/3]"));
@@ -229,17 +1536,102 @@ mod tests {
   #[test]
   fn chunk_max_bytes_one_still_works() {
     let text = "ab";
-    let chunks = chunk_lines_with_footer(text, 1);
+    let chunks = chunk_lines_with_footer(text, 1, false);
     assert_eq!(chunks, vec!["a".to_string()]);
   }
 
+  #[test]
+  fn wrapped_long_sentence_splits_at_spaces() {
+    let text = "the quick brown fox jumps over the lazy dog";
+    let chunks = chunk_lines_with_footer(text, 20, true);
+    assert_eq!(
+      chunks,
+      vec![
+        "the quick\n\n[1/4]",
+        "brown fox\n\n[2/4]",
+        "jumps over\n\n[3/4]",
+        "the lazy dog\n\n[4/4]",
+      ]
+    );
+  }
+
+  #[test]
+  fn wrapped_long_line_keeps_all_words_when_footers_fit() {
+    let text = "the quick brown fox jumps over the lazy dog\nend\n";
+    let chunks = chunk_lines_with_footer(text, 30, true);
+    assert_eq!(
+      chunks,
+      vec![
+        "the quick brown fox\n\n[1/3]",
+        "jumps over the lazy dog\n\n[2/3]",
+        "end\n\n\n[3/3]",
+      ]
+    );
+  }
+
+  #[test]
+  fn wrapped_chunks_leave_room_for_two_digit_footers() {
+    let text = ["word"; 12].join(" ");
+    let chunks = chunk_lines_with_footer(&text, 14, true);
+    assert_eq!(chunks.len(), 12);
+    assert_eq!(chunks[11], "word\n\n[12/12]");
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 14));
+  }
+
+  #[test]
+  fn wrapped_word_longer_than_chunk_is_hard_cut() {
+    let text = "supercalifragilistic ok";
+    let chunks = chunk_lines_with_footer(text, 16, true);
+    assert_eq!(chunks[0], "supercali\n\n[1/3]");
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 16));
+  }
+
+  #[test]
+  fn wrapping_respects_utf8_char_boundaries() {
+    let text = "héllo wörld again";
+    let chunks = chunk_lines_with_footer(text, 14, true);
+    assert_eq!(
+      chunks,
+      vec!["héllo\n\n[1/3]", "wörld\n\n[2/3]", "again\n\n[3/3]"]
+    );
+  }
+
+  const HELP: &str =
+    "Commands:\n  !a - first\n  !b\n  !c\n  !d\n  !e\n\nSend !<command> --help for details.";
+
+  #[test]
+  fn help_chunks_hold_at_most_the_configured_entries() {
+    let chunks = chunk_help_with_footer(HELP, 200, false, 2);
+    assert_eq!(
+      chunks,
+      vec![
+        "Commands:\n  !a - first\n  !b\n\n\n[1/3]",
+        "  !c\n  !d\n\n\n[2/3]",
+        "  !e\n\nSend !<command> --help for details.\n\n[3/3]",
+      ]
+    );
+  }
+
+  #[test]
+  fn help_within_the_entry_limit_is_one_chunk() {
+    assert_eq!(chunk_help_with_footer(HELP, 200, false, 5), vec![HELP]);
+  }
+
+  #[test]
+  fn help_page_over_the_byte_budget_is_split_further() {
+    let chunks = chunk_help_with_footer(HELP, 40, false, 5);
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 40));
+    assert!(chunks[0].starts_with("Commands:\n  !a - first\n"));
+  }
+
   proptest! {
       #[test]
       fn chunk_output_never_exceeds_max_bytes(
           text in "[ -~\n]{0,500}",
           max_bytes in 10usize..256
       ) {
-          let chunks = chunk_lines_with_footer(&text, max_bytes);
+          let chunks = chunk_lines_with_footer(&text, max_bytes, false);
           for chunk in &chunks {
               prop_assert!(
                   chunk.len() <= max_bytes,
@@ -249,11 +1641,21 @@ mod tests {
           }
       }
 
+      #[test]
+      fn wrapped_chunks_never_exceed_max_bytes(
+          text in "[ -~\n]{0,500}",
+          max_bytes in 10usize..256
+      ) {
+          for chunk in &chunk_lines_with_footer(&text, max_bytes, true) {
+              prop_assert!(chunk.len() <= max_bytes, "chunk too long: {:?}", chunk);
+          }
+      }
+
       #[test]
       fn chunk_preserves_all_content_when_single_chunk(
           text in "[a-z]{1,50}"
       ) {
-          let chunks = chunk_lines_with_footer(&text, 1000);
+          let chunks = chunk_lines_with_footer(&text, 1000, false);
           prop_assert_eq!(chunks.len(), 1);
           prop_assert_eq!(&chunks[0], &text);
       }
@@ -263,7 +1665,7 @@ mod tests {
           text in "[a-z ]{20,200}\n[a-z ]{20,200}\n",
           max_bytes in 20usize..60
       ) {
-          let chunks = chunk_lines_with_footer(&text, max_bytes);
+          let chunks = chunk_lines_with_footer(&text, max_bytes, false);
           let total = chunks.len();
           if total > 1 {
               for (i, chunk) in chunks.iter().enumerate() {
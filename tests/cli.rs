@@ -53,6 +53,22 @@ fn unknown_subcommand_exits_with_error() {
   meshexec().arg("foobar").assert().failure();
 }
 
+#[test]
+fn log_to_file_accepts_false() {
+  meshexec()
+    .args(["--log-to-file", "false", "serve", "--help"])
+    .assert()
+    .success();
+}
+
+#[test]
+fn log_to_file_rejects_non_bool_value() {
+  meshexec()
+    .args(["--log-to-file", "maybe", "config-path"])
+    .assert()
+    .failure();
+}
+
 #[test]
 fn log_level_accepts_all_valid_values() {
   for level in ["off", "error", "warn", "info", "debug", "trace"] {
@@ -132,6 +148,135 @@ fn tail_logs_fails_at_runtime_not_arg_parsing() {
     .stderr(predicates::str::contains("Usage").not());
 }
 
+#[test]
+fn replay_prints_resolution_for_each_recorded_message() {
+  meshexec()
+    .args([
+      "--log-to-file",
+      "false",
+      "--config-file",
+      "tests/fixtures/replay/config.yaml",
+      "replay",
+      "tests/fixtures/replay/messages.txt",
+    ])
+    .assert()
+    .success()
+    .stdout(
+      predicates::str::contains("> !greet Alice\nrun: echo \"hello ${name}\"\n  name=Alice\n")
+        .and(predicates::str::contains(
+          "> !greet\nrun: echo \"hello ${name}\"\n  name=World\n",
+        ))
+        .and(predicates::str::contains(
+          "> just chatting\nignored: not a command\n",
+        ))
+        .and(predicates::str::contains(
+          "> !unknown\nreply:\n  Unknown command: !unknown\n",
+        )),
+    );
+}
+
+#[test]
+fn replay_export_prints_sourceable_exports() {
+  meshexec()
+    .args([
+      "--log-to-file",
+      "false",
+      "--config-file",
+      "tests/fixtures/replay/config.yaml",
+      "replay",
+      "--export",
+      "tests/fixtures/replay/export.txt",
+    ])
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      "export env='prod'\\''s'\nexport service='api'\ndeploy.sh \"${service}\" --env \"${env}\"\n",
+    ));
+}
+
+#[test]
+fn replay_fails_for_missing_messages_file() {
+  meshexec()
+    .args([
+      "--log-to-file",
+      "false",
+      "--config-file",
+      "tests/fixtures/replay/config.yaml",
+      "replay",
+      "tests/fixtures/replay/missing.txt",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("Failed to read messages"));
+}
+
+#[test]
+fn validate_reports_valid_config() {
+  meshexec()
+    .args([
+      "--log-to-file",
+      "false",
+      "--config-file",
+      "tests/fixtures/replay/config.yaml",
+      "validate",
+    ])
+    .assert()
+    .success()
+    .stdout("config OK: 2 commands\n");
+}
+
+#[test]
+fn validate_fails_for_invalid_config() {
+  meshexec()
+    .args([
+      "--log-to-file",
+      "false",
+      "--config-file",
+      "tests/fixtures/validate/invalid.yaml",
+      "validate",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains(
+      "Command 'broken': must have either 'command' or 'commands'",
+    ));
+}
+
+#[test]
+fn validate_fails_for_missing_config() {
+  meshexec()
+    .args([
+      "--log-to-file",
+      "false",
+      "--config-file",
+      "tests/fixtures/validate/missing.yaml",
+      "validate",
+    ])
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("config invalid:"));
+}
+
+#[test]
+fn completions_prints_script_for_each_shell() {
+  for shell in ["bash", "zsh", "fish", "powershell"] {
+    meshexec()
+      .args(["--log-to-file", "false", "completions", shell])
+      .assert()
+      .success()
+      .stdout(predicates::str::contains("meshexec").and(predicates::str::contains("validate")));
+  }
+}
+
+#[test]
+fn completions_rejects_unknown_shell() {
+  meshexec()
+    .args(["completions", "tcsh"])
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("invalid value 'tcsh'"));
+}
+
 #[test]
 fn config_path_help_shows_description() {
   meshexec()